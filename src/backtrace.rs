@@ -0,0 +1,202 @@
+//! # Backtraces
+//!
+//! The kernel is built with frame pointers (see `.cargo/config`), so every
+//! function saves its return address and the caller's frame pointer right
+//! below its own frame pointer:
+//!
+//! ```text
+//! fp - 8   return address
+//! fp - 16  caller's fp
+//! ```
+//!
+//! Following that chain gives us the return addresses, which we turn into
+//! function names with [`KSYMS`]. The table is filled in after linking by
+//! `tools/ksyms.py`, which the cargo runner calls before it starts QEMU. If
+//! that didn't happen, we print bare addresses.
+
+use core::{arch::asm, str};
+
+use crate::page::heap_end;
+
+extern "C" {
+    static TEXT_START: usize;
+    static TEXT_END: usize;
+}
+
+/// Where RAM starts on the virt machine. Stacks live somewhere above.
+const MEMORY_START: usize = 0x8000_0000;
+/// We give up after this many frames. A corrupted stack could make us go
+/// in circles otherwise.
+const MAX_DEPTH: usize = 32;
+/// Room for the symbol table. Debug builds need the most, and this leaves
+/// them plenty of room to grow. `tools/ksyms.py` says so if it ever isn't
+/// enough.
+const KSYMS_SIZE: usize = 2 * 1024 * 1024;
+const KSYMS_MAGIC: &[u8; 8] = b"KSYMTAB\0";
+// Header: magic and the number of entries
+const KSYMS_HEADER: usize = 16;
+// Entry: address (u64), size (u32), name offset (u32)
+const KSYMS_ENTRY: usize = 16;
+
+/// The symbol table. It stays "KSYMNONE" until `tools/ksyms.py` has written
+/// the real table over it.
+#[no_mangle]
+#[used]
+static KSYMS: [u8; KSYMS_SIZE] = empty_ksyms();
+
+const fn empty_ksyms() -> [u8; KSYMS_SIZE] {
+    let mut table = [0; KSYMS_SIZE];
+    let magic = b"KSYMNONE";
+    let mut i = 0;
+    while i < magic.len() {
+        table[i] = magic[i];
+        i += 1;
+    }
+    table
+}
+
+/// The table, as far as the compiler is concerned, is full of zeroes. We
+/// hide where the pointer comes from, so it can't fold the reads away.
+fn ksyms() -> *const u8 {
+    let table: *const u8;
+    unsafe {
+        asm!("mv {}, {}", out(reg) table, in(reg) KSYMS.as_ptr());
+    }
+    table
+}
+
+unsafe fn read_u32(p: *const u8) -> u32 {
+    (p as *const u32).read_unaligned()
+}
+
+unsafe fn read_u64(p: *const u8) -> u64 {
+    (p as *const u64).read_unaligned()
+}
+
+/// Find the function that contains `addr` and return its name together
+/// with how far into the function `addr` is.
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    let table = ksyms();
+    unsafe {
+        if core::slice::from_raw_parts(table, 8) != KSYMS_MAGIC {
+            return None;
+        }
+        let count = read_u64(table.add(8)) as usize;
+        let entries = table.add(KSYMS_HEADER);
+        let names = entries.add(count * KSYMS_ENTRY);
+        // Binary search for the last entry that starts at or before addr.
+        let (mut lo, mut hi) = (0, count);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if read_u64(entries.add(mid * KSYMS_ENTRY)) as usize <= addr {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        if lo == 0 {
+            return None;
+        }
+        let entry = entries.add((lo - 1) * KSYMS_ENTRY);
+        let start = read_u64(entry) as usize;
+        let size = read_u32(entry.add(8)) as usize;
+        if size != 0 && addr >= start + size {
+            return None;
+        }
+        let name = names.add(read_u32(entry.add(12)) as usize);
+        let mut len = 0;
+        while *name.add(len) != 0 {
+            len += 1;
+        }
+        let name = str::from_utf8(core::slice::from_raw_parts(name, len)).ok()?;
+        Some((name, addr - start))
+    }
+}
+
+fn print_frame(depth: usize, addr: usize, lookup: usize) {
+    match symbolize(lookup) {
+        Some((name, offset)) => println!(
+            "  #{:<2} 0x{:016x} {}+0x{:x}",
+            depth,
+            addr,
+            name,
+            offset + addr - lookup
+        ),
+        None => println!("  #{:<2} 0x{:016x} ???", depth, addr),
+    }
+}
+
+fn is_text(addr: usize) -> bool {
+    unsafe { addr >= TEXT_START && addr < TEXT_END }
+}
+
+fn is_stack(fp: usize) -> bool {
+    fp % 8 == 0 && fp > MEMORY_START && fp <= heap_end()
+}
+
+/// Follow the frame pointers from `fp` and call `f` with every return
+/// address, until it returns false.
+fn walk(fp: usize, mut f: impl FnMut(usize) -> bool) {
+    let mut fp = fp;
+    let mut depth = 1;
+    while depth < MAX_DEPTH && is_stack(fp) {
+        let (ra, caller_fp) =
+            unsafe { (*((fp - 8) as *const usize), *((fp - 16) as *const usize)) };
+        if !is_text(ra) || !f(ra) {
+            break;
+        }
+        // Callers' frames are further up the stack. Anything else means the
+        // chain is broken.
+        if caller_fp <= fp {
+            break;
+        }
+        fp = caller_fp;
+        depth += 1;
+    }
+}
+
+/// Print the call chain that starts at `pc` with the frame pointer `fp`.
+pub fn print_backtrace(pc: usize, fp: usize) {
+    println!("Backtrace:");
+    print_frame(0, pc, pc);
+    let mut depth = 1;
+    walk(fp, |ra| {
+        // The return address is the instruction after the call. That may
+        // already be the next function if the call was the last thing in
+        // this one, so look up the call itself.
+        print_frame(depth, ra, ra - 4);
+        depth += 1;
+        true
+    });
+}
+
+/// Fill `out` with the return addresses of whoever called us, innermost
+/// first. Returns how many there were.
+#[inline(never)]
+pub fn callers(out: &mut [usize]) -> usize {
+    let fp: usize;
+    unsafe {
+        asm!("mv {}, s0", out(reg) fp);
+    }
+    let mut n = 0;
+    walk(fp, |ra| {
+        if n == out.len() {
+            return false;
+        }
+        out[n] = ra;
+        n += 1;
+        true
+    });
+    n
+}
+
+/// Print how we got here.
+#[inline(never)]
+pub fn print_current() {
+    let pc: usize;
+    let fp: usize;
+    unsafe {
+        asm!("auipc {}, 0", "mv {}, s0", out(reg) pc, out(reg) fp);
+    }
+    print_backtrace(pc, fp);
+}
@@ -0,0 +1,320 @@
+//! # Console
+//!
+//! The serial console as a terminal. Every byte that arrives on the UART is
+//! handed to [`receive`], which runs it through the line discipline:
+//!
+//! * In canonical mode (the default) input is collected into a line that
+//!   can be edited with backspace and ^U. The line only becomes readable
+//!   once it is finished with enter or ^D. ^D on an empty line is end of
+//!   file.
+//! * In raw mode every byte is readable right away.
+//!
+//! Unless `ISIG` is turned off, ^C, ^\ and ^Z send SIGINT, SIGQUIT and
+//! SIGTSTP to the foreground process instead of being read.
+//!
+//! Readers block in [`ProcessState::Waiting`] and retry their system call
+//! once input arrives, just like pipe readers. The modes are switched
+//! with the `TCGETS` and `TCSETS` ioctls, which use the Linux `termios`
+//! layout. `TIOCGWINSZ` reports the size of the terminal, which we can't
+//! know over a serial line, so it is always 80x24.
+//!
+//! [`ProcessState::Waiting`]: crate::process::ProcessState::Waiting
+
+use alloc::{collections::VecDeque, vec::Vec};
+
+use crate::{
+    process::{set_running, FOREGROUND_PID},
+    signal::{send_signal, SIGINT, SIGQUIT, SIGTSTP},
+};
+
+/// How many bytes of input we hold on to. Anything typed beyond that is
+/// dropped, like a real terminal does.
+pub const INPUT_SIZE: usize = 4096;
+
+// c_lflag bits of termios that we understand
+/// Turn ^C, ^\ and ^Z into signals
+pub const ISIG: u32 = 0o000001;
+/// Canonical mode: input is made available line by line
+pub const ICANON: u32 = 0o000002;
+/// Echo input characters
+pub const ECHO: u32 = 0o000010;
+/// Erase characters on the screen with backspace
+pub const ECHOE: u32 = 0o000020;
+
+// The ioctl requests that the console answers.
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+pub const TIOCGWINSZ: usize = 0x5413;
+
+// Control characters
+const CTRL_C: u8 = 0x03;
+const CTRL_D: u8 = 0x04;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const CTRL_Z: u8 = 0x1a;
+const CTRL_BACKSLASH: u8 = 0x1c;
+const DELETE: u8 = 0x7f;
+
+/// The `termios` structure as Linux lays it out for riscv64. We only look at
+/// `c_lflag`, the rest is kept so that programs can read back what they set.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; 19],
+}
+
+/// `struct winsize`, the answer to `TIOCGWINSZ`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Winsize {
+    pub ws_row: u16,
+    pub ws_col: u16,
+    pub ws_xpixel: u16,
+    pub ws_ypixel: u16,
+}
+
+/// The size of every terminal we pretend to have
+pub const WINSIZE: Winsize = Winsize {
+    ws_row: 24,
+    ws_col: 80,
+    ws_xpixel: 0,
+    ws_ypixel: 0,
+};
+
+impl Termios {
+    const fn new() -> Self {
+        Self {
+            c_iflag: 0,
+            c_oflag: 0,
+            c_cflag: 0,
+            c_lflag: ISIG | ICANON | ECHO | ECHOE,
+            c_line: 0,
+            c_cc: [0; 19],
+        }
+    }
+}
+
+/// What happened to a read from the console.
+pub enum ConsoleResult {
+    /// This many bytes were read. 0 means end of file.
+    Done(usize),
+    /// Nothing is there yet, the caller has to wait and try again.
+    WouldBlock,
+}
+
+struct Console {
+    termios: Termios,
+    /// The line being edited in canonical mode
+    line: Vec<u8>,
+    /// Finished lines in canonical mode. An empty line is end of file.
+    lines: VecDeque<Vec<u8>>,
+    /// Bytes that can be read in raw mode
+    raw: VecDeque<u8>,
+    /// How many bytes are in `line`, `lines` and `raw` together
+    buffered: usize,
+    /// PIDs of processes waiting for input
+    waiters: VecDeque<u16>,
+}
+
+impl Console {
+    fn new() -> Self {
+        Self {
+            termios: Termios::new(),
+            line: Vec::new(),
+            lines: VecDeque::new(),
+            raw: VecDeque::new(),
+            buffered: 0,
+            waiters: VecDeque::new(),
+        }
+    }
+
+    fn is_canonical(&self) -> bool {
+        self.termios.c_lflag & ICANON != 0
+    }
+
+    fn echo(&self, c: u8) {
+        if self.termios.c_lflag & ECHO != 0 {
+            match c {
+                b'\r' | b'\n' => println!(),
+                _ => print!("{}", c as char),
+            }
+        }
+    }
+
+    /// Erase the last character of the line being edited.
+    fn erase(&mut self) -> bool {
+        if self.line.pop().is_none() {
+            return false;
+        }
+        self.buffered -= 1;
+        if self.termios.c_lflag & (ECHO | ECHOE) == ECHO | ECHOE {
+            print!("{} {}", BACKSPACE as char, BACKSPACE as char);
+        }
+        true
+    }
+
+    /// Make the line being edited readable.
+    fn finish_line(&mut self) {
+        let line = core::mem::take(&mut self.line);
+        self.lines.push_back(line);
+        while let Some(pid) = self.waiters.pop_front() {
+            set_running(pid);
+        }
+    }
+
+    /// Run one received byte through the line discipline.
+    fn input(&mut self, c: u8) {
+        if self.termios.c_lflag & ISIG != 0 {
+            let signo = match c {
+                CTRL_C => Some(SIGINT),
+                CTRL_BACKSLASH => Some(SIGQUIT),
+                CTRL_Z => Some(SIGTSTP),
+                _ => None,
+            };
+            if let Some(signo) = signo {
+                // The current line goes away with the signal.
+                self.buffered -= self.line.len();
+                self.line.clear();
+                print!("^{}", (c + b'@') as char);
+                println!();
+                let fg = unsafe { FOREGROUND_PID };
+                if fg != 0 {
+                    let _ = send_signal(fg, signo);
+                }
+                return;
+            }
+        }
+        if !self.is_canonical() {
+            if self.buffered < INPUT_SIZE {
+                self.raw.push_back(c);
+                self.buffered += 1;
+                self.echo(c);
+                while let Some(pid) = self.waiters.pop_front() {
+                    set_running(pid);
+                }
+            }
+            return;
+        }
+        match c {
+            BACKSPACE | DELETE => {
+                self.erase();
+            }
+            CTRL_U => while self.erase() {},
+            CTRL_D => {
+                // Hand over what we have without a newline. If that's
+                // nothing, the reader sees end of file.
+                self.finish_line();
+            }
+            b'\r' | b'\n' => {
+                // We always leave room for the newline.
+                self.line.push(b'\n');
+                self.buffered += 1;
+                self.echo(c);
+                self.finish_line();
+            }
+            _ => {
+                if self.buffered < INPUT_SIZE - 1 {
+                    self.line.push(c);
+                    self.buffered += 1;
+                    self.echo(c);
+                }
+            }
+        }
+    }
+
+    fn read(&mut self, pid: u16, buffer: &mut [u8]) -> ConsoleResult {
+        if buffer.is_empty() {
+            // Nothing was asked for, so leave the input alone. Popping a
+            // line here could eat an end of file.
+            return ConsoleResult::Done(0);
+        }
+        if self.is_canonical() {
+            // A read never returns more than one line.
+            let mut line = match self.lines.pop_front() {
+                Some(line) => line,
+                None => {
+                    self.waiters.push_back(pid);
+                    return ConsoleResult::WouldBlock;
+                }
+            };
+            let n = buffer.len().min(line.len());
+            buffer[..n].copy_from_slice(&line[..n]);
+            self.buffered -= n;
+            if n < line.len() {
+                // Whatever doesn't fit is left for the next read.
+                line.drain(..n);
+                self.lines.push_front(line);
+            }
+            ConsoleResult::Done(n)
+        } else {
+            if self.raw.is_empty() {
+                self.waiters.push_back(pid);
+                return ConsoleResult::WouldBlock;
+            }
+            let n = buffer.len().min(self.raw.len());
+            for (dst, src) in buffer.iter_mut().zip(self.raw.drain(..n)) {
+                *dst = src;
+            }
+            self.buffered -= n;
+            ConsoleResult::Done(n)
+        }
+    }
+
+    fn set_termios(&mut self, termios: Termios) {
+        let was_canonical = self.is_canonical();
+        self.termios = termios;
+        if was_canonical && !self.is_canonical() {
+            // Everything typed so far becomes readable as is.
+            for line in self.lines.drain(..) {
+                self.raw.extend(line);
+            }
+            self.raw.extend(self.line.drain(..));
+            while let Some(pid) = self.waiters.pop_front() {
+                set_running(pid);
+            }
+        } else if !was_canonical && self.is_canonical() && !self.raw.is_empty() {
+            // Unread raw input becomes the start of the next line.
+            let mut line: Vec<u8> = self.raw.drain(..).collect();
+            line.append(&mut self.line);
+            self.line = line;
+        }
+    }
+}
+
+// Just like the process list, this is an Option since we can't allocate
+// the buffers at compile time.
+static mut CONSOLE: Option<Console> = None;
+
+/// Run `f` on the console, creating it the first time around.
+fn with_console<R>(f: impl FnOnce(&mut Console) -> R) -> R {
+    unsafe {
+        let mut console = CONSOLE.take().unwrap_or_else(Console::new);
+        let ret = f(&mut console);
+        CONSOLE.replace(console);
+        ret
+    }
+}
+
+/// A byte arrived on the UART. This is called from the interrupt handler.
+pub fn receive(c: u8) {
+    with_console(|console| console.input(c));
+}
+
+/// Read from the console for process `pid`. If there is nothing to read
+/// yet, `pid` is woken up once there is.
+pub fn read(pid: u16, buffer: &mut [u8]) -> ConsoleResult {
+    with_console(|console| console.read(pid, buffer))
+}
+
+pub fn get_termios() -> Termios {
+    with_console(|console| console.termios)
+}
+
+pub fn set_termios(termios: Termios) {
+    with_console(|console| console.set_termios(termios));
+}
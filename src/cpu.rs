@@ -6,6 +6,8 @@
 
 use core::arch::asm;
 
+use crate::{fdt, page::PAGE_SIZE};
+
 /// The frequency of QEMU timer interrupt
 pub const FREQ: u64 = 10_000_000;
 /// Switch process context of process 250 time per second
@@ -32,6 +34,9 @@ pub enum CpuMode {
     Machine = 3,
 }
 
+/// The machine interrupt enable bit of `mstatus`
+pub const MSTATUS_MIE: usize = 1 << 3;
+
 /// General purpose registers of RISC-V architecture
 #[repr(usize)]
 pub enum Registers {
@@ -132,6 +137,9 @@ pub struct TrapFrame {
     pub pid: usize, // 544
     /// Address translation mode scheme
     pub mode: usize, // 552
+    /// What a supervisor-mode kernel thread reads and writes as `mstatus`.
+    /// Only MIE means anything, see `trap::emulate_csr`.
+    pub mstatus: usize, // 560
 }
 
 /// Rust requires that we initialize our structures
@@ -153,6 +161,7 @@ impl TrapFrame {
             qm: 1,
             pid: 0,
             mode: 0,
+            mstatus: 0,
         }
     }
 }
@@ -192,6 +201,22 @@ pub fn mie_write(val: usize) {
     }
 }
 
+/// Set Machine Counter-Enable register, which says which counters the
+/// modes below may read
+pub fn mcounteren_write(val: usize) {
+    unsafe {
+        asm!("csrw mcounteren, {}", in(reg) val);
+    }
+}
+
+/// Set Supervisor Counter-Enable register, which says which counters user
+/// mode may read
+pub fn scounteren_write(val: usize) {
+    unsafe {
+        asm!("csrw scounteren, {}", in(reg) val);
+    }
+}
+
 /// Set Machine Status register
 pub fn mstatus_write(val: usize) {
     unsafe {
@@ -345,12 +370,81 @@ pub fn satp_fence_asid(asid: usize) {
     }
 }
 
-/// Memory mapped value of machine timer register
-const MMIO_MTIME: *const u64 = 0x0200_BFF8 as *const u64;
+/// Forget the translations of every address space, after changing the page
+/// tables of more than one.
+pub fn satp_fence_all() {
+    unsafe {
+        asm!("sfence.vma zero, zero");
+    }
+}
+
+// The fields of a pmpcfg entry
+const PMP_RWX: usize = 0b111;
+/// The entry covers a naturally aligned power of two bytes.
+const PMP_NAPOT: usize = 0b11 << 3;
+/// The entry holds for machine mode as well, and stays until reset.
+const PMP_LOCK: usize = 1 << 7;
+
+/// Make the page at `addr` off limits to this hart, machine mode included.
+/// Machine mode doesn't translate addresses, so physical memory protection
+/// is the only way to guard a stack it runs on.
+///
+/// The guard is PMP entry 0, which is locked, so this only works once per
+/// hart. Entry 1 lets supervisor and user mode at everything else, since
+/// once an entry is in use, anything no entry matches is off limits to
+/// them.
+pub fn pmp_guard(addr: usize) {
+    // A NAPOT region of 2^n bytes has n - 3 ones at the bottom of its
+    // address, and all ones is all of memory.
+    let guard = (addr >> 2) | (PAGE_SIZE / 8 - 1);
+    let cfg = (PMP_LOCK | PMP_NAPOT) | (PMP_NAPOT | PMP_RWX) << 8;
+    unsafe {
+        asm!("csrw pmpaddr0, {}", in(reg) guard);
+        asm!("csrw pmpaddr1, {}", in(reg) usize::MAX);
+        asm!("csrw pmpcfg0, {}", in(reg) cfg);
+    }
+}
+
+/// Where the CLINT is on the QEMU virt machine, if the device tree doesn't
+/// say
+const CLINT_BASE: usize = 0x0200_0000;
+// CLINT registers, as offsets from its base. MSIP and MTIMECMP are per
+// hart.
+const CLINT_MSIP: usize = 0x0000;
+const CLINT_MTIMECMP: usize = 0x4000;
+const CLINT_MTIME: usize = 0xbff8;
+
+/// How many bytes of registers the CLINT has
+const CLINT_SIZE: usize = 0x1_0000;
+
+fn clint_base() -> usize {
+    fdt::clint().unwrap_or(CLINT_BASE)
+}
+
+/// Where the CLINT's registers are, and how many bytes of them
+pub fn clint_mmio() -> (usize, usize) {
+    (clint_base(), CLINT_SIZE)
+}
 
 /// Give Machine Timer value
 pub fn get_mtime() -> usize {
-    unsafe { (*MMIO_MTIME) as usize }
+    unsafe { ((clint_base() + CLINT_MTIME) as *const u64).read_volatile() as usize }
+}
+
+/// Have the timer interrupt this hart when it gets to `time`.
+pub fn set_mtimecmp(time: u64) {
+    let reg = clint_base() + CLINT_MTIMECMP + 8 * mhartid_read();
+    unsafe {
+        (reg as *mut u64).write_volatile(time);
+    }
+}
+
+/// Raise a software interrupt on `hart`.
+pub fn send_ipi(hart: usize) {
+    let reg = clint_base() + CLINT_MSIP + 4 * hart;
+    unsafe {
+        (reg as *mut u32).write_volatile(1);
+    }
 }
 
 /// Copy one data from one memory location to another.
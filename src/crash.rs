@@ -0,0 +1,255 @@
+//! # Crash reports
+//!
+//! When a user process is killed by a signal that dumps core (SIGSEGV,
+//! SIGILL, SIGABRT, ...), we write down what it was doing: which program
+//! it was, why it trapped, its registers, how much memory and CPU time it
+//! used, how the faulting address is mapped and, if the program keeps
+//! frame pointers, how it got there. The report goes to the kernel log.
+//!
+//! A process that asks for it with `prctl(PR_SET_DUMPABLE, 1)` also gets
+//! the report written to the disk as `/core.<pid>.<seconds since boot>`.
+//! That is off by default, since files can't be deleted yet.
+
+use alloc::{boxed::Box, format, string::String};
+use core::fmt::{self, Write};
+
+use crate::{
+    cpu::{get_mtime, CpuMode, Registers, TrapFrame, FREQ},
+    fs, mmap,
+    page::{lookup, EntryBits, Table, PAGE_SIZE},
+    process::{add_kernel_process_args, Process, STACK_PAGES},
+    signal::{SIGABRT, SIGBUS, SIGFPE, SIGILL, SIGQUIT, SIGSEGV, SIGTRAP, SIGXCPU},
+    uaccess::{read_user, Space},
+};
+
+// prctl options for the core dump switch, same as Linux
+pub const PR_GET_DUMPABLE: usize = 3;
+pub const PR_SET_DUMPABLE: usize = 4;
+
+/// The disk core files are written to
+const CORE_DEVICE: usize = 8;
+/// We give up on the backtrace after this many frames.
+const MAX_DEPTH: usize = 32;
+
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// What the trap handler saw when a process faulted. It is kept until the
+/// signal it caused is delivered.
+#[derive(Clone, Copy)]
+pub struct FaultInfo {
+    /// The signal the fault turned into
+    pub signo: usize,
+    /// mcause
+    pub cause: usize,
+    /// mtval: the faulting address, or the instruction itself if it was
+    /// illegal
+    pub tval: usize,
+}
+
+/// The name of a synchronous exception from mcause.
+pub const fn cause_name(cause: usize) -> &'static str {
+    match cause {
+        0 => "Instruction address misaligned",
+        1 => "Instruction access fault",
+        2 => "Illegal instruction",
+        3 => "Breakpoint",
+        4 => "Load address misaligned",
+        5 => "Load access fault",
+        6 => "Store address misaligned",
+        7 => "Store access fault",
+        8 => "Environment call from U-mode",
+        9 => "Environment call from S-mode",
+        11 => "Environment call from M-mode",
+        12 => "Instruction page fault",
+        13 => "Load page fault",
+        15 => "Store page fault",
+        _ => "Unknown exception",
+    }
+}
+
+const fn signal_name(signo: usize) -> &'static str {
+    match signo {
+        SIGQUIT => "SIGQUIT",
+        SIGILL => "SIGILL",
+        SIGTRAP => "SIGTRAP",
+        SIGABRT => "SIGABRT",
+        SIGBUS => "SIGBUS",
+        SIGFPE => "SIGFPE",
+        SIGSEGV => "SIGSEGV",
+        SIGXCPU => "SIGXCPU",
+        _ => "?",
+    }
+}
+
+/// Does mtval hold an address for this cause?
+const fn tval_is_address(cause: usize) -> bool {
+    matches!(cause, 0 | 1 | 4..=7 | 12 | 13 | 15)
+}
+
+/// One line saying how `vaddr` is mapped: the page size, where it ends up
+/// and the permission bits of the leaf entry.
+fn write_mapping(out: &mut String, table: &Table, what: &str, vaddr: usize) -> fmt::Result {
+    let (entry, level) = match lookup(table, vaddr) {
+        Some(found) => found,
+        None => return writeln!(out, "  {:4} 0x{:016x}: not mapped", what, vaddr),
+    };
+    let bits = entry.get_entry();
+    let flag = |bit: EntryBits, c: char| if bits & bit.val() != 0 { c } else { '-' };
+    let off_mask = (1 << (12 + level * 9)) - 1;
+    let paddr = ((bits << 2) as usize & !off_mask) | (vaddr & off_mask);
+    writeln!(
+        out,
+        "  {:4} 0x{:016x}: {} page -> 0x{:016x} {}{}{}{}{}{}",
+        what,
+        vaddr,
+        ["4K", "2M", "1G"][level],
+        paddr,
+        flag(EntryBits::User, 'u'),
+        flag(EntryBits::Read, 'r'),
+        flag(EntryBits::Write, 'w'),
+        flag(EntryBits::Execute, 'x'),
+        flag(EntryBits::Access, 'a'),
+        flag(EntryBits::Dirty, 'd'),
+    )
+}
+
+/// Follow the frame pointers on the user stack. This only works for
+/// programs built with `-fno-omit-frame-pointer`, and the caller of a leaf
+/// function may be missing. Anything that isn't mapped or doesn't go up the
+/// stack ends the walk.
+unsafe fn write_backtrace(out: &mut String, table: *mut Table, frame: &TrapFrame) -> fmt::Result {
+    writeln!(out, "Backtrace:")?;
+    writeln!(out, "  #0  0x{:016x}", frame.pc)?;
+    let sp = frame.regs[Registers::Sp as usize];
+    let top = sp + STACK_PAGES * PAGE_SIZE;
+    let mut fp = frame.regs[Registers::S0 as usize];
+    let mut depth = 1;
+    while depth < MAX_DEPTH && fp % 8 == 0 && fp > sp && fp <= top {
+        let (ra, caller_fp) = match (
+            read_user::<usize>(Space::User(table), fp - 8),
+            read_user::<usize>(Space::User(table), fp - 16),
+        ) {
+            (Ok(ra), Ok(caller_fp)) if ra != 0 => (ra, caller_fp),
+            _ => break,
+        };
+        writeln!(out, "  #{:<2} 0x{:016x}", depth, ra)?;
+        if caller_fp <= fp {
+            break;
+        }
+        fp = caller_fp;
+        depth += 1;
+    }
+    Ok(())
+}
+
+unsafe fn write_report(
+    out: &mut String,
+    p: &mut Process,
+    frame: &TrapFrame,
+    signo: usize,
+) -> fmt::Result {
+    let path = if p.data.path.is_empty() {
+        "?"
+    } else {
+        p.data.path.as_str()
+    };
+    writeln!(
+        out,
+        "Process {} ({}) killed by signal {} ({})",
+        frame.pid,
+        path,
+        signo,
+        signal_name(signo)
+    )?;
+    // A fault we recorded earlier is only ours if it raised this signal.
+    // Otherwise somebody sent it with kill or from the console.
+    let fault = p.data.fault.take().filter(|fault| fault.signo == signo);
+    match fault {
+        Some(fault) => writeln!(
+            out,
+            "{} at mepc 0x{:016x}, mtval 0x{:016x}",
+            cause_name(fault.cause),
+            frame.pc,
+            fault.tval
+        )?,
+        None => writeln!(out, "Signal sent at pc 0x{:016x}", frame.pc)?,
+    }
+    if let Some(fault) = fault {
+        if tval_is_address(fault.cause) && mmap::is_guard(p.root, fault.tval) {
+            writeln!(out, "Stack overflow in pid {}", frame.pid)?;
+        }
+    }
+    writeln!(out, "Registers:")?;
+    for (i, name) in REGISTER_NAMES.iter().enumerate().skip(1) {
+        write!(out, "  {:>4} 0x{:016x}", name, frame.regs[i])?;
+        if i % 4 == 3 || i == 31 {
+            writeln!(out)?;
+        }
+    }
+    if let Some(usage) = mmap::usage(p.root) {
+        writeln!(
+            out,
+            "Memory: {} pages resident, {} at most, {} in swap, {} page table pages",
+            usage.resident, usage.peak, usage.swapped, usage.tables
+        )?;
+    }
+    let ticks = p.data.cpu_time;
+    writeln!(
+        out,
+        "CPU time: {}.{:03} s",
+        ticks / FREQ as usize,
+        ticks % FREQ as usize * 1000 / FREQ as usize
+    )?;
+    let table = p.get_table_address() as *mut Table;
+    writeln!(out, "Mappings:")?;
+    write_mapping(out, &*table, "pc", frame.pc)?;
+    if let Some(fault) = fault {
+        if tval_is_address(fault.cause) {
+            write_mapping(out, &*table, "addr", fault.tval)?;
+        }
+    }
+    write_backtrace(out, table, frame)
+}
+
+/// Everything [`write_core`] needs
+struct CoreFile {
+    path: String,
+    report: String,
+}
+
+/// Kernel process that writes a core file. Writing to the disk blocks, so
+/// this can't happen in the trap handler.
+fn write_core(args: usize) {
+    let core = unsafe { Box::from_raw(args as *mut CoreFile) };
+    match fs::MinixFileSystem::create(CORE_DEVICE, &core.path, core.report.as_bytes()) {
+        Ok(_) => info!("Wrote {}", core.path),
+        Err(_) => warn!("Could not write {}", core.path),
+    }
+}
+
+/// Process `p` is about to die from `signo`, whose default action is to
+/// dump core, with its registers in `frame`. Log what it was doing and, if
+/// it asked for that, write it to the disk as well.
+///
+/// Writing starts a kernel process, so `p` must not be used afterwards.
+pub unsafe fn dump(p: *mut Process, frame: *const TrapFrame, signo: usize) {
+    if (*frame).mode != CpuMode::User as usize {
+        // Kernel processes don't have much of a user state to report.
+        info!("Process {} terminated by signal {}", (*frame).pid, signo);
+        return;
+    }
+    let mut report = String::new();
+    let _ = write_report(&mut report, &mut *p, &*frame, signo);
+    for line in report.lines() {
+        error!("{}", line);
+    }
+    if (*p).data.core_dump {
+        let path = format!("/core.{}.{}", (*frame).pid, get_mtime() as u64 / FREQ);
+        let core = Box::new(CoreFile { path, report });
+        add_kernel_process_args(write_core, Box::into_raw(core) as usize);
+    }
+}
@@ -1,10 +1,17 @@
-use alloc::collections::VecDeque;
+use alloc::{collections::VecDeque, vec::Vec};
+use core::mem::size_of;
 
 use crate::{
     buffer::Buffer,
-    cpu::{build_satp, memcpy, satp_fence_asid, CpuMode, Registers, SatpMode, TrapFrame},
-    page::{align_val, map, zalloc, EntryBits, Table, PAGE_SIZE},
+    cpu::{
+        build_satp, get_mtime, memcpy, satp_fence_asid, CpuMode, Registers, SatpMode, TrapFrame,
+    },
+    mmap,
+    page::{map_range, zalloc, EntryBits, Table, PAGE_SIZE},
     process::{Process, ProcessData, ProcessState, NEXT_PID, STACK_ADDR, STACK_PAGES},
+    resource::Limits,
+    signal::map_trampoline,
+    vdso::map_time_page,
 };
 // Every ELF file starts with ELF "magic", which is a sequence of four bytes 0x7f followed by
 // capital ELF, which is 0x45, 0x4c, and 0x46 respectively.
@@ -63,6 +70,36 @@ pub const PH_SEG_TYPE_DYNAMIC: u32 = 2;
 pub const PH_SEG_TYPE_INTERP: u32 = 3;
 pub const PH_SEG_TYPE_NOTE: u32 = 4;
 
+/// How many bytes of arguments and environment a program may get, pointers
+/// included. They go on its stack, so they must leave room for the program.
+pub const ARG_MAX: usize = STACK_PAGES * PAGE_SIZE / 4;
+
+// Auxiliary vector entries, see getauxval(3)
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_UID: usize = 11;
+const AT_EUID: usize = 12;
+const AT_GID: usize = 13;
+const AT_EGID: usize = 14;
+const AT_HWCAP: usize = 16;
+const AT_CLKTCK: usize = 17;
+const AT_SECURE: usize = 23;
+const AT_RANDOM: usize = 25;
+
+/// One bit per single-letter ISA extension, here RV64IMAFDC.
+const HWCAP: usize = 1 << (b'i' - b'a')
+    | 1 << (b'm' - b'a')
+    | 1 << (b'a' - b'a')
+    | 1 << (b'f' - b'a')
+    | 1 << (b'd' - b'a')
+    | 1 << (b'c' - b'a');
+/// The unit of times(), which Linux keeps at 100 no matter what.
+const CLOCKS_PER_SEC: usize = 100;
+
 pub struct Program {
     pub header: ProgramHeader,
     pub data: Buffer,
@@ -73,6 +110,10 @@ pub enum LoadErrors {
     Machine,
     TypeExec,
     FileRead,
+    /// Dynamically linked programs need an interpreter, which we can't do.
+    Interpreter,
+    /// The program or its stack are bigger than its resource limits allow.
+    Limit,
 }
 
 pub struct File {
@@ -110,6 +151,9 @@ impl File {
         for i in 0..elf_hdr.phnum as usize {
             unsafe {
                 let ph = ph_tab.add(i).as_ref().unwrap();
+                if ph.seg_type == PH_SEG_TYPE_INTERP {
+                    return Err(LoadErrors::Interpreter);
+                }
                 // If the segment isn't marked as LOAD (loaded into memory),
                 // then there is no point to this. Most executables use a LOAD
                 // type for their program headers.
@@ -120,9 +164,18 @@ impl File {
                 if ph.memsz == 0 {
                     continue;
                 }
+                if ph.filesz > ph.memsz || ph.off + ph.filesz > buffer.len() {
+                    return Err(LoadErrors::FileRead);
+                }
                 let mut ph_buffer = Buffer::new(ph.memsz);
 
-                memcpy(ph_buffer.get_mut(), buffer.get().add(ph.off), ph.memsz);
+                // Only filesz bytes come from the file. The rest is .bss,
+                // which starts out zeroed.
+                memcpy(ph_buffer.get_mut(), buffer.get().add(ph.off), ph.filesz);
+                ph_buffer
+                    .get_mut()
+                    .add(ph.filesz)
+                    .write_bytes(0, ph.memsz - ph.filesz);
                 ret.programs.push_back(Program {
                     header: *ph,
                     data: ph_buffer,
@@ -132,8 +185,25 @@ impl File {
         Ok(ret)
     }
 
-    // load
-    pub fn load_proc(buffer: &Buffer) -> Result<Process, LoadErrors> {
+    /// Where the program headers end up in memory, if a segment loads
+    /// them. C libraries look for their thread-local storage in there.
+    fn phdr_addr(&self) -> usize {
+        let phoff = self.header.phoff;
+        self.programs
+            .iter()
+            .find(|p| p.header.off <= phoff && phoff < p.header.off + p.header.filesz)
+            .map_or(0, |p| p.header.vaddr + phoff - p.header.off)
+    }
+
+    /// Load the ELF file in `buffer` into a new process with `limits`,
+    /// which gets `argv` and `envp` on its stack. Together they must fit in
+    /// [`ARG_MAX`].
+    pub fn load_proc(
+        buffer: &Buffer,
+        argv: &[Vec<u8>],
+        envp: &[Vec<u8>],
+        limits: Limits,
+    ) -> Result<Process, LoadErrors> {
         let elf_fl = Self::load(buffer);
         if elf_fl.is_err() {
             return Err(elf_fl.err().unwrap());
@@ -141,13 +211,19 @@ impl File {
         let elf_fl = elf_fl.ok().unwrap();
         let mut sz = 0_usize;
         // Get the size, in memory, that we're going to need for the program storage.
+        // Each segment goes where it is in the file, so that is as far as the
+        // furthest one reaches.
         for p in elf_fl.programs.iter() {
-            sz += p.header.memsz;
+            sz = sz.max(p.header.off + p.header.memsz);
         }
         // We add two pages since we could possibly split the front and back pages, hence
         // necessitating the need for two extra pages. This can get wasteful, but for now
         // if we don't do this, we could end up mapping into the MMU table!
         let program_pages = (sz + PAGE_SIZE * 2) / PAGE_SIZE;
+        if !limits.stack_fits() || program_pages + STACK_PAGES > limits.pages() {
+            return Err(LoadErrors::Limit);
+        }
+        mmap::make_room(program_pages + STACK_PAGES);
         // I did this to demonstrate the expressive nature of Rust. Kinda cool, no?
         let my_pid = unsafe {
             let p = NEXT_PID + 1;
@@ -195,41 +271,64 @@ impl File {
                 bits |= EntryBits::Write.val();
             }
             // Now we map the program counter. The virtual address
-            // is provided in the ELF program header.
-            let pages = (PAGE_SIZE + align_val(p.header.memsz, 12)) / PAGE_SIZE;
-            for i in 0..pages {
-                let vaddr = p.header.vaddr + i * PAGE_SIZE;
-                // The ELF specifies a paddr, but not when we
-                // use the vaddr!
-                let paddr = program_mem as usize + p.header.off + i * PAGE_SIZE;
-                // There is no checking here! This is very dangerous, and I have already
-                // been bitten by it. I mapped too far and mapped userspace into the MMU
-                // table, which is AWFUL!
-                map(table, vaddr, paddr, bits, 0);
-                // println!("DEBUG: Map 0x{:08x} to 0x{:08x} {:02x}", vaddr, paddr, bits);
-            }
+            // is provided in the ELF program header. The ELF specifies a
+            // paddr, but not when we use the vaddr! Big segments whose vaddr
+            // lines up with the program memory get megapages.
+            // There is no checking here! This is very dangerous, and I have already
+            // been bitten by it. I mapped too far and mapped userspace into the MMU
+            // table, which is AWFUL!
+            let paddr = program_mem as usize + p.header.off;
+            map_range(table, p.header.vaddr, paddr, p.header.memsz, bits);
         }
+        // The heap starts after the last segment.
+        let end = elf_fl
+            .programs
+            .iter()
+            .map(|p| p.header.vaddr + p.header.memsz)
+            .max()
+            .unwrap_or(0);
+        mmap::init(my_proc.root, end, program_pages + STACK_PAGES);
+        my_proc.init_space(limits);
         // This will map all of the program pages. Notice that in linker.lds in
         // userspace we set the entry point address to 0x2000_0000. This is the
         // same address as PROCESS_STARTING_ADDR, and they must match.
         // Map the stack
-        let ptr = my_proc.stack as *mut u8;
-        for i in 0..STACK_PAGES {
-            let v_addr = STACK_ADDR + i * PAGE_SIZE;
-            let p_addr = ptr as usize + i * PAGE_SIZE;
-            // We create the stack. We don't load a stack from the disk.
-            // This is why I don't need to make the stack executable.
-            map(table, v_addr, p_addr, EntryBits::UserReadWrite.val(), 0);
-        }
+        // We create the stack. We don't load a stack from the disk.
+        // This is why I don't need to make the stack executable.
+        map_range(
+            table,
+            STACK_ADDR,
+            my_proc.stack as usize,
+            STACK_PAGES * PAGE_SIZE,
+            EntryBits::UserReadWrite.val(),
+        );
+        my_proc.data.stack_vaddr = STACK_ADDR;
+        mmap::guard(my_proc.root, STACK_ADDR - PAGE_SIZE);
+        // Signal handlers return through the sigreturn trampoline.
+        map_trampoline(table);
+        // Programs read the clock from the time page, see vdso.rs.
+        map_time_page(table);
         // Set everything up in the trap frame
         unsafe {
             // The program counter is a virtual memory address and is loaded
             // into mepc when we execute mret.
             (*my_proc.frame).pc = elf_fl.header.entry_addr;
-            // Stack pointer. The stack starts at the bottom and works its
-            // way up, so we have to set the stack pointer to the bottom.
-            (*my_proc.frame).regs[Registers::Sp as usize] =
-                STACK_ADDR as usize + STACK_PAGES * PAGE_SIZE;
+            // The stack grows down from the top, where we put the arguments.
+            let auxv = [
+                (AT_PHDR, elf_fl.phdr_addr()),
+                (AT_PHENT, usize::from(elf_fl.header.phentsize)),
+                (AT_PHNUM, usize::from(elf_fl.header.phnum)),
+                (AT_PAGESZ, PAGE_SIZE),
+                (AT_ENTRY, elf_fl.header.entry_addr),
+                (AT_UID, 0),
+                (AT_EUID, 0),
+                (AT_GID, 0),
+                (AT_EGID, 0),
+                (AT_HWCAP, HWCAP),
+                (AT_CLKTCK, CLOCKS_PER_SEC),
+                (AT_SECURE, 0),
+            ];
+            setup_stack(&mut my_proc, &auxv, argv, envp);
             // USER MODE! This is how we set what'll go into mstatus when we
             // run the process.
             (*my_proc.frame).mode = CpuMode::User as usize;
@@ -249,3 +348,90 @@ impl File {
         Ok(my_proc)
     }
 }
+
+/// Copy `bytes` onto the stack of `proc` below `sp` and return where they
+/// went.
+unsafe fn push_bytes(proc: &Process, sp: &mut usize, bytes: &[u8]) -> usize {
+    *sp -= bytes.len();
+    let paddr = proc.stack as usize + (*sp - STACK_ADDR);
+    memcpy(paddr as *mut u8, bytes.as_ptr(), bytes.len());
+    *sp
+}
+
+/// Lay out the initial stack the way riscv64 Linux does, which is where a C
+/// library's `_start` looks for everything:
+///
+/// ```text
+/// sp -> argc
+///       argv[0] ... argv[argc - 1], 0
+///       envp[0] ... 0
+///       auxv pairs ... AT_NULL, 0
+///       the strings and the AT_RANDOM bytes
+///       STACK_ADDR + STACK_PAGES * PAGE_SIZE
+/// ```
+///
+/// Our own startlib calls `main` without looking at the stack, so argc,
+/// argv and envp go into A0 to A2 as well.
+unsafe fn setup_stack(
+    proc: &mut Process,
+    auxv: &[(usize, usize)],
+    argv: &[Vec<u8>],
+    envp: &[Vec<u8>],
+) {
+    let mut sp = STACK_ADDR + STACK_PAGES * PAGE_SIZE;
+    let random = push_bytes(proc, &mut sp, &random_bytes(proc.pid));
+    let mut strings = |list: &[Vec<u8>]| {
+        list.iter()
+            .map(|s| {
+                push_bytes(proc, &mut sp, &[0]);
+                push_bytes(proc, &mut sp, s)
+            })
+            .collect::<Vec<usize>>()
+    };
+    let argv_addrs = strings(argv);
+    let envp_addrs = strings(envp);
+
+    let mut words = Vec::new();
+    words.push(argv.len());
+    words.extend(argv_addrs);
+    words.push(0);
+    words.extend(envp_addrs);
+    words.push(0);
+    for (key, value) in auxv {
+        words.push(*key);
+        words.push(*value);
+    }
+    words.extend([AT_RANDOM, random, AT_NULL, 0]);
+    // The ABI wants sp 16-byte aligned on entry.
+    sp = (sp - words.len() * size_of::<usize>()) & !15;
+    let paddr = proc.stack as usize + (sp - STACK_ADDR);
+    memcpy(
+        paddr as *mut u8,
+        words.as_ptr() as *const u8,
+        words.len() * size_of::<usize>(),
+    );
+
+    let frame = &mut *proc.frame;
+    frame.regs[Registers::Sp as usize] = sp;
+    frame.regs[Registers::A0 as usize] = argv.len();
+    frame.regs[Registers::A1 as usize] = sp + size_of::<usize>();
+    frame.regs[Registers::A2 as usize] = sp + (argv.len() + 2) * size_of::<usize>();
+}
+
+/// 16 bytes for AT_RANDOM, which C libraries turn into stack canaries. The
+/// entropy device doesn't work yet, so this is just the time and the PID
+/// stirred with splitmix64: different every time, but not a secret.
+fn random_bytes(pid: u16) -> [u8; 16] {
+    let mut state = get_mtime() as u64 ^ u64::from(pid) << 48;
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&next().to_le_bytes());
+    bytes[8..].copy_from_slice(&next().to_le_bytes());
+    bytes
+}
@@ -0,0 +1,89 @@
+//! # Error numbers
+//!
+//! A system call that fails returns the negated error number in A0, the
+//! way Linux does it, so anything from -4095 to -1 is an error and
+//! everything else is a result. The numbers are those of Linux as well.
+//!
+//! `tools/gen_syscall_h.py` turns the constants below into
+//! `userspace/startlib/errno.h`, so keep them in this form.
+
+use crate::uaccess::Fault;
+
+/// The reason a system call failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Errno(pub usize);
+
+/// Operation not permitted
+pub const EPERM: Errno = Errno(1);
+/// No such file or directory
+pub const ENOENT: Errno = Errno(2);
+/// No such process
+pub const ESRCH: Errno = Errno(3);
+/// Interrupted system call
+pub const EINTR: Errno = Errno(4);
+/// I/O error
+pub const EIO: Errno = Errno(5);
+/// Argument list too long
+pub const E2BIG: Errno = Errno(7);
+/// Exec format error
+pub const ENOEXEC: Errno = Errno(8);
+/// Bad file descriptor
+pub const EBADF: Errno = Errno(9);
+/// No child processes
+pub const ECHILD: Errno = Errno(10);
+/// Try again
+pub const EAGAIN: Errno = Errno(11);
+/// Out of memory
+pub const ENOMEM: Errno = Errno(12);
+/// Permission denied
+pub const EACCES: Errno = Errno(13);
+/// Bad address
+pub const EFAULT: Errno = Errno(14);
+/// Device or resource busy
+pub const EBUSY: Errno = Errno(16);
+/// File exists
+pub const EEXIST: Errno = Errno(17);
+/// No such device
+pub const ENODEV: Errno = Errno(19);
+/// Invalid argument
+pub const EINVAL: Errno = Errno(22);
+/// Too many open files
+pub const EMFILE: Errno = Errno(24);
+/// Not a typewriter
+pub const ENOTTY: Errno = Errno(25);
+/// Illegal seek
+pub const ESPIPE: Errno = Errno(29);
+/// Read-only file system
+pub const EROFS: Errno = Errno(30);
+/// Broken pipe
+pub const EPIPE: Errno = Errno(32);
+/// Math result not representable
+pub const ERANGE: Errno = Errno(34);
+/// File name too long
+pub const ENAMETOOLONG: Errno = Errno(36);
+/// Function not implemented
+pub const ENOSYS: Errno = Errno(38);
+
+impl Errno {
+    /// What goes into A0 for this error
+    pub const fn as_return(self) -> usize {
+        self.0.wrapping_neg()
+    }
+}
+
+impl From<Fault> for Errno {
+    fn from(_: Fault) -> Self {
+        EFAULT
+    }
+}
+
+/// What a system call comes up with
+pub type SysResult = Result<usize, Errno>;
+
+/// The value for A0: the result, or the negated error number.
+pub fn to_return(result: SysResult) -> usize {
+    match result {
+        Ok(value) => value,
+        Err(errno) => errno.as_return(),
+    }
+}
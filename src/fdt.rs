@@ -0,0 +1,337 @@
+//! # Device tree
+//!
+//! QEMU describes the machine in a flattened device tree and hands us its
+//! address in a1 at boot. We take from it:
+//!
+//! - how much RAM there is (`/memory`), which sizes the page allocator,
+//! - how many harts there are (`/cpus/cpu@*`),
+//! - where the `ns16550a` UART, the PLIC and the CLINT are,
+//! - every `virtio,mmio` transport with its interrupt,
+//! - the kernel command line from `/chosen/bootargs`, which QEMU fills in
+//!   from `-append`:
+//!
+//! ```text
+//! cargo run -- -append "strace"
+//! ```
+//!
+//! So `-m 512M` or `-smp 2` change what the kernel uses. Without a device
+//! tree, everybody falls back to the addresses of QEMU's virt machine.
+//!
+//! The tree sits in RAM that the page allocator will hand out later, so
+//! [`init`] copies what we need before anything is allocated. It can't
+//! print anything either, the UART isn't set up yet; [`report`] does that
+//! afterwards.
+
+use crate::power::MAX_HARTS;
+
+/// The magic number at the start of every device tree blob
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+// Tokens in the structure block
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// The longest command line we keep
+const BOOTARGS_SIZE: usize = 256;
+/// How many virtio transports we keep track of
+pub const MAX_VIRTIO: usize = 8;
+/// How deep nodes may nest. The virt machine goes three levels deep.
+const MAX_DEPTH: usize = 8;
+
+static mut BOOTARGS: [u8; BOOTARGS_SIZE] = [0; BOOTARGS_SIZE];
+static mut BOOTARGS_LEN: usize = 0;
+
+/// A device with registers at `base` that raises interrupt `irq` on the
+/// PLIC
+#[derive(Clone, Copy)]
+pub struct Device {
+    pub base: usize,
+    pub irq: u32,
+}
+
+/// What the device tree told us about the machine
+struct Machine {
+    /// Start and size of RAM
+    memory: Option<(usize, usize)>,
+    harts: usize,
+    uart: Option<Device>,
+    plic: Option<usize>,
+    clint: Option<usize>,
+    /// Sorted by address
+    virtio: [Device; MAX_VIRTIO],
+    virtio_count: usize,
+}
+
+static mut MACHINE: Machine = Machine {
+    memory: None,
+    harts: 0,
+    uart: None,
+    plic: None,
+    clint: None,
+    virtio: [Device { base: 0, irq: 0 }; MAX_VIRTIO],
+    virtio_count: 0,
+};
+
+/// The properties of a node we care about
+#[derive(Clone, Copy)]
+struct Node {
+    name: &'static [u8],
+    /// A list of NUL-terminated strings
+    compatible: &'static [u8],
+    device_type: &'static [u8],
+    bootargs: &'static [u8],
+    /// Where the `reg` and `interrupts` properties are, and how long
+    reg: (usize, usize),
+    interrupts: (usize, usize),
+    /// How many cells addresses and sizes of children take
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl Node {
+    const fn new(name: &'static [u8]) -> Self {
+        Self {
+            name,
+            compatible: &[],
+            device_type: &[],
+            bootargs: &[],
+            reg: (0, 0),
+            interrupts: (0, 0),
+            // The defaults the specification gives
+            address_cells: 2,
+            size_cells: 1,
+        }
+    }
+
+    fn is_compatible(&self, with: &[&[u8]]) -> bool {
+        self.compatible
+            .split(|&c| c == 0)
+            .any(|compatible| with.contains(&compatible))
+    }
+
+    /// The first address and size in `reg`. How many cells they take is up
+    /// to the parent.
+    unsafe fn reg(&self, parent: &Node) -> Option<(usize, usize)> {
+        let (addr, len) = self.reg;
+        if len < (parent.address_cells + parent.size_cells) * 4 {
+            return None;
+        }
+        let base = read_cells(addr, parent.address_cells);
+        let size = read_cells(addr + parent.address_cells * 4, parent.size_cells);
+        Some((base, size))
+    }
+
+    /// The first interrupt, for devices behind the PLIC, which takes one
+    /// cell per interrupt
+    unsafe fn irq(&self) -> Option<u32> {
+        let (addr, len) = self.interrupts;
+        (len >= 4).then(|| read_u32(addr))
+    }
+}
+
+/// Everything in a device tree is big-endian.
+unsafe fn read_u32(addr: usize) -> u32 {
+    u32::from_be((addr as *const u32).read_unaligned())
+}
+
+/// A number that takes `cells` 32-bit cells
+unsafe fn read_cells(addr: usize, cells: usize) -> usize {
+    (0..cells).fold(0, |n, i| n << 32 | read_u32(addr + i * 4) as usize)
+}
+
+/// The NUL-terminated string at `addr`, without the NUL.
+unsafe fn c_str(addr: usize) -> &'static [u8] {
+    let mut len = 0;
+    while *((addr + len) as *const u8) != 0 {
+        len += 1;
+    }
+    core::slice::from_raw_parts(addr as *const u8, len)
+}
+
+const fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Look at a node once we have all of its properties.
+unsafe fn found(node: &Node, parent: &Node, depth: usize) {
+    let machine = &mut MACHINE;
+    if depth == 1 && node.name == b"chosen" {
+        let len = node.bootargs.len().min(BOOTARGS_SIZE);
+        BOOTARGS[..len].copy_from_slice(&node.bootargs[..len]);
+        BOOTARGS_LEN = len;
+    } else if node.device_type == b"memory" {
+        // The kernel is in the first bank, and that's the only one we use.
+        if machine.memory.is_none() {
+            machine.memory = node.reg(parent);
+        }
+    } else if node.device_type == b"cpu" {
+        machine.harts += 1;
+    } else if node.is_compatible(&[b"ns16550a", b"ns16550"]) {
+        if let (None, Some((base, _)), Some(irq)) = (machine.uart, node.reg(parent), node.irq()) {
+            machine.uart = Some(Device { base, irq });
+        }
+    } else if node.is_compatible(&[b"riscv,plic0", b"sifive,plic-1.0.0"]) {
+        machine.plic = machine.plic.or(node.reg(parent).map(|(base, _)| base));
+    } else if node.is_compatible(&[b"riscv,clint0", b"sifive,clint0"]) {
+        machine.clint = machine.clint.or(node.reg(parent).map(|(base, _)| base));
+    } else if node.is_compatible(&[b"virtio,mmio"]) {
+        if let (Some((base, _)), Some(irq)) = (node.reg(parent), node.irq()) {
+            if machine.virtio_count < MAX_VIRTIO {
+                machine.virtio[machine.virtio_count] = Device { base, irq };
+                machine.virtio_count += 1;
+            }
+        }
+    }
+}
+
+/// Walk the tree at `fdt` and remember what we need. Properties come before
+/// the children of a node, so by the time a node ends we know all of it.
+unsafe fn parse(fdt: usize) -> Option<()> {
+    if fdt == 0 || read_u32(fdt) != FDT_MAGIC {
+        return None;
+    }
+    let structs = fdt + read_u32(fdt + 8) as usize;
+    let strings = fdt + read_u32(fdt + 12) as usize;
+    let mut pos = structs;
+    // The nodes from the root down to where we are
+    let mut nodes = [Node::new(&[]); MAX_DEPTH];
+    let mut depth = 0;
+    loop {
+        let token = read_u32(pos);
+        pos += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = c_str(pos);
+                pos = align4(pos + name.len() + 1);
+                if depth == MAX_DEPTH {
+                    return None;
+                }
+                // Unit addresses don't matter to us: memory@80000000 is
+                // memory.
+                let name = name.split(|&c| c == b'@').next().unwrap_or(name);
+                nodes[depth] = Node::new(name);
+                depth += 1;
+            }
+            FDT_END_NODE => {
+                if depth == 0 {
+                    return None;
+                }
+                depth -= 1;
+                if depth > 0 {
+                    found(&nodes[depth], &nodes[depth - 1], depth);
+                }
+            }
+            FDT_PROP => {
+                let len = read_u32(pos) as usize;
+                let name = c_str(strings + read_u32(pos + 4) as usize);
+                let value = pos + 8;
+                pos = align4(value + len);
+                if depth == 0 {
+                    return None;
+                }
+                let node = &mut nodes[depth - 1];
+                let bytes = core::slice::from_raw_parts(value as *const u8, len);
+                let string = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+                match name {
+                    b"compatible" => node.compatible = bytes,
+                    b"device_type" => node.device_type = string,
+                    b"bootargs" => node.bootargs = string,
+                    b"reg" => node.reg = (value, len),
+                    b"interrupts" => node.interrupts = (value, len),
+                    b"#address-cells" if len == 4 => node.address_cells = read_u32(value) as usize,
+                    b"#size-cells" if len == 4 => node.size_cells = read_u32(value) as usize,
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => return Some(()),
+            // Something we don't understand
+            _ => return None,
+        }
+    }
+}
+
+/// Read what we need from the device tree at `fdt`. This must happen
+/// before the page allocator gets to the memory it is in.
+pub fn init(fdt: usize) {
+    unsafe {
+        if parse(fdt).is_none() {
+            // Half a machine is worse than the defaults.
+            MACHINE.memory = None;
+            MACHINE.uart = None;
+            MACHINE.plic = None;
+            MACHINE.clint = None;
+            MACHINE.virtio_count = 0;
+        }
+        MACHINE.virtio[..MACHINE.virtio_count].sort_unstable_by_key(|dev| dev.base);
+    }
+}
+
+/// Tell the log what we found.
+pub fn report() {
+    unsafe {
+        match MACHINE.memory {
+            Some((base, size)) => info!(
+                "{} MiB of RAM at 0x{:x}, {} hart(s)",
+                size >> 20,
+                base,
+                MACHINE.harts
+            ),
+            None => warn!("No device tree, assuming QEMU's virt machine"),
+        }
+        if MACHINE.harts > MAX_HARTS {
+            warn!("Only the first {} harts will be used", MAX_HARTS);
+        }
+        if let Some(uart) = MACHINE.uart {
+            info!("UART at 0x{:x}, interrupt {}", uart.base, uart.irq);
+        }
+        if let (Some(plic), Some(clint)) = (MACHINE.plic, MACHINE.clint) {
+            info!("PLIC at 0x{:x}, CLINT at 0x{:x}", plic, clint);
+        }
+        info!("{} virtio transport(s)", MACHINE.virtio_count);
+    }
+}
+
+/// Start and size of RAM
+pub fn memory() -> Option<(usize, usize)> {
+    unsafe { MACHINE.memory }
+}
+
+/// How many harts there are, if we know
+pub fn harts() -> Option<usize> {
+    unsafe { (MACHINE.harts > 0).then(|| MACHINE.harts) }
+}
+
+pub fn uart() -> Option<Device> {
+    unsafe { MACHINE.uart }
+}
+
+pub fn plic() -> Option<usize> {
+    unsafe { MACHINE.plic }
+}
+
+pub fn clint() -> Option<usize> {
+    unsafe { MACHINE.clint }
+}
+
+/// The virtio transports, by address
+pub fn virtio() -> &'static [Device] {
+    unsafe { &MACHINE.virtio[..MACHINE.virtio_count] }
+}
+
+/// The kernel command line, empty if there is none.
+pub fn bootargs() -> &'static str {
+    unsafe { core::str::from_utf8(&BOOTARGS[..BOOTARGS_LEN]).unwrap_or("") }
+}
+
+/// Look for `name` on the kernel command line. Returns the part after the
+/// `=` for `name=value`, or an empty string for a plain `name`.
+pub fn bootarg(name: &str) -> Option<&'static str> {
+    bootargs().split_whitespace().find_map(|arg| {
+        let (key, value) = arg.split_once('=').unwrap_or((arg, ""));
+        (key == name).then(|| value)
+    })
+}
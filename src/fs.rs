@@ -1,14 +1,16 @@
-use alloc::{boxed::Box, collections::BTreeMap, string::String};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec, vec::Vec};
 use core::mem::size_of;
 
 use crate::{
     buffer::Buffer,
-    cpu::{memcpy, Registers},
+    cpu::memcpy,
     process::{add_kernel_process_args, get_by_pid, set_running, set_waiting},
-    syscall::syscall_block_read,
+    syscall::{syscall_block_read, syscall_block_write},
 };
 
 pub const MAGIC: u16 = 0x4d5a;
+/// The block device the file system is on
+pub const ROOT_DEVICE: usize = 8;
 pub const BLOCK_SIZE: u32 = 1024;
 pub const NUM_IPTRS: usize = BLOCK_SIZE as usize / 4;
 pub const S_IFDIR: u16 = 0o040_000;
@@ -18,6 +20,7 @@ pub const S_IFREG: u16 = 0o100_000;
 /// us all the information we need to read the file system and navigate
 /// the file system, including where to find the inodes and zones (blocks).
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub struct SuperBlock {
     pub ninodes: u32,
     pub pad0: u16,
@@ -132,15 +135,21 @@ impl MinixFileSystem {
     /// it over and over again, like we do for read right now.
     fn cache_at(btm: &mut BTreeMap<String, Inode>, cwd: &str, inode_num: u32, bdev: usize) {
         let ino = Self::get_inode(bdev, inode_num).unwrap();
-        let mut buf = Buffer::new(((ino.size + BLOCK_SIZE - 1) & !BLOCK_SIZE) as usize);
+        // Directories grow when we create files, so read all of it.
+        let size = (ino.size + BLOCK_SIZE - 1) & !(BLOCK_SIZE - 1);
+        let mut buf = Buffer::new(size as usize);
         let dirents = buf.get() as *const DirEntry;
-        let sz = Self::read(bdev, &ino, buf.get_mut(), BLOCK_SIZE, 0);
+        let sz = Self::read(bdev, &ino, buf.get_mut(), size, 0);
         let num_dirents = sz as usize / size_of::<DirEntry>();
         // We start at 2 because the first two entries are . and ..
         for i in 2..num_dirents {
             unsafe {
                 // let d = &*dirents.add(i);
                 let d = &(*dirents.add(i));
+                // Slot of a file that was removed
+                if d.inode == 0 {
+                    continue;
+                }
                 let d_ino = Self::get_inode(bdev, d.inode).unwrap();
                 let mut new_cwd = String::with_capacity(120);
                 for i in cwd.bytes() {
@@ -182,10 +191,7 @@ impl MinixFileSystem {
                 MFS_INODE_CACHE[bdev - 1] = Some(btm);
             }
         } else {
-            println!(
-                "KERNEL: Initialized an already initialized filesystem {}",
-                bdev
-            );
+            warn!("Initialized an already initialized filesystem {}", bdev);
         }
     }
 
@@ -489,6 +495,231 @@ impl MinixFileSystem {
         0
     }
 
+    /// Create the regular file `path` in the root directory and fill it with
+    /// `data`. This is all the writing we can do for now: a file can't be
+    /// changed once it exists, and only the direct and singly indirect zones
+    /// are used, so it can be at most 263 KiB. If we run out of space
+    /// halfway, whatever was allocated up to then is lost.
+    ///
+    /// NOTE: Run this ONLY in a process!
+    pub fn create(bdev: usize, path: &str, data: &[u8]) -> Result<Inode, FsError> {
+        let name = match path.strip_prefix('/') {
+            Some(name) if !name.is_empty() && !name.contains('/') && name.len() < 60 => name,
+            _ => return Err(FsError::FileNotFound),
+        };
+        if Self::open(bdev, path).is_ok() {
+            return Err(FsError::IsFile);
+        }
+        let super_block = Self::super_block(bdev).ok_or(FsError::FileNotFound)?;
+        let num_blocks = (data.len() + BLOCK_SIZE as usize - 1) / BLOCK_SIZE as usize;
+        if num_blocks > 7 + NUM_IPTRS {
+            return Err(FsError::NoSpace);
+        }
+        let inode_num = Self::alloc_inode(bdev, &super_block)?;
+        let mut inode = Inode {
+            mode: S_IFREG | 0o644,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            size: data.len() as u32,
+            // We have no idea what time it is.
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            zones: [0; 10],
+        };
+        let mut block_buffer = Buffer::new(BLOCK_SIZE as usize);
+        let mut indirect_buffer = Buffer::new(BLOCK_SIZE as usize);
+        let izones = indirect_buffer.get_mut() as *mut u32;
+        for (i, chunk) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+            let zone = Self::alloc_zone(bdev, &super_block)?;
+            unsafe {
+                // The tail of the last block is zeroes, not old heap contents.
+                block_buffer.get_mut().write_bytes(0, BLOCK_SIZE as usize);
+                memcpy(block_buffer.get_mut(), chunk.as_ptr(), chunk.len());
+            }
+            syc_write(bdev, block_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * zone);
+            if i < 7 {
+                inode.zones[i] = zone;
+            } else {
+                unsafe { izones.add(i - 7).write(zone) };
+            }
+        }
+        if num_blocks > 7 {
+            unsafe {
+                izones
+                    .add(num_blocks - 7)
+                    .write_bytes(0, 7 + NUM_IPTRS - num_blocks);
+            }
+            let zone = Self::alloc_zone(bdev, &super_block)?;
+            syc_write(
+                bdev,
+                indirect_buffer.get_mut(),
+                BLOCK_SIZE,
+                BLOCK_SIZE * zone,
+            );
+            inode.zones[7] = zone;
+        }
+        Self::put_inode(bdev, &super_block, inode_num, &inode);
+        Self::add_dir_entry(bdev, &super_block, 1, name, inode_num)?;
+        unsafe {
+            if let Some(cache) = MFS_INODE_CACHE[bdev - 1].as_mut() {
+                cache.insert(String::from(path), inode);
+            }
+        }
+        Ok(inode)
+    }
+
+    fn super_block(bdev: usize) -> Option<SuperBlock> {
+        let mut buffer = Buffer::new(512);
+        syc_read(bdev, buffer.get_mut(), 512, 1024);
+        let super_block = unsafe { *(buffer.get() as *const SuperBlock) };
+        if super_block.magic == MAGIC {
+            Some(super_block)
+        } else {
+            None
+        }
+    }
+
+    /// Find a clear bit in the bitmap that starts at block `first_block`,
+    /// set it and return its number. Bit 0 is never used, and neither is
+    /// anything at or past `max`.
+    fn alloc_bit(bdev: usize, first_block: u32, num_blocks: u16, max: u32) -> Option<u32> {
+        let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+        let bits_per_block = BLOCK_SIZE * 8;
+        for block in 0..num_blocks as u32 {
+            let offset = (first_block + block) * BLOCK_SIZE;
+            syc_read(bdev, buffer.get_mut(), BLOCK_SIZE, offset);
+            for i in 0..bits_per_block {
+                let bit = block * bits_per_block + i;
+                if bit >= max {
+                    return None;
+                }
+                let byte = &mut buffer[i as usize / 8];
+                if bit != 0 && *byte & (1 << (i % 8)) == 0 {
+                    *byte |= 1 << (i % 8);
+                    syc_write(bdev, buffer.get_mut(), BLOCK_SIZE, offset);
+                    return Some(bit);
+                }
+            }
+        }
+        None
+    }
+
+    /// Inode bit n is inode number n. The inode map comes right after the
+    /// super block.
+    fn alloc_inode(bdev: usize, super_block: &SuperBlock) -> Result<u32, FsError> {
+        Self::alloc_bit(bdev, 2, super_block.imap_blocks, super_block.ninodes + 1)
+            .ok_or(FsError::NoSpace)
+    }
+
+    /// Zone bit n is zone first_data_zone + n - 1. The zone map comes right
+    /// after the inode map.
+    fn alloc_zone(bdev: usize, super_block: &SuperBlock) -> Result<u32, FsError> {
+        let first = u32::from(super_block.first_data_zone);
+        Self::alloc_bit(
+            bdev,
+            2 + u32::from(super_block.imap_blocks),
+            super_block.zmap_blocks,
+            super_block.zones - first + 1,
+        )
+        .map(|bit| first + bit - 1)
+        .ok_or(FsError::NoSpace)
+    }
+
+    /// Write `inode` to the disk as number `inode_num`. This is the same
+    /// math as in [`MinixFileSystem::get_inode`].
+    fn put_inode(bdev: usize, super_block: &SuperBlock, inode_num: u32, inode: &Inode) {
+        let per_block = BLOCK_SIZE as usize / size_of::<Inode>();
+        let offset = (2 + super_block.imap_blocks + super_block.zmap_blocks) as usize
+            * BLOCK_SIZE as usize
+            + ((inode_num as usize - 1) / per_block) * BLOCK_SIZE as usize;
+        let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+        syc_read(bdev, buffer.get_mut(), BLOCK_SIZE, offset as u32);
+        unsafe {
+            (buffer.get_mut() as *mut Inode)
+                .add((inode_num as usize - 1) % per_block)
+                .write(*inode);
+        }
+        syc_write(bdev, buffer.get_mut(), BLOCK_SIZE, offset as u32);
+    }
+
+    /// Link `inode_num` into the directory `dir_num` as `name`. We reuse an
+    /// empty slot if there is one and grow the directory otherwise. Only
+    /// the direct zones are looked at.
+    fn add_dir_entry(
+        bdev: usize,
+        super_block: &SuperBlock,
+        dir_num: u32,
+        name: &str,
+        inode_num: u32,
+    ) -> Result<(), FsError> {
+        let mut dir = Self::get_inode(bdev, dir_num).ok_or(FsError::FileNotFound)?;
+        let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+        let dirents = buffer.get_mut() as *mut DirEntry;
+        let per_block = BLOCK_SIZE as usize / size_of::<DirEntry>();
+        let mut entry = DirEntry {
+            inode: inode_num,
+            name: [0; 60],
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+
+        let mut slot = None;
+        'search: for i in 0..7 {
+            if dir.zones[i] == 0 || i * BLOCK_SIZE as usize >= dir.size as usize {
+                break;
+            }
+            syc_read(
+                bdev,
+                buffer.get_mut(),
+                BLOCK_SIZE,
+                BLOCK_SIZE * dir.zones[i],
+            );
+            for j in 0..per_block {
+                if (i * per_block + j) * size_of::<DirEntry>() >= dir.size as usize {
+                    break 'search;
+                }
+                if unsafe { (*dirents.add(j)).inode } == 0 {
+                    slot = Some((i, j));
+                    break 'search;
+                }
+            }
+        }
+        let (i, j) = match slot {
+            Some(slot) => slot,
+            None => {
+                // Append to the end of the directory.
+                let end = dir.size as usize / size_of::<DirEntry>();
+                let (i, j) = (end / per_block, end % per_block);
+                if i >= 7 {
+                    return Err(FsError::NoSpace);
+                }
+                if j == 0 {
+                    dir.zones[i] = Self::alloc_zone(bdev, super_block)?;
+                    unsafe { buffer.get_mut().write_bytes(0, BLOCK_SIZE as usize) };
+                } else {
+                    syc_read(
+                        bdev,
+                        buffer.get_mut(),
+                        BLOCK_SIZE,
+                        BLOCK_SIZE * dir.zones[i],
+                    );
+                }
+                dir.size += size_of::<DirEntry>() as u32;
+                Self::put_inode(bdev, super_block, dir_num, &dir);
+                (i, j)
+            }
+        };
+        unsafe { dirents.add(j).write(entry) };
+        syc_write(
+            bdev,
+            buffer.get_mut(),
+            BLOCK_SIZE,
+            BLOCK_SIZE * dir.zones[i],
+        );
+        Ok(())
+    }
+
     pub const fn stat(&self, inode: &Inode) -> Stat {
         Stat {
             mode: inode.mode,
@@ -504,20 +735,33 @@ impl MinixFileSystem {
 /// This allows me to do other things before I call the system call (or after).
 /// However, all the things I wanted to do are no longer there, so this
 /// is a worthless function.
-fn syc_read(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
+fn syc_read(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> isize {
     syscall_block_read(bdev, buffer, size, offset)
 }
 
+/// The other direction of [`syc_read`].
+fn syc_write(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> isize {
+    syscall_block_write(bdev, buffer, size, offset)
+}
+
 // We have to start a process when reading from a file since the block
 // device will block. We only want to block in a process context, not an
 // interrupt context.
 struct ProcArgs {
     pub pid: u16,
     pub dev: usize,
-    pub buffer: *mut u8,
+    pub fd: u16,
+    pub inode: Inode,
     pub size: u32,
     pub offset: u32,
-    pub node: u32,
+}
+
+/// What a kernel process read from a file for a user process. The
+/// process picks it up when its read() starts over.
+pub struct FileRead {
+    pub fd: u16,
+    pub offset: u32,
+    pub data: Vec<u8>,
 }
 
 // This is the actual code ran inside of the read process.
@@ -526,40 +770,44 @@ fn read_proc(args_addr: usize) {
 
     // Start the read! Since we're in a kernel process, we can block by putting this
     // process into a waiting state and wait until the block driver returns.
-    let inode = MinixFileSystem::get_inode(args.dev, args.node);
+    // We read into our own buffer, not the user's. Its pages might not even be
+    // there while it waits, and only the system call can fault them back in.
+    let mut data = vec![0_u8; args.size as usize];
     let bytes = MinixFileSystem::read(
         args.dev,
-        &inode.unwrap(),
-        args.buffer,
+        &args.inode,
+        data.as_mut_ptr(),
         args.size,
         args.offset,
     );
+    data.truncate(bytes as usize);
 
-    // Let's write the return result into regs[10], which is A0.
     unsafe {
         let ptr = get_by_pid(args.pid);
         if !ptr.is_null() {
-            (*(*ptr).get_frame_mut()).regs[Registers::A0 as usize] = bytes as usize;
+            (*ptr).data.file_read = Some(FileRead {
+                fd: args.fd,
+                offset: args.offset,
+                data,
+            });
         }
     }
     // This is the process making the system call. The system itself spawns another process
     // which goes out to the block device. Since we're passed the read call, we need to awaken
-    // the process and get it ready to go. The only thing this process needs to clean up is the
-    // tfree(), but the user process doesn't care about that.
+    // the process and get it ready to go. It runs read() again, which now finds the data.
     set_running(args.pid);
 }
 
 /// System calls will call process_read, which will spawn off a kernel process to read
-/// the requested data.
-pub fn process_read(pid: u16, dev: usize, node: u32, buffer: *mut u8, size: u32, offset: u32) {
-    // println!("FS read {}, {}, 0x{:x}, {}, {}", pid, dev, buffer as usize, size, offset);
+/// `size` bytes at `offset` of the file `fd` refers to.
+pub fn process_read(pid: u16, dev: usize, fd: u16, inode: Inode, size: u32, offset: u32) {
     let args = ProcArgs {
         pid,
         dev,
-        buffer,
+        fd,
+        inode,
         size,
         offset,
-        node,
     };
     let boxed_args = Box::new(args);
     set_waiting(pid);
@@ -583,4 +831,5 @@ pub enum FsError {
     Permission,
     IsFile,
     IsDirectory,
+    NoSpace,
 }
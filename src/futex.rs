@@ -0,0 +1,134 @@
+//! # Futex
+//!
+//! Fast user-space mutexes. A thread can go to sleep on a 32-bit word in
+//! its memory with `FUTEX_WAIT` and be woken up by another thread with
+//! `FUTEX_WAKE` on the same word. Waiters are keyed by the physical
+//! address of the word, so threads sharing a page table, or processes
+//! sharing a page, all end up in the same queue.
+//!
+//! Everything else, like the actual locking, is done in user space (see
+//! `userspace/startlib/thread.cpp`). The kernel only ever gets involved when
+//! somebody has to wait.
+
+use alloc::collections::{BTreeMap, VecDeque};
+
+use crate::{
+    cpu::{Registers, TrapFrame},
+    process::{get_by_pid, set_running, set_waiting},
+    uaccess::{self, Access},
+};
+
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+/// Linux lets processes promise that a futex is private to them. Every
+/// futex is keyed by physical address here, so we simply ignore the flag.
+pub const FUTEX_PRIVATE_FLAG: usize = 128;
+
+/// Why a futex operation didn't go through.
+pub enum FutexError {
+    /// The address isn't mapped or isn't 4-byte aligned.
+    Fault,
+    /// `FUTEX_WAIT` found a different value than expected.
+    WouldBlock,
+    /// We don't know this operation.
+    InvalidOp,
+}
+
+// PIDs waiting on each physical address, in the order they went to sleep.
+static mut FUTEX_QUEUES: Option<BTreeMap<usize, VecDeque<u16>>> = None;
+
+/// Translate the futex word of the process that owns `frame`. Kernel
+/// processes run with the MMU off and already pass physical addresses.
+unsafe fn futex_key(frame: *const TrapFrame, uaddr: usize) -> Result<usize, FutexError> {
+    if uaddr % 4 != 0 {
+        return Err(FutexError::Fault);
+    }
+    uaccess::caller(frame)
+        .and_then(|space| uaccess::translate(space, uaddr, Access::Read))
+        .map_err(|_| FutexError::Fault)
+}
+
+/// `FUTEX_WAIT`: if the word at `uaddr` still holds `val`, put the caller to
+/// sleep until somebody wakes it. The check and the sleep can't be torn
+/// apart because we run with interrupts off.
+pub unsafe fn wait(frame: *mut TrapFrame, uaddr: usize, val: u32) -> Result<(), FutexError> {
+    let key = futex_key(frame, uaddr)?;
+    if (key as *const u32).read_volatile() != val {
+        return Err(FutexError::WouldBlock);
+    }
+    let pid = (*frame).pid as u16;
+    let mut queues = FUTEX_QUEUES.take().unwrap_or_default();
+    queues.entry(key).or_default().push_back(pid);
+    FUTEX_QUEUES.replace(queues);
+    // Whoever wakes us up sets our return value.
+    set_waiting(pid);
+    Ok(())
+}
+
+/// `FUTEX_WAKE`: wake up to `count` processes waiting on `uaddr` and return
+/// how many there were.
+pub unsafe fn wake(
+    frame: *const TrapFrame,
+    uaddr: usize,
+    count: usize,
+) -> Result<usize, FutexError> {
+    let key = futex_key(frame, uaddr)?;
+    Ok(wake_key(key, count))
+}
+
+/// Is anybody waiting on a futex in the `len` bytes at the physical address
+/// `addr`? If that memory moved, they would never be woken up.
+pub fn has_waiters(addr: usize, len: usize) -> bool {
+    unsafe {
+        FUTEX_QUEUES.as_ref().map_or(false, |queues| {
+            queues.range(addr..addr + len).next().is_some()
+        })
+    }
+}
+
+/// Wake up to `count` waiters of the physical address `key`.
+pub fn wake_key(key: usize, count: usize) -> usize {
+    let mut woken = 0;
+    unsafe {
+        if let Some(mut queues) = FUTEX_QUEUES.take() {
+            if let Some(queue) = queues.get_mut(&key) {
+                while woken < count {
+                    let pid = match queue.pop_front() {
+                        Some(pid) => pid,
+                        None => break,
+                    };
+                    // The waiter may have died in the meantime. It doesn't
+                    // count then.
+                    let p = get_by_pid(pid);
+                    if p.is_null() {
+                        continue;
+                    }
+                    (*(*p).get_frame_mut()).regs[Registers::A0 as usize] = 0;
+                    set_running(pid);
+                    woken += 1;
+                }
+                if queue.is_empty() {
+                    queues.remove(&key);
+                }
+            }
+            FUTEX_QUEUES.replace(queues);
+        }
+    }
+    woken
+}
+
+/// The futex system call: `futex(uaddr, op, val)`. Returns None if the
+/// caller went to sleep, or the value for A0 otherwise. Timeouts aren't
+/// supported, a waiter sleeps until it is woken.
+pub unsafe fn futex(
+    frame: *mut TrapFrame,
+    uaddr: usize,
+    op: usize,
+    val: usize,
+) -> Result<Option<usize>, FutexError> {
+    match op & !FUTEX_PRIVATE_FLAG {
+        FUTEX_WAIT => wait(frame, uaddr, val as u32).map(|_| None),
+        FUTEX_WAKE => wake(frame, uaddr, val).map(Some),
+        _ => Err(FutexError::InvalidOp),
+    }
+}
@@ -0,0 +1,958 @@
+//! # GDB stub
+//!
+//! A server for GDB's remote serial protocol on the virtio console (see
+//! [`crate::virtio::console`]). Every process is a thread as far as GDB is
+//! concerned, so user programs and kernel processes can be debugged one at
+//! a time while the rest of the system keeps going:
+//!
+//! ```text
+//! $ riscv64-unknown-elf-gdb userspace/shell.elf
+//! (gdb) target remote localhost:4321
+//! (gdb) info threads
+//! (gdb) thread 3
+//! (gdb) break main
+//! (gdb) continue
+//! ```
+//!
+//! A process is held when it runs into one of our breakpoints, when GDB
+//! asks where we are with `?` or when the user presses ^C in GDB. The
+//! scheduler skips held processes until GDB continues them. Memory is read
+//! and written through the page table of the selected process, kernel
+//! processes see physical memory.
+//!
+//! Breakpoints are `ebreak` instructions written over the program. There is
+//! no single-step in M-mode, so stepping puts a temporary breakpoint at the
+//! instruction the process is about to execute next.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::String,
+    vec,
+    vec::Vec,
+};
+use core::{arch::asm, fmt::Write, mem};
+
+use crate::{
+    cpu::{CpuMode, TrapFrame},
+    page::{heap_end, Table, PAGE_SIZE},
+    process::{get_by_pid, ProcessState, FOREGROUND_PID, PROCESS_LIST},
+    uaccess::{self, read_raw, write_raw, Access},
+    virtio::console,
+};
+
+/// Where RAM starts on the virt machine
+const MEMORY_START: usize = 0x8000_0000;
+/// The largest packet we take, 0x1000 as we tell GDB in `qSupported`
+const MAX_PACKET: usize = 4096;
+/// The most memory we send back for one `m` packet
+const MAX_READ: usize = 2048;
+
+const EBREAK: u32 = 0x0010_0073;
+const C_EBREAK: u16 = 0x9002;
+
+// Signals in stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The `g` packet has x0 to x31 and the pc.
+const NUM_GDB_REGS: usize = 33;
+const PC_REGNUM: usize = 32;
+const FIRST_FP_REGNUM: usize = 33;
+const LAST_FP_REGNUM: usize = 64;
+
+/// The process that must never be held: the scheduler needs somebody to
+/// run.
+const INIT_PID: u16 = 1;
+
+/// Where a process' memory is: the address of its root page table, or 0
+/// for the physical memory that kernel processes use.
+type Space = usize;
+
+/// An `ebreak` written over a piece of a program, and what was there
+/// before.
+struct Patch {
+    space: Space,
+    addr: usize,
+    orig: [u8; 4],
+    len: usize,
+}
+
+impl Patch {
+    /// Put a breakpoint of `len` bytes (2 or 4) at `addr`.
+    unsafe fn insert(space: Space, addr: usize, len: usize) -> Option<Self> {
+        let mut orig = [0; 4];
+        if read_mem(space, addr, &mut orig[..len]) != len {
+            return None;
+        }
+        let patch = Self {
+            space,
+            addr,
+            orig,
+            len,
+        };
+        patch.apply().then(|| patch)
+    }
+
+    unsafe fn apply(&self) -> bool {
+        let code = if self.len == 2 {
+            u32::from(C_EBREAK).to_le_bytes()
+        } else {
+            EBREAK.to_le_bytes()
+        };
+        write_mem(self.space, self.addr, &code[..self.len])
+    }
+
+    unsafe fn restore(&self) {
+        write_mem(self.space, self.addr, &self.orig[..self.len]);
+    }
+}
+
+/// A single step in progress
+struct Step {
+    pid: u16,
+    /// The temporary breakpoint at the next instruction. There is none if a
+    /// real breakpoint is already there.
+    temp: Option<Patch>,
+    /// The breakpoint we are stepping off of, which is out of the program
+    /// until the step is done.
+    reinsert: Option<(Space, usize)>,
+    /// Tell GDB once we get there (`s`), or just keep going (`c`)
+    report: bool,
+}
+
+enum RxState {
+    /// Between packets
+    Idle,
+    /// After the '$'
+    Data,
+    /// After the '#', with how many of the two digits we have and their
+    /// value
+    Checksum(u8, u8),
+}
+
+struct Stub {
+    state: RxState,
+    packet: Vec<u8>,
+    /// The last packet we sent, in case GDB asks for it again
+    last: Vec<u8>,
+    /// The thread GDB looks at (`Hg`) and continues (`Hc`)
+    thread: u16,
+    breakpoints: BTreeMap<(Space, usize), Patch>,
+    step: Option<Step>,
+}
+
+// Created once GDB says something.
+static mut STUB: Option<Stub> = None;
+// Processes that GDB has stopped. This is separate from the stub since the
+// scheduler looks at it all the time.
+static mut HELD: Option<BTreeSet<u16>> = None;
+
+/// Is `pid` stopped by the debugger?
+pub fn is_held(pid: u16) -> bool {
+    unsafe { HELD.as_ref().map_or(false, |held| held.contains(&pid)) }
+}
+
+fn hold(pid: u16) {
+    unsafe { HELD.get_or_insert_with(BTreeSet::new).insert(pid) };
+}
+
+fn release_all() {
+    unsafe { HELD = None };
+}
+
+fn first_held() -> Option<u16> {
+    unsafe { HELD.as_ref().and_then(|held| held.iter().next().copied()) }
+}
+
+fn fence_i() {
+    unsafe { asm!("fence.i") };
+}
+
+unsafe fn frame_of(pid: u16) -> Option<*mut TrapFrame> {
+    let p = get_by_pid(pid);
+    if p.is_null() {
+        None
+    } else {
+        Some((*p).get_frame_mut())
+    }
+}
+
+fn space_of(frame: &TrapFrame) -> Space {
+    if frame.mode != CpuMode::User as usize {
+        0
+    } else {
+        (frame.satp & ((1 << 44) - 1)) << 12
+    }
+}
+
+/// Kernel processes can only look at RAM, anything else might be a device
+/// that doesn't like to be read.
+unsafe fn in_ram(addr: usize) -> bool {
+    addr >= MEMORY_START && addr < heap_end()
+}
+
+/// The memory of a user process, for uaccess. Like ptrace, we may read and
+/// write any of its pages, but nothing else its page table maps.
+fn user_space(space: Space) -> uaccess::Space {
+    uaccess::Space::User(space as *mut Table)
+}
+
+/// Read as much of `buf` as we can and return how many bytes that was.
+unsafe fn read_mem(space: Space, addr: usize, buf: &mut [u8]) -> usize {
+    if space == 0 {
+        for (i, dst) in buf.iter_mut().enumerate() {
+            if !in_ram(addr + i) {
+                return i;
+            }
+            *dst = ((addr + i) as *const u8).read_volatile();
+        }
+        return buf.len();
+    }
+    // A page at a time, so that we get everything up to the first page we
+    // can't read.
+    let mut done = 0;
+    while done < buf.len() {
+        let vaddr = addr + done;
+        let chunk = (PAGE_SIZE - vaddr % PAGE_SIZE).min(buf.len() - done);
+        let dst = buf[done..].as_mut_ptr();
+        if read_raw(user_space(space), dst, vaddr, chunk, Access::Debug).is_err() {
+            break;
+        }
+        done += chunk;
+    }
+    done
+}
+
+/// Write `data`, even over read-only pages. Nothing is written unless all
+/// of it is mapped.
+unsafe fn write_mem(space: Space, addr: usize, data: &[u8]) -> bool {
+    if space != 0 {
+        return write_raw(
+            user_space(space),
+            addr,
+            data.as_ptr(),
+            data.len(),
+            Access::Debug,
+        )
+        .is_ok();
+    }
+    if (0..data.len()).any(|i| !in_ram(addr + i)) {
+        return false;
+    }
+    for (i, c) in data.iter().enumerate() {
+        ((addr + i) as *mut u8).write_volatile(*c);
+    }
+    true
+}
+
+fn sign_extend(value: u32, bits: u32) -> usize {
+    ((value << (32 - bits)) as i32 >> (32 - bits)) as isize as usize
+}
+
+/// Where the instruction at the pc of `frame` takes the process. Branches
+/// are decided with the registers as they are, so there is only one
+/// answer.
+unsafe fn next_pc(space: Space, frame: &TrapFrame) -> Option<(usize, usize)> {
+    let pc = frame.pc;
+    let reg = |r: u32| frame.regs[r as usize];
+    let mut code = [0; 4];
+    if read_mem(space, pc, &mut code[..2]) != 2 {
+        return None;
+    }
+    let half = u32::from(u16::from_le_bytes([code[0], code[1]]));
+    let next = if half & 3 != 3 {
+        let funct3 = half >> 13 & 7;
+        match (half & 3, funct3) {
+            // c.j
+            (1, 0b101) => {
+                let imm = (half >> 12 & 1) << 11
+                    | (half >> 11 & 1) << 4
+                    | (half >> 9 & 3) << 8
+                    | (half >> 8 & 1) << 10
+                    | (half >> 7 & 1) << 6
+                    | (half >> 6 & 1) << 7
+                    | (half >> 3 & 7) << 1
+                    | (half >> 2 & 1) << 5;
+                pc.wrapping_add(sign_extend(imm, 12))
+            }
+            // c.beqz, c.bnez
+            (1, 0b110 | 0b111) => {
+                let imm = (half >> 12 & 1) << 8
+                    | (half >> 10 & 3) << 3
+                    | (half >> 5 & 3) << 6
+                    | (half >> 3 & 3) << 1
+                    | (half >> 2 & 1) << 5;
+                let is_zero = reg(8 + (half >> 7 & 7)) == 0;
+                if is_zero == (funct3 == 0b110) {
+                    pc.wrapping_add(sign_extend(imm, 9))
+                } else {
+                    pc + 2
+                }
+            }
+            // c.jr, c.jalr
+            (2, 0b100) if half >> 2 & 0x1f == 0 && half >> 7 & 0x1f != 0 => {
+                reg(half >> 7 & 0x1f) & !1
+            }
+            _ => pc + 2,
+        }
+    } else {
+        if read_mem(space, pc, &mut code) != 4 {
+            return None;
+        }
+        let inst = u32::from_le_bytes(code);
+        let (rs1, rs2) = (inst >> 15 & 0x1f, inst >> 20 & 0x1f);
+        match inst & 0x7f {
+            // jal
+            0x6f => {
+                let imm = (inst >> 31 & 1) << 20
+                    | (inst >> 21 & 0x3ff) << 1
+                    | (inst >> 20 & 1) << 11
+                    | (inst >> 12 & 0xff) << 12;
+                pc.wrapping_add(sign_extend(imm, 21))
+            }
+            // jalr
+            0x67 => reg(rs1).wrapping_add(sign_extend(inst >> 20, 12)) & !1,
+            // Conditional branches
+            0x63 => {
+                let (a, b) = (reg(rs1), reg(rs2));
+                let taken = match inst >> 12 & 7 {
+                    0 => a == b,
+                    1 => a != b,
+                    4 => (a as i64) < (b as i64),
+                    5 => (a as i64) >= (b as i64),
+                    6 => a < b,
+                    7 => a >= b,
+                    _ => false,
+                };
+                if taken {
+                    let imm = (inst >> 31 & 1) << 12
+                        | (inst >> 25 & 0x3f) << 5
+                        | (inst >> 8 & 0xf) << 1
+                        | (inst >> 7 & 1) << 11;
+                    pc.wrapping_add(sign_extend(imm, 13))
+                } else {
+                    pc + 4
+                }
+            }
+            _ => pc + 4,
+        }
+    };
+    // The breakpoint has to be as long as the instruction it replaces.
+    let mut lo = [0; 2];
+    if read_mem(space, next, &mut lo) != 2 {
+        return None;
+    }
+    Some((next, if lo[0] & 3 == 3 { 4 } else { 2 }))
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    s.chunks(2)
+        .map(|pair| Some(hex_value(pair[0])? << 4 | hex_value(pair[1])?))
+        .collect()
+}
+
+fn encode_hex(out: &mut String, data: &[u8]) {
+    for c in data {
+        let _ = write!(out, "{:02x}", c);
+    }
+}
+
+/// Registers go over the wire in target byte order.
+fn parse_register(s: &str) -> Option<usize> {
+    let bytes = decode_hex(s)?;
+    let mut value = [0; 8];
+    value.get_mut(..bytes.len())?.copy_from_slice(&bytes);
+    Some(usize::from_le_bytes(value))
+}
+
+/// A stop reply for `pid`
+fn stop_reply(signo: u8, pid: u16) -> String {
+    format!("T{:02x}thread:{:x};", signo, pid)
+}
+
+/// Who to stop when GDB doesn't say: whoever has the console, or else the
+/// first process that isn't init.
+fn default_thread() -> Option<u16> {
+    unsafe {
+        if FOREGROUND_PID != 0 && !get_by_pid(FOREGROUND_PID).is_null() {
+            return Some(FOREGROUND_PID);
+        }
+        PROCESS_LIST
+            .as_ref()
+            .and_then(|pl| pl.iter().map(|p| p.get_pid()).find(|pid| *pid != INIT_PID))
+    }
+}
+
+impl Stub {
+    fn new() -> Self {
+        Self {
+            state: RxState::Idle,
+            packet: Vec::new(),
+            last: Vec::new(),
+            thread: 0,
+            breakpoints: BTreeMap::new(),
+            step: None,
+        }
+    }
+
+    fn send(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0_u8, u8::wrapping_add);
+        let mut packet = format!("${}#{:02x}", data, checksum).into_bytes();
+        console::write(&packet);
+        mem::swap(&mut self.last, &mut packet);
+    }
+
+    /// Run one byte from GDB through the packet framing.
+    fn input(&mut self, c: u8) {
+        match self.state {
+            RxState::Idle => match c {
+                b'$' => {
+                    self.packet.clear();
+                    self.state = RxState::Data;
+                }
+                // ^C
+                0x03 => self.interrupt(),
+                // GDB didn't get our last packet right.
+                b'-' => {
+                    console::write(&self.last);
+                }
+                // '+' acknowledges our last packet. Anything else is noise.
+                _ => {}
+            },
+            RxState::Data => {
+                if c == b'#' {
+                    self.state = RxState::Checksum(0, 0);
+                } else if self.packet.len() < MAX_PACKET {
+                    self.packet.push(c);
+                } else {
+                    self.state = RxState::Idle;
+                    console::write(b"-");
+                }
+            }
+            RxState::Checksum(digits, value) => {
+                // A bad digit makes the checksum wrong, which is all we need.
+                let value = value << 4 | hex_value(c).unwrap_or(0xff);
+                if digits == 0 {
+                    self.state = RxState::Checksum(1, value);
+                    return;
+                }
+                self.state = RxState::Idle;
+                let checksum = self.packet.iter().fold(0_u8, |a, b| a.wrapping_add(*b));
+                if value != checksum {
+                    console::write(b"-");
+                    return;
+                }
+                console::write(b"+");
+                let packet = mem::take(&mut self.packet);
+                // Everything we understand is plain ASCII.
+                let reply = match core::str::from_utf8(&packet) {
+                    Ok(packet) => self.handle(packet),
+                    Err(_) => Some(String::new()),
+                };
+                if let Some(reply) = reply {
+                    self.send(&reply);
+                }
+            }
+        }
+    }
+
+    /// Answer a packet. None means that the answer comes later, once the
+    /// process stops again.
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let args = packet.get(1..).unwrap_or("");
+        let reply = match packet.bytes().next() {
+            Some(b'?') => self.query_stop(),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(args),
+            Some(b'p') => self.read_register(args),
+            Some(b'P') => self.write_register(args),
+            Some(b'm') => self.read_memory(args),
+            Some(b'M') => self.write_memory(args),
+            Some(b'c') => return self.resume(args, false),
+            Some(b's') => return self.resume(args, true),
+            Some(b'Z') => self.insert_breakpoint(args),
+            Some(b'z') => self.remove_breakpoint(args),
+            Some(b'H') => self.set_thread(args),
+            Some(b'T') => self.thread_alive(args),
+            Some(b'q') => self.query(args),
+            Some(b'D') => {
+                self.detach();
+                String::from("OK")
+            }
+            // We don't kill anything just because GDB goes away.
+            Some(b'k') => {
+                self.detach();
+                return None;
+            }
+            // An empty reply means we don't know the packet.
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    /// The frame of the thread GDB looks at.
+    fn frame(&self) -> Option<&'static mut TrapFrame> {
+        unsafe { frame_of(self.thread).map(|frame| &mut *frame) }
+    }
+
+    /// Hold `pid` and tell GDB about it.
+    fn stop(&mut self, pid: u16, signo: u8) {
+        hold(pid);
+        self.thread = pid;
+        self.send(&stop_reply(signo, pid));
+    }
+
+    /// Pick a thread to hold when GDB wants one stopped and we have none.
+    fn stop_someone(&mut self) -> Option<u16> {
+        let pid = if self.thread != INIT_PID && self.frame().is_some() {
+            self.thread
+        } else {
+            default_thread()?
+        };
+        hold(pid);
+        self.thread = pid;
+        Some(pid)
+    }
+
+    /// `?`: why did we stop? GDB asks this right after connecting, so that
+    /// is when we stop somebody.
+    fn query_stop(&mut self) -> String {
+        let pid = if is_held(self.thread) {
+            Some(self.thread)
+        } else {
+            first_held().or_else(|| self.stop_someone())
+        };
+        match pid {
+            Some(pid) => {
+                self.thread = pid;
+                stop_reply(SIGTRAP, pid)
+            }
+            // Nobody but init is there. Let GDB look at it, but don't
+            // actually hold it.
+            None => {
+                self.thread = INIT_PID;
+                stop_reply(SIGTRAP, INIT_PID)
+            }
+        }
+    }
+
+    /// ^C from GDB
+    fn interrupt(&mut self) {
+        match self.stop_someone() {
+            Some(pid) => self.send(&stop_reply(SIGINT, pid)),
+            None => self.send(&stop_reply(SIGINT, INIT_PID)),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let frame = match self.frame() {
+            Some(frame) => frame,
+            None => return String::from("E01"),
+        };
+        let mut out = String::with_capacity(NUM_GDB_REGS * 16);
+        encode_hex(&mut out, &0_usize.to_le_bytes());
+        for reg in &frame.regs[1..] {
+            encode_hex(&mut out, &reg.to_le_bytes());
+        }
+        encode_hex(&mut out, &frame.pc.to_le_bytes());
+        out
+    }
+
+    fn write_registers(&self, args: &str) -> String {
+        let frame = match self.frame() {
+            Some(frame) => frame,
+            None => return String::from("E01"),
+        };
+        for (regnum, value) in args.as_bytes().chunks(16).enumerate().take(NUM_GDB_REGS) {
+            let value = match core::str::from_utf8(value).ok().and_then(parse_register) {
+                Some(value) => value,
+                None => return String::from("E01"),
+            };
+            match regnum {
+                0 => {}
+                PC_REGNUM => frame.pc = value,
+                _ => frame.regs[regnum] = value,
+            }
+        }
+        String::from("OK")
+    }
+
+    fn register(frame: &mut TrapFrame, regnum: usize) -> Option<&mut usize> {
+        match regnum {
+            1..=31 => Some(&mut frame.regs[regnum]),
+            PC_REGNUM => Some(&mut frame.pc),
+            FIRST_FP_REGNUM..=LAST_FP_REGNUM => Some(&mut frame.fregs[regnum - FIRST_FP_REGNUM]),
+            _ => None,
+        }
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let (frame, regnum) = match (self.frame(), parse_hex(args)) {
+            (Some(frame), Some(regnum)) => (frame, regnum),
+            _ => return String::from("E01"),
+        };
+        let mut out = String::new();
+        match Self::register(frame, regnum) {
+            Some(value) => encode_hex(&mut out, &value.to_le_bytes()),
+            // x0, and the CSRs GDB knows about but we don't keep
+            None if regnum == 0 => encode_hex(&mut out, &[0; 8]),
+            None => out.push_str("xxxxxxxxxxxxxxxx"),
+        }
+        out
+    }
+
+    fn write_register(&self, args: &str) -> String {
+        let parsed = args
+            .split_once('=')
+            .and_then(|(regnum, value)| Some((parse_hex(regnum)?, parse_register(value)?)));
+        match (self.frame(), parsed) {
+            // x0 stays zero.
+            (Some(_), Some((0, _))) => String::from("OK"),
+            (Some(frame), Some((regnum, value))) => match Self::register(frame, regnum) {
+                Some(reg) => {
+                    *reg = value;
+                    String::from("OK")
+                }
+                None => String::from("E01"),
+            },
+            _ => String::from("E01"),
+        }
+    }
+
+    /// `m addr,length`
+    fn read_memory(&self, args: &str) -> String {
+        let parsed = args
+            .split_once(',')
+            .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)));
+        let (frame, (addr, len)) = match (self.frame(), parsed) {
+            (Some(frame), Some(parsed)) => (frame, parsed),
+            _ => return String::from("E01"),
+        };
+        let mut buf = vec![0; len.min(MAX_READ)];
+        let n = unsafe { read_mem(space_of(frame), addr, &mut buf) };
+        if n == 0 && len != 0 {
+            return String::from("E14");
+        }
+        let mut out = String::with_capacity(n * 2);
+        encode_hex(&mut out, &buf[..n]);
+        out
+    }
+
+    /// `M addr,length:data`
+    fn write_memory(&self, args: &str) -> String {
+        let parsed = args.split_once(':').and_then(|(range, data)| {
+            let (addr, len) = range.split_once(',')?;
+            let data = decode_hex(data)?;
+            if parse_hex(len)? != data.len() {
+                return None;
+            }
+            Some((parse_hex(addr)?, data))
+        });
+        let (frame, (addr, data)) = match (self.frame(), parsed) {
+            (Some(frame), Some(parsed)) => (frame, parsed),
+            _ => return String::from("E01"),
+        };
+        if unsafe { write_mem(space_of(frame), addr, &data) } {
+            // GDB may have changed code.
+            fence_i();
+            String::from("OK")
+        } else {
+            String::from("E14")
+        }
+    }
+
+    /// `c [addr]` and `s [addr]`. All held threads go again, but only the
+    /// current one steps.
+    fn resume(&mut self, args: &str, step: bool) -> Option<String> {
+        if let (Some(frame), Some(addr)) = (self.frame(), parse_hex(args)) {
+            frame.pc = addr;
+        }
+        if step {
+            if !self.start_step(true) {
+                // We can't tell where it goes. Say we're done right away.
+                return Some(stop_reply(SIGTRAP, self.thread));
+            }
+        } else if let Some(frame) = self.frame() {
+            // Sitting on a breakpoint, the thread has to get past it
+            // before the breakpoint can go back in.
+            if is_held(self.thread) && self.breakpoints.contains_key(&(space_of(frame), frame.pc)) {
+                self.start_step(false);
+            }
+        }
+        release_all();
+        None
+    }
+
+    /// Make the current thread run exactly one instruction.
+    fn start_step(&mut self, report: bool) -> bool {
+        let frame = match self.frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let space = space_of(frame);
+        let (next, len) = match unsafe { next_pc(space, frame) } {
+            Some(next) => next,
+            None => return false,
+        };
+        self.cancel_step();
+        let here = (space, frame.pc);
+        let reinsert = self.breakpoints.get(&here).map(|bp| {
+            unsafe { bp.restore() };
+            here
+        });
+        let temp = if self.breakpoints.contains_key(&(space, next)) {
+            None
+        } else {
+            match unsafe { Patch::insert(space, next, len) } {
+                Some(patch) => Some(patch),
+                None => {
+                    if let Some(bp) = self.breakpoints.get(&here) {
+                        unsafe { bp.apply() };
+                    }
+                    return false;
+                }
+            }
+        };
+        fence_i();
+        self.step = Some(Step {
+            pid: self.thread,
+            temp,
+            reinsert,
+            report,
+        });
+        true
+    }
+
+    /// Undo whatever a step that is still in progress did to the program.
+    fn cancel_step(&mut self) {
+        if let Some(step) = self.step.take() {
+            unsafe {
+                if let Some(temp) = step.temp {
+                    temp.restore();
+                }
+                if let Some(bp) = step.reinsert.and_then(|key| self.breakpoints.get(&key)) {
+                    bp.apply();
+                }
+            }
+            fence_i();
+        }
+    }
+
+    /// `Z0,addr,kind` and `z0,addr,kind`. The kind is the length of the
+    /// breakpoint, 2 for compressed instructions. Only software
+    /// breakpoints are supported.
+    fn parse_breakpoint(&self, args: &str) -> Option<(Space, usize, usize)> {
+        let mut fields = args.split(',');
+        if fields.next()? != "0" {
+            return None;
+        }
+        let addr = parse_hex(fields.next()?)?;
+        let len = match parse_hex(fields.next()?)? {
+            2 => 2,
+            _ => 4,
+        };
+        Some((space_of(self.frame()?), addr, len))
+    }
+
+    fn insert_breakpoint(&mut self, args: &str) -> String {
+        let (space, addr, len) = match self.parse_breakpoint(args) {
+            Some(bp) => bp,
+            None => return String::new(),
+        };
+        if self.breakpoints.contains_key(&(space, addr)) {
+            return String::from("OK");
+        }
+        match unsafe { Patch::insert(space, addr, len) } {
+            Some(patch) => {
+                fence_i();
+                self.breakpoints.insert((space, addr), patch);
+                String::from("OK")
+            }
+            None => String::from("E14"),
+        }
+    }
+
+    fn remove_breakpoint(&mut self, args: &str) -> String {
+        let (space, addr, _) = match self.parse_breakpoint(args) {
+            Some(bp) => bp,
+            None => return String::new(),
+        };
+        if let Some(patch) = self.breakpoints.remove(&(space, addr)) {
+            // If a step took it out already, it must stay out.
+            let stepping_off = self
+                .step
+                .as_ref()
+                .map_or(false, |step| step.reinsert == Some((space, addr)));
+            if !stepping_off {
+                unsafe { patch.restore() };
+                fence_i();
+            }
+        }
+        String::from("OK")
+    }
+
+    /// `Hg tid` and `Hc tid`. We use the same thread for both. 0 and -1
+    /// (any and all) leave it as it is.
+    fn set_thread(&mut self, args: &str) -> String {
+        let tid = args.get(1..).unwrap_or("");
+        if tid == "0" || tid == "-1" {
+            return String::from("OK");
+        }
+        match parse_hex(tid) {
+            Some(pid) if unsafe { frame_of(pid as u16).is_some() } => {
+                self.thread = pid as u16;
+                String::from("OK")
+            }
+            _ => String::from("E01"),
+        }
+    }
+
+    fn thread_alive(&self, args: &str) -> String {
+        match parse_hex(args) {
+            Some(pid) if unsafe { frame_of(pid as u16).is_some() } => String::from("OK"),
+            _ => String::from("E01"),
+        }
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            format!("PacketSize={:x}", MAX_PACKET)
+        } else if args == "fThreadInfo" {
+            let pids: Vec<String> = unsafe {
+                PROCESS_LIST
+                    .as_ref()
+                    .map(|pl| pl.iter().map(|p| format!("{:x}", p.get_pid())).collect())
+                    .unwrap_or_default()
+            };
+            format!("m{}", pids.join(","))
+        } else if args == "sThreadInfo" {
+            String::from("l")
+        } else if args == "C" {
+            format!("QC{:x}", self.thread)
+        } else if args == "Attached" {
+            String::from("1")
+        } else if let Some(tid) = args.strip_prefix("ThreadExtraInfo,") {
+            let mut out = String::new();
+            let info = parse_hex(tid).map_or_else(String::new, |pid| describe(pid as u16));
+            encode_hex(&mut out, info.as_bytes());
+            out
+        } else {
+            String::new()
+        }
+    }
+
+    /// GDB is going away. Take all breakpoints out and let everybody run.
+    fn detach(&mut self) {
+        self.cancel_step();
+        for patch in self.breakpoints.values() {
+            unsafe { patch.restore() };
+        }
+        self.breakpoints.clear();
+        fence_i();
+        release_all();
+    }
+
+    /// Process `pid` ran into an `ebreak` at `pc`. Returns true if it was
+    /// one of ours.
+    fn trapped(&mut self, pid: u16, space: Space, pc: usize) -> bool {
+        let key = (space, pc);
+        let on_breakpoint = self.breakpoints.contains_key(&key);
+        if let Some(step) = self.step.take() {
+            let on_temp = step
+                .temp
+                .as_ref()
+                .map_or(false, |temp| (temp.space, temp.addr) == key);
+            if step.pid == pid && (on_temp || on_breakpoint) {
+                unsafe {
+                    if let Some(temp) = &step.temp {
+                        temp.restore();
+                    }
+                    if let Some(bp) = step.reinsert.and_then(|key| self.breakpoints.get(&key)) {
+                        bp.apply();
+                    }
+                }
+                fence_i();
+                if step.report || on_breakpoint {
+                    self.stop(pid, SIGTRAP);
+                }
+                return true;
+            }
+            self.step = Some(step);
+            if on_temp {
+                // Another thread of the same program got there first. It
+                // tries again once the step is over and the ebreak is gone.
+                return true;
+            }
+        }
+        if on_breakpoint {
+            self.stop(pid, SIGTRAP);
+        }
+        on_breakpoint
+    }
+}
+
+/// What `info threads` shows next to a thread
+fn describe(pid: u16) -> String {
+    unsafe {
+        let p = get_by_pid(pid);
+        if p.is_null() {
+            return String::new();
+        }
+        let state = match (*p).get_state() {
+            ProcessState::Running => "running",
+            ProcessState::Sleeping => "sleeping",
+            ProcessState::Waiting => "waiting",
+            ProcessState::Stopped => "stopped",
+            ProcessState::Dead => "dead",
+        };
+        let program = if (*(*p).get_frame()).mode == CpuMode::User as usize {
+            (*p).data.path.as_str()
+        } else {
+            "kernel"
+        };
+        let held = if is_held(pid) { ", held" } else { "" };
+        format!("{} ({}{})", program, state, held)
+    }
+}
+
+/// A byte from GDB. This is called from the console's interrupt handler.
+pub fn receive(c: u8) {
+    unsafe {
+        let mut stub = STUB.take().unwrap_or_else(Stub::new);
+        stub.input(c);
+        STUB.replace(stub);
+    }
+}
+
+/// A process ran into an `ebreak`. Returns true if it belongs to the
+/// debugger, in which case the process is either held now or may simply
+/// go on where it was. Otherwise the `ebreak` is the program's own.
+pub fn breakpoint(frame: *const TrapFrame) -> bool {
+    unsafe {
+        let mut stub = match STUB.take() {
+            Some(stub) => stub,
+            None => return false,
+        };
+        let ret = stub.trapped((*frame).pid as u16, space_of(&*frame), (*frame).pc);
+        STUB.replace(stub);
+        ret
+    }
+}
@@ -1,68 +1,149 @@
 use core::{mem::size_of, ptr::null_mut};
 
-use crate::page::{align_val, zalloc, Table, PAGE_SIZE};
+use crate::{
+    cpu::{build_satp, clint_mmio, pmp_guard, satp_fence_asid, SatpMode},
+    page::{
+        alloc, dealloc, heap_end, map, map_range, unmap_page, zalloc, EntryBits, Table, PAGE_SIZE,
+    },
+    plic, power, uart, virtio,
+};
 
-#[repr(usize)]
-enum AllocListFlags {
-    Taken = 1 << 63,
+// The kernel heap hands out small objects from slabs: pages cut into
+// objects of one size class, powers of two from MIN_CLASS to MAX_CLASS
+// bytes. A slab starts with its Slab header, and its objects are aligned
+// to their size, so no object is ever page-aligned. Anything bigger gets
+// pages of its own straight from the page allocator, which are. That is
+// how kfree tells the two apart, and why it never has to search.
+//
+// The heap grows a page at a time as slabs fill up and gives slabs back
+// to the page allocator when they're empty, so it can use all of memory.
+
+const MIN_CLASS_ORDER: usize = 4;
+const MAX_CLASS_ORDER: usize = 10;
+/// The smallest object we hand out, and the alignment you get at least
+const MIN_CLASS: usize = 1 << MIN_CLASS_ORDER;
+/// The biggest object that comes from a slab
+const MAX_CLASS: usize = 1 << MAX_CLASS_ORDER;
+const NUM_CLASSES: usize = MAX_CLASS_ORDER - MIN_CLASS_ORDER + 1;
+
+// A free object points to the next free object of its slab.
+struct FreeObject {
+    next: *mut FreeObject,
 }
 
-impl AllocListFlags {
-    pub const fn val(self) -> usize {
-        self as usize
-    }
+// The start of every slab page
+struct Slab {
+    // Slabs of a class that have free objects are on a list.
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    class: usize,
+    used: usize,
 }
 
-struct AllocList {
-    pub flags_size: usize,
+// Per size class, the slabs with free objects.
+static mut PARTIAL: [*mut Slab; NUM_CLASSES] = [null_mut(); NUM_CLASSES];
+// How many pages are slabs. Big allocations aren't counted, they're just
+// pages.
+static mut KMEM_ALLOC: usize = 0;
+static mut KMEM_PAGE_TABLE: *mut Table = null_mut();
+
+pub fn get_page_table() -> *mut Table {
+    unsafe { KMEM_PAGE_TABLE as *mut Table }
 }
-impl AllocList {
-    pub const fn is_taken(&self) -> bool {
-        self.flags_size & AllocListFlags::Taken.val() != 0
-    }
 
-    pub const fn is_free(&self) -> bool {
-        !self.is_taken()
-    }
+/// The address space ID of the kernel page table. PIDs, which user
+/// processes use as theirs, start at 1.
+const KERNEL_ASID: usize = 0;
 
-    pub fn set_taken(&mut self) {
-        self.flags_size |= AllocListFlags::Taken.val();
-    }
+/// What kernel threads have in `satp`: the kernel page table
+pub fn kernel_satp() -> usize {
+    build_satp(SatpMode::Sv39, KERNEL_ASID, get_page_table() as usize)
+}
 
-    pub fn set_free(&mut self) {
-        self.flags_size &= !AllocListFlags::Taken.val();
-    }
+extern "C" {
+    static TEXT_START: usize;
+    static RODATA_START: usize;
+    static DATA_START: usize;
+    static KERNEL_STACK_START: usize;
+    static KERNEL_STACK_END: usize;
+}
 
-    pub fn set_size(&mut self, sz: usize) {
-        let k = self.is_taken();
-        self.flags_size = sz & !AllocListFlags::Taken.val();
-        if k {
-            self.flags_size |= AllocListFlags::Taken.val();
-        }
+/// How much of the boot stack each hart gets, counting down from its end.
+/// boot.S carves it up the same way.
+pub const BOOT_STACK_SIZE: usize = 0x10000;
+
+/// The guard page at the bottom of `hart`'s part of the boot stack, unless
+/// that part doesn't fit. Hart 0 boots on its part, and the trap handler
+/// runs on it too.
+pub fn boot_stack_guard(hart: usize) -> Option<usize> {
+    unsafe {
+        KERNEL_STACK_END
+            .checked_sub((hart + 1) * BOOT_STACK_SIZE)
+            .filter(|&guard| guard >= KERNEL_STACK_START)
     }
+}
 
-    pub const fn get_size(&self) -> usize {
-        self.flags_size & !AllocListFlags::Taken.val()
+/// Put the guard page below this hart's boot stack, see [`pmp_guard`].
+pub fn guard_boot_stack(hart: usize) {
+    if let Some(guard) = boot_stack_guard(hart) {
+        pmp_guard(guard);
     }
 }
 
-// This is the head of the allocation. We start here when
-// we search for a free memory location.
-static mut KMEM_HEAD: *mut AllocList = null_mut();
-// In the future, we will have on-demand pages
-// so, we need to keep track of our memory footprint to
-// see if we actually need to allocate more.
-static mut KMEM_ALLOC: usize = 0;
-static mut KMEM_PAGE_TABLE: *mut Table = null_mut();
+/// Unmap `page` from the kernel page table, so that a kernel thread whose
+/// stack runs into it faults. Machine mode doesn't translate addresses, so
+/// the page allocator can still write to it.
+pub fn guard_page(page: *mut u8) {
+    unsafe {
+        unmap_page(&mut *KMEM_PAGE_TABLE, page as usize);
+    }
+    satp_fence_asid(KERNEL_ASID);
+}
 
-// These functions are safe helpers around an unsafe
-// operation.
-pub fn get_head() -> *mut u8 {
-    unsafe { KMEM_HEAD as *mut u8 }
+/// Map `page` again after [`guard_page`], before it is freed.
+pub fn unguard_page(page: *mut u8) {
+    unsafe {
+        map(
+            &mut *KMEM_PAGE_TABLE,
+            page as usize,
+            page as usize,
+            EntryBits::ReadWrite.val(),
+            0,
+        );
+    }
+    satp_fence_asid(KERNEL_ASID);
 }
 
-pub fn get_page_table() -> *mut Table {
-    unsafe { KMEM_PAGE_TABLE as *mut Table }
+/// Map the kernel one to one for kernel threads. No page is both writable
+/// and executable: .text is read/execute and .rodata read-only (virt.lds
+/// starts both on a page), while .data, .bss, the stack, the heap and the
+/// devices are read/write.
+fn map_kernel(table: &mut Table) {
+    unsafe {
+        let text = TEXT_START;
+        let rodata = RODATA_START;
+        let data = DATA_START;
+        map_range(
+            table,
+            text,
+            text,
+            rodata - text,
+            EntryBits::ReadExecute.val(),
+        );
+        map_range(table, rodata, rodata, data - rodata, EntryBits::Read.val());
+        map_range(
+            table,
+            data,
+            data,
+            heap_end() - data,
+            EntryBits::ReadWrite.val(),
+        );
+    }
+    let devices = [uart::mmio(), plic::mmio(), clint_mmio(), power::mmio()];
+    for (base, len) in devices.into_iter().chain(virtio::mmio()) {
+        map_range(table, base, base, len, EntryBits::ReadWrite.val());
+    }
 }
 
 pub fn get_num_allocations() -> usize {
@@ -75,22 +156,135 @@ pub fn get_num_allocations() -> usize {
 /// alloc/dealloc from the page crate.
 pub fn init() {
     unsafe {
-        // Allocate kernel pages (KMEM_ALLOC)
-        KMEM_ALLOC = 2048;
-        let k_alloc = zalloc(KMEM_ALLOC);
-        assert!(!k_alloc.is_null());
-        KMEM_HEAD = k_alloc as *mut AllocList;
-        (*KMEM_HEAD).set_free();
-        (*KMEM_HEAD).set_size(KMEM_ALLOC * PAGE_SIZE);
+        // The heap itself starts out empty, it takes pages as it goes.
         KMEM_PAGE_TABLE = zalloc(1) as *mut Table;
+        map_kernel(&mut *KMEM_PAGE_TABLE);
+    }
+}
+
+/// The size class index for `size` bytes aligned to `align`, if that is
+/// small enough for a slab
+fn class_of(size: usize, align: usize) -> Option<usize> {
+    let size = size.max(align).max(MIN_CLASS).checked_next_power_of_two()?;
+    (size <= MAX_CLASS).then(|| size.trailing_zeros() as usize - MIN_CLASS_ORDER)
+}
+
+/// Where the first object of a slab of `class` is. Objects are aligned to
+/// their size, and the header comes first.
+const fn first_object(class: usize) -> usize {
+    let size = MIN_CLASS << class;
+    (size_of::<Slab>() + size - 1) & !(size - 1)
+}
+
+/// Put `slab` on the list of its class.
+unsafe fn push_partial(slab: *mut Slab) {
+    let class = (*slab).class;
+    (*slab).prev = null_mut();
+    (*slab).next = PARTIAL[class];
+    if let Some(next) = PARTIAL[class].as_mut() {
+        next.prev = slab;
+    }
+    PARTIAL[class] = slab;
+}
+
+/// Take `slab` off the list of its class.
+unsafe fn remove_partial(slab: *mut Slab) {
+    match (*slab).prev.as_mut() {
+        Some(prev) => prev.next = (*slab).next,
+        None => PARTIAL[(*slab).class] = (*slab).next,
+    }
+    if let Some(next) = (*slab).next.as_mut() {
+        next.prev = (*slab).prev;
+    }
+    (*slab).next = null_mut();
+    (*slab).prev = null_mut();
+}
+
+/// Grow the heap by a slab of `class`.
+unsafe fn new_slab(class: usize) -> *mut Slab {
+    let slab = alloc(1) as *mut Slab;
+    if slab.is_null() {
+        return slab;
+    }
+    let size = MIN_CLASS << class;
+    let mut free = null_mut();
+    // Thread the objects back to front, so they are handed out in order.
+    let mut off = PAGE_SIZE - size;
+    while off >= first_object(class) {
+        let object = (slab as *mut u8).add(off) as *mut FreeObject;
+        (*object).next = free;
+        free = object;
+        off -= size;
+    }
+    slab.write(Slab {
+        next: null_mut(),
+        prev: null_mut(),
+        free,
+        class,
+        used: 0,
+    });
+    push_partial(slab);
+    KMEM_ALLOC += 1;
+    slab
+}
+
+/// Allocate `size` bytes aligned to `align`, which must be a power of two.
+pub fn kmalloc_aligned(size: usize, align: usize) -> *mut u8 {
+    #[cfg(feature = "heap-debug")]
+    return debug::kmalloc(size, align);
+    #[cfg(not(feature = "heap-debug"))]
+    raw_alloc(size, align)
+}
+
+/// Free a sub-page level allocation
+pub fn kfree(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    #[cfg(feature = "heap-debug")]
+    debug::kfree(ptr);
+    #[cfg(not(feature = "heap-debug"))]
+    raw_free(ptr);
+}
+
+fn raw_alloc(size: usize, align: usize) -> *mut u8 {
+    unsafe {
+        let class = match class_of(size, align) {
+            Some(class) => class,
+            None => {
+                // The page allocator aligns blocks to their size rounded
+                // up to a power of two, so asking for more pages gets us a
+                // bigger alignment.
+                let pages = ((size + PAGE_SIZE - 1) / PAGE_SIZE).max(align / PAGE_SIZE);
+                return alloc(pages.max(1));
+            }
+        };
+        let mut slab = PARTIAL[class];
+        if slab.is_null() {
+            slab = new_slab(class);
+            if slab.is_null() {
+                return null_mut();
+            }
+        }
+        let object = (*slab).free;
+        (*slab).free = (*object).next;
+        (*slab).used += 1;
+        if (*slab).free.is_null() {
+            // Full
+            remove_partial(slab);
+        }
+        object as *mut u8
     }
 }
 
 /// Allocate sub-page level allocation based on bytes and zero the memory
 pub fn kzmalloc(sz: usize) -> *mut u8 {
-    let size = align_val(sz, 3);
-    let ret = kmalloc(size);
+    kzmalloc_aligned(sz, 8)
+}
 
+/// Allocate `size` zeroed bytes aligned to `align`.
+pub fn kzmalloc_aligned(size: usize, align: usize) -> *mut u8 {
+    let ret = kmalloc_aligned(size, align);
     if !ret.is_null() {
         for i in 0..size {
             unsafe {
@@ -103,104 +297,258 @@ pub fn kzmalloc(sz: usize) -> *mut u8 {
 
 /// Allocate sub-page level allocation based on bytes
 pub fn kmalloc(sz: usize) -> *mut u8 {
+    kmalloc_aligned(sz, 8)
+}
+
+fn raw_free(ptr: *mut u8) {
     unsafe {
-        let size = align_val(sz, 3) + size_of::<AllocList>();
-        let mut head = KMEM_HEAD;
-        // .add() uses pointer arithmetic, so we type-cast into a u8
-        // so that we multiply by an absolute size (KMEM_ALLOC *
-        // PAGE_SIZE).
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
-
-        while head < tail {
-            if (*head).is_free() && size <= (*head).get_size() {
-                let chunk_size = (*head).get_size();
-                let rem = chunk_size - size;
-                (*head).set_taken();
-                if rem > size_of::<AllocList>() {
-                    let next = (head as *mut u8).add(size) as *mut AllocList;
-                    // There is space remaining here.
-                    (*next).set_free();
-                    (*next).set_size(rem);
-                    (*head).set_size(size);
-                } else {
-                    // If we get here, take the entire chunk
-                    (*head).set_size(chunk_size);
-                }
-                return head.add(1) as *mut u8;
-            }
-            // If we get here, what we saw wasn't a free
-            // chunk, move on to the next.
-            head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+        if ptr as usize % PAGE_SIZE == 0 {
+            // Too big for a slab
+            dealloc(ptr);
+            return;
+        }
+        let slab = (ptr as usize & !(PAGE_SIZE - 1)) as *mut Slab;
+        let class = (*slab).class;
+        let off = ptr as usize % PAGE_SIZE;
+        assert!(
+            class < NUM_CLASSES
+                && off >= first_object(class)
+                && (off - first_object(class)) % (MIN_CLASS << class) == 0,
+            "kfree of {:p}, which kmalloc didn't hand out",
+            ptr
+        );
+        let object = ptr as *mut FreeObject;
+        (*object).next = (*slab).free;
+        if (*slab).free.is_null() {
+            // It was full, now it has room again.
+            push_partial(slab);
+        }
+        (*slab).free = object;
+        (*slab).used -= 1;
+        // Give empty slabs back, but keep the last one of a class around so
+        // that allocating and freeing a single object doesn't take and
+        // return a page every time.
+        if (*slab).used == 0 && !(PARTIAL[class] == slab && (*slab).next.is_null()) {
+            remove_partial(slab);
+            dealloc(slab as *mut u8);
+            KMEM_ALLOC -= 1;
         }
     }
-    // If we get here, we didn't find any free chunks--i.e. there isn't
-    // enough memory for this. TODO: Add on-demand page allocation.
-    null_mut()
 }
 
-/// Free a sub-page level allocation
-pub fn kfree(ptr: *mut u8) {
+/// For debugging purposes, print the kmem table
+pub fn print_table() {
     unsafe {
-        if !ptr.is_null() {
-            let p = (ptr as *mut AllocList).offset(-1);
-            if (*p).is_taken() {
-                (*p).set_free();
+        println!("Kernel heap: {} slab page(s)", KMEM_ALLOC);
+        for (class, &head) in PARTIAL.iter().enumerate() {
+            let mut slab = head;
+            while !slab.is_null() {
+                println!(
+                    "{:p}: Size = {:<5} Used = {}",
+                    slab,
+                    MIN_CLASS << class,
+                    (*slab).used
+                );
+                slab = (*slab).next;
             }
-            // After we free, see if we can combine adjacent free
-            // spots to see if we can reduce fragmentation.
-            coalesce();
         }
     }
+    #[cfg(feature = "heap-debug")]
+    debug::print_leaks();
 }
 
-/// Merge smaller chunks into a bigger chunk
-pub fn coalesce() {
-    unsafe {
-        let mut head = KMEM_HEAD;
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
-
-        while head < tail {
-            let next = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
-            if (*head).get_size() == 0 {
-                // If this happens, then we have a bad heap
-                // (double free or something). However, that
-                // will cause an infinite loop since the next
-                // pointer will never move beyond the current
-                // location.
-                break;
-            } else if next >= tail {
-                // We calculated the next by using the size
-                // given as get_size(), however this could push
-                // us past the tail. In that case, the size is
-                // wrong, hence we break and stop doing what we
-                // need to do.
-                break;
-            } else if (*head).is_free() && (*next).is_free() {
-                // This means we have adjacent blocks needing to
-                // be freed. So, we combine them into one
-                // allocation.
-                (*head).set_size((*head).get_size() + (*next).get_size());
+// ///////////////////////////////////
+// / HEAP DEBUGGING
+// ///////////////////////////////////
+
+// With the heap-debug feature, every chunk gets a header and red zones:
+//
+//     | Header | guard bytes | what kmalloc returns ... | guard bytes |
+//
+// The guard bytes are checked when the chunk is freed, so writing past
+// either end of a chunk is caught. Fresh chunks are filled with
+// POISON_ALLOC and freed ones with POISON_FREE. Freed chunks aren't given
+// back right away but sit in a quarantine for a while, so a second free
+// still finds the header marked as freed, and a write after the free
+// shows up in the poison when the chunk leaves the quarantine. Live
+// chunks are on a list with where they were allocated, which is the leak
+// report of print_table().
+#[cfg(feature = "heap-debug")]
+mod debug {
+    use core::{mem::size_of, ptr::null_mut};
+
+    use super::{raw_alloc, raw_free, MIN_CLASS};
+    use crate::backtrace::{callers, symbolize};
+
+    const REDZONE: usize = 16;
+    const GUARD: u8 = 0xfd;
+    const POISON_ALLOC: u8 = 0xa5;
+    const POISON_FREE: u8 = 0x6b;
+    const MAGIC_LIVE: usize = 0x4b4d_454d_4c49_5645;
+    const MAGIC_FREED: usize = 0x4b4d_454d_4652_4545;
+    /// How many return addresses we keep for an allocation and a free
+    const CALLERS: usize = 8;
+    /// How many freed chunks wait in the quarantine
+    const QUARANTINE_SIZE: usize = 64;
+
+    #[repr(C)]
+    struct Header {
+        // Live chunks are on a list. A freed chunk doesn't need the links
+        // anymore, so the allocator may put its own over them.
+        next: *mut Header,
+        prev: *mut Header,
+        /// What raw_alloc() gave us
+        base: *mut u8,
+        size: usize,
+        allocated_by: [usize; CALLERS],
+        freed_by: [usize; CALLERS],
+        magic: usize,
+    }
+
+    static mut LIVE: *mut Header = null_mut();
+    static mut QUARANTINE: [*mut Header; QUARANTINE_SIZE] = [null_mut(); QUARANTINE_SIZE];
+    static mut QUARANTINE_NEXT: usize = 0;
+
+    unsafe fn header(ptr: *mut u8) -> *mut Header {
+        ptr.sub(REDZONE + size_of::<Header>()) as *mut Header
+    }
+
+    unsafe fn user(header: *mut Header) -> *mut u8 {
+        (header as *mut u8).add(size_of::<Header>() + REDZONE)
+    }
+
+    unsafe fn is_filled(ptr: *const u8, len: usize, byte: u8) -> bool {
+        (0..len).all(|i| *ptr.add(i) == byte)
+    }
+
+    /// Print where the interesting part of a call chain is. The first few
+    /// frames are the allocator itself, which nobody wants to see.
+    fn print_site(what: &str, addrs: &[usize]) {
+        let mut frames = addrs
+            .iter()
+            .take_while(|&&ra| ra != 0)
+            .map(|&ra| (ra, symbolize(ra - 4)))
+            .skip_while(|(_, sym)| {
+                sym.map_or(false, |(name, _)| {
+                    name.contains("kmem::")
+                        || name.starts_with("__rust_")
+                        || name.starts_with("__rg_")
+                        || name.starts_with("alloc::alloc::")
+                })
+            })
+            .peekable();
+        if frames.peek().is_none() {
+            println!("    {} at an unknown place", what);
+        }
+        for (i, (ra, sym)) in frames.take(3).enumerate() {
+            let at = if i == 0 { what } else { "" };
+            match sym {
+                Some((name, off)) => println!("    {:>13} {}+0x{:x}", at, name, off + 4),
+                None => println!("    {:>13} 0x{:x}", at, ra),
             }
-            // If we get here, we might've moved. Recalculate new
-            // head.
-            head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
         }
     }
-}
 
-/// For debugging purposes, print the kmem table
-pub fn print_table() {
-    unsafe {
-        let mut head = KMEM_HEAD;
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
-        while head < tail {
-            println!(
-                "{:p}: Length = {:<10} Taken = {}",
-                head,
-                (*head).get_size(),
-                (*head).is_taken()
-            );
-            head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+    fn print_chunk(header: &Header, ptr: *mut u8) {
+        println!("  {:p}: {} bytes", ptr, header.size);
+        print_site("allocated by", &header.allocated_by);
+        if header.magic == MAGIC_FREED {
+            print_site("freed by", &header.freed_by);
+        }
+    }
+
+    /// Report a broken chunk and stop.
+    unsafe fn bad_chunk(what: &str, ptr: *mut u8) -> ! {
+        println!("kmem: {} {:p}", what, ptr);
+        print_chunk(&*header(ptr), ptr);
+        panic!("Kernel heap corruption: {} {:p}", what, ptr);
+    }
+
+    pub fn kmalloc(size: usize, align: usize) -> *mut u8 {
+        unsafe {
+            // Whatever kmalloc returns has to stay aligned.
+            let front = (size_of::<Header>() + REDZONE + align.max(MIN_CLASS) - 1)
+                & !(align.max(MIN_CLASS) - 1);
+            let base = raw_alloc(front + size + REDZONE, align);
+            if base.is_null() {
+                return base;
+            }
+            let ptr = base.add(front);
+            let header = header(ptr);
+            header.write(Header {
+                next: LIVE,
+                prev: null_mut(),
+                base,
+                size,
+                allocated_by: [0; CALLERS],
+                freed_by: [0; CALLERS],
+                magic: MAGIC_LIVE,
+            });
+            callers(&mut (*header).allocated_by);
+            if let Some(next) = LIVE.as_mut() {
+                next.prev = header;
+            }
+            LIVE = header;
+            ptr.sub(REDZONE).write_bytes(GUARD, REDZONE);
+            ptr.write_bytes(POISON_ALLOC, size);
+            ptr.add(size).write_bytes(GUARD, REDZONE);
+            ptr
+        }
+    }
+
+    pub fn kfree(ptr: *mut u8) {
+        unsafe {
+            let header = header(ptr);
+            match (*header).magic {
+                MAGIC_LIVE => {}
+                MAGIC_FREED => bad_chunk("double free of", ptr),
+                _ => panic!("kfree of {:p}, which kmalloc didn't hand out", ptr),
+            }
+            let size = (*header).size;
+            if !is_filled(ptr.sub(REDZONE), REDZONE, GUARD) {
+                bad_chunk("write before the start of", ptr);
+            }
+            if !is_filled(ptr.add(size), REDZONE, GUARD) {
+                bad_chunk("write past the end of", ptr);
+            }
+            match (*header).prev.as_mut() {
+                Some(prev) => prev.next = (*header).next,
+                None => LIVE = (*header).next,
+            }
+            if let Some(next) = (*header).next.as_mut() {
+                next.prev = (*header).prev;
+            }
+            (*header).magic = MAGIC_FREED;
+            callers(&mut (*header).freed_by);
+            ptr.write_bytes(POISON_FREE, size);
+            // Make room in the quarantine. Whatever was there for the
+            // longest is really freed now.
+            let old = QUARANTINE[QUARANTINE_NEXT];
+            QUARANTINE[QUARANTINE_NEXT] = header;
+            QUARANTINE_NEXT = (QUARANTINE_NEXT + 1) % QUARANTINE_SIZE;
+            if !old.is_null() {
+                if !is_filled(user(old), (*old).size, POISON_FREE) {
+                    bad_chunk("use after free of", user(old));
+                }
+                (*old).magic = 0;
+                raw_free((*old).base);
+            }
+        }
+    }
+
+    /// List every chunk that is still allocated.
+    pub fn print_leaks() {
+        unsafe {
+            println!("Live kernel heap allocations:");
+            let (mut count, mut bytes) = (0, 0);
+            let mut header = LIVE;
+            while !header.is_null() {
+                print_chunk(&*header, user(header));
+                count += 1;
+                bytes += (*header).size;
+                header = (*header).next;
+            }
+            println!("{} allocation(s), {} bytes", count, bytes);
         }
     }
 }
@@ -211,8 +559,6 @@ pub fn print_table() {
 
 // The global allocator allows us to use the data structures
 // in the core library, such as a linked list or B-tree.
-// We want to use these sparingly since we have a coarse-grained
-// allocator.
 use core::alloc::{GlobalAlloc, Layout};
 
 // The global allocator is a static constant to a global allocator
@@ -222,15 +568,18 @@ struct OsGlobalAlloc;
 
 unsafe impl GlobalAlloc for OsGlobalAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // We align to the next page size so that when
-        // we divide by PAGE_SIZE, we get exactly the number
-        // of pages necessary.
-        kzmalloc(layout.size())
+        // Not zeroed, so heap-debug's poison is what uninitialized memory
+        // reads as.
+        kmalloc_aligned(layout.size(), layout.align())
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        kzmalloc_aligned(layout.size(), layout.align())
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        // We ignore layout since our allocator uses ptr_start -> last
-        // to determine the span of an allocation.
+        // We ignore layout since the pointer alone tells us whether this is
+        // a slab object or pages.
         kfree(ptr);
     }
 }
@@ -0,0 +1,234 @@
+//! # Kernel log
+//!
+//! A small `log`-style facade. The [`error!`], [`warn!`], [`info!`],
+//! [`debug!`] and [`trace!`] macros record a message together with its
+//! level, the module it came from (the target) and the time since boot.
+//! Every record that passes the filter ends up in an in-memory ring buffer
+//! that userspace can read back with the `syslog` system call (`dmesg`).
+//! Records at or above the console level are printed as well.
+//!
+//! Filtering happens twice. [`STATIC_MAX_LEVEL`] is known at compile time,
+//! so anything more verbose than that isn't even compiled in. On top of
+//! that, [`set_level`] and [`set_target_level`] decide at runtime what is
+//! recorded, for everything or for a module and everything below it.
+//!
+//! [`error!`]: crate::error
+//! [`warn!`]: crate::warn
+//! [`info!`]: crate::info
+//! [`debug!`]: crate::debug
+//! [`trace!`]: crate::trace
+
+use alloc::{collections::BTreeMap, string::String};
+use core::fmt::{self, Write};
+
+use crate::{
+    cpu::{get_mtime, mstatus_read, mstatus_write, FREQ},
+    lock::Mutex,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(usize)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN ",
+            Self::Info => "INFO ",
+            Self::Debug => "DEBUG",
+            Self::Trace => "TRACE",
+        }
+    }
+
+    pub const fn from_usize(level: usize) -> Option<Self> {
+        match level {
+            1 => Some(Self::Error),
+            2 => Some(Self::Warn),
+            3 => Some(Self::Info),
+            4 => Some(Self::Debug),
+            5 => Some(Self::Trace),
+            _ => None,
+        }
+    }
+}
+
+/// The most verbose level that is compiled in at all.
+pub const STATIC_MAX_LEVEL: Level = if cfg!(debug_assertions) {
+    Level::Trace
+} else {
+    Level::Debug
+};
+
+/// What we record until somebody says otherwise.
+pub const BOOT_LOG_LEVEL: Level = Level::Info;
+
+/// How many bytes of log we keep around
+pub const LOG_BUFFER_SIZE: usize = 16384;
+
+struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    /// Where the next byte goes
+    tail: usize,
+    /// How many bytes are valid, at most LOG_BUFFER_SIZE
+    len: usize,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        Self {
+            data: [0; LOG_BUFFER_SIZE],
+            tail: 0,
+            len: 0,
+        }
+    }
+
+    /// The oldest byte we still have
+    const fn head(&self) -> usize {
+        (self.tail + LOG_BUFFER_SIZE - self.len) % LOG_BUFFER_SIZE
+    }
+}
+
+impl Write for LogBuffer {
+    fn write_str(&mut self, out: &str) -> fmt::Result {
+        // Once we are full, new bytes overwrite the oldest ones.
+        for c in out.bytes() {
+            self.data[self.tail] = c;
+            self.tail = (self.tail + 1) % LOG_BUFFER_SIZE;
+            self.len = (self.len + 1).min(LOG_BUFFER_SIZE);
+        }
+        Ok(())
+    }
+}
+
+static mut LOG_BUFFER: LogBuffer = LogBuffer::new();
+static mut LOG_LOCK: Mutex = Mutex::new();
+static mut LOG_LEVEL: Level = BOOT_LOG_LEVEL;
+static mut CONSOLE_LEVEL: Level = Level::Info;
+// Per-target levels. We only allocate this once somebody sets one, so
+// logging works before the kernel heap is up.
+static mut TARGET_LEVELS: Option<BTreeMap<String, Level>> = None;
+
+// The machine interrupt enable bit in mstatus
+const MSTATUS_MIE: usize = 1 << 3;
+
+/// Run `f` with the log locked. Interrupt handlers log too, so they must
+/// not be able to cut in on this hart while we hold the lock.
+fn locked<R>(f: impl FnOnce() -> R) -> R {
+    let mstatus = mstatus_read();
+    mstatus_write(mstatus & !MSTATUS_MIE);
+    unsafe { LOG_LOCK.spin_lock() };
+    let ret = f();
+    unsafe { LOG_LOCK.unlock() };
+    mstatus_write(mstatus);
+    ret
+}
+
+/// Record everything at `level` and below.
+pub fn set_level(level: Level) {
+    unsafe { LOG_LEVEL = level };
+}
+
+/// Print everything at `level` and below to the console as well.
+pub fn set_console_level(level: Level) {
+    unsafe { CONSOLE_LEVEL = level };
+}
+
+/// Record everything at `level` and below for `target` and the modules
+/// inside of it, for example `hak::virtio`.
+pub fn set_target_level(target: &str, level: Level) {
+    locked(|| unsafe {
+        TARGET_LEVELS
+            .get_or_insert_with(BTreeMap::new)
+            .insert(String::from(target), level);
+    });
+}
+
+/// Would a record at `level` for `target` be kept?
+pub fn enabled(level: Level, target: &str) -> bool {
+    if level > STATIC_MAX_LEVEL {
+        return false;
+    }
+    unsafe {
+        if let Some(targets) = &TARGET_LEVELS {
+            // The longest matching prefix wins.
+            let found = targets
+                .iter()
+                .filter(|(t, _)| {
+                    target.starts_with(t.as_str())
+                        && (target.len() == t.len() || target[t.len()..].starts_with("::"))
+                })
+                .max_by_key(|(t, _)| t.len());
+            if let Some((_, max)) = found {
+                return level <= *max;
+            }
+        }
+        level <= LOG_LEVEL
+    }
+}
+
+/// Record a message. This is what the macros expand to.
+pub fn log(level: Level, target: &str, args: fmt::Arguments) {
+    if !enabled(level, target) {
+        return;
+    }
+    let now = get_mtime() as u64;
+    let secs = now / FREQ;
+    let micros = now % FREQ / (FREQ / 1_000_000);
+    // Drop the crate name, every target starts with it.
+    let target = target.strip_prefix("hak::").unwrap_or(target);
+    locked(|| unsafe {
+        let _ = writeln!(
+            LOG_BUFFER,
+            "[{:5}.{:06}] {} {}: {}",
+            secs,
+            micros,
+            level.name(),
+            target,
+            args
+        );
+    });
+    if level <= unsafe { CONSOLE_LEVEL } {
+        println!(
+            "[{:5}.{:06}] {} {}: {}",
+            secs,
+            micros,
+            level.name(),
+            target,
+            args
+        );
+    }
+}
+
+/// How many bytes of log there are right now
+pub fn len() -> usize {
+    locked(|| unsafe { LOG_BUFFER.len })
+}
+
+/// Copy the newest `buffer.len()` bytes of the log into `buffer`, oldest
+/// first, and return how many that were. With `clear`, the log is emptied
+/// afterwards.
+pub fn read(buffer: &mut [u8], clear: bool) -> usize {
+    locked(|| unsafe {
+        let n = buffer.len().min(LOG_BUFFER.len);
+        let start = (LOG_BUFFER.head() + LOG_BUFFER.len - n) % LOG_BUFFER_SIZE;
+        for (i, dst) in buffer[..n].iter_mut().enumerate() {
+            *dst = LOG_BUFFER.data[(start + i) % LOG_BUFFER_SIZE];
+        }
+        if clear {
+            LOG_BUFFER.len = 0;
+        }
+        n
+    })
+}
+
+/// Forget everything that was logged so far.
+pub fn clear() {
+    locked(|| unsafe { LOG_BUFFER.len = 0 });
+}
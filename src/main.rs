@@ -24,8 +24,7 @@ extern crate alloc;
 #[macro_export]
 macro_rules! print {
     ($($args:tt)+) => ({
-        use core::fmt::Write;
-        let _ = write!(crate::uart::Uart::new(0x1000_0000), $($args)+);
+        crate::uart::print(format_args!($($args)+));
     });
 }
 
@@ -41,6 +40,45 @@ macro_rules! println {
     );
 }
 
+/// Record a message in the kernel log at the given [`log::Level`]. The
+/// module it comes from is the target.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($args:tt)+) => ({
+        crate::log::log($level, module_path!(), format_args!($($args)+));
+    });
+}
+
+/// Log something that went wrong and can't be recovered from
+#[macro_export]
+macro_rules! error {
+    ($($args:tt)+) => (log!(crate::log::Level::Error, $($args)+));
+}
+
+/// Log something that went wrong but that we can live with
+#[macro_export]
+macro_rules! warn {
+    ($($args:tt)+) => (log!(crate::log::Level::Warn, $($args)+));
+}
+
+/// Log something worth knowing
+#[macro_export]
+macro_rules! info {
+    ($($args:tt)+) => (log!(crate::log::Level::Info, $($args)+));
+}
+
+/// Log something that helps debugging
+#[macro_export]
+macro_rules! debug {
+    ($($args:tt)+) => (log!(crate::log::Level::Debug, $($args)+));
+}
+
+/// Log everything else
+#[macro_export]
+macro_rules! trace {
+    ($($args:tt)+) => (log!(crate::log::Level::Trace, $($args)+));
+}
+
 /// Exception handler presonality
 ///
 /// Empty function for compiler
@@ -48,8 +86,15 @@ macro_rules! println {
 extern "C" fn eh_personality() {}
 
 /// Custom panic handler
+///
+/// Prints what went wrong, the registers of whoever trapped last, and how
+/// we got here. Then the machine is turned off, so that nobody sits in
+/// front of a hung QEMU.
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    // Whatever the kernel was doing, the UART has to work from here on.
+    unsafe { uart::UART0.panic_mode() };
+    power::stop_other_harts();
     print!("Aborting: ");
     if let Some(p) = info.location() {
         println!(
@@ -61,7 +106,16 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     } else {
         println!("no information available.");
     }
-    abort();
+    // The trap frame in mscratch belongs to whatever ran last.
+    let frame = cpu::mscratch_read() as *const cpu::TrapFrame;
+    if !frame.is_null() {
+        unsafe {
+            println!("Current pid: {}", (*frame).pid);
+        }
+        cpu::dump_registers(frame);
+    }
+    backtrace::print_current();
+    power::poweroff_with_failure(1);
 }
 
 /// Never return function that waits for interrupt
@@ -86,7 +140,15 @@ extern "C" {
 /// Since it will jump to another program counter,
 /// it will never return back here. We don't care if we leak
 /// the stack, since we will recapture the stack during `m_trap`.
+///
+/// Pending signals are acted upon here, right before the process runs
+/// again. That might kill it, in which case we run whoever is next.
+/// Whoever ran before is charged for the CPU time until now.
 fn rust_switch_to_user(frame: usize) -> ! {
+    resource::charge();
+    let frame = signal::deliver_pending(frame);
+    resource::start(frame);
+    strace::resume(frame);
     unsafe {
         switch_to_user(frame);
     }
@@ -94,24 +156,30 @@ fn rust_switch_to_user(frame: usize) -> ! {
 
 /// Kernel entry point
 #[no_mangle]
-extern "C" fn kinit() {
-    uart::Uart::new(0x1000_0000).init();
+extern "C" fn kinit(fdt: usize) {
+    // Before anything is allocated, the page allocator owns the memory
+    // the device tree is in. The tree also says where the UART is.
+    fdt::init(fdt);
+    unsafe { uart::UART0.init() };
+    // From here on, running out of boot stack faults.
+    kmem::guard_boot_stack(0);
+    fdt::report();
+    power::set_hart_online();
     page::init();
     kmem::init();
+    vdso::init();
     process::init();
     // We lower the threshold wall so our interrupts can jump over it.
     // Any priority > 0 will be able to be "heard"
     plic::set_threshold(0);
-    // VIRTIO = [1..8]
-    // UART0 = 10
-    // PCIE = [32..35]
-    // Enable PLIC interrupts.
-    for i in 1..=10 {
-        plic::enable(i);
-        plic::set_priority(i, 1);
+    // Enable PLIC interrupts for the UART and the virtio transports.
+    for irq in virtio::irqs().chain(core::iter::once(uart::irq())) {
+        plic::enable(irq);
+        plic::set_priority(irq, 1);
     }
     // Set up virtio. This requires a working heap and page-grained allocator.
     virtio::probe();
+    swap::init();
     // Test the block driver!
     process::add_kernel_process(test::test);
     // Get the GPU going
@@ -125,40 +193,79 @@ extern "C" fn kinit() {
 
 /// Function for hardware thread(hart) initialization
 #[no_mangle]
-extern "C" fn kinit_hart(_hartid: usize) {
+extern "C" fn kinit_hart(hartid: usize) {
     // We aren't going to do anything here until we get SMP going.
     // All non-0 harts initialize here.
+    kmem::guard_boot_stack(hartid);
 }
 
 /// Export RISC-V assembly files for bootloader and trap handler
 pub mod assembly;
+/// Symbolized kernel backtraces
+pub mod backtrace;
 /// Buffer management stuff
 pub mod buffer;
+/// Serial console with a line discipline
+pub mod console;
 /// RISC-V cpu instructions wrapper
 pub mod cpu;
+/// Crash reports for user processes
+pub mod crash;
 /// Elf binary format execution
 pub mod elf;
+/// Error numbers that failed system calls return
+pub mod errno;
+/// Device tree and the kernel command line
+pub mod fdt;
 /// Minix3 file system implementation
 pub mod fs;
+/// Fast user-space mutexes
+pub mod futex;
+/// GDB remote serial protocol stub
+pub mod gdb;
 /// Kernel memory management
 pub mod kmem;
 /// Synchronization primitives
 pub mod lock;
+/// Leveled kernel log with a dmesg ring buffer
+pub mod log;
+/// Anonymous memory for brk and mmap
+pub mod mmap;
 /// Paging and related functions implementation
 pub mod page;
+/// Inter-process communication through pipes
+pub mod pipe;
 /// Programmable interrupt controller functionality
 pub mod plic;
+/// Powering off and stopping harts
+pub mod power;
 /// Process data
 pub mod process;
+/// Process tracing for debuggers and strace
+pub mod ptrace;
+/// Resource limits and usage
+pub mod resource;
 /// Process scheduling
 pub mod sched;
+/// System V shared memory segments
+pub mod shm;
+/// POSIX-like signals
+pub mod signal;
+/// System call tracing to the kernel log
+pub mod strace;
+/// Swapping anonymous memory to a block device
+pub mod swap;
 /// System calls
 pub mod syscall;
 /// First initalized process
 pub mod test;
 /// Trampoline for interrupts
 pub mod trap;
+/// Safe access to the memory of the process making a system call
+pub mod uaccess;
 /// Universal Asynchronous Receiver-Transmitter
 pub mod uart;
+/// The time page programs read the clock from
+pub mod vdso;
 /// Virtual input/output protocol
 pub mod virtio;
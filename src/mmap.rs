@@ -0,0 +1,845 @@
+//! # Anonymous memory
+//!
+//! `brk` and `mmap` give user processes zeroed pages on top of what the
+//! ELF loader mapped for them. This is all a C library needs for `malloc`.
+//!
+//! The heap that `brk` moves starts right after the highest segment of the
+//! program. `mmap` puts its pages between [`MMAP_BASE`] and [`MMAP_END`],
+//! wherever it finds a free run. We only hand out anonymous memory, and
+//! only ever touch pages that we handed out ourselves: `munmap` and
+//! `mprotect` won't take the program or a stack away from under a process.
+//! Nor do we map anything over the guard page below a stack.
+//!
+//! Threads share their page table, so the bookkeeping is kept per page
+//! table and goes away with it, see [`release`]. That includes how many
+//! pages the process has all in all, which `RLIMIT_AS` caps (see
+//! resource.rs).
+//!
+//! These pages are also the ones that go to swap (see swap.rs). Before we
+//! hand out memory, [`make_room`] sweeps over all of them and pages out
+//! whatever wasn't used lately. A page in swap isn't mapped, so touching it
+//! faults and [`fault`] brings it back.
+//!
+//! `shmat` maps the pages of a shared memory segment (see shm.rs) in here
+//! too, as an attachment. Those pages belong to the segment, so they are
+//! never freed or paged out from here: when the last page of an attachment
+//! is unmapped, the segment loses one attachment, and it frees its pages
+//! once it has none left.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::ptr::null_mut;
+
+use crate::{
+    cpu::{
+        mstatus_read, mstatus_write, satp_fence_all, satp_fence_asid, CpuMode, TrapFrame,
+        MSTATUS_MIE,
+    },
+    errno::{SysResult, EACCES, EINVAL, ENODEV, ENOMEM},
+    futex,
+    page::{
+        dealloc, lookup_mut, map, table_pages, unmap_page, virt_to_phys, zalloc, EntryBits, Table,
+        PAGE_SIZE,
+    },
+    process::get_by_pid,
+    shm, swap,
+    virtio::block,
+};
+
+/// Where `mmap` and `shmat` start looking for room. The framebuffer goes to
+/// 0x3000_0000, so we stay clear of it.
+pub const MMAP_BASE: usize = 0x4000_0000;
+/// Where `mmap` stops looking. Thread stacks grow down from below the main
+/// stack, so we leave them a good part of the space.
+pub const MMAP_END: usize = 0x8000_0000;
+
+// mmap() and mprotect() protections. PROT_NONE is 0.
+pub const PROT_READ: usize = 1;
+pub const PROT_WRITE: usize = 2;
+pub const PROT_EXEC: usize = 4;
+
+// mmap() flags
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+
+/// A page we handed out
+struct Page {
+    /// Where it is in memory, or null while it is in swap
+    frame: *mut u8,
+    /// The bits it is mapped with. `PROT_NONE` pages aren't in the page
+    /// table at all, since a leaf needs at least one of R, W or X.
+    bits: i64,
+    /// The swap slot with a copy of the page. Once the page is back in
+    /// memory, the copy is only good while the page stays clean.
+    slot: Option<usize>,
+    /// Where the attachment of a shared memory segment starts that this
+    /// page is part of
+    shared: Option<usize>,
+}
+
+impl Page {
+    /// The page is in memory, and we may page it out.
+    fn can_swap(&self) -> bool {
+        !self.frame.is_null() && self.shared.is_none()
+    }
+}
+
+/// A shared memory segment that `shmat` mapped
+struct Attachment {
+    /// The segment
+    id: usize,
+    /// How many pages it was mapped with
+    pages: usize,
+    /// How many of those are still mapped
+    mapped: usize,
+    /// `SHM_RDONLY`: mprotect can't make it writable.
+    read_only: bool,
+}
+
+/// The anonymous memory of one page table
+struct Memory {
+    brk_start: usize,
+    brk: usize,
+    /// Everything brk, mmap and shmat handed out, by virtual address
+    pages: BTreeMap<usize, Page>,
+    /// The shared memory segments, by where they start
+    attached: BTreeMap<usize, Attachment>,
+    /// The unmapped pages below the stacks
+    guards: BTreeSet<usize>,
+    /// The pages mapped in here that belong to the process: the program,
+    /// the stacks and `pages`
+    resident: usize,
+    /// The most `resident` ever was
+    peak: usize,
+    /// The pages of `pages` that are in swap
+    swapped: usize,
+}
+
+/// How much memory a page table has, in pages
+pub struct Usage {
+    pub resident: usize,
+    pub peak: usize,
+    pub swapped: usize,
+    /// The page table itself
+    pub tables: usize,
+}
+
+// Keyed by the address of the page table.
+static mut MEMORY: Option<BTreeMap<usize, Memory>> = None;
+
+/// Set up the heap of a freshly loaded program whose last segment ends at
+/// `end`. The program and its stack take up `resident` pages.
+pub fn init(table: *mut Table, end: usize, resident: usize) {
+    let brk_start = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    unsafe {
+        let mut memory = MEMORY.take().unwrap_or_default();
+        memory.insert(
+            table as usize,
+            Memory {
+                brk_start,
+                brk: brk_start,
+                pages: BTreeMap::new(),
+                attached: BTreeMap::new(),
+                guards: BTreeSet::new(),
+                resident,
+                peak: resident,
+                swapped: 0,
+            },
+        );
+        MEMORY.replace(memory);
+    }
+}
+
+/// Free every page of `table` that we handed out and detach the shared
+/// memory segments. This is for when the page table itself goes away: we
+/// don't bother unmapping anything.
+pub fn release(table: *mut Table) {
+    unsafe {
+        if let Some(mut memory) = MEMORY.take() {
+            if let Some(mem) = memory.remove(&(table as usize)) {
+                for page in mem.pages.values().filter(|page| page.shared.is_none()) {
+                    if !page.frame.is_null() {
+                        dealloc(page.frame);
+                    }
+                    if let Some(slot) = page.slot {
+                        swap::free(slot);
+                    }
+                }
+                for attachment in mem.attached.values() {
+                    shm::detach(attachment.id);
+                }
+            }
+            MEMORY.replace(memory);
+        }
+    }
+}
+
+/// Run `f` on the memory of `table`, if it has any.
+fn with_table<R>(table: *mut Table, f: impl FnOnce(&mut Memory) -> R) -> Option<R> {
+    unsafe {
+        let mut memory = MEMORY.take().unwrap_or_default();
+        let result = memory.get_mut(&(table as usize)).map(f);
+        MEMORY.replace(memory);
+        result
+    }
+}
+
+/// Keep the page at `vaddr` of `table` free, as the guard page below a
+/// stack.
+pub fn guard(table: *mut Table, vaddr: usize) {
+    with_table(table, |mem| mem.guards.insert(vaddr));
+}
+
+/// The stack above `vaddr` is gone, and so is its guard page.
+pub fn unguard(table: *mut Table, vaddr: usize) {
+    with_table(table, |mem| mem.guards.remove(&vaddr));
+}
+
+/// Is `vaddr` in the guard page below a stack of `table`?
+pub fn is_guard(table: *mut Table, vaddr: usize) -> bool {
+    with_table(table, |mem| {
+        mem.guards.contains(&(vaddr & !(PAGE_SIZE - 1)))
+    })
+    .unwrap_or(false)
+}
+
+/// How much memory `table` has. Kernel threads don't have a table of
+/// their own, so there is nothing to say about a null one.
+pub fn usage(table: *mut Table) -> Option<Usage> {
+    if table.is_null() {
+        return None;
+    }
+    let tables = unsafe { table_pages(&*table) };
+    with_table(table, |mem| Usage {
+        resident: mem.resident,
+        peak: mem.peak,
+        swapped: mem.swapped,
+        tables,
+    })
+}
+
+/// Count `pages` more pages that were mapped into `table` some other way,
+/// like a thread stack, unless that would take it over `limit` pages.
+pub fn charge(table: *mut Table, pages: usize, limit: usize) -> bool {
+    let tables = unsafe { table_pages(&*table) };
+    with_table(table, |mem| mem.charge(pages, tables, limit)).unwrap_or(true)
+}
+
+/// The `pages` that [`charge`] counted are gone again.
+pub fn uncharge(table: *mut Table, pages: usize) {
+    with_table(table, |mem| mem.resident -= pages);
+}
+
+/// Run `f` on the page table, the ASID, the `RLIMIT_AS` in pages and the
+/// memory of the process behind `frame`. Kernel processes and programs
+/// that weren't loaded from an ELF file don't have any, so they get
+/// `ENOMEM`.
+unsafe fn with_memory(
+    frame: *const TrapFrame,
+    f: impl FnOnce(&mut Table, usize, usize, &mut Memory) -> SysResult,
+) -> SysResult {
+    let p = get_by_pid((*frame).pid as u16);
+    if p.is_null() || (*frame).mode != CpuMode::User as usize {
+        return Err(ENOMEM);
+    }
+    let root = (*p).root;
+    let asid = ((*frame).satp >> 44) & 0xffff;
+    let limit = (*p).limits().pages();
+    with_table(root, |mem| f(&mut *root, asid, limit, mem)).unwrap_or(Err(ENOMEM))
+}
+
+/// The page table bits for `prot`
+fn prot_bits(prot: usize) -> i64 {
+    let mut bits = 0;
+    // There are no write-only pages on RISC-V.
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        bits |= EntryBits::Read.val();
+    }
+    if prot & PROT_WRITE != 0 {
+        bits |= EntryBits::Write.val();
+    }
+    if prot & PROT_EXEC != 0 {
+        bits |= EntryBits::Execute.val();
+    }
+    if bits != 0 {
+        bits |= EntryBits::User.val();
+    }
+    bits
+}
+
+impl Memory {
+    /// Nothing is mapped at `vaddr`, not even a `PROT_NONE` page, and it
+    /// isn't a guard page.
+    fn is_free(&self, table: &Table, vaddr: usize) -> bool {
+        !self.pages.contains_key(&vaddr)
+            && !self.guards.contains(&vaddr)
+            && virt_to_phys(table, vaddr).is_none()
+    }
+
+    /// Count `pages` more resident pages, unless that, what is in swap and
+    /// the `tables` pages of the page table come to more than `limit`.
+    fn charge(&mut self, pages: usize, tables: usize, limit: usize) -> bool {
+        if self.resident + self.swapped + tables + pages > limit {
+            return false;
+        }
+        self.resident += pages;
+        self.peak = self.peak.max(self.resident);
+        true
+    }
+
+    /// Map `pages` fresh pages at `vaddr`, as long as the process stays
+    /// within `limit` pages. If we run out of memory halfway, whatever we
+    /// did get is given back.
+    fn add(
+        &mut self,
+        table: &mut Table,
+        vaddr: usize,
+        pages: usize,
+        bits: i64,
+        limit: usize,
+    ) -> bool {
+        if !self.charge(pages, table_pages(table), limit) {
+            return false;
+        }
+        // remove() uncounts each page again.
+        for i in 0..pages {
+            let frame = zalloc(1);
+            if frame.is_null() {
+                for j in 0..i {
+                    self.remove(table, vaddr + j * PAGE_SIZE);
+                }
+                self.resident -= pages - i;
+                return false;
+            }
+            let page_vaddr = vaddr + i * PAGE_SIZE;
+            if bits != 0 {
+                map(table, page_vaddr, frame as usize, bits, 0);
+            }
+            self.pages.insert(
+                page_vaddr,
+                Page {
+                    frame,
+                    bits,
+                    slot: None,
+                    shared: None,
+                },
+            );
+        }
+        true
+    }
+
+    /// Map the `frames` of shared memory segment `id` at `vaddr`, as long
+    /// as the process stays within `limit` pages.
+    fn attach(
+        &mut self,
+        table: &mut Table,
+        vaddr: usize,
+        id: usize,
+        frames: &[*mut u8],
+        prot: usize,
+        limit: usize,
+    ) -> bool {
+        if !self.charge(frames.len(), table_pages(table), limit) {
+            return false;
+        }
+        let bits = prot_bits(prot);
+        for (i, &frame) in frames.iter().enumerate() {
+            let page_vaddr = vaddr + i * PAGE_SIZE;
+            map(table, page_vaddr, frame as usize, bits, 0);
+            self.pages.insert(
+                page_vaddr,
+                Page {
+                    frame,
+                    bits,
+                    slot: None,
+                    shared: Some(vaddr),
+                },
+            );
+        }
+        self.attached.insert(
+            vaddr,
+            Attachment {
+                id,
+                pages: frames.len(),
+                mapped: frames.len(),
+                read_only: prot & PROT_WRITE == 0,
+            },
+        );
+        true
+    }
+
+    /// Unmap and free the page at `vaddr` if it is one of ours. A page of
+    /// a shared memory segment is only unmapped.
+    fn remove(&mut self, table: &mut Table, vaddr: usize) {
+        if let Some(page) = self.pages.remove(&vaddr) {
+            if let Some(start) = page.shared {
+                if page.bits != 0 {
+                    unmap_page(table, vaddr);
+                }
+                self.resident -= 1;
+                let attachment = self.attached.get_mut(&start).unwrap();
+                attachment.mapped -= 1;
+                if attachment.mapped == 0 {
+                    shm::detach(attachment.id);
+                    self.attached.remove(&start);
+                }
+                return;
+            }
+            if let Some(slot) = page.slot {
+                swap::free(slot);
+            }
+            if page.frame.is_null() {
+                self.swapped -= 1;
+                return;
+            }
+            if page.bits != 0 {
+                unmap_page(table, vaddr);
+            }
+            dealloc(page.frame);
+            self.resident -= 1;
+        }
+    }
+
+    /// Page out the page at `vaddr`, unless it was used since the last
+    /// sweep, in which case it only loses its Access bit.
+    fn page_out(&mut self, table: &mut Table, vaddr: usize) -> Sweep {
+        let page = match self.pages.get_mut(&vaddr) {
+            Some(page) if page.can_swap() => page,
+            _ => return Sweep::Kept,
+        };
+        // The device may be reading into the page, and futex waiters are
+        // keyed by its physical address, so it has to stay where it is.
+        let frame = page.frame as usize;
+        if block::in_flight(frame, PAGE_SIZE) || futex::has_waiters(frame, PAGE_SIZE) {
+            return Sweep::Kept;
+        }
+        // Without a page table entry, we can't tell whether the page is
+        // clean, so it is written again.
+        let mut dirty = true;
+        if page.bits != 0 {
+            if let Some((entry, _)) = lookup_mut(table, vaddr) {
+                let bits = entry.get_entry();
+                if bits & EntryBits::Access.val() != 0 {
+                    entry.set_entry(bits & !EntryBits::Access.val());
+                    return Sweep::Kept;
+                }
+                dirty = bits & EntryBits::Dirty.val() != 0;
+            }
+        }
+        let slot = match page.slot {
+            Some(slot) if !dirty => slot,
+            slot => {
+                let slot = match slot.or_else(swap::alloc) {
+                    Some(slot) => slot,
+                    None => return Sweep::Full,
+                };
+                if swap::write(slot, page.frame).is_err() {
+                    swap::free(slot);
+                    page.slot = None;
+                    return Sweep::Kept;
+                }
+                slot
+            }
+        };
+        if page.bits != 0 {
+            unmap_page(table, vaddr);
+        }
+        dealloc(page.frame);
+        page.frame = null_mut();
+        page.slot = Some(slot);
+        self.resident -= 1;
+        self.swapped += 1;
+        Sweep::Out
+    }
+
+    /// Bring the page at `vaddr` back from swap. Returns whether it is in
+    /// memory now.
+    fn page_in(&mut self, table: &mut Table, vaddr: usize) -> bool {
+        let page = match self.pages.get_mut(&vaddr) {
+            Some(page) if page.frame.is_null() => page,
+            _ => return false,
+        };
+        let frame = zalloc(1);
+        if frame.is_null() {
+            return false;
+        }
+        if swap::read(page.slot.unwrap(), frame).is_err() {
+            dealloc(frame);
+            return false;
+        }
+        page.frame = frame;
+        if page.bits != 0 {
+            map(table, vaddr, frame as usize, page.bits, 0);
+            // The copy in swap is good until the page is written to.
+            if let Some((entry, _)) = lookup_mut(table, vaddr) {
+                entry.set_entry(entry.get_entry() & !EntryBits::Dirty.val());
+            }
+        }
+        self.swapped -= 1;
+        self.resident += 1;
+        self.peak = self.peak.max(self.resident);
+        true
+    }
+
+    /// Find `pages` free pages in a row in the mmap area.
+    fn find_free(&self, table: &Table, pages: usize) -> Option<usize> {
+        let mut start = MMAP_BASE;
+        let mut vaddr = start;
+        while start + pages * PAGE_SIZE <= MMAP_END {
+            if vaddr == start + pages * PAGE_SIZE {
+                return Some(start);
+            }
+            if self.is_free(table, vaddr) {
+                vaddr += PAGE_SIZE;
+            } else {
+                start = vaddr + PAGE_SIZE;
+                vaddr = start;
+            }
+        }
+        None
+    }
+}
+
+/// What [`Memory::page_out`] did with a page
+enum Sweep {
+    Out,
+    Kept,
+    /// There is no room left in swap.
+    Full,
+}
+
+// Where the clock hand of the sweep stands: a page table and a virtual
+// address in it.
+static mut HAND: (usize, usize) = (0, 0);
+
+/// The next page after `at` that is in memory and may be paged out, going
+/// round through all page tables
+fn next_resident(memory: &BTreeMap<usize, Memory>, at: (usize, usize)) -> Option<(usize, usize)> {
+    let first_in = |table: usize, mem: &Memory, from: usize| {
+        mem.pages
+            .range(from..)
+            .find(|(_, page)| page.can_swap())
+            .map(|(&vaddr, _)| (table, vaddr))
+    };
+    let (table, vaddr) = at;
+    memory
+        .range(table..)
+        .find_map(|(&t, mem)| first_in(t, mem, if t == table { vaddr + 1 } else { 0 }))
+        .or_else(|| memory.iter().find_map(|(&t, mem)| first_in(t, mem, 0)))
+}
+
+/// Page out up to `wanted` pages of any process but the one with page
+/// table `keep`. Every page gets two chances, so we go round at most twice.
+unsafe fn reclaim(memory: &mut BTreeMap<usize, Memory>, wanted: usize, keep: *mut Table) -> usize {
+    let candidates: usize = memory
+        .values()
+        .map(|mem| mem.pages.values().filter(|page| page.can_swap()).count())
+        .sum();
+    let mut freed = 0;
+    for _ in 0..2 * candidates {
+        if freed == wanted {
+            break;
+        }
+        let (table, vaddr) = match next_resident(memory, HAND) {
+            Some(at) => at,
+            None => break,
+        };
+        HAND = (table, vaddr);
+        if table == keep as usize {
+            continue;
+        }
+        let mem = memory.get_mut(&table).unwrap();
+        match mem.page_out(&mut *(table as *mut Table), vaddr) {
+            Sweep::Out => freed += 1,
+            Sweep::Kept => {}
+            Sweep::Full => break,
+        }
+    }
+    // We cleared Access bits and unmapped pages in every address space.
+    satp_fence_all();
+    freed
+}
+
+/// If there is swap, page out enough for `pages` more pages to be handed
+/// out while the kernel keeps its reserve. Whoever then can't get memory
+/// gets `ENOMEM` like before.
+pub fn make_room(pages: usize) {
+    make_room_keeping(pages, null_mut());
+}
+
+/// [`make_room`], but leave the pages of page table `keep` alone.
+fn make_room_keeping(pages: usize, keep: *mut Table) {
+    let wanted = swap::shortfall(pages);
+    if wanted == 0 {
+        return;
+    }
+    // Kernel threads come here when they load a program, and the interrupt
+    // handlers must not find MEMORY taken.
+    let mstatus = mstatus_read();
+    mstatus_write(mstatus & !MSTATUS_MIE);
+    unsafe {
+        let mut memory = MEMORY.take().unwrap_or_default();
+        let freed = reclaim(&mut memory, wanted, keep);
+        MEMORY.replace(memory);
+        if freed < wanted {
+            debug!("Paged out {} of {} pages", freed, wanted);
+        }
+    }
+    mstatus_write(mstatus);
+}
+
+/// Bring the page at `vaddr` of `table` back from swap, if that is where it
+/// is. This is for the kernel, which is about to access the page on behalf
+/// of the process. It may still hold on to other pages of the process, so
+/// those stay where they are.
+pub fn page_in(table: *mut Table, vaddr: usize) -> bool {
+    make_room_keeping(1, table);
+    let vaddr = vaddr & !(PAGE_SIZE - 1);
+    let result = with_table(table, |mem| unsafe { mem.page_in(&mut *table, vaddr) });
+    satp_fence_all();
+    result.unwrap_or(false)
+}
+
+/// A user process faulted at `vaddr` on a fetch, load or store (`cause`
+/// 12, 13 or 15). If that's because the page is in swap, or because the
+/// hardware wants us to set the Access or Dirty bit that a sweep cleared,
+/// we put it right and return true: the process tries again.
+pub unsafe fn fault(frame: *const TrapFrame, vaddr: usize, cause: usize) -> bool {
+    let p = get_by_pid((*frame).pid as u16);
+    if p.is_null() || (*frame).mode != CpuMode::User as usize {
+        return false;
+    }
+    let root = (*p).root;
+    let vaddr = vaddr & !(PAGE_SIZE - 1);
+    let (needed, set) = match cause {
+        12 => (EntryBits::Execute.val(), EntryBits::Access.val()),
+        13 => (EntryBits::Read.val(), EntryBits::Access.val()),
+        _ => (
+            EntryBits::Write.val(),
+            EntryBits::Access.val() | EntryBits::Dirty.val(),
+        ),
+    };
+    make_room(1);
+    let handled = with_table(root, |mem| {
+        let page = match mem.pages.get(&vaddr) {
+            Some(page) if page.bits & needed != 0 => page,
+            _ => return false,
+        };
+        if page.frame.is_null() {
+            return mem.page_in(&mut *root, vaddr);
+        }
+        match lookup_mut(&mut *root, vaddr) {
+            Some((entry, _)) if entry.get_entry() & set != set => {
+                entry.set_entry(entry.get_entry() | set);
+                true
+            }
+            _ => false,
+        }
+    });
+    satp_fence_asid(((*frame).satp >> 44) & 0xffff);
+    handled.unwrap_or(false)
+}
+
+/// `brk(addr)`: move the end of the heap to `addr`. Returns the new end,
+/// or the old one if it can't be moved, which is how Linux reports
+/// failure. `brk(0)` just asks where the end is.
+pub unsafe fn brk(frame: *const TrapFrame, addr: usize) -> usize {
+    // Paging out needs all of MEMORY, so it happens before we take it.
+    let grow = with_memory(frame, |_, _, _, mem| Ok(addr.saturating_sub(mem.brk))).unwrap_or(0);
+    make_room(grow / PAGE_SIZE + 1);
+    let result = with_memory(frame, |table, asid, limit, mem| {
+        if addr < mem.brk_start {
+            return Ok(mem.brk);
+        }
+        let old_end = (mem.brk + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let new_end = match addr.checked_add(PAGE_SIZE - 1) {
+            Some(end) => end & !(PAGE_SIZE - 1),
+            None => return Ok(mem.brk),
+        };
+        if new_end > old_end {
+            if new_end > MMAP_BASE
+                || !(old_end..new_end)
+                    .step_by(PAGE_SIZE)
+                    .all(|vaddr| mem.is_free(table, vaddr))
+            {
+                return Ok(mem.brk);
+            }
+            let pages = (new_end - old_end) / PAGE_SIZE;
+            if !mem.add(table, old_end, pages, EntryBits::UserReadWrite.val(), limit) {
+                return Ok(mem.brk);
+            }
+        } else {
+            for vaddr in (new_end..old_end).step_by(PAGE_SIZE) {
+                mem.remove(table, vaddr);
+            }
+            satp_fence_asid(asid);
+        }
+        mem.brk = addr;
+        Ok(addr)
+    });
+    // Whoever has no heap has it end at 0.
+    result.unwrap_or(0)
+}
+
+/// `mmap(addr, length, prot, flags, fd, offset)` for anonymous memory.
+/// `addr` is only a hint, unless `MAP_FIXED` says otherwise.
+pub unsafe fn mmap(
+    frame: *const TrapFrame,
+    addr: usize,
+    length: usize,
+    prot: usize,
+    flags: usize,
+) -> SysResult {
+    // Without fork, nobody could ever tell shared and private anonymous
+    // memory apart.
+    if flags & MAP_ANONYMOUS == 0 {
+        return Err(ENODEV);
+    }
+    if length == 0 || flags & (MAP_SHARED | MAP_PRIVATE) == 0 {
+        return Err(EINVAL);
+    }
+    let pages = length.checked_add(PAGE_SIZE - 1).ok_or(ENOMEM)? / PAGE_SIZE;
+    make_room(pages);
+    with_memory(frame, |table, asid, limit, mem| {
+        let vaddr = if flags & MAP_FIXED != 0 {
+            if addr % PAGE_SIZE != 0 {
+                return Err(EINVAL);
+            }
+            // We only map over our own pages, which we replace.
+            let end = addr.checked_add(pages * PAGE_SIZE).ok_or(ENOMEM)?;
+            let range = (addr..end).step_by(PAGE_SIZE);
+            if !range
+                .clone()
+                .all(|vaddr| mem.pages.contains_key(&vaddr) || mem.is_free(table, vaddr))
+            {
+                return Err(ENOMEM);
+            }
+            for vaddr in range {
+                mem.remove(table, vaddr);
+            }
+            satp_fence_asid(asid);
+            addr
+        } else {
+            mem.find_free(table, pages).ok_or(ENOMEM)?
+        };
+        if mem.add(table, vaddr, pages, prot_bits(prot), limit) {
+            Ok(vaddr)
+        } else {
+            Err(ENOMEM)
+        }
+    })
+}
+
+/// `munmap(addr, length)`. Pages that mmap or brk didn't hand out are left
+/// alone.
+pub unsafe fn munmap(frame: *const TrapFrame, addr: usize, length: usize) -> SysResult {
+    if addr % PAGE_SIZE != 0 || length == 0 {
+        return Err(EINVAL);
+    }
+    let end = addr.checked_add(length).ok_or(EINVAL)?;
+    with_memory(frame, |table, asid, _, mem| {
+        for vaddr in (addr..end).step_by(PAGE_SIZE) {
+            mem.remove(table, vaddr);
+        }
+        satp_fence_asid(asid);
+        Ok(0)
+    })
+}
+
+/// `mprotect(addr, length, prot)` on pages we handed out. A shared memory
+/// segment that was attached read-only stays that way.
+pub unsafe fn mprotect(
+    frame: *const TrapFrame,
+    addr: usize,
+    length: usize,
+    prot: usize,
+) -> SysResult {
+    if addr % PAGE_SIZE != 0 {
+        return Err(EINVAL);
+    }
+    let end = addr.checked_add(length).ok_or(ENOMEM)?;
+    let bits = prot_bits(prot);
+    with_memory(frame, |table, asid, _, mem| {
+        let range = (addr..end).step_by(PAGE_SIZE);
+        if !range.clone().all(|vaddr| mem.pages.contains_key(&vaddr)) {
+            return Err(ENOMEM);
+        }
+        if prot & PROT_WRITE != 0
+            && range.clone().any(|vaddr| {
+                mem.pages[&vaddr]
+                    .shared
+                    .map_or(false, |start| mem.attached[&start].read_only)
+            })
+        {
+            return Err(EACCES);
+        }
+        for vaddr in range {
+            let page = mem.pages.get_mut(&vaddr).unwrap();
+            // A page in swap gets its new bits when it comes back.
+            if !page.frame.is_null() {
+                if bits != 0 {
+                    map(table, vaddr, page.frame as usize, bits, 0);
+                } else if page.bits != 0 {
+                    unmap_page(table, vaddr);
+                }
+            }
+            page.bits = bits;
+        }
+        satp_fence_asid(asid);
+        Ok(0)
+    })
+}
+
+/// `shmat`: map the `frames` of shared memory segment `id` at `addr`, or
+/// wherever there is room in the mmap area if `addr` is 0. Nothing may be
+/// mapped there yet. Returns where the segment went.
+pub unsafe fn attach(
+    frame: *const TrapFrame,
+    addr: usize,
+    id: usize,
+    frames: &[*mut u8],
+    prot: usize,
+) -> SysResult {
+    with_memory(frame, |table, _, limit, mem| {
+        let vaddr = if addr == 0 {
+            mem.find_free(table, frames.len()).ok_or(ENOMEM)?
+        } else {
+            let end = addr.checked_add(frames.len() * PAGE_SIZE).ok_or(EINVAL)?;
+            if !(addr..end)
+                .step_by(PAGE_SIZE)
+                .all(|vaddr| mem.is_free(table, vaddr))
+            {
+                return Err(EINVAL);
+            }
+            addr
+        };
+        if mem.attach(table, vaddr, id, frames, prot, limit) {
+            Ok(vaddr)
+        } else {
+            Err(ENOMEM)
+        }
+    })
+}
+
+/// `shmdt`: unmap whatever is left of the shared memory segment that was
+/// attached at `addr`. Returns the segment.
+pub unsafe fn detach(frame: *const TrapFrame, addr: usize) -> SysResult {
+    with_memory(frame, |table, asid, _, mem| {
+        let (id, pages) = match mem.attached.get(&addr) {
+            Some(attachment) => (attachment.id, attachment.pages),
+            None => return Err(EINVAL),
+        };
+        for vaddr in (addr..addr + pages * PAGE_SIZE).step_by(PAGE_SIZE) {
+            if mem
+                .pages
+                .get(&vaddr)
+                .map_or(false, |page| page.shared == Some(addr))
+            {
+                mem.remove(table, vaddr);
+            }
+        }
+        satp_fence_asid(asid);
+        Ok(id)
+    })
+}
@@ -1,5 +1,7 @@
 use core::{mem::size_of, ptr::null_mut};
 
+use crate::fdt;
+
 // ////////////////////////////////
 // // Allocation routines
 // ////////////////////////////////
@@ -11,9 +13,15 @@ extern "C" {
 // We will use ALLOC_START to mark the start of the actual
 // memory we can dish out.
 static mut ALLOC_START: usize = 0;
+// Where RAM ends
+static mut HEAP_END: usize = 0;
 const PAGE_ORDER: usize = 12;
 pub const PAGE_SIZE: usize = 1 << 12;
 
+/// The biggest block the buddy allocator keeps track of is 2^MAX_ORDER
+/// pages, 1 GiB. That's more than the whole machine has.
+const MAX_ORDER: usize = 18;
+
 /// Align (set to a multiple of some power of two)
 /// This takes an order which is the exponent to 2^order
 /// Therefore, all alignments must be made as a power of two.
@@ -28,6 +36,8 @@ pub enum PageBits {
     Empty = 0,
     Taken = 1 << 0,
     Last = 1 << 1,
+    /// The first page of a free block, which is on a free list
+    Free = 1 << 2,
 }
 
 impl PageBits {
@@ -43,6 +53,8 @@ impl PageBits {
 // associated with it. However, there structure is much larger.
 pub struct Page {
     flags: u8,
+    /// The order of the free block this page starts, if it starts one
+    order: u8,
 }
 
 impl Page {
@@ -66,6 +78,7 @@ impl Page {
     // Clear the Page structure and all associated allocations.
     pub fn clear(&mut self) {
         self.flags = PageBits::Empty.val();
+        self.order = 0;
     }
 
     // Set a certain flag. We ran into trouble here since PageBits
@@ -78,18 +91,120 @@ impl Page {
     pub fn clear_flag(&mut self, flag: PageBits) {
         self.flags &= !(flag.val());
     }
+
+    /// Does a free block of 2^`order` pages start here?
+    const fn is_free_block(&self, order: usize) -> bool {
+        self.flags & PageBits::Free.val() != 0 && self.order as usize == order
+    }
+}
+
+// A free block keeps its free list links in its own first page.
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+// Memory is handed out by a buddy allocator. Free memory is cut into
+// blocks of 2^order pages that are aligned to their size, one list per
+// order. An allocation takes the smallest block that fits, splitting
+// bigger ones in halves ("buddies") as needed, and gives the pages it
+// doesn't need back. When a block is freed and its buddy is free too, the
+// two merge into a block of the next order, and so on up. Nothing ever
+// scans the page descriptors.
+static mut FREE_LISTS: [*mut FreeBlock; MAX_ORDER + 1] = [null_mut(); MAX_ORDER + 1];
+// Page frame number (physical address / PAGE_SIZE) of ALLOC_START. Blocks
+// are aligned to their size in physical memory, not relative to the heap.
+static mut FIRST_PFN: usize = 0;
+// How many pages we dish out, and how many of them are free.
+static mut NUM_PAGES: usize = 0;
+static mut FREE_PAGES: usize = 0;
+// Check the allocator after every alloc and dealloc, see check().
+static mut CHECK: bool = false;
+
+/// The descriptor of page frame `pfn`
+unsafe fn page(pfn: usize) -> &'static mut Page {
+    &mut *(HEAP_START as *mut Page).add(pfn - FIRST_PFN)
+}
+
+/// Put the block of 2^`order` pages at `pfn` on its free list.
+unsafe fn push_block(pfn: usize, order: usize) {
+    let block = (pfn * PAGE_SIZE) as *mut FreeBlock;
+    (*block).next = FREE_LISTS[order];
+    (*block).prev = null_mut();
+    if let Some(next) = FREE_LISTS[order].as_mut() {
+        next.prev = block;
+    }
+    FREE_LISTS[order] = block;
+    let p = page(pfn);
+    p.set_flag(PageBits::Free);
+    p.order = order as u8;
+    FREE_PAGES += 1 << order;
+}
+
+/// Take the free block of 2^`order` pages at `pfn` off its free list.
+unsafe fn remove_block(pfn: usize, order: usize) {
+    let block = (pfn * PAGE_SIZE) as *mut FreeBlock;
+    match (*block).prev.as_mut() {
+        Some(prev) => prev.next = (*block).next,
+        None => FREE_LISTS[order] = (*block).next,
+    }
+    if let Some(next) = (*block).next.as_mut() {
+        next.prev = (*block).prev;
+    }
+    page(pfn).clear();
+    FREE_PAGES -= 1 << order;
+}
+
+/// Free the block of 2^`order` pages at `pfn`, merging it with its buddy
+/// for as long as the buddy is free as well.
+unsafe fn free_block(mut pfn: usize, mut order: usize) {
+    while order < MAX_ORDER {
+        let buddy = pfn ^ (1 << order);
+        if buddy < FIRST_PFN
+            || buddy + (1 << order) > FIRST_PFN + NUM_PAGES
+            || !page(buddy).is_free_block(order)
+        {
+            break;
+        }
+        remove_block(buddy, order);
+        pfn &= !(1 << order);
+        order += 1;
+    }
+    push_block(pfn, order);
+}
+
+/// Free `count` pages from `pfn` on, which needn't be a block: we cut the
+/// range into the biggest aligned blocks that fit.
+unsafe fn free_range(mut pfn: usize, mut count: usize) {
+    while count > 0 {
+        let mut order = (pfn.trailing_zeros() as usize).min(MAX_ORDER);
+        while 1 << order > count {
+            order -= 1;
+        }
+        free_block(pfn, order);
+        pfn += 1 << order;
+        count -= 1 << order;
+    }
 }
 
 /// Initialize the allocation system. There are several ways that we can
 /// implement the page allocator:
 /// 1. Free list (singly linked list where it starts at the first free
 /// allocation) 2. Bookkeeping list (structure contains a taken and length)
-/// 3. Allocate one Page structure per 4096 bytes (this is what I chose)
+/// 3. Allocate one Page structure per 4096 bytes
 /// 4. Others
+/// We do 3 to know what is taken and free lists of buddy blocks to find
+/// free memory fast.
 pub fn init() {
     unsafe {
+        // The linker script guesses how much RAM there is, the device
+        // tree knows.
+        HEAP_END = match fdt::memory() {
+            Some((base, size)) if base + size > HEAP_START => base + size,
+            _ => HEAP_START + HEAP_SIZE,
+        };
         // let desc_per_page = PAGE_SIZE / size_of::<Page>();
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
+        let num_pages = (HEAP_END - HEAP_START) / PAGE_SIZE;
         // let num_desc_pages = num_pages / desc_per_page;
         let ptr = HEAP_START as *mut Page;
         // Clear all pages to make sure that they aren't accidentally
@@ -103,63 +218,119 @@ pub fn init() {
         // (HEAP_START + num_pages * size_of::<Page>() + PAGE_SIZE - 1)
         // & !(PAGE_SIZE - 1);
         ALLOC_START = align_val(HEAP_START + num_pages * size_of::<Page>(), PAGE_ORDER);
+        FIRST_PFN = ALLOC_START / PAGE_SIZE;
+        NUM_PAGES = (HEAP_END - ALLOC_START) / PAGE_SIZE;
+        free_range(FIRST_PFN, NUM_PAGES);
+        // `page_check` on the kernel command line checks the allocator
+        // on every call. It's slow, it's for debugging the allocator.
+        CHECK = fdt::bootarg("page_check").is_some();
+    }
+}
+
+/// Where the memory the page allocator hands out ends
+pub fn heap_end() -> usize {
+    unsafe {
+        if HEAP_END == 0 {
+            // Not initialized yet
+            HEAP_START + HEAP_SIZE
+        } else {
+            HEAP_END
+        }
+    }
+}
+
+/// How many pages are free
+pub fn free_pages() -> usize {
+    unsafe { FREE_PAGES }
+}
+
+/// How many pages the allocator manages, free or not
+pub fn total_pages() -> usize {
+    unsafe { NUM_PAGES }
+}
+
+/// How many pages the page descriptors say are taken
+fn taken_pages() -> usize {
+    unsafe {
+        (FIRST_PFN..FIRST_PFN + NUM_PAGES)
+            .filter(|&pfn| page(pfn).is_taken())
+            .count()
+    }
+}
+
+/// Cross-check the free lists against the page descriptors, which is what
+/// [`print_page_allocations`] shows. If they disagree, print the
+/// allocations and panic.
+fn check() {
+    unsafe {
+        let mut listed = 0;
+        for (order, &head) in FREE_LISTS.iter().enumerate() {
+            let mut block = head;
+            while !block.is_null() {
+                let pfn = block as usize / PAGE_SIZE;
+                if pfn < FIRST_PFN
+                    || pfn + (1 << order) > FIRST_PFN + NUM_PAGES
+                    || pfn % (1 << order) != 0
+                    || !page(pfn).is_free_block(order)
+                    || (pfn..pfn + (1 << order)).any(|pfn| page(pfn).is_taken())
+                {
+                    print_page_allocations();
+                    panic!("Bad free block of order {} at {:p}", order, block);
+                }
+                listed += 1 << order;
+                block = (*block).next;
+            }
+        }
+        let taken = taken_pages();
+        if listed != FREE_PAGES || taken + FREE_PAGES != NUM_PAGES {
+            print_page_allocations();
+            panic!(
+                "{} pages taken, {} free, {} on the free lists, of {}",
+                taken, FREE_PAGES, listed, NUM_PAGES
+            );
+        }
     }
 }
 
 /// Allocate a page or multiple pages
 /// pages: the number of [`PAGE_SIZE`] pages to allocate
+/// The pages are aligned to `pages` rounded up to a power of two.
 pub fn alloc(pages: usize) -> *mut u8 {
-    // We have to find a contiguous allocation of pages
     assert!(pages > 0);
+    let order = match pages.checked_next_power_of_two() {
+        Some(block) if block <= 1 << MAX_ORDER => block.trailing_zeros() as usize,
+        _ => return null_mut(),
+    };
     unsafe {
-        // We create a Page structure for each page on the heap. We
-        // actually might have more since HEAP_SIZE moves and so does
-        // the size of our structure, but we'll only waste a few bytes.
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
-        let ptr = HEAP_START as *mut Page;
-        for i in 0..num_pages - pages {
-            let mut found = false;
-            // Check to see if this Page is free. If so, we have our
-            // first candidate memory address.
-            if (*ptr.add(i)).is_free() {
-                // It was FREE! Yay!
-                found = true;
-                for j in i..i + pages {
-                    // Now check to see if we have a
-                    // contiguous allocation for all of the
-                    // request pages. If not, we should
-                    // check somewhere else.
-                    if (*ptr.add(j)).is_taken() {
-                        found = false;
-                        break;
-                    }
-                }
-            }
-            // We've checked to see if there are enough contiguous
-            // pages to form what we need. If we couldn't, found
-            // will be false, otherwise it will be true, which means
-            // we've found valid memory we can allocate.
-            if found {
-                for k in i..i + pages - 1 {
-                    (*ptr.add(k)).set_flag(PageBits::Taken);
-                }
-                // The marker for the last page is
-                // PageBits::Last This lets us know when we've
-                // hit the end of this particular allocation.
-                (*ptr.add(i + pages - 1)).set_flag(PageBits::Taken);
-                (*ptr.add(i + pages - 1)).set_flag(PageBits::Last);
-                // The Page structures themselves aren't the
-                // useful memory. Instead, there is 1 Page
-                // structure per 4096 bytes starting at
-                // ALLOC_START.
-                return (ALLOC_START + PAGE_SIZE * i) as *mut u8;
+        // The smallest free block that is big enough
+        let mut block_order = order;
+        while FREE_LISTS[block_order].is_null() {
+            block_order += 1;
+            if block_order > MAX_ORDER {
+                return null_mut();
             }
         }
+        let pfn = FREE_LISTS[block_order] as usize / PAGE_SIZE;
+        remove_block(pfn, block_order);
+        // Split it until it is just big enough, keeping the upper halves
+        // free.
+        while block_order > order {
+            block_order -= 1;
+            push_block(pfn + (1 << block_order), block_order);
+        }
+        for i in pfn..pfn + pages {
+            page(i).set_flag(PageBits::Taken);
+        }
+        // The marker for the last page is PageBits::Last. This lets us
+        // know when we've hit the end of this particular allocation.
+        page(pfn + pages - 1).set_flag(PageBits::Last);
+        // A block of 2^order pages may be more than we were asked for.
+        free_range(pfn + pages, (1 << order) - pages);
+        if CHECK {
+            check();
+        }
+        (pfn * PAGE_SIZE) as *mut u8
     }
-
-    // If we get here, that means that no contiguous allocation was
-    // found.
-    null_mut()
 }
 
 /// Allocate and zero a page or multiple pages
@@ -189,33 +360,39 @@ pub fn zalloc(pages: usize) -> *mut u8 {
 }
 
 /// Deallocate a page by its pointer
-/// The way we've structured this, it will automatically coalesce
-/// contiguous pages.
+/// The pages go back to the free lists, where they merge with free
+/// neighbours.
 pub fn dealloc(ptr: *mut u8) {
     // Make sure we don't try to free a null pointer.
     assert!(!ptr.is_null());
     unsafe {
-        let addr = HEAP_START + (ptr as usize - ALLOC_START) / PAGE_SIZE;
-        // Make sure that the address makes sense. The address we
-        // calculate here is the page structure, not the HEAP address!
-        assert!(addr >= HEAP_START && addr < ALLOC_START);
-        let mut p = addr as *mut Page;
-        // println!("PTR in is {:p}, addr is 0x{:x}", ptr, addr);
-        assert!((*p).is_taken(), "Freeing a non-taken page?");
+        let first = ptr as usize / PAGE_SIZE;
+        // Make sure that the address makes sense.
+        assert!(
+            ptr as usize % PAGE_SIZE == 0 && first >= FIRST_PFN && first < FIRST_PFN + NUM_PAGES,
+            "Freeing {:p}, which isn't a page we handed out",
+            ptr
+        );
+        assert!(page(first).is_taken(), "Freeing a non-taken page?");
         // Keep clearing pages until we hit the last page.
-        while (*p).is_taken() && !(*p).is_last() {
-            (*p).clear();
-            p = p.add(1);
+        let mut pfn = first;
+        while page(pfn).is_taken() && !page(pfn).is_last() {
+            page(pfn).clear();
+            pfn += 1;
         }
         // If the following assertion fails, it is most likely
         // caused by a double-free.
         assert!(
-            (*p).is_last(),
+            page(pfn).is_last(),
             "Possible double-free detected! (Not taken found before last)"
         );
         // If we get here, we've taken care of all previous pages and
         // we are on the last page.
-        (*p).clear();
+        page(pfn).clear();
+        free_range(first, pfn - first + 1);
+        if CHECK {
+            check();
+        }
     }
 }
 
@@ -223,8 +400,8 @@ pub fn dealloc(ptr: *mut u8) {
 /// This is mainly used for debugging.
 pub fn print_page_allocations() {
     unsafe {
-        let num_pages = (HEAP_SIZE - (ALLOC_START - HEAP_START)) / PAGE_SIZE;
-        let mut beg = HEAP_START as *const Page;
+        let num_pages = NUM_PAGES;
+        let beg = HEAP_START as *const Page;
         let end = beg.add(num_pages);
         let alloc_beg = ALLOC_START;
         let alloc_end = ALLOC_START + num_pages * PAGE_SIZE;
@@ -235,24 +412,23 @@ pub fn print_page_allocations() {
         );
         println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
         let mut num = 0;
-        while beg < end {
-            if (*beg).is_taken() {
-                let start = beg as usize;
-                let memaddr = ALLOC_START + (start - HEAP_START) * PAGE_SIZE;
-                print!("0x{:x} => ", memaddr);
+        let mut pfn = FIRST_PFN;
+        while pfn < FIRST_PFN + num_pages {
+            if page(pfn).is_taken() {
+                let start = pfn;
+                print!("0x{:x} => ", start * PAGE_SIZE);
                 loop {
                     num += 1;
-                    if (*beg).is_last() {
-                        let end = beg as usize;
-                        let memaddr = ALLOC_START + (end - HEAP_START) * PAGE_SIZE + PAGE_SIZE - 1;
-                        print!("0x{:x}: {:>3} page(s)", memaddr, (end - start + 1));
+                    if page(pfn).is_last() || pfn + 1 == FIRST_PFN + num_pages {
+                        let memaddr = (pfn + 1) * PAGE_SIZE - 1;
+                        print!("0x{:x}: {:>3} page(s)", memaddr, (pfn - start + 1));
                         println!(".");
                         break;
                     }
-                    beg = beg.add(1);
+                    pfn += 1;
                 }
             }
-            beg = beg.add(1);
+            pfn += 1;
         }
         println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
         println!(
@@ -265,6 +441,19 @@ pub fn print_page_allocations() {
             num_pages - num,
             (num_pages - num) * PAGE_SIZE
         );
+        print!("Free lists:");
+        for (order, &head) in FREE_LISTS.iter().enumerate() {
+            let mut blocks = 0;
+            let mut block = head;
+            while !block.is_null() {
+                blocks += 1;
+                block = (*block).next;
+            }
+            if blocks > 0 {
+                print!(" {}x{}", blocks, 1 << order);
+            }
+        }
+        println!(" = {} pages", free_pages());
         println!();
     }
 }
@@ -358,21 +547,32 @@ impl Table {
     }
 }
 
-/// Map a virtual address to a physical address using 4096-byte page
-/// size.
+/// How many bytes a leaf at `level` maps: 4 KiB at level 0, 2 MiB at
+/// level 1 and 1 GiB at level 2.
+pub const fn leaf_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+/// Map a virtual address to a physical address with a leaf at `level`,
+/// see [`leaf_size`].
 /// root: a mutable reference to the root Table
-/// vaddr: The virtual address to map
-/// paddr: The physical address to map
+/// vaddr: The virtual address to map, aligned to the leaf size
+/// paddr: The physical address to map, aligned to the leaf size
 /// bits: An OR'd bitset containing the bits the leaf should have.
 ///       The bits should contain only the following:
 ///          Read, Write, Execute, User, and/or Global
 ///       The bits MUST include one or more of the following:
 ///          Read, Write, Execute
 ///       The valid bit automatically gets added.
+/// A huge leaf on the way down is split so that the rest of it stays
+/// mapped. A huge leaf that replaces tables frees them, but not the pages
+/// they pointed to.
 pub fn map(root: &mut Table, v_addr: usize, p_addr: usize, bits: i64, level: usize) {
     // Make sure that Read, Write, or Execute have been provided
     // otherwise, we'll leak memory and always create a page fault.
     assert!(bits & 0xe != 0);
+    // The MMU faults on a megapage or gigapage that isn't aligned.
+    assert!(level <= 2 && (v_addr | p_addr) % leaf_size(level) == 0);
     // Extract out each VPN from the virtual address
     // On the virtual address, each VPN is exactly 9 bits,
     // which is why we use the mask 0x1ff = 0b1_1111_1111 (9 bits)
@@ -415,6 +615,8 @@ pub fn map(root: &mut Table, v_addr: usize, p_addr: usize, bits: i64, level: usi
             // directly The page is stored in the entry shifted
             // right by 2 places.
             v.set_entry((page as i64 >> 2) | EntryBits::Valid.val());
+        } else if v.is_leaf() {
+            split(v, i + 1);
         }
         let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
         v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
@@ -431,11 +633,68 @@ pub fn map(root: &mut Table, v_addr: usize, p_addr: usize, bits: i64, level: usi
 				EntryBits::Dirty.val() |  // Some machines require this to =1
 				EntryBits::Access.val()   // Just like dirty, some machines require this
 				;
+    // A huge leaf takes the place of whatever tables were below it.
+    if level > 0 && v.is_valid() && v.is_branch() {
+        free_table(v, level);
+    }
     // Set the entry. V should be set to the correct pointer by the loop
     // above.
     v.set_entry(entry);
 }
 
+/// Map `len` bytes at `v_addr` to `p_addr`, rounded out to whole pages,
+/// using the biggest leaves that fit. Wherever both addresses are aligned
+/// to 2 MiB or 1 GiB and enough of the range is left, one leaf does the
+/// work of 512 or 262,144 pages. [`alloc`] aligns blocks to their size
+/// rounded up to a power of two, so anything of 512 pages or more comes back
+/// megapage aligned.
+pub fn map_range(root: &mut Table, v_addr: usize, p_addr: usize, len: usize, bits: i64) {
+    let end = align_val(v_addr + len, PAGE_ORDER);
+    let mut v_addr = v_addr & !(PAGE_SIZE - 1);
+    let mut p_addr = p_addr & !(PAGE_SIZE - 1);
+    while v_addr < end {
+        let level = (0..=2)
+            .rev()
+            .find(|&level| {
+                let size = leaf_size(level);
+                (v_addr | p_addr) % size == 0 && end - v_addr >= size
+            })
+            .unwrap_or(0);
+        map(root, v_addr, p_addr, bits, level);
+        v_addr += leaf_size(level);
+        p_addr += leaf_size(level);
+    }
+}
+
+/// Turn the huge leaf `v` at `level` into a branch to a new table of leaves
+/// one level down, which map the same memory with the same bits.
+fn split(v: &mut Entry, level: usize) {
+    let table = zalloc(1) as *mut Table;
+    let bits = v.get_entry() & 0x3ff;
+    let ppn = v.get_entry() & !0x3ff;
+    // The PPN sits at bit 10 rather than 12
+    let step = (leaf_size(level - 1) >> 2) as i64;
+    let entries = unsafe { &mut (*table).entries };
+    for (j, child) in entries.iter_mut().enumerate() {
+        child.set_entry((ppn + j as i64 * step) | bits);
+    }
+    v.set_entry((table as i64 >> 2) | EntryBits::Valid.val());
+}
+
+/// Free the table that the branch `v` at `level` points to, and every table
+/// below it. The pages the leaves map are left alone.
+fn free_table(v: &Entry, level: usize) {
+    let table = ((v.get_entry() & !0x3ff) << 2) as *mut Table;
+    if level > 1 {
+        for child in unsafe { (*table).entries.iter() } {
+            if child.is_valid() && child.is_branch() {
+                free_table(child, level - 1);
+            }
+        }
+    }
+    dealloc(table as *mut u8);
+}
+
 /// Unmaps and frees all memory associated with a table.
 /// root: The root table to start freeing.
 /// NOTE: This does NOT free root directly. This must be
@@ -443,30 +702,59 @@ pub fn map(root: &mut Table, v_addr: usize, p_addr: usize, bits: i64, level: usi
 /// The reason we don't free the root is because it is
 /// usually embedded into the Process structure.
 pub fn unmap(root: &mut Table) {
-    // Start with level 2
-    for lv2 in 0..Table::len() {
-        let entry_lv2 = &root.entries[lv2];
-        if entry_lv2.is_valid() && entry_lv2.is_branch() {
-            // This is a valid entry, so drill down and free.
-            let memaddr_lv1 = (entry_lv2.get_entry() & !0x3ff) << 2;
-            let table_lv1 = unsafe {
-                // Make table_lv1 a mutable reference instead of
-                // a pointer.
-                (memaddr_lv1 as *mut Table).as_mut().unwrap()
-            };
-            for lv1 in 0..Table::len() {
-                let entry_lv1 = &table_lv1.entries[lv1];
-                if entry_lv1.is_valid() && entry_lv1.is_branch() {
-                    let memaddr_lv0 = (entry_lv1.get_entry() & !0x3ff) << 2;
-                    // The next level is level 0, which
-                    // cannot have branches, therefore,
-                    // we free here.
-                    dealloc(memaddr_lv0 as *mut u8);
-                }
+    // Leaves at level 2 are gigapages, which have no tables to free.
+    for entry in root.entries.iter() {
+        if entry.is_valid() && entry.is_branch() {
+            free_table(entry, 2);
+        }
+    }
+}
+
+/// How many pages the page table under `root` takes up, `root` included.
+pub fn table_pages(root: &Table) -> usize {
+    fn count(table: &Table, level: usize) -> usize {
+        let mut pages = 1;
+        for entry in table.entries.iter() {
+            if level > 0 && entry.is_valid() && entry.is_branch() {
+                let next = ((entry.get_entry() & !0x3ff) << 2) as *const Table;
+                pages += count(unsafe { &*next }, level - 1);
             }
-            dealloc(memaddr_lv1 as *mut u8);
         }
+        pages
+    }
+    count(root, 2)
+}
+
+/// Remove the 4 KiB leaf that maps `v_addr`, if there is one, and return the
+/// physical address it pointed to. A megapage or gigapage is split first, so
+/// the rest of it stays mapped. The page itself is NOT freed and the
+/// intermediate tables stay in place for whoever maps here next.
+/// The caller has to fence the TLB.
+pub fn unmap_page(root: &mut Table, v_addr: usize) -> Option<usize> {
+    let vpn = [
+        // VPN[0] = vaddr[20:12]
+        (v_addr >> 12) & 0x1ff,
+        // VPN[1] = vaddr[29:21]
+        (v_addr >> 21) & 0x1ff,
+        // VPN[2] = vaddr[38:30]
+        (v_addr >> 30) & 0x1ff,
+    ];
+    let mut v = &mut root.entries[vpn[2]];
+    for i in (0..2).rev() {
+        if v.is_invalid() {
+            return None;
+        } else if v.is_leaf() {
+            split(v, i + 1);
+        }
+        let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
+        v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
     }
+    if v.is_invalid() {
+        return None;
+    }
+    let p_addr = ((v.get_entry() & !0x3ff) << 2) as usize;
+    v.set_entry(EntryBits::None.val());
+    Some(p_addr)
 }
 
 /// Walk the page table to convert a virtual address to a
@@ -495,18 +783,20 @@ pub fn virt_to_phys(root: &Table, v_addr: usize) -> Option<usize> {
             // The offset mask masks off the PPN. Each PPN is 9
             // bits and they start at bit #12. So, our formula
             // 12 + i * 9
-            let off_mask = (1 << (12 + i * 9)) - 1;
+            let off_mask = leaf_size(i) - 1;
             let vaddr_pgoff = v_addr & off_mask;
             let addr = ((v.get_entry() << 2) as usize) & !off_mask;
             return Some(addr | vaddr_pgoff);
+        } else if i == 0 {
+            // A branch at the last level is malformed.
+            break;
         }
         // Set v to the next entry which is pointed to by this
         // entry. However, the address was shifted right by 2 places
         // when stored in the page table entry, so we shift it left
         // to get it back into place.
         let entry = ((v.get_entry() & !0x3ff) << 2) as *const Entry;
-        // We do i - 1 here, however we should get None or Some() above
-        // before we do 0 - 1 = -1.
+        // We do i - 1 here, but level 0 has returned or broken out above.
         v = unsafe { entry.add(vpn[i - 1]).as_ref().unwrap() };
     }
 
@@ -514,3 +804,61 @@ pub fn virt_to_phys(root: &Table, v_addr: usize) -> Option<usize> {
     // found a leaf.
     None
 }
+
+/// Walk the page table like [`virt_to_phys`], but return the leaf entry
+/// that maps `v_addr` together with the level it sits at: 0 for a 4 KiB
+/// page, 1 for a 2 MiB megapage and 2 for a 1 GiB gigapage.
+pub fn lookup(root: &Table, v_addr: usize) -> Option<(&Entry, usize)> {
+    let vpn = [
+        (v_addr >> 12) & 0x1ff,
+        (v_addr >> 21) & 0x1ff,
+        (v_addr >> 30) & 0x1ff,
+    ];
+
+    let mut v = &root.entries[vpn[2]];
+    for i in (0..=2).rev() {
+        if v.is_invalid() {
+            return None;
+        } else if v.is_leaf() {
+            return Some((v, i));
+        } else if i == 0 {
+            // A branch at the last level is malformed.
+            return None;
+        }
+        let entry = ((v.get_entry() & !0x3ff) << 2) as *const Entry;
+        v = unsafe { entry.add(vpn[i - 1]).as_ref().unwrap() };
+    }
+    None
+}
+
+/// [`lookup`] for changing the entry, like the Access and Dirty bits that
+/// swap keeps track of pages with.
+pub fn lookup_mut(root: &mut Table, v_addr: usize) -> Option<(&mut Entry, usize)> {
+    let vpn = [
+        (v_addr >> 12) & 0x1ff,
+        (v_addr >> 21) & 0x1ff,
+        (v_addr >> 30) & 0x1ff,
+    ];
+
+    let mut v = &mut root.entries[vpn[2]];
+    for i in (0..=2).rev() {
+        if v.is_invalid() {
+            return None;
+        } else if v.is_leaf() {
+            return Some((v, i));
+        } else if i == 0 {
+            return None;
+        }
+        let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
+        v = unsafe { entry.add(vpn[i - 1]).as_mut().unwrap() };
+    }
+    None
+}
+
+/// Set the Dirty bit of the leaf that maps `v_addr`. The kernel writes to
+/// user pages through their physical address, which the MMU doesn't see.
+pub fn mark_dirty(root: &mut Table, v_addr: usize) {
+    if let Some((entry, _)) = lookup_mut(root, v_addr) {
+        entry.set_entry(entry.get_entry() | EntryBits::Dirty.val());
+    }
+}
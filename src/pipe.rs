@@ -0,0 +1,181 @@
+//! # Pipes
+//!
+//! A pipe is a kernel ring buffer with a read end and a write end. Both
+//! ends live in process file descriptor tables as
+//! [`FileDescriptor::PipeRead`] and [`FileDescriptor::PipeWrite`], which
+//! refer to the pipe by id. The pipe itself stays in [`PIPES`] for as long
+//! as any end is open, and the ends keep the reader and writer counts up
+//! to date when they are duplicated or dropped.
+//!
+//! Readers block in [`ProcessState::Waiting`] while the buffer is empty and
+//! writers block while it is full. A blocked process simply runs its system
+//! call again once it is woken up.
+//!
+//! [`ProcessState::Waiting`]: crate::process::ProcessState::Waiting
+//! [`FileDescriptor::PipeRead`]: crate::process::FileDescriptor::PipeRead
+//! [`FileDescriptor::PipeWrite`]: crate::process::FileDescriptor::PipeWrite
+
+use alloc::collections::{BTreeMap, VecDeque};
+
+use crate::process::set_running;
+
+/// How many bytes a pipe can hold before writers have to wait.
+pub const PIPE_SIZE: usize = 4096;
+
+pub struct Pipe {
+    buffer: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+    /// PIDs of processes waiting for data
+    read_waiters: VecDeque<u16>,
+    /// PIDs of processes waiting for room
+    write_waiters: VecDeque<u16>,
+}
+
+impl Pipe {
+    fn new() -> Self {
+        Self {
+            buffer: VecDeque::with_capacity(PIPE_SIZE),
+            readers: 1,
+            writers: 1,
+            read_waiters: VecDeque::new(),
+            write_waiters: VecDeque::new(),
+        }
+    }
+}
+
+/// What happened to a read or write on a pipe.
+pub enum PipeResult {
+    /// This many bytes were transferred. A read of 0 bytes means end of
+    /// file: the buffer is empty and nobody can write to it anymore.
+    Done(usize),
+    /// The caller has to wait and try again.
+    WouldBlock,
+    /// Writing to a pipe with no readers left.
+    Broken,
+}
+
+// All pipes in the system, keyed by id. Just like the process list, this
+// is an Option since we can't create a BTreeMap at compile time.
+static mut PIPES: Option<BTreeMap<u16, Pipe>> = None;
+static mut NEXT_PIPE_ID: u16 = 1;
+
+/// Create a new pipe with one reader and one writer and return its id.
+pub fn create() -> u16 {
+    unsafe {
+        let mut pipes = PIPES.take().unwrap_or_default();
+        let id = NEXT_PIPE_ID;
+        NEXT_PIPE_ID = NEXT_PIPE_ID.wrapping_add(1).max(1);
+        pipes.insert(id, Pipe::new());
+        PIPES.replace(pipes);
+        id
+    }
+}
+
+/// Run `f` on the pipe `id`, if it still exists.
+fn with_pipe<R>(id: u16, f: impl FnOnce(&mut Pipe) -> R) -> Option<R> {
+    unsafe {
+        let mut pipes = PIPES.take()?;
+        let ret = pipes.get_mut(&id).map(f);
+        PIPES.replace(pipes);
+        ret
+    }
+}
+
+/// Wake up everyone in `waiters`. They will retry their system call.
+fn wake_all(waiters: &mut VecDeque<u16>) {
+    while let Some(pid) = waiters.pop_front() {
+        set_running(pid);
+    }
+}
+
+/// Read up to `buffer.len()` bytes from the pipe for process `pid`. If there
+/// is nothing to read yet, `pid` is remembered and woken up by the next
+/// write or by the last writer going away.
+pub fn read(id: u16, pid: u16, buffer: &mut [u8]) -> PipeResult {
+    with_pipe(id, |pipe| {
+        if pipe.buffer.is_empty() {
+            if pipe.writers == 0 {
+                return PipeResult::Done(0);
+            }
+            pipe.read_waiters.push_back(pid);
+            return PipeResult::WouldBlock;
+        }
+        let n = buffer.len().min(pipe.buffer.len());
+        for (dst, src) in buffer.iter_mut().zip(pipe.buffer.drain(..n)) {
+            *dst = src;
+        }
+        wake_all(&mut pipe.write_waiters);
+        PipeResult::Done(n)
+    })
+    .unwrap_or(PipeResult::Done(0))
+}
+
+/// Write as much of `data` as fits into the pipe for process `pid`. If the
+/// pipe is full, `pid` is remembered and woken up by the next read.
+pub fn write(id: u16, pid: u16, data: &[u8]) -> PipeResult {
+    with_pipe(id, |pipe| {
+        if pipe.readers == 0 {
+            return PipeResult::Broken;
+        }
+        let room = PIPE_SIZE - pipe.buffer.len();
+        if room == 0 {
+            pipe.write_waiters.push_back(pid);
+            return PipeResult::WouldBlock;
+        }
+        let n = data.len().min(room);
+        pipe.buffer.extend(&data[..n]);
+        wake_all(&mut pipe.read_waiters);
+        PipeResult::Done(n)
+    })
+    .unwrap_or(PipeResult::Broken)
+}
+
+/// Another file descriptor now refers to the read end.
+pub fn add_reader(id: u16) {
+    with_pipe(id, |pipe| pipe.readers += 1);
+}
+
+/// Another file descriptor now refers to the write end.
+pub fn add_writer(id: u16) {
+    with_pipe(id, |pipe| pipe.writers += 1);
+}
+
+/// A read end was closed. Once there are no readers left, blocked writers
+/// are woken up so that they can find out that the pipe is broken.
+pub fn drop_reader(id: u16) {
+    with_pipe(id, |pipe| {
+        pipe.readers -= 1;
+        if pipe.readers == 0 {
+            wake_all(&mut pipe.write_waiters);
+        }
+    });
+    release_if_unused(id);
+}
+
+/// A write end was closed. Once there are no writers left, blocked readers
+/// are woken up so that they can see the end of file.
+pub fn drop_writer(id: u16) {
+    with_pipe(id, |pipe| {
+        pipe.writers -= 1;
+        if pipe.writers == 0 {
+            wake_all(&mut pipe.read_waiters);
+        }
+    });
+    release_if_unused(id);
+}
+
+/// Free the pipe once both of its ends are gone.
+fn release_if_unused(id: u16) {
+    unsafe {
+        if let Some(mut pipes) = PIPES.take() {
+            if pipes
+                .get(&id)
+                .map_or(false, |pipe| pipe.readers == 0 && pipe.writers == 0)
+            {
+                pipes.remove(&id);
+            }
+            PIPES.replace(pipes);
+        }
+    }
+}
@@ -1,10 +1,16 @@
-use crate::{uart::Uart, virtio};
+use crate::{fdt, uart, virtio};
 
-const PLIC_PRIORITY: usize = 0x0c00_0000;
-const PLIC_PENDING: usize = 0x0c00_1000;
-const PLIC_INT_ENABLE: usize = 0x0c00_2000;
-const PLIC_THRESHOLD: usize = 0x0c20_0000;
-const PLIC_CLAIM: usize = 0x0c20_0004;
+/// Where the PLIC is on the QEMU virt machine, if the device tree doesn't
+/// say
+const PLIC_BASE: usize = 0x0c00_0000;
+/// The most a PLIC can take up, with all of its 15,872 contexts
+const PLIC_SIZE: usize = 0x400_0000;
+// Registers, as offsets from the base
+const PLIC_PRIORITY: usize = 0x0000;
+const PLIC_PENDING: usize = 0x1000;
+const PLIC_INT_ENABLE: usize = 0x2000;
+const PLIC_THRESHOLD: usize = 0x20_0000;
+const PLIC_CLAIM: usize = 0x20_0004;
 
 // Each register is 4-bytes (u32)
 // The PLIC is an external interrupt controller. The one
@@ -13,18 +19,29 @@ const PLIC_CLAIM: usize = 0x0c20_0004;
 
 // Chapter 10 explains the priority, pending, interrupt enable, threshold and claims
 
-// The virt machine has the following external interrupts (from Qemu source):
-// Interrupt 0 is a "null" interrupt and is hardwired to 0.
+// Interrupt 0 is a "null" interrupt and is hardwired to 0. Which device
+// raises which interrupt is in the device tree. On the virt machine (from
+// Qemu source):
 // VIRTIO = [1..8]
 // UART0 = 10
 // PCIE = [32..35]
 
+/// The address of PLIC register `reg`
+fn reg(reg: usize) -> usize {
+    fdt::plic().unwrap_or(PLIC_BASE) + reg
+}
+
+/// Where the PLIC's registers are, and how many bytes of them
+pub fn mmio() -> (usize, usize) {
+    (reg(0), PLIC_SIZE)
+}
+
 /// Get the next available interrupt. This is the "claim" process.
 /// The plic will automatically sort by priority and hand us the
 /// ID of the interrupt. For example, if the UART is interrupting
 /// and it's next, we will get the value 10.
 pub fn next() -> Option<u32> {
-    let claim_reg = PLIC_CLAIM as *const u32;
+    let claim_reg = reg(PLIC_CLAIM) as *const u32;
     let claim_no;
     // The claim register is filled with the highest-priority, enabled interrupt.
     unsafe {
@@ -43,7 +60,7 @@ pub fn next() -> Option<u32> {
 /// Complete a pending interrupt by id. The id should come
 /// from the next() function above.
 pub fn complete(id: u32) {
-    let complete_reg = PLIC_CLAIM as *mut u32;
+    let complete_reg = reg(PLIC_CLAIM) as *mut u32;
     unsafe {
         // We actually write a u32 into the entire complete_register.
         // This is the same register as the claim register, but it can
@@ -61,7 +78,7 @@ pub fn set_threshold(tsh: u8) {
     // is a 3-bit 0b111. So, we and with 7 (0b111) to just get the
     // last three bits.
     let actual_tsh = tsh & 0b111;
-    let tsh_reg = PLIC_THRESHOLD as *mut u32;
+    let tsh_reg = reg(PLIC_THRESHOLD) as *mut u32;
     unsafe {
         tsh_reg.write_volatile(actual_tsh as u32);
     }
@@ -69,7 +86,7 @@ pub fn set_threshold(tsh: u8) {
 
 /// See if a given interrupt id is pending.
 pub fn is_pending(id: u32) -> bool {
-    let pend = PLIC_PENDING as *const u32;
+    let pend = reg(PLIC_PENDING) as *const u32;
     let actual_id = 1 << id;
     let pend_ids;
     unsafe {
@@ -80,7 +97,7 @@ pub fn is_pending(id: u32) -> bool {
 
 /// Enable a given interrupt id
 pub fn enable(id: u32) {
-    let enables = PLIC_INT_ENABLE as *mut u32;
+    let enables = reg(PLIC_INT_ENABLE) as *mut u32;
     let actual_id = 1 << id;
     unsafe {
         // Unlike the complete and claim registers, the plic_int_enable
@@ -95,7 +112,7 @@ pub fn enable(id: u32) {
 /// The priority must be [0..7]
 pub fn set_priority(id: u32, prio: u8) {
     let actual_prio = prio as u32 & 7;
-    let prio_reg = PLIC_PRIORITY as *mut u32;
+    let prio_reg = reg(PLIC_PRIORITY) as *mut u32;
     unsafe {
         // The offset for the interrupt id is:
         // PLIC_PRIORITY + 4 * id
@@ -110,42 +127,12 @@ pub fn handle_interrupt() {
         // If we get here, we've got an interrupt from the claim register. The PLIC will
         // automatically prioritize the next interrupt, so when we get it from claim, it
         // will be the next in priority order.
-        match interrupt {
-            1..=8 => {
-                virtio::handle_interrupt(interrupt);
-            }
-            10 => {
-                // Interrupt 10 is the UART interrupt.
-                // We would typically set this to be handled out of the interrupt context,
-                // but we're testing here! C'mon!
-                // We haven't yet used the singleton pattern for my_uart, but remember, this
-                // just simply wraps 0x1000_0000 (UART).
-                let mut my_uart = Uart::new(0x1000_0000);
-                // If we get here, the UART better have something! If not, what happened??
-                if let Some(c) = my_uart.get() {
-                    // If you recognize this code, it used to be in the lib.rs under kmain(). That
-                    // was because we needed to poll for UART data. Now that we have interrupts,
-                    // here it goes!
-                    match c {
-                        8 => {
-                            // This is a backspace, so we
-                            // essentially have to write a space and
-                            // backup again:
-                            print!("{} {}", 8 as char, 8 as char);
-                        }
-                        10 | 13 => {
-                            // Newline or carriage-return
-                            println!();
-                        }
-                        _ => {
-                            print!("{}", c as char);
-                        }
-                    }
-                }
-            }
-            _ => {
-                println!("Unknown external interrupt: {}", interrupt);
-            }
+        if interrupt == uart::irq() {
+            // The UART has either received something for the console or is
+            // ready for more output.
+            unsafe { uart::UART0.handle_interrupt() };
+        } else if !virtio::handle_interrupt(interrupt) {
+            warn!("Unknown external interrupt: {}", interrupt);
         }
         // We've claimed it, so now say that we've handled it. This resets the interrupt pending
         // and allows the UART to interrupt again. Otherwise, the UART will get "stuck".
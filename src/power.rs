@@ -0,0 +1,100 @@
+//! # Power
+//!
+//! Stopping harts and turning the machine off. QEMU's virt machine has a
+//! `sifive_test` device (the syscon) that ends the simulation when we write
+//! to it, with an exit code of our choice. That lets a panic end a CI run
+//! instead of leaving it spinning.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    cpu::{mhartid_read, send_ipi},
+    page::PAGE_SIZE,
+};
+
+/// The `sifive_test` device on the QEMU virt machine
+const SYSCON_BASE: usize = 0x0010_0000;
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+/// The virt machine has at most this many harts.
+pub const MAX_HARTS: usize = 8;
+
+// Harts that have a trap frame and can take a software interrupt. Harts
+// that are still parked in boot.S don't, and must be left alone.
+static HART_ONLINE: [AtomicBool; MAX_HARTS] = [
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+    AtomicBool::new(false),
+];
+static HALTING: AtomicBool = AtomicBool::new(false);
+
+/// Where the syscon's register is, and the page it takes up
+pub fn mmio() -> (usize, usize) {
+    (SYSCON_BASE, PAGE_SIZE)
+}
+
+/// The calling hart is ready to handle traps.
+pub fn set_hart_online() {
+    HART_ONLINE[mhartid_read()].store(true, Ordering::SeqCst);
+}
+
+/// Are we in the middle of taking the system down? A hart that gets a
+/// software interrupt while this is true should call [`park`].
+pub fn is_halting() -> bool {
+    HALTING.load(Ordering::SeqCst)
+}
+
+/// Ask every other hart to stop what it is doing.
+pub fn stop_other_harts() {
+    HALTING.store(true, Ordering::SeqCst);
+    let me = mhartid_read();
+    for (hart, online) in HART_ONLINE.iter().enumerate() {
+        if hart != me && online.load(Ordering::SeqCst) {
+            send_ipi(hart);
+        }
+    }
+}
+
+/// Stop this hart for good.
+pub fn park() -> ! {
+    loop {
+        unsafe {
+            core::arch::asm!("csrci mstatus, 8", "wfi");
+        }
+    }
+}
+
+fn finish(value: u32) -> ! {
+    unsafe {
+        (SYSCON_BASE as *mut u32).write_volatile(value);
+    }
+    // If there is no syscon, at least stop doing anything.
+    park();
+}
+
+/// Turn the machine off. QEMU exits with status 0.
+pub fn poweroff() -> ! {
+    // Whatever the kernel heap still holds by now is a leak.
+    #[cfg(feature = "heap-debug")]
+    crate::kmem::print_table();
+    finish(FINISHER_PASS);
+}
+
+/// Turn the machine off and make QEMU exit with status `code`.
+pub fn poweroff_with_failure(code: u16) -> ! {
+    finish(FINISHER_FAIL | u32::from(code) << 16);
+}
+
+/// Reset the machine.
+pub fn reboot() -> ! {
+    #[cfg(feature = "heap-debug")]
+    crate::kmem::print_table();
+    finish(FINISHER_RESET);
+}
@@ -1,19 +1,30 @@
 use alloc::{
     collections::{vec_deque::VecDeque, BTreeMap},
     string::String,
+    vec::Vec,
 };
 use core::{arch::asm, ptr::null_mut};
 
 use crate::{
-    cpu::{build_satp, get_mtime, satp_fence_asid, CpuMode, Registers, SatpMode, TrapFrame},
-    fs::Inode,
+    cpu::{get_mtime, satp_fence_asid, CpuMode, Registers, TrapFrame, MSTATUS_MIE},
+    crash::FaultInfo,
+    errno::{Errno, EINVAL, EMFILE, ENOMEM, EPERM},
+    fs::{FileRead, Inode},
+    futex, kmem,
     lock::Mutex,
-    page::{alloc, dealloc, map, unmap, zalloc, EntryBits, Table, PAGE_SIZE},
+    mmap,
+    page::{dealloc, map, unmap, unmap_page, virt_to_phys, zalloc, EntryBits, Table, PAGE_SIZE},
+    pipe, ptrace,
+    resource::{Limits, RLIMIT_NOFILE},
+    signal::SignalState,
+    strace,
     syscall::syscall_exit,
+    uaccess::{translate, write_user, Access, Space},
 };
 
 // How many pages are we going to give a process for their
-// stack?
+// stack? Every stack has an unmapped guard page below that, so
+// running out of it faults.
 pub const STACK_PAGES: usize = 16;
 // We want to adjust the stack to be at the bottom of the memory allocation
 // regardless of where it is on the kernel heap.
@@ -36,6 +47,44 @@ pub static mut PROCESS_LIST_MUTEX: Mutex = Mutex::new();
 // We can search through the process list to get a new PID, but
 // it's probably easier and faster just to increase the pid:
 pub static mut NEXT_PID: u16 = 1;
+// The process that owns the console. This is who gets SIGINT when somebody
+// presses Ctrl-C. A PID of 0 means that nobody does.
+pub static mut FOREGROUND_PID: u16 = 0;
+
+// clone() flags. We only do threads, so CLONE_VM is mandatory.
+pub const CLONE_VM: usize = 0x0000_0100;
+pub const CLONE_FILES: usize = 0x0000_0400;
+pub const CLONE_SIGHAND: usize = 0x0000_0800;
+pub const CLONE_THREAD: usize = 0x0001_0000;
+pub const CLONE_SETTLS: usize = 0x0008_0000;
+pub const CLONE_PARENT_SETTID: usize = 0x0010_0000;
+pub const CLONE_CHILD_CLEARTID: usize = 0x0020_0000;
+
+// Threads share their page table (and with it the program memory) with
+// the process that created them, and so everything that belongs to the
+// process as a whole. Every user process has one of these, keyed by the
+// address of its page table. It only gets torn down once its last user is
+// dropped.
+struct AddressSpace {
+    users: usize,
+    program: *mut u8,
+    // getrlimit() and setrlimit(), see resource.rs
+    limits: Limits,
+    // The timer ticks all threads ran for, the ones that are gone included
+    cpu_time: usize,
+}
+static mut ADDRESS_SPACES: Option<BTreeMap<usize, AddressSpace>> = None;
+
+/// Run `f` on the address space of `root`, if it has one. Kernel threads
+/// don't.
+fn with_space<R>(root: *mut Table, f: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    unsafe {
+        let mut spaces = ADDRESS_SPACES.take().unwrap_or_default();
+        let ret = spaces.get_mut(&(root as usize)).map(f);
+        ADDRESS_SPACES.replace(spaces);
+        ret
+    }
+}
 
 // The following set_* and get_by_pid functions are C-style functions
 // They probably need to be re-written in a more Rusty style, but for
@@ -120,12 +169,11 @@ pub fn set_sleeping(pid: u16, duration: usize) -> bool {
 pub fn delete_process(pid: u16) {
     unsafe {
         if let Some(mut pl) = PROCESS_LIST.take() {
+            let mut removed = None;
             for i in 0..pl.len() {
                 let p = pl.get_mut(i).unwrap();
                 if p.get_pid() == pid {
-                    // When the structure gets dropped, all
-                    // of the allocations get deallocated.
-                    pl.remove(i);
+                    removed = pl.remove(i);
                     break;
                 }
             }
@@ -133,8 +181,15 @@ pub fn delete_process(pid: u16) {
             // back by replacing the PROCESS_LIST's None with the
             // Some(pl).
             PROCESS_LIST.replace(pl);
+            // When the structure gets dropped, all of the allocations
+            // get deallocated. We do this after the list is back in
+            // place, since closing file descriptors can wake up other
+            // processes.
+            drop(removed);
         }
     }
+    ptrace::forget(pid);
+    strace::forget(pid);
 }
 
 /// Get a process by PID. Since we leak the process list, this is
@@ -153,12 +208,32 @@ pub unsafe fn get_by_pid(pid: u16) -> *mut Process {
     ret
 }
 
+/// The other threads that share a page table with `pid`.
+pub unsafe fn other_threads(pid: u16) -> Vec<u16> {
+    let p = get_by_pid(pid);
+    if p.is_null() {
+        return Vec::new();
+    }
+    let root = (*p).root;
+    if root.is_null() {
+        // Kernel threads all run on the kernel page table, but they aren't
+        // threads of each other.
+        return Vec::new();
+    }
+    PROCESS_LIST.as_ref().map_or_else(Vec::new, |pl| {
+        pl.iter()
+            .filter(|q| q.root == root && q.pid != pid)
+            .map(|q| q.pid)
+            .collect()
+    })
+}
+
 /// We will eventually move this function out of here, but its
 /// job is just to take a slot in the process list.
 fn init_process() {
     // We can't do much here until we have system calls because
     // we're running in User space.
-    println!("Init process started...");
+    info!("Init process started...");
     loop {
         // Alright, I forgot. We cannot put init to sleep since the
         // scheduler will loop until it finds a process to run. Since
@@ -222,45 +297,8 @@ pub fn add_kernel_process(func: fn()) -> u16 {
     // the process list will get None rather than the Deque.
     // .take() will replace PROCESS_LIST with None and give
     // us the only copy of the Deque.
-    let func_addr = func as usize;
-    let func_v_addr = func_addr; //- 0x6000_0000;
-                                 // println!("func_addr = {:x} -> {:x}", func_addr, func_vaddr);
-                                 // We will convert NEXT_PID below into an atomic increment when
-                                 // we start getting into multi-hart processing. For now, we want
-                                 // a process. Get it to work, then improve it!
-    let my_pid = unsafe { NEXT_PID };
-    let mut ret_proc = Process {
-        frame: zalloc(1) as *mut TrapFrame,
-        stack: zalloc(STACK_PAGES),
-        pid: my_pid,
-        root: zalloc(1) as *mut Table,
-        state: ProcessState::Running,
-        data: ProcessData::new(),
-        sleep_until: 0,
-        program: null_mut(),
-    };
-    unsafe {
-        NEXT_PID += 1;
-    }
-    // Now we move the stack pointer to the bottom of the
-    // allocation. The spec shows that register x2 (2) is the stack
-    // pointer.
-    // We could use ret_proc.stack.add, but that's an unsafe
-    // function which would require an unsafe block. So, convert it
-    // to usize first and then add PAGE_SIZE is better.
-    // We also need to set the stack adjustment so that it is at the
-    // bottom of the memory and far away from heap allocations.
-    unsafe {
-        (*ret_proc.frame).pc = func_v_addr;
-        // 1 is the return address register. This makes it so we
-        // don't have to do syscall_exit() when a kernel process
-        // finishes.
-        (*ret_proc.frame).regs[Registers::Ra as usize] = ra_delete_proc as usize;
-        (*ret_proc.frame).regs[Registers::Sp as usize] =
-            ret_proc.stack as usize + STACK_PAGES * 4096;
-        (*ret_proc.frame).mode = CpuMode::Machine as usize;
-        (*ret_proc.frame).pid = ret_proc.pid as usize;
-    }
+    let ret_proc = Process::new_default(func);
+    let my_pid = ret_proc.pid;
 
     unsafe { PROCESS_LIST.take() }.map_or_else(
         || {
@@ -313,45 +351,10 @@ pub fn add_kernel_process_args(func: fn(args_ptr: usize), args: usize) -> u16 {
     if let Some(mut pl) = unsafe { PROCESS_LIST.take() } {
         // .take() will replace PROCESS_LIST with None and give
         // us the only copy of the Deque.
-        let func_addr = func as usize;
-        let func_v_addr = func_addr; //- 0x6000_0000;
-                                     // println!("func_addr = {:x} -> {:x}", func_addr, func_vaddr);
-                                     // We will convert NEXT_PID below into an atomic increment when
-                                     // we start getting into multi-hart processing. For now, we want
-                                     // a process. Get it to work, then improve it!
-        let my_pid = unsafe { NEXT_PID };
-        let mut ret_proc = Process {
-            frame: zalloc(1) as *mut TrapFrame,
-            stack: zalloc(STACK_PAGES),
-            pid: my_pid,
-            root: zalloc(1) as *mut Table,
-            state: ProcessState::Running,
-            data: ProcessData::new(),
-            sleep_until: 0,
-            program: null_mut(),
-        };
-        unsafe {
-            NEXT_PID += 1;
-        }
-        // Now we move the stack pointer to the bottom of the
-        // allocation. The spec shows that register x2 (2) is the stack
-        // pointer.
-        // We could use ret_proc.stack.add, but that's an unsafe
-        // function which would require an unsafe block. So, convert it
-        // to usize first and then add PAGE_SIZE is better.
-        // We also need to set the stack adjustment so that it is at the
-        // bottom of the memory and far away from heap allocations.
+        let ret_proc = Process::new_kernel(func as usize);
+        let my_pid = ret_proc.pid;
         unsafe {
-            (*ret_proc.frame).pc = func_v_addr;
             (*ret_proc.frame).regs[Registers::A0 as usize] = args;
-            // 1 is the return address register. This makes it so we
-            // don't have to do syscall_exit() when a kernel process
-            // finishes.
-            (*ret_proc.frame).regs[Registers::Ra as usize] = ra_delete_proc as usize;
-            (*ret_proc.frame).regs[Registers::Sp as usize] =
-                ret_proc.stack as usize + STACK_PAGES * 4096;
-            (*ret_proc.frame).mode = CpuMode::Machine as usize;
-            (*ret_proc.frame).pid = ret_proc.pid as usize;
         }
         pl.push_back(ret_proc);
         // Now, we no longer need the owned Deque, so we hand it
@@ -405,12 +408,15 @@ pub fn init() -> usize {
 // Running - means that when the scheduler finds this process, it can run it.
 // Sleeping - means that the process is waiting on a certain amount of time.
 // Waiting - means that the process is waiting on I/O
+// Stopped - means that the process got a stop signal (SIGSTOP, SIGTSTP) and
+//           won't run until somebody sends it SIGCONT.
 // Dead - We should never get here, but we can flag a process as Dead and clean
 //        it out of the list later.
 pub enum ProcessState {
     Running,
     Sleeping,
     Waiting,
+    Stopped,
     Dead,
 }
 
@@ -418,6 +424,7 @@ pub struct Process {
     pub frame: *mut TrapFrame,
     pub stack: *mut u8,
     pub pid: u16,
+    /// The page table of a user process, null for a kernel thread
     pub root: *mut Table,
     pub state: ProcessState,
     pub data: ProcessData,
@@ -451,6 +458,69 @@ impl Process {
         unsafe { (*self.frame).pc }
     }
 
+    /// Give a new user process its address space, with `limits`. Its
+    /// threads will share both.
+    pub fn init_space(&self, limits: Limits) {
+        unsafe {
+            let mut spaces = ADDRESS_SPACES.take().unwrap_or_default();
+            spaces.insert(
+                self.root as usize,
+                AddressSpace {
+                    users: 1,
+                    program: self.program,
+                    limits,
+                    cpu_time: 0,
+                },
+            );
+            ADDRESS_SPACES.replace(spaces);
+        }
+    }
+
+    /// The resource limits of our process. Kernel threads have none.
+    pub fn limits(&self) -> Limits {
+        with_space(self.root, |space| space.limits).unwrap_or_default()
+    }
+
+    pub fn set_limits(&self, limits: Limits) {
+        with_space(self.root, |space| space.limits = limits);
+    }
+
+    /// The CPU time of our process, all threads together
+    pub fn cpu_time(&self) -> usize {
+        with_space(self.root, |space| space.cpu_time).unwrap_or(self.data.cpu_time)
+    }
+
+    /// Count `ticks` more CPU time for us and our process. Returns what
+    /// the process had before and what it has now.
+    pub fn add_cpu_time(&mut self, ticks: usize) -> (usize, usize) {
+        self.data.cpu_time += ticks;
+        with_space(self.root, |space| {
+            space.cpu_time += ticks;
+            (space.cpu_time - ticks, space.cpu_time)
+        })
+        .unwrap_or((self.data.cpu_time - ticks, self.data.cpu_time))
+    }
+
+    /// Put `desc` into the lowest free file descriptor and return it.
+    pub fn add_fd(&mut self, desc: FileDescriptor) -> Result<u16, Errno> {
+        self.add_fd_from(0, desc)
+    }
+
+    /// Put `desc` into the lowest free file descriptor that is at least
+    /// `min`, as fcntl(F_DUPFD) does, and return it. Past `RLIMIT_NOFILE`,
+    /// that's `EMFILE`.
+    pub fn add_fd_from(&mut self, min: u16, desc: FileDescriptor) -> Result<u16, Errno> {
+        let mut fd = min;
+        while self.data.fdesc.contains_key(&fd) {
+            fd += 1;
+        }
+        if usize::from(fd) >= self.limits().cur(RLIMIT_NOFILE) {
+            return Err(EMFILE);
+        }
+        self.data.fdesc.insert(fd, desc);
+        Ok(fd)
+    }
+
     pub fn get_program_address_mut(&mut self) -> *mut u8 {
         self.program
     }
@@ -479,77 +549,68 @@ impl Process {
         self.sleep_until = until;
     }
 
+    /// Is this a kernel thread?
+    pub fn is_kernel(&self) -> bool {
+        unsafe { (*self.frame).mode == CpuMode::Supervisor as usize }
+    }
+
+    /// Is `addr` in the guard page below one of our stacks? For a user
+    /// process, that includes the stacks of the other threads.
+    pub fn is_stack_guard(&self, addr: usize) -> bool {
+        if self.is_kernel() {
+            let guard = self.stack as usize;
+            !self.stack.is_null() && (guard..guard + PAGE_SIZE).contains(&addr)
+        } else {
+            mmap::is_guard(self.root, addr)
+        }
+    }
+
+    /// A kernel thread that runs `func`, see [`Process::new_kernel`].
     pub fn new_default(func: fn()) -> Self {
-        let func_addr = func as usize;
-        let func_v_addr = func_addr;
-        // println!("func_addr = {:x} -> {:x}", func_addr, func_vaddr);
+        Self::new_kernel(func as usize)
+    }
+
+    /// A kernel thread that starts at `pc`. It runs in supervisor mode on
+    /// the kernel page table, where its code can't be written and its data
+    /// can't be run, so it has no page table of its own. When the function
+    /// returns, it returns to [`ra_delete_proc`], which exits.
+    ///
+    /// Its stack starts with the guard page, which the kernel page table
+    /// doesn't map.
+    fn new_kernel(pc: usize) -> Self {
         // We will convert NEXT_PID below into an atomic increment when
         // we start getting into multi-hart processing. For now, we want
         // a process. Get it to work, then improve it!
-        let mut ret_proc = Self {
+        let ret_proc = Self {
             frame: zalloc(1) as *mut TrapFrame,
-            stack: alloc(STACK_PAGES),
+            stack: zalloc(STACK_PAGES + 1),
             pid: unsafe { NEXT_PID },
-            root: zalloc(1) as *mut Table,
+            root: null_mut(),
             state: ProcessState::Running,
             data: ProcessData::new(),
             sleep_until: 0,
             program: null_mut(),
         };
         unsafe {
-            satp_fence_asid(NEXT_PID as usize);
             NEXT_PID += 1;
         }
-        // Now we move the stack pointer to the bottom of the
-        // allocation. The spec shows that register x2 (2) is the stack
-        // pointer.
-        // We could use ret_proc.stack.add, but that's an unsafe
-        // function which would require an unsafe block. So, convert it
-        // to usize first and then add PAGE_SIZE is better.
-        // We also need to set the stack adjustment so that it is at the
-        // bottom of the memory and far away from heap allocations.
-        let s_addr = ret_proc.stack as usize;
-        unsafe {
-            (*ret_proc.frame).pc = func_v_addr;
-            (*ret_proc.frame).regs[Registers::Sp as usize] = STACK_ADDR + PAGE_SIZE * STACK_PAGES;
-            (*ret_proc.frame).mode = CpuMode::User as usize;
-            (*ret_proc.frame).pid = ret_proc.pid as usize;
-        }
-        // Map the stack on the MMU
-        let pt;
+        kmem::guard_page(ret_proc.stack);
+        // The stack grows down, so the stack pointer starts at the end of
+        // the allocation. The kernel is mapped one to one, so that is
+        // where it is for the thread too.
         unsafe {
-            pt = &mut *ret_proc.root;
-            (*ret_proc.frame).satp = build_satp(
-                SatpMode::Sv39,
-                ret_proc.pid as usize,
-                ret_proc.root as usize,
-            );
-        }
-        // We need to map the stack onto the user process' virtual
-        // memory This gets a little hairy because we need to also map
-        // the function code too.
-        for i in 0..STACK_PAGES {
-            let addr = i * PAGE_SIZE;
-            map(
-                pt,
-                STACK_ADDR + addr,
-                s_addr + addr,
-                EntryBits::UserReadWrite.val(),
-                0,
-            );
-            // println!("Set stack from 0x{:016x} -> 0x{:016x}",
-            // STACK_ADDR + addr, saddr + addr);
-        }
-        // Map the program counter on the MMU and other bits
-        for i in 0..=100 {
-            let modifier = i * 0x1000;
-            map(
-                pt,
-                func_v_addr + modifier,
-                func_addr + modifier,
-                EntryBits::UserReadWriteExecute.val(),
-                0,
-            );
+            let frame = &mut *ret_proc.frame;
+            frame.pc = pc;
+            // 1 is the return address register. This makes it so we
+            // don't have to do syscall_exit() when a kernel process
+            // finishes.
+            frame.regs[Registers::Ra as usize] = ra_delete_proc as usize;
+            frame.regs[Registers::Sp as usize] =
+                ret_proc.stack as usize + (STACK_PAGES + 1) * PAGE_SIZE;
+            frame.mode = CpuMode::Supervisor as usize;
+            frame.satp = kmem::kernel_satp();
+            frame.mstatus = MSTATUS_MIE;
+            frame.pid = ret_proc.pid as usize;
         }
         ret_proc
     }
@@ -559,31 +620,258 @@ impl Drop for Process {
     /// Since we're storing ownership of a Process in the linked list,
     /// we can cause it to deallocate automatically when it is removed.
     fn drop(&mut self) {
-        // We allocate the stack as a page.
-        dealloc(self.stack);
-        // This is unsafe, but it's at the drop stage, so we won't
-        // be using this again.
+        let mut program = self.program;
         unsafe {
-            // Remember that unmap unmaps all levels of page tables
-            // except for the root. It also deallocates the memory
-            // associated with the tables.
-            unmap(&mut *self.root);
+            if let Some(mut spaces) = ADDRESS_SPACES.take() {
+                let mut shared = false;
+                if let Some(space) = spaces.get_mut(&(self.root as usize)) {
+                    space.users -= 1;
+                    if space.users > 0 {
+                        shared = true;
+                    } else {
+                        program = space.program;
+                        spaces.remove(&(self.root as usize));
+                    }
+                }
+                ADDRESS_SPACES.replace(spaces);
+                if shared {
+                    // Other threads are still running in this page table,
+                    // so we only take our own stack out of it.
+                    self.exit_thread();
+                    return;
+                }
+            }
+        }
+        // We allocate the stack as a page. A kernel thread's starts with
+        // its guard page, which has to be mapped again for whoever gets
+        // it next.
+        if !self.stack.is_null() {
+            if self.is_kernel() {
+                kmem::unguard_page(self.stack);
+            }
+            dealloc(self.stack);
+        }
+        if !self.root.is_null() {
+            // So are the pages brk and mmap handed out.
+            mmap::release(self.root);
+            // This is unsafe, but it's at the drop stage, so we won't
+            // be using this again.
+            unsafe {
+                // Remember that unmap unmaps all levels of page tables
+                // except for the root. It also deallocates the memory
+                // associated with the tables.
+                unmap(&mut *self.root);
+            }
+            dealloc(self.root as *mut u8);
         }
-        dealloc(self.root as *mut u8);
         dealloc(self.frame as *mut u8);
-        if !self.program.is_null() {
-            dealloc(self.program);
+        if !program.is_null() {
+            dealloc(program);
+        }
+    }
+}
+
+impl Process {
+    /// Create a thread that shares our page table, as clone(CLONE_VM) does.
+    /// The thread starts right after the ecall in `frame` with A0 = 0. If
+    /// `stack` is 0, the kernel gives it a stack of its own below ours.
+    /// Returns the new thread's PID.
+    pub unsafe fn clone_thread(
+        &mut self,
+        mepc: usize,
+        flags: usize,
+        stack: usize,
+        ptid: usize,
+        tls: usize,
+        ctid: usize,
+    ) -> Result<u16, Errno> {
+        // Without fork, all we can make are threads.
+        if flags & CLONE_VM == 0 {
+            return Err(EINVAL);
+        }
+        if (*self.frame).mode != CpuMode::User as usize {
+            return Err(EPERM);
+        }
+        let table = &mut *self.root;
+        let pid = NEXT_PID;
+        // This is the one thing that can fail because of the caller's
+        // pointers, so do it while there is nothing to take back yet.
+        if flags & CLONE_PARENT_SETTID != 0 {
+            write_user(Space::User(table), ptid, &(pid as u32))?;
+        }
+        // Get the stack before there is a thread. Dropping a thread that
+        // isn't in ADDRESS_SPACES yet would tear down our page table.
+        let mut stack_vaddr = 0;
+        let mut stack_pages = null_mut();
+        if stack == 0 {
+            // Find the first free stack slot below the main stack. We leave
+            // one unmapped page between slots so that an overflowing thread
+            // faults instead of eating its neighbor's stack. mmap won't
+            // map anything there either.
+            let limits = self.limits();
+            if !limits.stack_fits() || !mmap::charge(table, STACK_PAGES, limits.pages()) {
+                return Err(ENOMEM);
+            }
+            mmap::make_room(STACK_PAGES);
+            stack_pages = zalloc(STACK_PAGES);
+            if stack_pages.is_null() {
+                mmap::uncharge(table, STACK_PAGES);
+                return Err(ENOMEM);
+            }
+            let slot_size = (STACK_PAGES + 1) * PAGE_SIZE;
+            stack_vaddr = STACK_ADDR - slot_size;
+            while virt_to_phys(table, stack_vaddr).is_some() {
+                stack_vaddr -= slot_size;
+            }
+        }
+        let trap_frame = zalloc(1) as *mut TrapFrame;
+        if trap_frame.is_null() {
+            if !stack_pages.is_null() {
+                dealloc(stack_pages);
+                mmap::uncharge(table, STACK_PAGES);
+            }
+            return Err(ENOMEM);
+        }
+        // Nothing can fail from here on.
+        let mut thread = Self {
+            frame: trap_frame,
+            stack: stack_pages,
+            pid,
+            root: self.root,
+            state: ProcessState::Running,
+            data: ProcessData::new(),
+            sleep_until: 0,
+            program: null_mut(),
+        };
+        // Everything from the registers to the satp (and with it the ASID)
+        // is the same, except for what clone() has to change.
+        *thread.frame = *self.frame;
+        let frame = &mut *thread.frame;
+        frame.pid = pid as usize;
+        frame.pc = mepc + 4;
+        frame.regs[Registers::A0 as usize] = 0;
+        if flags & CLONE_SETTLS != 0 {
+            frame.regs[Registers::Tp as usize] = tls;
+        }
+        if stack != 0 {
+            frame.regs[Registers::Sp as usize] = stack;
+        } else {
+            for i in 0..STACK_PAGES {
+                let addr = i * PAGE_SIZE;
+                map(
+                    table,
+                    stack_vaddr + addr,
+                    stack_pages as usize + addr,
+                    EntryBits::UserReadWrite.val(),
+                    0,
+                );
+            }
+            mmap::guard(table, stack_vaddr - PAGE_SIZE);
+            thread.data.stack_vaddr = stack_vaddr;
+            frame.regs[Registers::Sp as usize] = stack_vaddr + STACK_PAGES * PAGE_SIZE;
+        }
+        if flags & CLONE_CHILD_CLEARTID != 0 {
+            thread.data.clear_child_tid = ctid;
+        }
+        // We don't have separate file tables or signal handler tables, so
+        // a thread gets copies of both.
+        for (fd, desc) in self.data.fdesc.iter() {
+            thread.data.fdesc.insert(*fd, desc.duplicate());
+        }
+        thread.data.signals.actions = self.data.signals.actions;
+        thread.data.signals.blocked = self.data.signals.blocked;
+        thread.data.path = self.data.path.clone();
+        thread.data.core_dump = self.data.core_dump;
+        thread.data.parent = self.pid;
+
+        with_space(self.root, |space| space.users += 1);
+
+        NEXT_PID += 1;
+        if let Some(mut pl) = PROCESS_LIST.take() {
+            pl.push_back(thread);
+            PROCESS_LIST.replace(pl);
+        }
+        Ok(pid)
+    }
+
+    /// Tear down a thread whose page table is still used by others.
+    unsafe fn exit_thread(&mut self) {
+        let table = &mut *self.root;
+        if self.data.clear_child_tid != 0 {
+            // Whoever joins us waits on this word.
+            let space = Space::User(table);
+            let tid = self.data.clear_child_tid;
+            if write_user(space, tid, &0u32).is_ok() {
+                if let Ok(paddr) = translate(space, tid, Access::Write) {
+                    futex::wake_key(paddr, usize::MAX);
+                }
+            }
         }
+        if !self.stack.is_null() {
+            for i in 0..STACK_PAGES {
+                unmap_page(table, self.data.stack_vaddr + i * PAGE_SIZE);
+            }
+            mmap::unguard(table, self.data.stack_vaddr - PAGE_SIZE);
+            satp_fence_asid(((*self.frame).satp >> 44) & 0xffff);
+            dealloc(self.stack);
+            mmap::uncharge(table, STACK_PAGES);
+        }
+        dealloc(self.frame as *mut u8);
     }
 }
 
+// Device numbers used by FileDescriptor::Device
+// The serial console, which is where the standard streams go by default.
+pub const CONSOLE_DEVICE: usize = 0;
+
+// The file descriptors that every user process expects to find open.
+pub const STDIN_FILENO: u16 = 0;
+pub const STDOUT_FILENO: u16 = 1;
+pub const STDERR_FILENO: u16 = 2;
+
 pub enum FileDescriptor {
-    File(Inode),
+    // A file on the disk and where in it the next read starts
+    File(Inode, u32),
     Device(usize),
+    // The read and write ends of the pipe with the given id
+    PipeRead(u16),
+    PipeWrite(u16),
     Network,
     Unknown,
 }
 
+impl FileDescriptor {
+    /// Make another descriptor that refers to the same thing, as dup does.
+    pub fn duplicate(&self) -> Self {
+        match self {
+            Self::File(inode, offset) => Self::File(*inode, *offset),
+            Self::Device(dev) => Self::Device(*dev),
+            Self::PipeRead(id) => {
+                pipe::add_reader(*id);
+                Self::PipeRead(*id)
+            }
+            Self::PipeWrite(id) => {
+                pipe::add_writer(*id);
+                Self::PipeWrite(*id)
+            }
+            Self::Network => Self::Network,
+            Self::Unknown => Self::Unknown,
+        }
+    }
+}
+
+impl Drop for FileDescriptor {
+    /// Closing a descriptor is just dropping it. Pipes need to know when
+    /// their last reader or writer goes away.
+    fn drop(&mut self) {
+        match self {
+            Self::PipeRead(id) => pipe::drop_reader(*id),
+            Self::PipeWrite(id) => pipe::drop_writer(*id),
+            _ => {}
+        }
+    }
+}
+
 // The private data in a process contains information
 // that is relevant to where we are, including the path
 // and open file descriptors.
@@ -594,6 +882,27 @@ pub enum FileDescriptor {
 pub struct ProcessData {
     environ: BTreeMap<String, String>,
     fdesc: BTreeMap<u16, FileDescriptor>,
+    pub signals: SignalState,
+    // Where this process' stack is mapped in its virtual memory
+    pub stack_vaddr: usize,
+    // Set by clone(CLONE_CHILD_CLEARTID). We write a 0 here and wake up
+    // anyone waiting on it when the thread exits.
+    pub clear_child_tid: usize,
+    // The program we are running, as given to execv
+    pub path: String,
+    // Write a crash report to the disk if we die from a signal that dumps
+    // core. Switched with prctl(PR_SET_DUMPABLE).
+    pub core_dump: bool,
+    // What the last fault was, for the crash report
+    pub fault: Option<FaultInfo>,
+    // The process that created us with clone(), or 0 if execv did. This is
+    // who PTRACE_TRACEME hands us to.
+    pub parent: u16,
+    // The timer ticks this thread ran for. The process' are in its
+    // address space.
+    pub cpu_time: usize,
+    // What the file system read for us while we were waiting in read()
+    pub file_read: Option<FileRead>,
 }
 
 // This is private data that we can query with system calls.
@@ -606,4 +915,39 @@ impl ProcessData {
         // }
         Self::default()
     }
+
+    pub fn get_fd(&self, fd: u16) -> Option<&FileDescriptor> {
+        self.fdesc.get(&fd)
+    }
+
+    pub fn get_fd_mut(&mut self, fd: u16) -> Option<&mut FileDescriptor> {
+        self.fdesc.get_mut(&fd)
+    }
+
+    /// Put `desc` into `fd`, closing whatever was there before.
+    pub fn set_fd(&mut self, fd: u16, desc: FileDescriptor) {
+        self.fdesc.insert(fd, desc);
+    }
+
+    /// Remove `fd` from the table. Dropping the result closes it.
+    pub fn close_fd(&mut self, fd: u16) -> Option<FileDescriptor> {
+        self.fdesc.remove(&fd)
+    }
+
+    /// Hand the whole descriptor table over, for example to the program
+    /// we are about to exec.
+    pub fn take_fds(&mut self) -> BTreeMap<u16, FileDescriptor> {
+        core::mem::take(&mut self.fdesc)
+    }
+
+    /// Install an inherited descriptor table. Any standard stream that
+    /// isn't in there is connected to the console.
+    pub fn inherit_fds(&mut self, fdesc: BTreeMap<u16, FileDescriptor>) {
+        self.fdesc = fdesc;
+        for fd in [STDIN_FILENO, STDOUT_FILENO, STDERR_FILENO] {
+            self.fdesc
+                .entry(fd)
+                .or_insert(FileDescriptor::Device(CONSOLE_DEVICE));
+        }
+    }
 }
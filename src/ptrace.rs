@@ -0,0 +1,598 @@
+//! # ptrace
+//!
+//! Process tracing along the lines of Linux' `ptrace(2)`. A tracer attaches
+//! to a user process, or a thread asks its creator to trace it with
+//! `PTRACE_TRACEME` before it calls `execv`. From then on, the tracee stops
+//! instead of acting on a signal, and with `PTRACE_SYSCALL` also on the way
+//! into and out of every system call. The tracer learns about stops with
+//! `wait4`, which only knows about tracees since there are no child
+//! processes, and can then look at and change the tracee's registers and
+//! memory before it lets it go on.
+//!
+//! Request numbers, the `wait4` status and the register layout
+//! (`struct user_regs_struct`: the pc followed by x1 to x31) are the same as
+//! on Linux, so `userspace/strace.cpp` reads like it would there. A few
+//! things are different:
+//!
+//!  * Execv starts a new process with a new PID. The trace moves over to
+//!    it and the new process starts in a SIGTRAP stop, so a tracer should
+//!    wait for any tracee.
+//!  * There is no single-step. A debugger writes `ebreak` instructions with
+//!    `PTRACE_POKETEXT` and gets the SIGTRAP they cause.
+//!  * A process that is blocked in a system call only stops once the call
+//!    is done.
+//!  * `PTRACE_GETSIGINFO` fills in only the start of `siginfo_t`, followed by
+//!    the mcause of the fault, see [`SigInfo`].
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::arch::asm;
+
+use crate::{
+    cpu::{CpuMode, TrapFrame},
+    crash::FaultInfo,
+    errno::{Errno, SysResult, ECHILD, EFAULT, EINVAL, EIO, EPERM, ESRCH},
+    page::Table,
+    process::{get_by_pid, set_running, Process, ProcessState},
+    signal::{send_signal, NSIG, SIGKILL, SIGSTOP, SIGTRAP},
+    uaccess::{read_raw, read_user, write_raw, write_user, Access, Space},
+};
+
+// Requests, numbered like Linux
+pub const PTRACE_TRACEME: usize = 0;
+pub const PTRACE_PEEKTEXT: usize = 1;
+pub const PTRACE_PEEKDATA: usize = 2;
+pub const PTRACE_POKETEXT: usize = 4;
+pub const PTRACE_POKEDATA: usize = 5;
+pub const PTRACE_CONT: usize = 7;
+pub const PTRACE_KILL: usize = 8;
+pub const PTRACE_GETREGS: usize = 12;
+pub const PTRACE_SETREGS: usize = 13;
+pub const PTRACE_GETFPREGS: usize = 14;
+pub const PTRACE_SETFPREGS: usize = 15;
+pub const PTRACE_ATTACH: usize = 16;
+pub const PTRACE_DETACH: usize = 17;
+pub const PTRACE_SYSCALL: usize = 24;
+pub const PTRACE_SETOPTIONS: usize = 0x4200;
+pub const PTRACE_GETSIGINFO: usize = 0x4202;
+
+/// Report system call stops as SIGTRAP | 0x80, so that they can't be
+/// mistaken for a real SIGTRAP.
+pub const PTRACE_O_TRACESYSGOOD: usize = 1;
+
+/// `wait4` option: don't block if no tracee has anything to report.
+pub const WNOHANG: usize = 1;
+
+// si_code values
+const SI_USER: i32 = 0;
+const SI_KERNEL: i32 = 0x80;
+
+/// What `PTRACE_GETSIGINFO` hands out. The first fields are laid out like
+/// Linux' `siginfo_t` for SIGSEGV. For signals caused by a fault, `code` is
+/// `SI_KERNEL`, `addr` is mtval and `cause` is mcause.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SigInfo {
+    pub signo: i32,
+    pub errno: i32,
+    pub code: i32,
+    pub pad: i32,
+    pub addr: usize,
+    pub cause: usize,
+}
+
+/// Why a tracee stopped
+#[derive(Clone, Copy)]
+enum Stop {
+    /// It was about to act on a signal, which it may have caused itself.
+    Signal(usize, Option<FaultInfo>),
+    SyscallEntry,
+    SyscallExit,
+}
+
+/// Where a tracee is with the system call it made, if it stops at them.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SyscallStage {
+    Outside,
+    /// The entry was reported, the call runs when the tracee goes on.
+    Entered,
+    /// The call ran, the exit is reported before the tracee goes on.
+    Leaving,
+}
+
+struct Tracee {
+    tracer: u16,
+    options: usize,
+    /// Stop at system calls (`PTRACE_SYSCALL` rather than `PTRACE_CONT`)
+    syscalls: bool,
+    stage: SyscallStage,
+    stop: Option<Stop>,
+    /// Has wait4 told the tracer about the stop or exit yet?
+    reported: bool,
+    /// The signal the tracer passed on when it let the tracee go. It is
+    /// acted upon without another stop.
+    inject: Option<usize>,
+    /// How the tracee ended, as wait4 reports it
+    status: u32,
+    exited: bool,
+}
+
+impl Tracee {
+    const fn new(tracer: u16) -> Self {
+        Self {
+            tracer,
+            options: 0,
+            syscalls: false,
+            stage: SyscallStage::Outside,
+            stop: None,
+            reported: false,
+            inject: None,
+            status: 0,
+            exited: false,
+        }
+    }
+
+    /// The status wait4 reports for the current stop or exit.
+    fn wait_status(&self) -> u32 {
+        let signo = match self.stop {
+            _ if self.exited => return self.status,
+            Some(Stop::Signal(signo, _)) => signo,
+            Some(Stop::SyscallEntry | Stop::SyscallExit) => {
+                if self.options & PTRACE_O_TRACESYSGOOD != 0 {
+                    SIGTRAP | 0x80
+                } else {
+                    SIGTRAP
+                }
+            }
+            None => 0,
+        };
+        (signo as u32) << 8 | 0x7f
+    }
+
+    /// Can the tracer tell it what to do right now?
+    const fn is_stopped(&self) -> bool {
+        self.stop.is_some() && !self.exited
+    }
+}
+
+/// A trace on its way through execv. See [`take_for_exec`].
+pub struct ExecTrace {
+    tracer: u16,
+    pid: u16,
+}
+
+// Every traced process by PID, until its tracer has seen it exit
+static mut TRACEES: Option<BTreeMap<u16, Tracee>> = None;
+// Tracers blocked in wait4
+static mut WAITERS: Option<BTreeSet<u16>> = None;
+
+fn with_tracee<R>(pid: u16, f: impl FnOnce(&mut Tracee) -> R) -> Option<R> {
+    unsafe {
+        let mut tracees = TRACEES.take()?;
+        let ret = tracees.get_mut(&pid).map(f);
+        TRACEES.replace(tracees);
+        ret
+    }
+}
+
+/// Wake up `tracer` if it is waiting for something to happen.
+fn wake(tracer: u16) {
+    unsafe {
+        if let Some(mut waiters) = WAITERS.take() {
+            if waiters.remove(&tracer) {
+                set_running(tracer);
+            }
+            WAITERS.replace(waiters);
+        }
+    }
+}
+
+pub fn is_traced(pid: u16) -> bool {
+    with_tracee(pid, |_| ()).is_some()
+}
+
+/// Is `pid` stopped for its tracer? SIGCONT must not wake such a process.
+pub fn is_stopped(pid: u16) -> bool {
+    with_tracee(pid, |t| t.is_stopped()).unwrap_or(false)
+}
+
+/// Put `p` into a tracing stop and tell its tracer.
+unsafe fn stop(p: *mut Process, why: Stop) {
+    let tracer = with_tracee((*p).get_pid(), |t| {
+        t.stop = Some(why);
+        t.reported = false;
+        t.tracer
+    });
+    if let Some(tracer) = tracer {
+        (*p).set_state(ProcessState::Stopped);
+        wake(tracer);
+    }
+}
+
+/// Is SIGKILL on its way? Nothing stops a process from dying.
+unsafe fn is_dying(p: *const Process) -> bool {
+    (*p).data.signals.pending & 1 << SIGKILL != 0
+}
+
+/// The process behind `frame` made a system call. Returns true if it
+/// stopped for its tracer first, in which case the ecall runs again once
+/// the tracer lets it go.
+pub unsafe fn syscall_entry(frame: *const TrapFrame) -> bool {
+    let pid = (*frame).pid as u16;
+    let stopping = with_tracee(pid, |t| match t.stage {
+        SyscallStage::Entered => {
+            t.stage = if t.syscalls {
+                SyscallStage::Leaving
+            } else {
+                SyscallStage::Outside
+            };
+            false
+        }
+        _ if t.syscalls => {
+            t.stage = SyscallStage::Entered;
+            true
+        }
+        _ => false,
+    });
+    if stopping != Some(true) {
+        return false;
+    }
+    let p = get_by_pid(pid);
+    if is_dying(p) {
+        return false;
+    }
+    stop(p, Stop::SyscallEntry);
+    true
+}
+
+/// Does the process behind `frame` still have to report the end of its
+/// system call? That happens on the way back to it, see
+/// [`syscall_exit_stop`].
+pub unsafe fn has_exit_stop(frame: *const TrapFrame) -> bool {
+    with_tracee((*frame).pid as u16, |t| t.stage == SyscallStage::Leaving).unwrap_or(false)
+}
+
+/// Report the end of a system call before `p` sees its result. The call
+/// may have blocked, so this only happens once the process is about to
+/// run again. Returns true if it stopped.
+pub unsafe fn syscall_exit_stop(p: *mut Process) -> bool {
+    let leaving = with_tracee((*p).get_pid(), |t| {
+        let leaving = t.stage == SyscallStage::Leaving;
+        t.stage = SyscallStage::Outside;
+        leaving
+    });
+    if leaving != Some(true) || is_dying(p) {
+        return false;
+    }
+    stop(p, Stop::SyscallExit);
+    true
+}
+
+/// The signal the tracer passed on to `pid`, if it did.
+pub fn take_injected(pid: u16) -> Option<usize> {
+    with_tracee(pid, |t| t.inject.take()).flatten()
+}
+
+/// `p` is about to act on `signo`. A traced process stops and lets its
+/// tracer decide what becomes of the signal. Returns true if it stopped.
+pub unsafe fn signal_stop(p: *mut Process, signo: usize) -> bool {
+    if signo == SIGKILL || !is_traced((*p).get_pid()) {
+        return false;
+    }
+    let fault = (*p).data.fault.filter(|fault| fault.signo == signo);
+    stop(p, Stop::Signal(signo, fault));
+    true
+}
+
+/// Remember how `pid` is about to end, in the format of wait4: the exit
+/// code in bits 8 to 15, or the signal that killed it in the low bits.
+pub fn exiting(pid: u16, status: u32) {
+    with_tracee(pid, |t| t.status = status);
+}
+
+/// `pid` is gone. Its tracer gets to see that, and whatever it traced is
+/// let go.
+pub fn forget(pid: u16) {
+    unsafe {
+        let mut tracees = match TRACEES.take() {
+            Some(tracees) => tracees,
+            None => return,
+        };
+        let mut tracer = None;
+        if let Some(t) = tracees.get_mut(&pid) {
+            t.exited = true;
+            t.stop = None;
+            t.reported = false;
+            tracer = Some(t.tracer);
+        }
+        let orphans: Vec<u16> = tracees
+            .iter()
+            .filter(|(_, t)| t.tracer == pid)
+            .map(|(tracee, _)| *tracee)
+            .collect();
+        let mut resume = Vec::new();
+        for tracee in orphans {
+            if let Some(t) = tracees.remove(&tracee) {
+                if t.is_stopped() {
+                    resume.push(tracee);
+                }
+            }
+        }
+        TRACEES.replace(tracees);
+        if let Some(mut waiters) = WAITERS.take() {
+            waiters.remove(&pid);
+            WAITERS.replace(waiters);
+        }
+        for tracee in resume {
+            set_running(tracee);
+        }
+        if let Some(tracer) = tracer {
+            wake(tracer);
+        }
+    }
+}
+
+/// `pid` calls execv, which ends it. Take its trace along to the process
+/// that runs the new program, see [`exec_done`].
+pub fn take_for_exec(pid: u16) -> Option<ExecTrace> {
+    unsafe {
+        let mut tracees = TRACEES.take()?;
+        let trace = tracees.remove(&pid).map(|t| ExecTrace {
+            tracer: t.tracer,
+            pid,
+        });
+        TRACEES.replace(tracees);
+        trace
+    }
+}
+
+/// Execv is done with `trace`. The new process (if the program could be
+/// loaded) is traced from the start and stops with SIGTRAP before its
+/// first instruction. Otherwise the tracer learns that the old one is gone.
+pub fn exec_done(trace: ExecTrace, proc: Option<&mut Process>) {
+    let (pid, t) = match proc {
+        Some(proc) => {
+            proc.data.signals.pending |= 1 << SIGTRAP;
+            (proc.get_pid(), Tracee::new(trace.tracer))
+        }
+        None => {
+            let mut t = Tracee::new(trace.tracer);
+            // Like a shell that can't find the program
+            t.status = 127 << 8;
+            t.exited = true;
+            (trace.pid, t)
+        }
+    };
+    unsafe {
+        TRACEES.get_or_insert_with(BTreeMap::new).insert(pid, t);
+    }
+    wake(trace.tracer);
+}
+
+/// Start tracing `pid` on behalf of `tracer`.
+unsafe fn attach(tracer: u16, pid: u16) -> SysResult {
+    let p = get_by_pid(pid);
+    if p.is_null() {
+        return Err(ESRCH);
+    }
+    if pid == tracer || (*(*p).get_frame()).mode != CpuMode::User as usize {
+        return Err(EPERM);
+    }
+    // Two processes tracing each other would wait for each other forever.
+    if is_traced(pid) || with_tracee(tracer, |t| t.tracer) == Some(pid) {
+        return Err(EPERM);
+    }
+    TRACEES
+        .get_or_insert_with(BTreeMap::new)
+        .insert(pid, Tracee::new(tracer));
+    // The stop for this is what the tracer waits for first.
+    send_signal(pid, SIGSTOP).map_err(|_| ESRCH)?;
+    Ok(0)
+}
+
+/// Let a stopped tracee go on, with `signo` (0 for none) as the signal it
+/// stopped for.
+unsafe fn resume(pid: u16, signo: usize, syscalls: bool, detach: bool) -> SysResult {
+    if signo >= NSIG {
+        return Err(EIO);
+    }
+    let mut tracees = TRACEES.take().ok_or(ESRCH)?;
+    let t = tracees.get_mut(&pid);
+    let ok = t.map(|t| {
+        t.syscalls = syscalls;
+        t.stop = None;
+        t.inject = (signo != 0).then(|| signo);
+    });
+    let inject = if detach {
+        tracees.remove(&pid).and_then(|t| t.inject)
+    } else {
+        None
+    };
+    TRACEES.replace(tracees);
+    ok.ok_or(ESRCH)?;
+    if let Some(signo) = inject {
+        // Nobody is there to skip the stop, so the signal goes back to
+        // being just pending.
+        (*get_by_pid(pid)).data.signals.pending |= 1 << signo;
+    }
+    set_running(pid);
+    Ok(0)
+}
+
+/// Read a word of the tracee's memory. Text pages are usually not
+/// writable, so we only care that the pages belong to the tracee.
+unsafe fn peek(space: Space, addr: usize) -> Result<usize, Errno> {
+    let mut word = [0_u8; 8];
+    read_raw(space, word.as_mut_ptr(), addr, word.len(), Access::Debug).map_err(|_| EIO)?;
+    Ok(usize::from_le_bytes(word))
+}
+
+unsafe fn poke(space: Space, addr: usize, value: usize) -> SysResult {
+    let word = value.to_le_bytes();
+    write_raw(space, addr, word.as_ptr(), word.len(), Access::Debug).map_err(|_| EIO)?;
+    // This may have been code, a breakpoint most likely.
+    asm!("fence.i");
+    Ok(0)
+}
+
+/// The ptrace system call: `ptrace(request, pid, addr, data)`. Only user
+/// processes can trace, and only other user processes.
+pub unsafe fn ptrace(
+    frame: *const TrapFrame,
+    request: usize,
+    pid: usize,
+    addr: usize,
+    data: usize,
+) -> SysResult {
+    let me = (*frame).pid as u16;
+    let caller = get_by_pid(me);
+    if caller.is_null() || (*frame).mode != CpuMode::User as usize {
+        return Err(EPERM);
+    }
+    match request {
+        PTRACE_TRACEME => {
+            // We are a thread and the one that created us traces us.
+            let tracer = (*caller).data.parent;
+            if tracer == 0 || is_traced(me) {
+                return Err(EPERM);
+            }
+            TRACEES
+                .get_or_insert_with(BTreeMap::new)
+                .insert(me, Tracee::new(tracer));
+            return Ok(0);
+        }
+        PTRACE_ATTACH => return attach(me, u16::try_from(pid).map_err(|_| ESRCH)?),
+        _ => {}
+    }
+    let pid = u16::try_from(pid).map_err(|_| ESRCH)?;
+    let stopped = with_tracee(pid, |t| (t.tracer == me).then(|| t.is_stopped()))
+        .flatten()
+        .ok_or(ESRCH)?;
+    if request == PTRACE_KILL {
+        return send_signal(pid, SIGKILL).map(|_| 0).map_err(|_| ESRCH);
+    }
+    // Everything else wants the tracee to hold still.
+    if !stopped {
+        return Err(ESRCH);
+    }
+    let p = get_by_pid(pid);
+    let tframe = &mut *(*p).get_frame_mut();
+    let space = Space::User((*p).get_table_address() as *mut Table);
+    let my_space = Space::User((*caller).get_table_address() as *mut Table);
+    match request {
+        PTRACE_PEEKTEXT | PTRACE_PEEKDATA => {
+            let word = peek(space, addr)?;
+            write_user(my_space, data, &word)?;
+            Ok(0)
+        }
+        PTRACE_POKETEXT | PTRACE_POKEDATA => poke(space, addr, data),
+        PTRACE_GETREGS => {
+            let mut regs = tframe.regs;
+            regs[0] = tframe.pc;
+            write_user(my_space, data, &regs)?;
+            Ok(0)
+        }
+        PTRACE_SETREGS => {
+            let regs: [usize; 32] = read_user(my_space, data)?;
+            tframe.pc = regs[0];
+            tframe.regs[1..].copy_from_slice(&regs[1..]);
+            Ok(0)
+        }
+        PTRACE_GETFPREGS => {
+            write_user(my_space, data, &tframe.fregs)?;
+            Ok(0)
+        }
+        PTRACE_SETFPREGS => {
+            tframe.fregs = read_user(my_space, data)?;
+            Ok(0)
+        }
+        PTRACE_CONT => resume(pid, data, false, false),
+        PTRACE_SYSCALL => resume(pid, data, true, false),
+        PTRACE_DETACH => resume(pid, data, false, true),
+        PTRACE_SETOPTIONS => with_tracee(pid, |t| t.options = data)
+            .map(|_| 0)
+            .ok_or(ESRCH),
+        PTRACE_GETSIGINFO => {
+            let info = match with_tracee(pid, |t| t.stop).flatten().ok_or(ESRCH)? {
+                Stop::Signal(signo, fault) => SigInfo {
+                    signo: signo as i32,
+                    errno: 0,
+                    code: fault.map_or(SI_USER, |_| SI_KERNEL),
+                    pad: 0,
+                    addr: fault.map_or(0, |fault| fault.tval),
+                    cause: fault.map_or(0, |fault| fault.cause),
+                },
+                // Linux has no siginfo for system call stops either.
+                Stop::SyscallEntry | Stop::SyscallExit => return Err(EINVAL),
+            };
+            write_user(my_space, data, &info)?;
+            Ok(0)
+        }
+        _ => Err(EIO),
+    }
+}
+
+/// What [`wait4`] found
+pub enum WaitResult {
+    /// The PID that stopped or exited, or 0 with WNOHANG if none did
+    Done(usize),
+    /// Nothing yet. The caller is woken up when a tracee stops or exits.
+    WouldBlock,
+    /// It didn't work out, for instance because `pid` isn't traced by the
+    /// caller.
+    Failed(Errno),
+}
+
+/// `wait4(pid, status, options)` for the tracees of the process behind
+/// `frame`. A `pid` of -1 waits for any of them. The status goes to
+/// `status_addr` unless that is 0.
+pub unsafe fn wait4(
+    frame: *const TrapFrame,
+    pid: isize,
+    status_addr: usize,
+    options: usize,
+) -> WaitResult {
+    let me = (*frame).pid as u16;
+    let caller = get_by_pid(me);
+    if caller.is_null() || (*frame).mode != CpuMode::User as usize {
+        return WaitResult::Failed(ECHILD);
+    }
+    let mut tracees = match TRACEES.take() {
+        Some(tracees) => tracees,
+        None => return WaitResult::Failed(ECHILD),
+    };
+    let mut any = false;
+    let mut found = None;
+    for (tracee, t) in tracees.iter_mut() {
+        if t.tracer != me || pid != -1 && pid != *tracee as isize {
+            continue;
+        }
+        any = true;
+        if !t.reported && (t.exited || t.stop.is_some()) {
+            t.reported = true;
+            found = Some((*tracee, t.wait_status(), t.exited));
+            break;
+        }
+    }
+    if let Some((tracee, _, true)) = found {
+        tracees.remove(&tracee);
+    }
+    TRACEES.replace(tracees);
+    match found {
+        Some((tracee, status, _)) => {
+            let space = Space::User((*caller).get_table_address() as *mut Table);
+            if status_addr != 0 && write_user(space, status_addr, &status).is_err() {
+                return WaitResult::Failed(EFAULT);
+            }
+            WaitResult::Done(tracee as usize)
+        }
+        None if !any => WaitResult::Failed(ECHILD),
+        None if options & WNOHANG != 0 => WaitResult::Done(0),
+        None => {
+            WAITERS.get_or_insert_with(BTreeSet::new).insert(me);
+            WaitResult::WouldBlock
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! # Resource limits and usage
+//!
+//! Like on Linux, every process has a soft and a hard limit for each
+//! resource. The soft limit is the one we enforce. A process may move it
+//! anywhere up to the hard limit and may lower the hard limit, but nobody
+//! can raise it again. Limits belong to the process, so all its threads
+//! share them, and they survive `execve`.
+//!
+//! We keep all of Linux' limits, but only enforce these:
+//!
+//! - `RLIMIT_AS`: the pages of a process, which are its program, its
+//!   stacks, what `brk` and `mmap` handed out and its page tables. Going
+//!   over makes `brk`, `mmap`, `clone` and `execve` fail with `ENOMEM`, so
+//!   a runaway program runs out of its own memory long before the machine
+//!   does.
+//! - `RLIMIT_STACK`: stacks have a fixed size of [`STACK_PAGES`], so a
+//!   program or a thread whose stack would be bigger than this isn't
+//!   started.
+//! - `RLIMIT_NOFILE`: file descriptors at or above it can't be opened.
+//! - `RLIMIT_CPU`: the seconds of CPU time a process may use, all its
+//!   threads together. Past the soft limit the thread that is running gets
+//!   `SIGXCPU` every second, at the hard limit `SIGKILL`.
+//!
+//! CPU time is charged whenever we switch to a process, to whoever ran
+//! before, so the time the kernel spends on behalf of a process counts
+//! as well.
+
+use core::convert::TryFrom;
+
+use crate::{
+    cpu::{get_mtime, TrapFrame, FREQ},
+    errno::{Errno, EINVAL, EPERM, ESRCH},
+    mmap,
+    page::{total_pages, PAGE_SIZE},
+    process::{get_by_pid, Process, STACK_PAGES},
+    signal::{send_signal, SIGKILL, SIGXCPU},
+};
+
+pub const RLIMIT_CPU: usize = 0;
+pub const RLIMIT_STACK: usize = 3;
+pub const RLIMIT_NOFILE: usize = 7;
+pub const RLIMIT_AS: usize = 9;
+/// How many limits there are
+pub const RLIM_NLIMITS: usize = 16;
+/// No limit
+pub const RLIM_INFINITY: u64 = !0;
+
+// getrusage() targets
+pub const RUSAGE_SELF: usize = 0;
+pub const RUSAGE_THREAD: usize = 1;
+
+/// What `getrlimit` and `setrlimit` take, `struct rlimit` in C
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Rlimit {
+    pub cur: u64,
+    pub max: u64,
+}
+
+impl Rlimit {
+    const fn infinite() -> Self {
+        Self {
+            cur: RLIM_INFINITY,
+            max: RLIM_INFINITY,
+        }
+    }
+}
+
+/// The limits of one process
+#[derive(Clone, Copy)]
+pub struct Limits([Rlimit; RLIM_NLIMITS]);
+
+impl Default for Limits {
+    /// Nothing is limited, except for what Linux limits too and memory.
+    /// A program may have half of all memory unless it asks for more.
+    fn default() -> Self {
+        let mut limits = [Rlimit::infinite(); RLIM_NLIMITS];
+        limits[RLIMIT_STACK].cur = 8 << 20;
+        limits[RLIMIT_NOFILE] = Rlimit {
+            cur: 1024,
+            max: 4096,
+        };
+        limits[RLIMIT_AS].cur = (total_pages() * PAGE_SIZE / 2) as u64;
+        Self(limits)
+    }
+}
+
+impl Limits {
+    /// The soft limit of `resource`. Anything too big for a usize is as
+    /// good as infinite.
+    pub fn cur(&self, resource: usize) -> usize {
+        usize::try_from(self.0[resource].cur).unwrap_or(usize::MAX)
+    }
+
+    /// How many pages `RLIMIT_AS` allows
+    pub fn pages(&self) -> usize {
+        self.cur(RLIMIT_AS) / PAGE_SIZE
+    }
+
+    /// Can a process with these limits have a stack?
+    pub fn stack_fits(&self) -> bool {
+        STACK_PAGES * PAGE_SIZE <= self.cur(RLIMIT_STACK)
+    }
+
+    fn set(&mut self, resource: usize, new: Rlimit) -> Result<(), Errno> {
+        if new.cur > new.max {
+            return Err(EINVAL);
+        }
+        if new.max > self.0[resource].max {
+            return Err(EPERM);
+        }
+        self.0[resource] = new;
+        Ok(())
+    }
+}
+
+/// The process `pid` refers to for prlimit64(), where 0 is the caller.
+/// Since there are no users, anybody may look at and change anybody's
+/// limits.
+unsafe fn target(frame: *const TrapFrame, pid: usize) -> Result<*mut Process, Errno> {
+    let pid = if pid == 0 { (*frame).pid } else { pid };
+    let p = get_by_pid(u16::try_from(pid).map_err(|_| ESRCH)?);
+    if p.is_null() {
+        Err(ESRCH)
+    } else {
+        Ok(p)
+    }
+}
+
+/// `prlimit64(pid, resource, new, old)`: return the limit of `resource`
+/// and set it to `new`, if there is one. getrlimit() and setrlimit() are
+/// both this for the caller.
+pub unsafe fn prlimit(
+    frame: *const TrapFrame,
+    pid: usize,
+    resource: usize,
+    new: Option<Rlimit>,
+) -> Result<Rlimit, Errno> {
+    if resource >= RLIM_NLIMITS {
+        return Err(EINVAL);
+    }
+    let p = target(frame, pid)?;
+    let mut limits = (*p).limits();
+    let old = limits.0[resource];
+    if let Some(new) = new {
+        limits.set(resource, new)?;
+        (*p).set_limits(limits);
+    }
+    Ok(old)
+}
+
+/// `struct timeval`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Timeval {
+    pub sec: i64,
+    pub usec: i64,
+}
+
+impl Timeval {
+    fn from_ticks(ticks: usize) -> Self {
+        Self {
+            sec: (ticks / FREQ as usize) as i64,
+            usec: (ticks % FREQ as usize * 1_000_000 / FREQ as usize) as i64,
+        }
+    }
+}
+
+/// What `getrusage` fills in, `struct rusage` in C. We don't tell user and
+/// system time apart, and of the rest, we only know the resident set.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Rusage {
+    pub utime: Timeval,
+    pub stime: Timeval,
+    /// The most memory the process ever had, in KiB
+    pub maxrss: isize,
+    /// Everything else Linux counts, which we don't
+    pub unused: [isize; 13],
+}
+
+/// `getrusage(who, usage)` for the process behind `frame`. For
+/// `RUSAGE_SELF`, that is all of its threads.
+pub unsafe fn getrusage(frame: *const TrapFrame, who: usize) -> Result<Rusage, Errno> {
+    let pid = (*frame).pid as u16;
+    let p = get_by_pid(pid);
+    if p.is_null() {
+        return Err(ESRCH);
+    }
+    let ticks = match who {
+        RUSAGE_SELF => (*p).cpu_time(),
+        RUSAGE_THREAD => (*p).data.cpu_time,
+        _ => return Err(EINVAL),
+    };
+    let peak = mmap::usage((*p).root).map_or(0, |usage| usage.peak);
+    Ok(Rusage {
+        utime: Timeval::from_ticks(ticks),
+        maxrss: (peak * PAGE_SIZE / 1024) as isize,
+        ..Rusage::default()
+    })
+}
+
+// Who runs on this hart, and since when
+static mut RUNNING_PID: u16 = 0;
+static mut RUNNING_SINCE: usize = 0;
+
+/// Charge whoever ran until now for their CPU time and hold them to
+/// `RLIMIT_CPU`.
+pub fn charge() {
+    unsafe {
+        let now = get_mtime();
+        let ticks = now - RUNNING_SINCE;
+        RUNNING_SINCE = now;
+        let p = get_by_pid(RUNNING_PID);
+        if RUNNING_PID == 0 || p.is_null() {
+            return;
+        }
+        // RLIMIT_CPU is for the whole process, so the other threads' time
+        // counts too.
+        let (before, after) = (*p).add_cpu_time(ticks);
+        let (before, seconds) = (before / FREQ as usize, after / FREQ as usize);
+        let limits = (*p).limits();
+        let hard = usize::try_from(limits.0[RLIMIT_CPU].max).unwrap_or(usize::MAX);
+        if seconds >= hard {
+            let _ = send_signal(RUNNING_PID, SIGKILL);
+        } else if seconds >= limits.cur(RLIMIT_CPU) && seconds > before {
+            let _ = send_signal(RUNNING_PID, SIGXCPU);
+        }
+    }
+}
+
+/// Start the clock for the process behind `frame`, which is about to run.
+pub fn start(frame: usize) {
+    unsafe {
+        RUNNING_PID = (*(frame as *const TrapFrame)).pid as u16;
+        RUNNING_SINCE = get_mtime();
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{
     cpu::get_mtime,
+    gdb,
     process::{ProcessState, PROCESS_LIST, PROCESS_LIST_MUTEX},
 };
 
@@ -18,6 +19,10 @@ pub fn schedule() -> usize {
             'procfindloop: loop {
                 pl.rotate_left(1);
                 if let Some(prc) = pl.front_mut() {
+                    // Leave whatever the debugger stopped alone.
+                    if gdb::is_held(prc.get_pid()) {
+                        continue 'procfindloop;
+                    }
                     match prc.get_state() {
                         ProcessState::Running => {
                             frame_addr = prc.get_frame_address();
@@ -38,7 +43,7 @@ pub fn schedule() -> usize {
             }
             PROCESS_LIST.replace(pl);
         } else {
-            println!("could not take process list");
+            error!("could not take process list");
         }
         PROCESS_LIST_MUTEX.unlock();
     }
@@ -0,0 +1,278 @@
+//! # Shared memory
+//!
+//! System V shared memory, the way Linux has it: `shmget` makes a segment
+//! of zeroed pages, or finds the one with a given key, and `shmat` maps it
+//! into a process, at the same physical pages for everyone. That is how a
+//! compositor and its clients hand each other pixels without copying them.
+//! Futexes are keyed by physical address, so they work across processes in
+//! a segment as well.
+//!
+//! A segment counts its attachments, which mmap.rs keeps track of, and it
+//! lives until `shmctl(IPC_RMID)` removes it and the last attachment is
+//! gone: `shmdt`, `munmap` of all its pages or the end of the process.
+//! Removing it only takes away its key, so like on Linux it can still be
+//! attached by id until then.
+//!
+//! Everyone is root here, so the permissions are kept, but not checked.
+//! The pages of a segment never go to swap, since that would mean unmapping
+//! them in every process that has them.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use crate::{
+    cpu::{TrapFrame, FREQ},
+    errno::{Errno, SysResult, EEXIST, EINVAL, ENOENT, ENOMEM},
+    mmap::{self, PROT_READ, PROT_WRITE},
+    page::{dealloc, zalloc, PAGE_SIZE},
+    vdso,
+};
+
+/// The key that always makes a new segment
+pub const IPC_PRIVATE: usize = 0;
+
+// shmget() flags, besides the permissions in the low 9 bits
+pub const IPC_CREAT: usize = 0o1000;
+pub const IPC_EXCL: usize = 0o2000;
+
+// shmctl() commands
+pub const IPC_RMID: usize = 0;
+pub const IPC_SET: usize = 1;
+pub const IPC_STAT: usize = 2;
+
+// shmat() flags
+pub const SHM_RDONLY: usize = 0o10000;
+pub const SHM_RND: usize = 0o20000;
+
+/// A shared memory segment
+struct Segment {
+    key: u32,
+    /// The size `shmget` was asked for
+    size: usize,
+    /// Its pages. We free them, not the processes that map them.
+    frames: Vec<*mut u8>,
+    mode: u32,
+    /// Who made it and who attached or detached it last
+    cpid: u16,
+    lpid: u16,
+    /// How many times it is mapped
+    attached: usize,
+    /// `IPC_RMID` took it away, so it goes once nobody has it.
+    removed: bool,
+    /// When it was attached, detached and changed last, in seconds
+    atime: usize,
+    dtime: usize,
+    ctime: usize,
+}
+
+/// The part of `struct ipc64_perm` we fill in. The rest is padding.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct IpcPerm {
+    pub key: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub cuid: u32,
+    pub cgid: u32,
+    pub mode: u32,
+    pub seq: u16,
+    pub pad: u16,
+    pub unused: [u64; 2],
+}
+
+/// What `shmctl(IPC_STAT)` fills in, `struct shmid_ds` in C
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct ShmidDs {
+    pub perm: IpcPerm,
+    pub segsz: u64,
+    pub atime: i64,
+    pub dtime: i64,
+    pub ctime: i64,
+    pub cpid: i32,
+    pub lpid: i32,
+    pub nattch: u64,
+    pub unused: [u64; 2],
+}
+
+// Keyed by id.
+static mut SEGMENTS: Option<BTreeMap<usize, Segment>> = None;
+static mut NEXT_ID: usize = 0;
+
+/// Run `f` on all segments.
+fn with_segments<R>(f: impl FnOnce(&mut BTreeMap<usize, Segment>) -> R) -> R {
+    unsafe {
+        let mut segments = SEGMENTS.take().unwrap_or_default();
+        let result = f(&mut segments);
+        SEGMENTS.replace(segments);
+        result
+    }
+}
+
+/// Seconds since boot, which is what all our clocks say
+fn now() -> usize {
+    vdso::uptime() / FREQ as usize
+}
+
+/// Free the pages of segment `id` if it was removed and nobody has it.
+fn free_if_unused(segments: &mut BTreeMap<usize, Segment>, id: usize) {
+    if segments
+        .get(&id)
+        .map_or(false, |segment| segment.removed && segment.attached == 0)
+    {
+        for frame in segments.remove(&id).unwrap().frames {
+            dealloc(frame);
+        }
+    }
+}
+
+/// `shmget(key, size, flags)`: the id of the segment with `key`, which is
+/// made with `size` bytes if there is none and `IPC_CREAT` says so.
+/// `IPC_PRIVATE` always makes a new one.
+pub unsafe fn shmget(frame: *const TrapFrame, key: usize, size: usize, flags: usize) -> SysResult {
+    // key_t is an int.
+    let key = key as u32;
+    if key as usize != IPC_PRIVATE {
+        let found = with_segments(|segments| {
+            segments
+                .iter()
+                .find(|(_, segment)| segment.key == key && !segment.removed)
+                .map(|(&id, segment)| (id, segment.size))
+        });
+        match found {
+            Some(_) if flags & IPC_CREAT != 0 && flags & IPC_EXCL != 0 => return Err(EEXIST),
+            Some((_, segment_size)) if size > segment_size => return Err(EINVAL),
+            Some((id, _)) => return Ok(id),
+            None if flags & IPC_CREAT == 0 => return Err(ENOENT),
+            None => {}
+        }
+    }
+    if size == 0 {
+        return Err(EINVAL);
+    }
+    let pages = size.checked_add(PAGE_SIZE - 1).ok_or(EINVAL)? / PAGE_SIZE;
+    mmap::make_room(pages);
+    let mut frames = Vec::with_capacity(pages);
+    for _ in 0..pages {
+        let frame = zalloc(1);
+        if frame.is_null() {
+            for frame in frames {
+                dealloc(frame);
+            }
+            return Err(ENOMEM);
+        }
+        frames.push(frame);
+    }
+    let segment = Segment {
+        key,
+        size,
+        frames,
+        mode: (flags & 0o777) as u32,
+        cpid: (*frame).pid as u16,
+        lpid: 0,
+        attached: 0,
+        removed: false,
+        atime: 0,
+        dtime: 0,
+        ctime: now(),
+    };
+    Ok(with_segments(|segments| {
+        let id = NEXT_ID;
+        NEXT_ID += 1;
+        segments.insert(id, segment);
+        id
+    }))
+}
+
+/// `shmat(id, addr, flags)`: map segment `id` at `addr`, or wherever there
+/// is room if `addr` is 0. Returns where it went.
+pub unsafe fn shmat(frame: *const TrapFrame, id: usize, addr: usize, flags: usize) -> SysResult {
+    let addr = if flags & SHM_RND != 0 {
+        addr & !(PAGE_SIZE - 1)
+    } else if addr % PAGE_SIZE != 0 {
+        return Err(EINVAL);
+    } else {
+        addr
+    };
+    let prot = if flags & SHM_RDONLY != 0 {
+        PROT_READ
+    } else {
+        PROT_READ | PROT_WRITE
+    };
+    let frames = with_segments(|segments| segments.get(&id).map(|segment| segment.frames.clone()))
+        .ok_or(EINVAL)?;
+    let vaddr = mmap::attach(frame, addr, id, &frames, prot)?;
+    with_segments(|segments| {
+        let segment = segments.get_mut(&id).unwrap();
+        segment.attached += 1;
+        segment.lpid = (*frame).pid as u16;
+        segment.atime = now();
+    });
+    Ok(vaddr)
+}
+
+/// `shmdt(addr)`: unmap the segment that `shmat` mapped at `addr`.
+pub unsafe fn shmdt(frame: *const TrapFrame, addr: usize) -> SysResult {
+    let id = mmap::detach(frame, addr)?;
+    with_segments(|segments| {
+        if let Some(segment) = segments.get_mut(&id) {
+            segment.lpid = (*frame).pid as u16;
+        }
+    });
+    Ok(0)
+}
+
+/// Segment `id` is mapped once less. This is for mmap.rs, when the last
+/// page of an attachment goes.
+pub fn detach(id: usize) {
+    with_segments(|segments| {
+        if let Some(segment) = segments.get_mut(&id) {
+            segment.attached -= 1;
+            segment.dtime = now();
+        }
+        free_if_unused(segments, id);
+    });
+}
+
+/// `shmctl(id, IPC_STAT, buf)`: what there is to know about segment `id`
+pub fn stat(id: usize) -> Result<ShmidDs, Errno> {
+    with_segments(|segments| {
+        let segment = segments.get(&id).ok_or(EINVAL)?;
+        Ok(ShmidDs {
+            perm: IpcPerm {
+                key: if segment.removed { 0 } else { segment.key },
+                mode: segment.mode,
+                ..IpcPerm::default()
+            },
+            segsz: segment.size as u64,
+            atime: segment.atime as i64,
+            dtime: segment.dtime as i64,
+            ctime: segment.ctime as i64,
+            cpid: i32::from(segment.cpid),
+            lpid: i32::from(segment.lpid),
+            nattch: segment.attached as u64,
+            ..ShmidDs::default()
+        })
+    })
+}
+
+/// `shmctl(id, IPC_SET, buf)`: take the permissions from `ds`.
+pub fn set(id: usize, ds: &ShmidDs) -> Result<(), Errno> {
+    with_segments(|segments| {
+        let segment = segments.get_mut(&id).ok_or(EINVAL)?;
+        segment.mode = ds.perm.mode & 0o777;
+        segment.ctime = now();
+        Ok(())
+    })
+}
+
+/// `shmctl(id, IPC_RMID, 0)`: take away the key of segment `id`, and the
+/// segment itself once nobody has it mapped.
+pub fn remove(id: usize) -> Result<(), Errno> {
+    with_segments(|segments| {
+        let segment = segments.get_mut(&id).ok_or(EINVAL)?;
+        segment.removed = true;
+        segment.ctime = now();
+        free_if_unused(segments, id);
+        Ok(())
+    })
+}
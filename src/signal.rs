@@ -0,0 +1,517 @@
+//! # Signals
+//!
+//! POSIX-like signals. Every process carries a pending and a blocked mask
+//! together with a table of actions (see [`SignalState`]). Signals are only
+//! ever acted upon right before a hart is handed back to a process in
+//! [`crate::rust_switch_to_user`]: either the default action is taken there,
+//! or a [`SignalFrame`] is pushed onto the user stack and the process
+//! resumes in its handler. The handler returns through a small trampoline
+//! page (see `asm/trap.S`) that performs the `sigreturn` system call.
+//!
+//! Signal numbers and the `sigaction` layout follow the generic Linux ABI,
+//! so that `userspace/startlib/signal.h` can mirror them one to one.
+
+use core::mem::size_of;
+
+use crate::{
+    cpu::{CpuMode, Registers, TrapFrame},
+    crash,
+    errno::{Errno, EINVAL, EPERM, ESRCH},
+    page::{map, EntryBits, Table},
+    process::{delete_process, get_by_pid, ProcessState},
+    ptrace,
+    sched::schedule,
+    uaccess::{caller, read_user, write_user, Space},
+};
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGXCPU: usize = 24;
+
+/// Number of signals we keep track of. Signal 0 is not a signal, it is
+/// only used by `kill` to probe whether a process exists.
+pub const NSIG: usize = 32;
+
+/// `sa_handler` value asking for the default action
+pub const SIG_DFL: usize = 0;
+/// `sa_handler` value asking for the signal to be ignored
+pub const SIG_IGN: usize = 1;
+
+/// Don't add the signal to the blocked mask while its handler runs
+pub const SA_NODEFER: usize = 0x4000_0000;
+/// Restore the default action once the handler has been invoked
+pub const SA_RESETHAND: usize = 0x8000_0000;
+
+/// `how` argument of `sigprocmask`
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+/// Virtual address of the sigreturn trampoline in every user process. It
+/// sits right below the user stack, so it is out of the way of the
+/// program image that the ELF loader maps at 0x2000_0000.
+pub const SIGRETURN_TRAMPOLINE_ADDR: usize = 0x0fff_f000;
+
+extern "C" {
+    /// Page-aligned code page with `li a7, SigReturn; ecall`, see `asm/trap.S`
+    fn sigreturn_trampoline();
+}
+
+/// Signals that can neither be caught, blocked nor ignored.
+const UNMASKABLE: u64 = 1 << SIGKILL | 1 << SIGSTOP;
+
+/// What happens to a process when a signal with [`SIG_DFL`] is delivered
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    CoreDump,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+impl DefaultAction {
+    pub const fn of(signo: usize) -> Self {
+        match signo {
+            SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE | SIGSEGV | SIGXCPU => {
+                Self::CoreDump
+            }
+            SIGCHLD => Self::Ignore,
+            SIGCONT => Self::Continue,
+            SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => Self::Stop,
+            _ => Self::Terminate,
+        }
+    }
+}
+
+/// Mirror of the generic Linux `struct sigaction` as it is passed to
+/// `rt_sigaction`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SigAction {
+    pub handler: usize,
+    pub flags: usize,
+    pub mask: u64,
+}
+
+impl SigAction {
+    pub const fn new() -> Self {
+        Self {
+            handler: SIG_DFL,
+            flags: 0,
+            mask: 0,
+        }
+    }
+}
+
+impl Default for SigAction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Per-process signal bookkeeping. Bit `n` of `pending` and `blocked`
+/// corresponds to signal number `n`.
+#[derive(Clone, Copy)]
+pub struct SignalState {
+    pub pending: u64,
+    pub blocked: u64,
+    pub actions: [SigAction; NSIG],
+}
+
+impl SignalState {
+    pub const fn new() -> Self {
+        Self {
+            pending: 0,
+            blocked: 0,
+            actions: [SigAction::new(); NSIG],
+        }
+    }
+
+    /// Pending signals that are allowed through the blocked mask.
+    pub const fn deliverable(&self) -> u64 {
+        self.pending & !(self.blocked & !UNMASKABLE)
+    }
+
+    /// Would delivering `signo` right now take the process down?
+    pub const fn is_fatal(&self, signo: usize) -> bool {
+        if signo == SIGKILL {
+            return true;
+        }
+        if self.blocked & 1 << signo != 0 {
+            return false;
+        }
+        self.actions[signo].handler == SIG_DFL
+            && matches!(
+                DefaultAction::of(signo),
+                DefaultAction::Terminate | DefaultAction::CoreDump
+            )
+    }
+}
+
+impl Default for SignalState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// This is what we push onto the user stack before jumping into a handler.
+/// `sigreturn` pops it again, so the layout is private to the kernel.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SignalFrame {
+    pub regs: [usize; 32],
+    pub fregs: [usize; 32],
+    pub pc: usize,
+    pub blocked: u64,
+    pub signo: usize,
+}
+
+/// Errors `kill` can run into.
+pub enum SignalError {
+    InvalidSignal,
+    NoSuchProcess,
+    Permission,
+}
+
+impl From<SignalError> for Errno {
+    fn from(err: SignalError) -> Self {
+        match err {
+            SignalError::InvalidSignal => EINVAL,
+            SignalError::NoSuchProcess => ESRCH,
+            SignalError::Permission => EPERM,
+        }
+    }
+}
+
+/// Map the sigreturn trampoline into a user page table.
+pub fn map_trampoline(table: &mut Table) {
+    map(
+        table,
+        SIGRETURN_TRAMPOLINE_ADDR,
+        sigreturn_trampoline as usize,
+        EntryBits::UserReadExecute.val(),
+        0,
+    );
+}
+
+/// Queue `signo` for `pid`. This never runs a handler by itself, delivery
+/// happens the next time the process is switched to. Terminating signals
+/// wake the process up so that it can actually die.
+pub fn send_signal(pid: u16, signo: usize) -> Result<(), SignalError> {
+    if signo >= NSIG {
+        return Err(SignalError::InvalidSignal);
+    }
+    unsafe {
+        let p = get_by_pid(pid);
+        if p.is_null() {
+            return Err(SignalError::NoSuchProcess);
+        }
+        // Kernel processes hold locks and half-finished I/O. We don't let
+        // anybody pull the rug from under them.
+        if (*(*p).get_frame()).mode != CpuMode::User as usize {
+            return Err(SignalError::Permission);
+        }
+        // Signal 0 only checks that the process exists.
+        if signo == 0 {
+            return Ok(());
+        }
+        let sigs = &mut (*p).data.signals;
+        match DefaultAction::of(signo) {
+            DefaultAction::Continue => {
+                sigs.pending &= !(1 << SIGSTOP | 1 << SIGTSTP | 1 << SIGTTIN | 1 << SIGTTOU);
+                // Only the tracer can let a traced process go.
+                if let ProcessState::Stopped = (*p).get_state() {
+                    if !ptrace::is_stopped(pid) {
+                        (*p).set_state(ProcessState::Running);
+                    }
+                }
+            }
+            DefaultAction::Stop => {
+                sigs.pending &= !(1 << SIGCONT);
+            }
+            _ => {}
+        }
+        // An ignored signal is discarded right away, unless it is one we
+        // aren't allowed to ignore.
+        if sigs.actions[signo].handler == SIG_IGN && 1 << signo & UNMASKABLE == 0 {
+            return Ok(());
+        }
+        sigs.pending |= 1 << signo;
+        let fatal = sigs.is_fatal(signo);
+        let unblocked = sigs.blocked & 1 << signo == 0 || 1 << signo & UNMASKABLE != 0;
+        match (*p).get_state() {
+            // A sleeping process is interrupted by any signal it will see.
+            ProcessState::Sleeping if unblocked => (*p).set_state(ProcessState::Running),
+            // Blocked I/O and stopped processes only come back to die. A
+            // traced process would only stop for its tracer again, so that
+            // takes SIGKILL.
+            ProcessState::Waiting if fatal => (*p).set_state(ProcessState::Running),
+            ProcessState::Stopped if fatal && (signo == SIGKILL || !ptrace::is_stopped(pid)) => {
+                (*p).set_state(ProcessState::Running);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Raise a synchronous signal caused by the process itself (a fault). If
+/// the process can't take it, because it is blocked or ignored, the
+/// signal is turned into the default action, just like Linux does.
+pub fn force_signal(pid: u16, signo: usize) {
+    unsafe {
+        let p = get_by_pid(pid);
+        if p.is_null() {
+            return;
+        }
+        let sigs = &mut (*p).data.signals;
+        if sigs.actions[signo].handler == SIG_IGN || sigs.blocked & 1 << signo != 0 {
+            sigs.actions[signo].handler = SIG_DFL;
+            sigs.blocked &= !(1 << signo);
+        }
+        sigs.pending |= 1 << signo;
+    }
+}
+
+/// Does the process behind `frame` have a signal to act on?
+pub fn has_deliverable(frame: *const TrapFrame) -> bool {
+    unsafe {
+        let p = get_by_pid((*frame).pid as u16);
+        !p.is_null() && (*p).data.signals.deliverable() != 0
+    }
+}
+
+/// Act on pending signals of the process that owns `frame_addr`. This is
+/// called right before we switch to a process and returns the frame we
+/// should really switch to, since the process may not survive delivery.
+pub fn deliver_pending(frame_addr: usize) -> usize {
+    let mut frame_addr = frame_addr;
+    // Each time a process is killed or stopped, we have to ask the
+    // scheduler for someone else, who may have signals pending as well.
+    loop {
+        if frame_addr == 0 {
+            return frame_addr;
+        }
+        let frame = frame_addr as *mut TrapFrame;
+        match unsafe { deliver_one(frame) } {
+            Delivery::Resume => return frame_addr,
+            Delivery::Reschedule => frame_addr = schedule(),
+        }
+    }
+}
+
+enum Delivery {
+    Resume,
+    Reschedule,
+}
+
+unsafe fn deliver_one(frame: *mut TrapFrame) -> Delivery {
+    let pid = (*frame).pid as u16;
+    let p = get_by_pid(pid);
+    if p.is_null() {
+        return Delivery::Resume;
+    }
+    // A traced process tells its tracer how its system call went before
+    // anything else happens.
+    if ptrace::syscall_exit_stop(p) {
+        return Delivery::Reschedule;
+    }
+    loop {
+        let signo = match ptrace::take_injected(pid) {
+            // The tracer already saw this one and passed it on.
+            Some(signo) => signo,
+            None => {
+                let sigs = &mut (*p).data.signals;
+                let deliverable = sigs.deliverable();
+                if deliverable == 0 {
+                    return Delivery::Resume;
+                }
+                let signo = deliverable.trailing_zeros() as usize;
+                sigs.pending &= !(1 << signo);
+                // The tracer decides what becomes of the signal. Unless it
+                // passes it back, it is gone.
+                if ptrace::signal_stop(p, signo) {
+                    return Delivery::Reschedule;
+                }
+                signo
+            }
+        };
+        let sigs = &mut (*p).data.signals;
+        let action = sigs.actions[signo];
+        if signo == SIGKILL || signo == SIGSTOP || action.handler == SIG_DFL {
+            match DefaultAction::of(signo) {
+                DefaultAction::Terminate => {
+                    info!("Process {} terminated by signal {}", pid, signo);
+                    ptrace::exiting(pid, signo as u32);
+                    delete_process(pid);
+                    return Delivery::Reschedule;
+                }
+                DefaultAction::CoreDump => {
+                    // Tell a tracer that it dumped core, like wait4 does.
+                    ptrace::exiting(pid, signo as u32 | 0x80);
+                    // This may start a kernel process, p is gone afterwards.
+                    crash::dump(p, frame, signo);
+                    delete_process(pid);
+                    return Delivery::Reschedule;
+                }
+                DefaultAction::Stop => {
+                    (*p).set_state(ProcessState::Stopped);
+                    return Delivery::Reschedule;
+                }
+                DefaultAction::Ignore | DefaultAction::Continue => continue,
+            }
+        }
+        if action.handler == SIG_IGN {
+            continue;
+        }
+        if !push_signal_frame(p, frame, signo, &action) {
+            // We couldn't set up the handler's stack. There's nothing
+            // sane left to do with this process.
+            warn!("Process {} has a bad stack, killing it", pid);
+            delete_process(pid);
+            return Delivery::Reschedule;
+        }
+        let sigs = &mut (*p).data.signals;
+        if action.flags & SA_NODEFER == 0 {
+            sigs.blocked |= 1 << signo;
+        }
+        sigs.blocked |= action.mask & !UNMASKABLE;
+        if action.flags & SA_RESETHAND != 0 {
+            sigs.actions[signo] = SigAction::new();
+        }
+        // One handler at a time. Whatever is still pending will be looked
+        // at when the handler returns through sigreturn.
+        return Delivery::Resume;
+    }
+}
+
+/// Save the interrupted context on the user stack and redirect the frame
+/// into the handler: `handler(signo)` returning into the trampoline.
+unsafe fn push_signal_frame(
+    p: *mut crate::process::Process,
+    frame: *mut TrapFrame,
+    signo: usize,
+    action: &SigAction,
+) -> bool {
+    if (*frame).mode != CpuMode::User as usize {
+        return false;
+    }
+    let space = Space::User((*p).get_table_address() as *mut Table);
+    let sf = SignalFrame {
+        regs: (*frame).regs,
+        fregs: (*frame).fregs,
+        pc: (*frame).pc,
+        blocked: (*p).data.signals.blocked,
+        signo,
+    };
+    // Keep the stack 16-byte aligned as the calling convention wants.
+    let sp = ((*frame).regs[Registers::Sp as usize] - size_of::<SignalFrame>()) & !15;
+    if write_user(space, sp, &sf).is_err() {
+        return false;
+    }
+    (*frame).regs[Registers::Sp as usize] = sp;
+    (*frame).regs[Registers::A0 as usize] = signo;
+    (*frame).regs[Registers::Ra as usize] = SIGRETURN_TRAMPOLINE_ADDR;
+    (*frame).pc = action.handler;
+    true
+}
+
+/// Undo [`push_signal_frame`]. Returns the program counter to resume at,
+/// or `None` if the frame on the stack couldn't be read.
+pub unsafe fn sigreturn(frame: *mut TrapFrame) -> Option<usize> {
+    let p = get_by_pid((*frame).pid as u16);
+    if p.is_null() {
+        return None;
+    }
+    let space = caller(frame).ok()?;
+    // The handler returned with its stack where we left it.
+    let sf: SignalFrame = read_user(space, (*frame).regs[Registers::Sp as usize]).ok()?;
+    (*frame).regs = sf.regs;
+    (*frame).fregs = sf.fregs;
+    (*frame).pc = sf.pc;
+    (*p).data.signals.blocked = sf.blocked & !UNMASKABLE;
+    Some(sf.pc)
+}
+
+/// `rt_sigaction(signo, act, oldact)`. `act` and `oldact` are user
+/// pointers and either one may be null.
+pub unsafe fn sigaction(
+    frame: *mut TrapFrame,
+    signo: usize,
+    act: usize,
+    oldact: usize,
+) -> Result<(), Errno> {
+    if signo == 0 || signo >= NSIG {
+        return Err(EINVAL);
+    }
+    let p = get_by_pid((*frame).pid as u16);
+    if p.is_null() {
+        return Err(ESRCH);
+    }
+    let space = caller(frame)?;
+    let sigs = &mut (*p).data.signals;
+    if oldact != 0 {
+        write_user(space, oldact, &sigs.actions[signo])?;
+    }
+    if act != 0 {
+        if 1 << signo & UNMASKABLE != 0 {
+            return Err(EINVAL);
+        }
+        let new = read_user::<SigAction>(space, act)?;
+        sigs.actions[signo] = new;
+        // POSIX: setting a pending signal to SIG_IGN discards it.
+        if new.handler == SIG_IGN {
+            sigs.pending &= !(1 << signo);
+        }
+    }
+    Ok(())
+}
+
+/// `rt_sigprocmask(how, set, oldset)`. `set` and `oldset` are user
+/// pointers to a 64-bit mask and either one may be null.
+pub unsafe fn sigprocmask(
+    frame: *mut TrapFrame,
+    how: usize,
+    set: usize,
+    oldset: usize,
+) -> Result<(), Errno> {
+    let p = get_by_pid((*frame).pid as u16);
+    if p.is_null() {
+        return Err(ESRCH);
+    }
+    let space = caller(frame)?;
+    let sigs = &mut (*p).data.signals;
+    if oldset != 0 {
+        write_user(space, oldset, &sigs.blocked)?;
+    }
+    if set != 0 {
+        let mask = read_user::<u64>(space, set)? & !UNMASKABLE;
+        match how {
+            SIG_BLOCK => sigs.blocked |= mask,
+            SIG_UNBLOCK => sigs.blocked &= !mask,
+            SIG_SETMASK => sigs.blocked = mask,
+            _ => return Err(EINVAL),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,348 @@
+//! # System call tracing
+//!
+//! With tracing switched on for a process, every system call it makes
+//! goes to the kernel log: its name, its arguments decoded as far as we
+//! know them, what it returned and how long it took.
+//!
+//! ```text
+//! [    3.141592] INFO  strace: [4] write(1, "hello\n", 6) = 6 <12us>
+//! ```
+//!
+//! A process switches tracing for itself with
+//! `prctl(PR_SET_SYSCALL_TRACE, 1)`. Threads and programs started with
+//! execv inherit it. Booting with `strace` on the kernel command line (see
+//! [`crate::fdt`]) traces every program from the start.
+//!
+//! A call that blocks is logged once the process runs again, so its
+//! duration includes the wait. If it was only waiting to be restarted,
+//! that shows up as `= ? (restarted)` followed by the call again.
+//!
+//! Nothing is traced most of the time, and then the only cost is a check
+//! whether the set of traced processes exists at all.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+};
+use core::fmt::Write;
+
+use crate::{
+    cpu::{get_mtime, Registers, TrapFrame, FREQ},
+    fdt,
+    process::{get_by_pid, ProcessState},
+    signal::NSIG,
+    syscall::Syscall,
+    uaccess,
+};
+
+// prctl options. Linux doesn't have these, so they are far away from its
+// own.
+pub const PR_GET_SYSCALL_TRACE: usize = 0x5354_0000;
+pub const PR_SET_SYSCALL_TRACE: usize = 0x5354_0001;
+
+/// The most bytes of a string or buffer argument we show
+const MAX_SHOWN: usize = 32;
+
+/// How to show an argument
+#[derive(Clone, Copy)]
+enum Arg {
+    /// A signed number
+    Int,
+    /// An address, flags, or anything else that reads best in hex
+    Hex,
+    /// A NUL-terminated string in the caller's memory
+    Str,
+    /// A buffer in the caller's memory whose length is the argument with
+    /// this index
+    Buf(usize),
+    /// A signal number
+    Signal,
+}
+
+use Arg::{Buf, Hex, Int, Signal, Str};
+
+/// The arguments of each system call
+const fn signature(syscall: Syscall) -> &'static [Arg] {
+    match syscall {
+        Syscall::GetChar
+        | Syscall::DumpRegisters
+        | Syscall::SigReturn
+        | Syscall::GetPid
+        | Syscall::SchedYield
+        | Syscall::GetPpid
+        | Syscall::GetUid
+        | Syscall::GetEuid
+        | Syscall::GetGid
+        | Syscall::GetEgid
+        | Syscall::GetTid
+        | Syscall::GetTime => &[],
+        Syscall::PutChar
+        | Syscall::Sleep
+        | Syscall::Dup
+        | Syscall::Close
+        | Syscall::Exit
+        | Syscall::ExitGroup => &[Int],
+        Syscall::SetTidAddress | Syscall::Uname | Syscall::Brk => &[Hex],
+        Syscall::Execve => &[Str, Hex, Hex],
+        Syscall::Dup3 => &[Int, Int, Hex],
+        Syscall::Ioctl => &[Int, Hex, Hex],
+        Syscall::Openat => &[Int, Str, Hex, Hex],
+        Syscall::Pipe => &[Hex, Hex],
+        Syscall::Read => &[Int, Hex, Int],
+        Syscall::Write => &[Int, Buf(2), Int],
+        Syscall::Readv | Syscall::Writev => &[Int, Hex, Int],
+        Syscall::Getcwd => &[Hex, Int],
+        Syscall::Fcntl => &[Int, Int, Hex],
+        Syscall::Lseek => &[Int, Int, Int],
+        Syscall::Nanosleep => &[Hex, Hex],
+        Syscall::ClockGettime => &[Int, Hex],
+        Syscall::Tkill => &[Int, Signal],
+        Syscall::Munmap => &[Hex, Int],
+        Syscall::Mmap => &[Hex, Int, Hex, Hex, Int, Int],
+        Syscall::Mprotect => &[Hex, Int, Hex],
+        Syscall::Madvise => &[Hex, Int, Int],
+        Syscall::Shmget => &[Hex, Int, Hex],
+        Syscall::Shmctl => &[Int, Int, Hex],
+        Syscall::Shmat => &[Int, Hex, Hex],
+        Syscall::Shmdt => &[Hex],
+        Syscall::Futex => &[Hex, Int, Int],
+        Syscall::Syslog => &[Int, Hex, Int],
+        Syscall::Ptrace => &[Int, Int, Hex, Hex],
+        Syscall::Kill => &[Int, Signal],
+        Syscall::SigAction => &[Signal, Hex, Hex],
+        Syscall::SigProcMask => &[Int, Hex, Hex],
+        Syscall::Prctl => &[Hex, Int],
+        Syscall::Getrlimit | Syscall::Setrlimit | Syscall::Getrusage => &[Int, Hex],
+        Syscall::Prlimit64 => &[Int, Int, Hex, Hex],
+        Syscall::BlockRead | Syscall::BlockWrite => &[Int, Hex, Int, Int],
+        Syscall::Clone => &[Hex, Hex, Hex, Hex, Hex],
+        Syscall::Wait4 => &[Int, Hex, Hex, Hex],
+        Syscall::GetFramebuffer | Syscall::Swapon => &[Int],
+        Syscall::SwapInfo => &[Hex],
+        Syscall::TransferRectangleAndInvalidate => &[Int, Int, Int, Int, Int],
+        Syscall::WaitForKeyboardEvents | Syscall::WaitForAbsEvents => &[Hex, Int],
+    }
+}
+
+/// A traced system call on its way through the kernel
+pub struct Call {
+    pid: u16,
+    /// Where the ecall is
+    pc: usize,
+    start: usize,
+    /// The call with its arguments, as it goes into the log
+    text: String,
+}
+
+// Traced processes. This stays None while there are none, which is all
+// that the fast path looks at.
+static mut TRACED: Option<BTreeSet<u16>> = None;
+// Calls that blocked, by PID. They are logged when the process runs again.
+static mut BLOCKED: Option<BTreeMap<u16, Call>> = None;
+
+/// Should every program be traced? That is what `strace` on the kernel
+/// command line asks for.
+pub fn trace_all() -> bool {
+    fdt::bootarg("strace").is_some()
+}
+
+pub fn is_enabled(pid: u16) -> bool {
+    unsafe {
+        TRACED
+            .as_ref()
+            .map_or(false, |traced| traced.contains(&pid))
+    }
+}
+
+/// Switch tracing for `pid` on or off.
+pub fn set_enabled(pid: u16, on: bool) {
+    unsafe {
+        let mut traced = TRACED.take().unwrap_or_default();
+        if on {
+            traced.insert(pid);
+        } else {
+            traced.remove(&pid);
+        }
+        if !traced.is_empty() {
+            TRACED.replace(traced);
+        }
+    }
+}
+
+/// `pid` is gone.
+pub fn forget(pid: u16) {
+    unsafe {
+        if TRACED.is_none() && BLOCKED.is_none() {
+            return;
+        }
+        if let Some(mut blocked) = BLOCKED.take() {
+            blocked.remove(&pid);
+            if !blocked.is_empty() {
+                BLOCKED.replace(blocked);
+            }
+        }
+    }
+    set_enabled(pid, false);
+}
+
+/// Show at most [`MAX_SHOWN`] bytes of the caller's memory at `addr`, up to
+/// `len` bytes or the first NUL if `len` is None.
+unsafe fn write_memory(out: &mut String, frame: &TrapFrame, addr: usize, len: Option<usize>) {
+    let space = match uaccess::caller(frame) {
+        Ok(space) => space,
+        Err(_) => return,
+    };
+    let limit = len.unwrap_or(usize::MAX).min(MAX_SHOWN);
+    out.push('"');
+    let mut shown = 0;
+    while shown < limit {
+        let c = match uaccess::read_user::<u8>(space, addr.wrapping_add(shown)) {
+            Ok(c) => c,
+            Err(_) => break,
+        };
+        if len.is_none() && c == 0 {
+            break;
+        }
+        let _ = match c {
+            b'\n' => write!(out, "\\n"),
+            b'\t' => write!(out, "\\t"),
+            b'"' | b'\\' => write!(out, "\\{}", c as char),
+            0x20..=0x7e => write!(out, "{}", c as char),
+            _ => write!(out, "\\x{:02x}", c),
+        };
+        shown += 1;
+    }
+    out.push('"');
+    let more = match len {
+        Some(len) => shown < len,
+        None => shown == limit,
+    };
+    if more {
+        out.push_str("...");
+    }
+}
+
+unsafe fn describe(frame: &TrapFrame) -> String {
+    let nr = frame.regs[Registers::A7 as usize];
+    let arg = |i: usize| frame.regs[Registers::A0 as usize + i];
+    let mut out = String::new();
+    let syscall = match Syscall::try_from(nr) {
+        Ok(syscall) => syscall,
+        Err(_) => {
+            let _ = write!(
+                out,
+                "syscall_{}(0x{:x}, 0x{:x}, 0x{:x}, 0x{:x}, 0x{:x}, 0x{:x})",
+                nr,
+                arg(0),
+                arg(1),
+                arg(2),
+                arg(3),
+                arg(4),
+                arg(5)
+            );
+            return out;
+        }
+    };
+    out.push_str(syscall.name());
+    out.push('(');
+    for (i, kind) in signature(syscall).iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        let value = arg(i);
+        let _ = match kind {
+            Int => write!(out, "{}", value as isize),
+            Hex => write!(out, "0x{:x}", value),
+            Str if value == 0 => write!(out, "NULL"),
+            Str => {
+                write_memory(&mut out, frame, value, None);
+                Ok(())
+            }
+            Buf(len) => {
+                write_memory(&mut out, frame, value, Some(arg(*len)));
+                Ok(())
+            }
+            Signal if value > 0 && value < NSIG => write!(out, "{}", value),
+            Signal => write!(out, "{} (invalid)", value as isize),
+        };
+    }
+    out.push(')');
+    out
+}
+
+/// The process behind `frame` is making a system call. Returns None unless
+/// it is traced.
+#[inline]
+pub fn enter(frame: *const TrapFrame) -> Option<Call> {
+    unsafe {
+        TRACED.as_ref()?;
+        let frame = &*frame;
+        let pid = frame.pid as u16;
+        if !is_enabled(pid) {
+            return None;
+        }
+        Some(Call {
+            pid,
+            pc: frame.pc,
+            start: get_mtime(),
+            text: describe(frame),
+        })
+    }
+}
+
+fn log_call(call: &Call, result: &str) {
+    let ticks = get_mtime().wrapping_sub(call.start) as u64;
+    let micros = ticks / (FREQ / 1_000_000);
+    info!("[{}] {} = {} <{}us>", call.pid, call.text, result, micros);
+}
+
+fn log_return(call: &Call, frame: &TrapFrame) {
+    let ret = frame.regs[Registers::A0 as usize] as isize;
+    let mut result = String::new();
+    let _ = write!(result, "{}", ret);
+    log_call(call, &result);
+}
+
+/// `call` went through do_syscall, which returned `return_pc`. If it is
+/// done, log it now. Otherwise it is logged when the process runs again.
+pub fn exit(call: Call, frame: *const TrapFrame, return_pc: usize) {
+    unsafe {
+        let p = get_by_pid(call.pid);
+        if p.is_null() {
+            // exit, execv, or a signal
+            log_call(&call, "?");
+            return;
+        }
+        if return_pc != 0 || matches!((*p).get_state(), ProcessState::Running) {
+            log_return(&call, &*frame);
+            return;
+        }
+        BLOCKED
+            .get_or_insert_with(BTreeMap::new)
+            .insert(call.pid, call);
+    }
+}
+
+/// We are about to switch to `frame`. If its process was blocked in a
+/// traced system call, log how that went.
+#[inline]
+pub fn resume(frame: usize) {
+    unsafe {
+        if BLOCKED.is_none() || frame == 0 {
+            return;
+        }
+        let frame = &*(frame as *const TrapFrame);
+        let mut blocked = BLOCKED.take().unwrap_or_default();
+        let call = blocked.remove(&(frame.pid as u16));
+        if !blocked.is_empty() {
+            BLOCKED.replace(blocked);
+        }
+        if let Some(call) = call {
+            if frame.pc == call.pc {
+                log_call(&call, "? (restarted)");
+            } else {
+                log_return(&call, frame);
+            }
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! # Swap
+//!
+//! When memory runs low, anonymous pages (what `brk` and `mmap` handed
+//! out) go to a swap area on a block device and come back when the process
+//! touches them again. Which pages go is up to mmap.rs, which sweeps them
+//! like a clock: a page whose Access bit is set gets it cleared and another
+//! round, one whose bit is still clear is paged out. A page that came back
+//! keeps its copy in the swap area, so as long as its Dirty bit stays clear
+//! it can go again without being written.
+//!
+//! The swap area is a whole disk in Linux' format, so `mkswap` on the host
+//! makes one. It is turned on with the `swapon` system call or with
+//! `swap=<device>` on the kernel command line, and stays on.
+//!
+//! Paging happens in the trap handler, which can't sleep, so the hart
+//! waits for the disk (see `block_op_sync`). That is slow, but only for
+//! the process that needs the memory.
+
+use alloc::{vec, vec::Vec};
+use core::{mem::size_of, slice};
+
+use crate::{
+    errno::{Errno, EBUSY, EINVAL, ENODEV, ENOMEM, EROFS},
+    fdt,
+    page::{dealloc, free_pages, zalloc, PAGE_SIZE},
+    virtio::block::{block_op_sync, capacity, read_only},
+};
+
+/// What the first page of a swap area ends with
+const SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+/// Where Linux' `struct swap_header` starts in the first page, after room
+/// for a boot block
+const SWAP_HEADER_OFFSET: usize = 1024;
+/// The only version of the header there is
+const SWAP_VERSION: u32 = 1;
+
+/// The part of Linux' swap header we need
+#[repr(C)]
+struct SwapHeader {
+    version: u32,
+    /// The last page of the area. Page 0 is the header itself.
+    last_page: u32,
+    nr_badpages: u32,
+    uuid: [u8; 16],
+    label: [u8; 16],
+    padding: [u32; 117],
+    /// `nr_badpages` pages that must not be used
+    badpages: [u32; 0],
+}
+
+/// How many pages we try to keep free for the kernel and for page tables.
+/// Below that, pages go to swap.
+pub const RESERVE_PAGES: usize = 256;
+/// How many pages we page out at least, once we have to
+const BATCH_PAGES: usize = 32;
+
+/// The swap area
+struct Swap {
+    dev: usize,
+    /// A bit for every page of the area, set while a slot is taken or
+    /// can't be used at all
+    used: Vec<u64>,
+    slots: usize,
+    /// How many slots can be used at all
+    usable: usize,
+    /// How many of those hold a page
+    taken: usize,
+    /// Where we start looking for a free slot
+    next: usize,
+}
+
+static mut SWAP: Option<Swap> = None;
+static mut PAGE_INS: usize = 0;
+static mut PAGE_OUTS: usize = 0;
+
+/// What `swapinfo` fills in. `startlib/swap.h` mirrors it.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SwapInfo {
+    /// The block device, or 0 if there is no swap
+    pub dev: u64,
+    /// Pages of the swap area
+    pub pages: u64,
+    /// Pages of it in use
+    pub used: u64,
+    /// Pages read back in since boot
+    pub page_ins: u64,
+    /// Pages written out since boot
+    pub page_outs: u64,
+}
+
+/// Turn on swap if the kernel command line asks for it with `swap=<dev>`.
+pub fn init() {
+    if let Some(arg) = fdt::bootarg("swap") {
+        match arg.parse().map_err(|_| EINVAL).and_then(swapon) {
+            Ok(()) => {}
+            Err(errno) => warn!("Can't swap to block device {}, error {}", arg, errno.0),
+        }
+    }
+}
+
+/// `swapon(dev)`: swap to block device `dev`, which must hold a swap area
+/// that `mkswap` made.
+pub fn swapon(dev: usize) -> Result<(), Errno> {
+    if is_on() {
+        return Err(EBUSY);
+    }
+    let bytes = capacity(dev).ok_or(ENODEV)?;
+    if read_only(dev) == Some(true) {
+        return Err(EROFS);
+    }
+    let page = zalloc(1);
+    if page.is_null() {
+        return Err(ENOMEM);
+    }
+    let result = block_op_sync(dev, page, PAGE_SIZE as u32, 0, false).and_then(|_| unsafe {
+        let magic = slice::from_raw_parts(page.add(PAGE_SIZE - SWAP_MAGIC.len()), SWAP_MAGIC.len());
+        let header = &*(page.add(SWAP_HEADER_OFFSET) as *const SwapHeader);
+        if magic != SWAP_MAGIC || header.version != SWAP_VERSION {
+            return Err(EINVAL);
+        }
+        let slots = (header.last_page as usize + 1).min(bytes as usize / PAGE_SIZE);
+        let mut swap = Swap {
+            dev,
+            used: vec![0; (slots + 63) / 64],
+            slots,
+            usable: slots,
+            taken: 0,
+            next: 1,
+        };
+        // The header isn't ours to overwrite, and neither are bad pages.
+        swap.mark(0);
+        let max_bad =
+            (PAGE_SIZE - SWAP_HEADER_OFFSET - size_of::<SwapHeader>() - SWAP_MAGIC.len()) / 4;
+        let badpages = header.badpages.as_ptr();
+        for i in 0..(header.nr_badpages as usize).min(max_bad) {
+            let bad = *badpages.add(i) as usize;
+            if bad < slots && !swap.is_taken(bad) {
+                swap.mark(bad);
+            }
+        }
+        swap.usable = slots
+            - swap
+                .used
+                .iter()
+                .map(|word| word.count_ones() as usize)
+                .sum::<usize>();
+        Ok(swap)
+    });
+    dealloc(page);
+    let swap = result?;
+    info!("Swapping to block device {}, {} pages", dev, swap.usable);
+    unsafe { SWAP = Some(swap) };
+    Ok(())
+}
+
+/// Is there a swap area?
+pub fn is_on() -> bool {
+    unsafe { SWAP.is_some() }
+}
+
+/// How many pages have to go to swap before `pages` more can be handed
+/// out, leaving [`RESERVE_PAGES`] for the kernel
+pub fn shortfall(pages: usize) -> usize {
+    if !is_on() {
+        return 0;
+    }
+    let wanted = pages + RESERVE_PAGES;
+    let free = free_pages();
+    if free >= wanted {
+        0
+    } else {
+        (wanted - free).max(BATCH_PAGES)
+    }
+}
+
+impl Swap {
+    fn mark(&mut self, slot: usize) {
+        self.used[slot / 64] |= 1 << (slot % 64);
+    }
+
+    fn is_taken(&self, slot: usize) -> bool {
+        self.used[slot / 64] & (1 << (slot % 64)) != 0
+    }
+}
+
+/// Take a free slot of the swap area, if there is one.
+pub fn alloc() -> Option<usize> {
+    unsafe {
+        let swap = SWAP.as_mut()?;
+        let slot = (0..swap.slots)
+            .map(|i| (swap.next + i) % swap.slots)
+            .find(|&slot| !swap.is_taken(slot))?;
+        swap.mark(slot);
+        swap.taken += 1;
+        swap.next = slot + 1;
+        Some(slot)
+    }
+}
+
+/// Give `slot` back.
+pub fn free(slot: usize) {
+    unsafe {
+        if let Some(swap) = SWAP.as_mut() {
+            swap.used[slot / 64] &= !(1 << (slot % 64));
+            swap.taken -= 1;
+        }
+    }
+}
+
+/// Copy the page at `frame` out to `slot`.
+pub fn write(slot: usize, frame: *mut u8) -> Result<(), Errno> {
+    let dev = unsafe { SWAP.as_ref().ok_or(ENODEV)?.dev };
+    block_op_sync(
+        dev,
+        frame,
+        PAGE_SIZE as u32,
+        (slot * PAGE_SIZE) as u64,
+        true,
+    )?;
+    unsafe { PAGE_OUTS += 1 };
+    Ok(())
+}
+
+/// Copy `slot` back into the page at `frame`.
+pub fn read(slot: usize, frame: *mut u8) -> Result<(), Errno> {
+    let dev = unsafe { SWAP.as_ref().ok_or(ENODEV)?.dev };
+    block_op_sync(
+        dev,
+        frame,
+        PAGE_SIZE as u32,
+        (slot * PAGE_SIZE) as u64,
+        false,
+    )?;
+    unsafe { PAGE_INS += 1 };
+    Ok(())
+}
+
+/// `swapinfo(info)`: how much swap there is and how much it has been used
+pub fn info() -> SwapInfo {
+    unsafe {
+        let mut info = SwapInfo {
+            page_ins: PAGE_INS as u64,
+            page_outs: PAGE_OUTS as u64,
+            ..SwapInfo::default()
+        };
+        if let Some(swap) = SWAP.as_ref() {
+            info.dev = swap.dev as u64;
+            info.pages = swap.usable as u64;
+            info.used = swap.taken as u64;
+        }
+        info
+    }
+}
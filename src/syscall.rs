@@ -1,67 +1,65 @@
 //! # Syscall
 //!
-//! We should implement some system calls for compatability with libgloss and
-//! respectivly with newlib. So there's list of libgloss syscalls
+//! The system call ABI is that of riscv64 Linux: the number goes in A7,
+//! up to six arguments in A0 to A5, and the result comes back in A0. A
+//! call that fails returns the negated error number from [`crate::errno`]
+//! instead, so -4095 to -1 are errors.
 //!
-//! ```
-//! // These system call numbers come from libgloss so that we can use newlib
-//! // for our system calls.
-//! // Libgloss wants the system call number in A7 and arguments in A0..A6
-//! #define SYS_getcwd 17
-//! #define SYS_dup 23
-//! #define SYS_fcntl 25
-//! #define SYS_faccessat 48
-//! #define SYS_chdir 49
-//! #define SYS_openat 56
-//! #define SYS_close 57
-//! #define SYS_getdents 61
-//! #define SYS_lseek 62
-//! #define SYS_read 63
-//! #define SYS_write 64
-//! #define SYS_writev 66
-//! #define SYS_pread 67
-//! #define SYS_pwrite 68
-//! #define SYS_fstatat 79
-//! #define SYS_fstat 80
-//! #define SYS_exit 93
-//! #define SYS_exit_group 94
-//! #define SYS_kill 129
-//! #define SYS_rt_sigaction 134
-//! #define SYS_times 153
-//! #define SYS_uname 160
-//! #define SYS_gettimeofday 169
-//! #define SYS_getpid 172
-//! #define SYS_getuid 174
-//! #define SYS_geteuid 175
-//! #define SYS_getgid 176
-//! #define SYS_getegid 177
-//! #define SYS_brk 214
-//! #define SYS_munmap 215
-//! #define SYS_mremap 216
-//! #define SYS_mmap 222
-//! #define SYS_open 1024
-//! #define SYS_link 1025
-//! #define SYS_unlink 1026
-//! #define SYS_mkdir 1030
-//! #define SYS_access 1033
-//! #define SYS_stat 1038
-//! #define SYS_lstat 1039
-//! #define SYS_time 1062
-//! #define SYS_getmainvars 2011
+//! Standard calls have Linux' numbers and behave like Linux' do, as far as
+//! they go. Calls that only make sense here (the GPU, input events, raw
+//! disk access, ...) are numbered from [`OS_SYSCALL_BASE`] up.
+//!
+//! That makes statically linked Linux programs our programs too. Together
+//! with the stack that the ELF loader sets up (arguments, environment and
+//! auxiliary vector), the standard calls cover what musl needs to start up,
+//! `malloc` and print: `set_tid_address`, `brk`, `mmap`, `writev`,
+//! `ioctl(TIOCGWINSZ)` and `exit_group`, plus what a shell like busybox
+//! `sh` asks about its surroundings (`uname`, `getcwd`, `fcntl`, the IDs,
+//! `clock_gettime`, ...). We are everyone and we are root: all IDs are 0.
+//!
+//! [`Syscall`] is the one place that lists them. Userspace gets its
+//! `startlib/syscall.h` and `startlib/errno.h` generated from this file and
+//! `errno.rs` by `tools/gen_syscall_h.py`:
+//!
+//! ```text
+//! python3 tools/gen_syscall_h.py
 //! ```
 
-use alloc::{boxed::Box, string::String};
-use core::convert::TryFrom;
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    vec,
+    vec::Vec,
+};
+use core::{convert::TryFrom, mem::size_of};
 
 use crate::{
     buffer::Buffer,
-    cpu::{dump_registers, Registers, TrapFrame},
-    elf, fs,
-    page::{map, virt_to_phys, EntryBits, Table, PAGE_SIZE},
+    console::{self, ConsoleResult, Termios},
+    cpu::{dump_registers, get_mtime, CpuMode, Registers, TrapFrame, FREQ},
+    crash, elf,
+    errno::{
+        to_return, Errno, SysResult, E2BIG, EAGAIN, EBADF, EFAULT, EINVAL, ENAMETOOLONG, ENODEV,
+        ENOENT, ENOSYS, ENOTTY, EPIPE, ERANGE, EROFS, ESPIPE, ESRCH,
+    },
+    fs,
+    futex::{self, FutexError},
+    log::{self, Level},
+    mmap,
+    page::{map_range, EntryBits, Table, PAGE_SIZE},
+    pipe::{self, PipeResult},
     process::{
-        add_kernel_process_args, delete_process, get_by_pid, set_sleeping, set_waiting,
-        PROCESS_LIST, PROCESS_LIST_MUTEX,
+        add_kernel_process_args, delete_process, get_by_pid, other_threads, set_running,
+        set_sleeping, set_waiting, FileDescriptor, CONSOLE_DEVICE, FOREGROUND_PID, PROCESS_LIST,
+        PROCESS_LIST_MUTEX,
     },
+    ptrace::{self, WaitResult},
+    resource::{self, Limits, Rlimit, RLIMIT_NOFILE},
+    shm::{self, ShmidDs, IPC_RMID, IPC_SET, IPC_STAT},
+    signal, strace, swap,
+    uaccess::{self, Access, Fault},
+    vdso,
     virtio::{
         block::block_op,
         gpu,
@@ -70,23 +68,149 @@ use crate::{
 };
 
 /// Contain all supported system calls
+///
+/// Standard calls have the numbers and the semantics of riscv64 Linux.
+/// Calls of our own start at [`OS_SYSCALL_BASE`], far above anything Linux
+/// uses. The first line of each doc comment is the call as userspace sees
+/// it: `tools/gen_syscall_h.py` turns it into `userspace/startlib/syscall.h`.
+#[derive(Clone, Copy)]
 #[repr(usize)]
 pub enum Syscall {
-    PutChar = 2,
-    DumpRegisters = 8,
-    Sleep = 10,
-    Execv = 11,
+    /// `getcwd(buf, size)`
+    Getcwd = 17,
+    /// `dup(fd)`
+    Dup = 23,
+    /// `dup3(oldfd, newfd, flags)`
+    Dup3 = 24,
+    /// `fcntl(fd, cmd, arg)`
+    Fcntl = 25,
+    /// `ioctl(fd, request, arg)`
+    Ioctl = 29,
+    /// `openat(dirfd, path, flags, mode)`
+    Openat = 56,
+    /// `close(fd)`
+    Close = 57,
+    /// `pipe2(fds, flags)`
+    Pipe = 59,
+    /// `lseek(fd, offset, whence)`
+    Lseek = 62,
+    /// `read(fd, buf, count)`
     Read = 63,
+    /// `write(fd, buf, count)`
+    Write = 64,
+    /// `readv(fd, iov, iovcnt)`
+    Readv = 65,
+    /// `writev(fd, iov, iovcnt)`
+    Writev = 66,
+    /// `exit(code)`
     Exit = 93,
+    /// `exit_group(code)`
+    ExitGroup = 94,
+    /// `set_tid_address(tidptr)`
+    SetTidAddress = 96,
+    /// `futex(uaddr, op, val)`
+    Futex = 98,
+    /// `nanosleep(req, rem)`
+    Nanosleep = 101,
+    /// `clock_gettime(clock, tp)`
+    ClockGettime = 113,
+    /// `syslog(action, buf, len)`
+    Syslog = 116,
+    /// `ptrace(request, pid, addr, data)`
+    Ptrace = 117,
+    /// `sched_yield()`
+    SchedYield = 124,
+    /// `kill(pid, sig)`
+    Kill = 129,
+    /// `tkill(tid, sig)`
+    Tkill = 130,
+    /// `rt_sigaction(sig, act, oldact)`
+    SigAction = 134,
+    /// `rt_sigprocmask(how, set, oldset)`
+    SigProcMask = 135,
+    /// `rt_sigreturn()`
+    SigReturn = 139,
+    /// `uname(buf)`
+    Uname = 160,
+    /// `getrlimit(resource, rlim)`
+    Getrlimit = 163,
+    /// `setrlimit(resource, rlim)`
+    Setrlimit = 164,
+    /// `getrusage(who, usage)`
+    Getrusage = 165,
+    /// `prctl(option, arg)`
+    Prctl = 167,
+    /// `getpid()`
     GetPid = 172,
-    BlockRead = 180,
+    /// `getppid()`
+    GetPpid = 173,
+    /// `getuid()`
+    GetUid = 174,
+    /// `geteuid()`
+    GetEuid = 175,
+    /// `getgid()`
+    GetGid = 176,
+    /// `getegid()`
+    GetEgid = 177,
+    /// `gettid()`
+    GetTid = 178,
+    /// `shmget(key, size, flags)`
+    Shmget = 194,
+    /// `shmctl(id, cmd, buf)`
+    Shmctl = 195,
+    /// `shmat(id, addr, flags)`
+    Shmat = 196,
+    /// `shmdt(addr)`
+    Shmdt = 197,
+    /// `brk(addr)`
+    Brk = 214,
+    /// `munmap(addr, length)`
+    Munmap = 215,
+    /// `clone(flags, stack, ptid, tls, ctid)`
+    Clone = 220,
+    /// `execve(path, argv, envp)`
+    Execve = 221,
+    /// `mmap(addr, length, prot, flags, fd, offset)`
+    Mmap = 222,
+    /// `mprotect(addr, length, prot)`
+    Mprotect = 226,
+    /// `madvise(addr, length, advice)`
+    Madvise = 233,
+    /// `wait4(pid, status, options, rusage)`
+    Wait4 = 260,
+    /// `prlimit64(pid, resource, new, old)`
+    Prlimit64 = 261,
+    /// `get_fb(dev)`: map the framebuffer of a GPU and return its address.
     GetFramebuffer = 1000,
+    /// `inv_rect(dev, x, y, width, height)`: show part of the framebuffer.
     TransferRectangleAndInvalidate = 1001,
+    /// `get_key(events, max)`: take keyboard events.
     WaitForKeyboardEvents = 1002,
+    /// `get_abs(events, max)`: take tablet events.
     WaitForAbsEvents = 1004,
-    GetTime = 1062,
+    /// `get_char()`: read a byte from the console, -1 at the end of input.
+    GetChar = 1005,
+    /// `put_char(c)`: write a byte to the console.
+    PutChar = 1006,
+    /// `dump_registers()`: print the caller's registers.
+    DumpRegisters = 1007,
+    /// `sleep(ticks)`: sleep for this many timer ticks.
+    Sleep = 1008,
+    /// `block_read(dev, buf, size, offset)`: read sectors of a disk.
+    BlockRead = 1009,
+    /// `block_write(dev, buf, size, offset)`: write sectors of a disk.
+    BlockWrite = 1010,
+    /// `get_time()`: the timer count.
+    GetTime = 1011,
+    /// `swapon(dev)`: swap to a block device that holds a swap area.
+    Swapon = 1012,
+    /// `swapinfo(info)`: how much swap there is and how much is used.
+    SwapInfo = 1013,
 }
 
+/// Where the system calls of our own start. Everything below is Linux'.
+pub const OS_SYSCALL_BASE: usize = 1000;
+
 /// Convert [`usize`] to [`Syscall`]
 ///
 /// If value equal to [`Syscall`] descriminant
@@ -97,24 +221,149 @@ impl TryFrom<usize> for Syscall {
 
     fn try_from(syscall: usize) -> Result<Self, Self::Error> {
         match syscall {
-            2 => Ok(Self::PutChar),
-            8 => Ok(Self::DumpRegisters),
-            10 => Ok(Self::Sleep),
-            11 => Ok(Self::Execv),
+            17 => Ok(Self::Getcwd),
+            23 => Ok(Self::Dup),
+            24 => Ok(Self::Dup3),
+            25 => Ok(Self::Fcntl),
+            29 => Ok(Self::Ioctl),
+            56 => Ok(Self::Openat),
+            57 => Ok(Self::Close),
+            59 => Ok(Self::Pipe),
+            62 => Ok(Self::Lseek),
             63 => Ok(Self::Read),
+            64 => Ok(Self::Write),
+            65 => Ok(Self::Readv),
+            66 => Ok(Self::Writev),
             93 => Ok(Self::Exit),
+            94 => Ok(Self::ExitGroup),
+            96 => Ok(Self::SetTidAddress),
+            98 => Ok(Self::Futex),
+            101 => Ok(Self::Nanosleep),
+            113 => Ok(Self::ClockGettime),
+            116 => Ok(Self::Syslog),
+            117 => Ok(Self::Ptrace),
+            124 => Ok(Self::SchedYield),
+            129 => Ok(Self::Kill),
+            130 => Ok(Self::Tkill),
+            134 => Ok(Self::SigAction),
+            135 => Ok(Self::SigProcMask),
+            139 => Ok(Self::SigReturn),
+            160 => Ok(Self::Uname),
+            163 => Ok(Self::Getrlimit),
+            164 => Ok(Self::Setrlimit),
+            165 => Ok(Self::Getrusage),
+            167 => Ok(Self::Prctl),
             172 => Ok(Self::GetPid),
-            180 => Ok(Self::BlockRead),
+            173 => Ok(Self::GetPpid),
+            174 => Ok(Self::GetUid),
+            175 => Ok(Self::GetEuid),
+            176 => Ok(Self::GetGid),
+            177 => Ok(Self::GetEgid),
+            178 => Ok(Self::GetTid),
+            194 => Ok(Self::Shmget),
+            195 => Ok(Self::Shmctl),
+            196 => Ok(Self::Shmat),
+            197 => Ok(Self::Shmdt),
+            214 => Ok(Self::Brk),
+            215 => Ok(Self::Munmap),
+            220 => Ok(Self::Clone),
+            221 => Ok(Self::Execve),
+            222 => Ok(Self::Mmap),
+            226 => Ok(Self::Mprotect),
+            233 => Ok(Self::Madvise),
+            260 => Ok(Self::Wait4),
+            261 => Ok(Self::Prlimit64),
             1000 => Ok(Self::GetFramebuffer),
             1001 => Ok(Self::TransferRectangleAndInvalidate),
             1002 => Ok(Self::WaitForKeyboardEvents),
             1004 => Ok(Self::WaitForAbsEvents),
-            1062 => Ok(Self::GetTime),
+            1005 => Ok(Self::GetChar),
+            1006 => Ok(Self::PutChar),
+            1007 => Ok(Self::DumpRegisters),
+            1008 => Ok(Self::Sleep),
+            1009 => Ok(Self::BlockRead),
+            1010 => Ok(Self::BlockWrite),
+            1011 => Ok(Self::GetTime),
+            1012 => Ok(Self::Swapon),
+            1013 => Ok(Self::SwapInfo),
             unexpected_syscal => Err(unexpected_syscal),
         }
     }
 }
 
+impl Syscall {
+    /// The name of the system call, as userspace knows it
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Getcwd => "getcwd",
+            Self::Dup => "dup",
+            Self::Dup3 => "dup3",
+            Self::Fcntl => "fcntl",
+            Self::Ioctl => "ioctl",
+            Self::Openat => "openat",
+            Self::Close => "close",
+            Self::Pipe => "pipe2",
+            Self::Lseek => "lseek",
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Readv => "readv",
+            Self::Writev => "writev",
+            Self::Exit => "exit",
+            Self::ExitGroup => "exit_group",
+            Self::SetTidAddress => "set_tid_address",
+            Self::Futex => "futex",
+            Self::Nanosleep => "nanosleep",
+            Self::ClockGettime => "clock_gettime",
+            Self::Syslog => "syslog",
+            Self::Ptrace => "ptrace",
+            Self::SchedYield => "sched_yield",
+            Self::Kill => "kill",
+            Self::Tkill => "tkill",
+            Self::SigAction => "rt_sigaction",
+            Self::SigProcMask => "rt_sigprocmask",
+            Self::SigReturn => "rt_sigreturn",
+            Self::Uname => "uname",
+            Self::Getrlimit => "getrlimit",
+            Self::Setrlimit => "setrlimit",
+            Self::Getrusage => "getrusage",
+            Self::Prctl => "prctl",
+            Self::GetPid => "getpid",
+            Self::GetPpid => "getppid",
+            Self::GetUid => "getuid",
+            Self::GetEuid => "geteuid",
+            Self::GetGid => "getgid",
+            Self::GetEgid => "getegid",
+            Self::GetTid => "gettid",
+            Self::Shmget => "shmget",
+            Self::Shmctl => "shmctl",
+            Self::Shmat => "shmat",
+            Self::Shmdt => "shmdt",
+            Self::Brk => "brk",
+            Self::Munmap => "munmap",
+            Self::Clone => "clone",
+            Self::Execve => "execve",
+            Self::Mmap => "mmap",
+            Self::Mprotect => "mprotect",
+            Self::Madvise => "madvise",
+            Self::Wait4 => "wait4",
+            Self::Prlimit64 => "prlimit64",
+            Self::GetFramebuffer => "get_fb",
+            Self::TransferRectangleAndInvalidate => "inv_rect",
+            Self::WaitForKeyboardEvents => "get_key",
+            Self::WaitForAbsEvents => "get_abs",
+            Self::GetChar => "get_char",
+            Self::PutChar => "put_char",
+            Self::DumpRegisters => "dump_registers",
+            Self::Sleep => "sleep",
+            Self::BlockRead => "block_read",
+            Self::BlockWrite => "block_write",
+            Self::GetTime => "get_time",
+            Self::Swapon => "swapon",
+            Self::SwapInfo => "swapinfo",
+        }
+    }
+}
+
 /// Return [`Syscall`] variant descriminant
 impl From<Syscall> for usize {
     fn from(syscall: Syscall) -> Self {
@@ -140,15 +389,42 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> usize {
     // A7 is X17, so it's register number 17.
     Syscall::try_from((*frame).regs[Registers::A7 as usize]).map_or_else(
         |unexpected_syscall| {
-            println!("Unknown syscall number {}", unexpected_syscall);
-            0
+            warn!(
+                "Unknown syscall number {} from pid {} at 0x{:x}",
+                unexpected_syscall,
+                (*frame).pid,
+                mepc
+            );
+            set_return(frame, Err(ENOSYS));
+            mepc + 4
         },
         |syscall| {
             match syscall {
-                Syscall::Exit => {
+                Syscall::Exit | Syscall::ExitGroup => {
+                    // A0 = exit code
+                    let code = (*frame).regs[Registers::A0 as usize] as u32 & 0xff;
+                    if let Syscall::ExitGroup = syscall {
+                        // The other threads go down with us.
+                        for tid in other_threads((*frame).pid as u16) {
+                            ptrace::exiting(tid, code << 8);
+                            delete_process(tid);
+                        }
+                    }
+                    ptrace::exiting((*frame).pid as u16, code << 8);
                     delete_process((*frame).pid as u16);
                     0
                 }
+                Syscall::GetChar => {
+                    // Read one byte from the console, or -1 at end of file.
+                    let mut c = [0_u8];
+                    (*frame).regs[Registers::A0 as usize] =
+                        match console::read((*frame).pid as u16, &mut c) {
+                            ConsoleResult::Done(1) => c[0] as usize,
+                            ConsoleResult::Done(_) => usize::MAX,
+                            ConsoleResult::WouldBlock => return block_and_restart(frame, mepc),
+                        };
+                    mepc + 4
+                }
                 Syscall::PutChar => {
                     print!("{}", (*frame).regs[Registers::A0 as usize] as u8 as char);
                     0
@@ -161,34 +437,59 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> usize {
                     set_sleeping((*frame).pid as u16, (*frame).regs[Registers::A0 as usize]);
                     0
                 }
-                Syscall::Execv => {
-                    // A0 = path
-                    // A1 = argv
-                    let mut path_addr = (*frame).regs[Registers::A0 as usize];
-                    // If the MMU is turned on, translate.
-                    if (*frame).satp >> 60 != 0 {
-                        let p = get_by_pid((*frame).pid as u16);
-                        let table = ((*p).get_table_address() as *mut Table).as_ref().unwrap();
-                        path_addr = virt_to_phys(table, path_addr).unwrap();
-                    }
-                    // Our path address here is now a physical address. If it came in virtual,
-                    // it is now physical.
-                    let path_bytes = path_addr as *const u8;
-                    let mut path = String::new();
-                    let mut iterator: usize = 0;
-                    // I really have to figure out how to change an array of bytes
-                    // to a string. For now, this is very C-style and mimics strcpy.
-                    loop {
-                        let ch = *path_bytes.add(iterator);
-                        if ch == 0 {
-                            break;
+                Syscall::Execve => {
+                    // A0 = path, A1 = argv, A2 = envp
+                    let path_addr = (*frame).regs[Registers::A0 as usize];
+                    let mut used = 0;
+                    let args = uaccess::caller(frame)
+                        .map_err(Errno::from)
+                        .and_then(|space| uaccess::string_from_user(space, path_addr, PATH_MAX))
+                        .and_then(|path| {
+                            let argv = strings_from_caller(
+                                frame,
+                                (*frame).regs[Registers::A1 as usize],
+                                &mut used,
+                            )?;
+                            let envp = strings_from_caller(
+                                frame,
+                                (*frame).regs[Registers::A2 as usize],
+                                &mut used,
+                            )?;
+                            Ok((path, argv, envp))
+                        });
+                    let (path, mut argv, envp) = match args {
+                        Ok(args) => args,
+                        Err(errno) => {
+                            set_return(frame, Err(errno));
+                            return mepc + 4;
                         }
-                        iterator += 1;
-                        path.push(ch as char);
+                    };
+                    // Plenty of programs look at argv[0] without checking
+                    // argc, so like Linux we never start one without it.
+                    if argv.is_empty() {
+                        argv.push(path.bytes().collect());
                     }
                     // See if we can find the path.
-                    if let Ok(inode) = fs::MinixFileSystem::open(8, &path) {
-                        let inode_heap = Box::new(inode);
+                    if let Ok(inode) = fs::MinixFileSystem::open(fs::ROOT_DEVICE, &path) {
+                        // Open file descriptors survive exec. That's how a shell hands
+                        // pipes to the programs it starts.
+                        let p = get_by_pid((*frame).pid as u16);
+                        let (fdesc, core_dump, limits) = if p.is_null() {
+                            (BTreeMap::new(), false, Limits::default())
+                        } else {
+                            ((*p).data.take_fds(), (*p).data.core_dump, (*p).limits())
+                        };
+                        let inode_heap = Box::new(ExecArgs {
+                            inode,
+                            fdesc,
+                            path,
+                            argv,
+                            envp,
+                            core_dump,
+                            limits,
+                            trace: ptrace::take_for_exec((*frame).pid as u16),
+                            strace: strace::is_enabled((*frame).pid as u16),
+                        });
                         // The Box above moves the Inode to a new memory location on the heap.
                         // This needs to be on the heap since we are about to hand over control
                         // to a kernel process.
@@ -203,66 +504,546 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> usize {
                         0
                     } else {
                         // If we get here, the path couldn't be found, or for some reason
-                        // open failed. So, we return -1 and move on.
-                        println!("Could not open path '{}'.", path);
-                        (*frame).regs[Registers::A0 as usize] = usize::MAX;
+                        // open failed.
+                        info!("Could not open path '{}'.", path);
+                        set_return(frame, Err(ENOENT));
                         mepc + 4
                     }
                 }
+                Syscall::Openat => {
+                    // A0 = directory fd, A1 = path, A2 = flags, A3 = mode
+                    set_return(frame, fd_open(frame));
+                    mepc + 4
+                }
                 Syscall::Read => {
-                    // This is an asynchronous call. This will get the
-                    // process going. We won't hear the answer until
-                    // we an interrupt back.
-                    // TODO: The buffer is a virtual memory address that
-                    // needs to be translated to a physical memory location.
-                    // This needs to be put into a process and ran.
-                    // The buffer (regs[12]) needs to be translated when ran
-                    // from a user process using virt_to_phys. If this turns
-                    // out to be a page fault, we need to NOT proceed with
-                    // the read!
-                    // If the MMU is turned on, we have to translate the
-                    // address. Eventually, I will put this code into a
-                    // convenient function, but for now, it will show how
-                    // translation will be done.
-                    let physical_buffer = if (*frame).satp != 0 {
-                        let p = get_by_pid((*frame).pid as u16);
-                        let table = ((*p).get_table_address() as *mut Table).as_ref().unwrap();
-                        let paddr = virt_to_phys(table, (*frame).regs[12]);
-                        if paddr.is_none() {
-                            (*frame).regs[Registers::A0 as usize] = usize::MAX;
-                            return 0;
+                    // A0 = fd, A1 = buffer, A2 = count
+                    fd_read(frame, mepc)
+                }
+                Syscall::Write => {
+                    // A0 = fd, A1 = buffer, A2 = count
+                    fd_write(frame, mepc)
+                }
+                Syscall::Ioctl => {
+                    // A0 = fd, A1 = request, A2 = argument
+                    fd_ioctl(frame, mepc)
+                }
+                Syscall::Close => {
+                    // A0 = fd
+                    let p = get_by_pid((*frame).pid as u16);
+                    let closed = (*p)
+                        .data
+                        .close_fd((*frame).regs[Registers::A0 as usize] as u16)
+                        .is_some();
+                    set_return(frame, if closed { Ok(0) } else { Err(EBADF) });
+                    mepc + 4
+                }
+                Syscall::Pipe => {
+                    // A0 = int fds[2], A1 = flags (ignored)
+                    let p = get_by_pid((*frame).pid as u16);
+                    let id = pipe::create();
+                    let read = match (*p).add_fd(FileDescriptor::PipeRead(id)) {
+                        Ok(fd) => fd,
+                        Err(errno) => {
+                            // Nobody gets the write end either.
+                            drop(FileDescriptor::PipeWrite(id));
+                            set_return(frame, Err(errno));
+                            return mepc + 4;
                         }
-                        paddr.unwrap()
+                    };
+                    let write = match (*p).add_fd(FileDescriptor::PipeWrite(id)) {
+                        Ok(fd) => fd,
+                        Err(errno) => {
+                            (*p).data.close_fd(read);
+                            set_return(frame, Err(errno));
+                            return mepc + 4;
+                        }
+                    };
+                    let fds = [i32::from(read), i32::from(write)];
+                    let fds_addr = (*frame).regs[Registers::A0 as usize];
+                    if write_to_caller(frame, fds_addr, &fds).is_ok() {
+                        set_return(frame, Ok(0));
                     } else {
-                        (*frame).regs[Registers::A2 as usize]
+                        // Nobody will ever see these, so close them again.
+                        (*p).data.close_fd(fds[0] as u16);
+                        (*p).data.close_fd(fds[1] as u16);
+                        set_return(frame, Err(EFAULT));
+                    }
+                    mepc + 4
+                }
+                Syscall::Dup => {
+                    // A0 = fd
+                    let p = get_by_pid((*frame).pid as u16);
+                    let old = (*frame).regs[Registers::A0 as usize] as u16;
+                    let result = (*p).data.get_fd(old).ok_or(EBADF).and_then(|desc| {
+                        let desc = desc.duplicate();
+                        (*p).add_fd(desc).map(usize::from)
+                    });
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Dup3 => {
+                    // A0 = old fd, A1 = new fd, A2 = flags (ignored)
+                    // Like dup2, except that old == new is an error.
+                    let p = get_by_pid((*frame).pid as u16);
+                    let old = (*frame).regs[Registers::A0 as usize] as u16;
+                    let new = (*frame).regs[Registers::A1 as usize] as u16;
+                    let result = match (*p).data.get_fd(old) {
+                        Some(_) if old == new => Err(EINVAL),
+                        Some(_) if usize::from(new) >= (*p).limits().cur(RLIMIT_NOFILE) => {
+                            Err(EBADF)
+                        }
+                        Some(desc) => {
+                            let desc = desc.duplicate();
+                            (*p).data.set_fd(new, desc);
+                            Ok(new as usize)
+                        }
+                        None => Err(EBADF),
                     };
-                    // TODO: Not only do we need to check the buffer, but it
-                    // is possible that the buffer spans multiple pages. We
-                    // need to check all pages that this might span. We
-                    // can't just do paddr and paddr + size, since there
-                    // could be a missing page somewhere in between.
-                    fs::process_read(
-                        (*frame).pid as u16,
-                        (*frame).regs[Registers::A0 as usize] as usize,
-                        (*frame).regs[Registers::A1 as usize] as u32,
-                        physical_buffer as *mut u8,
-                        (*frame).regs[Registers::A3 as usize] as u32,
-                        (*frame).regs[Registers::A4 as usize] as u32,
-                    );
-                    // If we return 0, the trap handler will schedule
-                    // another process.
-                    0
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Prctl => {
+                    // A0 = option, A1 = argument
+                    let p = get_by_pid((*frame).pid as u16);
+                    let option = (*frame).regs[Registers::A0 as usize];
+                    let arg = (*frame).regs[Registers::A1 as usize];
+                    let result = match option {
+                        _ if p.is_null() => Err(ESRCH),
+                        crash::PR_GET_DUMPABLE => Ok(usize::from((*p).data.core_dump)),
+                        crash::PR_SET_DUMPABLE if arg <= 1 => {
+                            (*p).data.core_dump = arg == 1;
+                            Ok(0)
+                        }
+                        strace::PR_GET_SYSCALL_TRACE => {
+                            Ok(usize::from(strace::is_enabled((*frame).pid as u16)))
+                        }
+                        strace::PR_SET_SYSCALL_TRACE if arg <= 1 => {
+                            strace::set_enabled((*frame).pid as u16, arg == 1);
+                            Ok(0)
+                        }
+                        _ => Err(EINVAL),
+                    };
+                    set_return(frame, result);
+                    mepc + 4
                 }
                 Syscall::GetPid => {
                     // A0 = pid
                     (*frame).regs[Registers::A0 as usize] = (*frame).pid;
                     0
                 }
+                Syscall::Kill => {
+                    // A0 = pid, A1 = signal number
+                    let pid = (*frame).regs[Registers::A0 as usize];
+                    let signo = (*frame).regs[Registers::A1 as usize];
+                    // We don't have process groups, so only positive PIDs
+                    // make sense.
+                    let result = match u16::try_from(pid) {
+                        Ok(pid) if pid > 0 => signal::send_signal(pid, signo)
+                            .map(|_| 0)
+                            .map_err(Errno::from),
+                        _ => Err(ESRCH),
+                    };
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::SigAction => {
+                    // A0 = signal number, A1 = new action, A2 = old action
+                    let result = signal::sigaction(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize],
+                        (*frame).regs[Registers::A1 as usize],
+                        (*frame).regs[Registers::A2 as usize],
+                    );
+                    set_return(frame, result.map(|_| 0));
+                    mepc + 4
+                }
+                Syscall::SigProcMask => {
+                    // A0 = how, A1 = new set, A2 = old set
+                    let result = signal::sigprocmask(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize],
+                        (*frame).regs[Registers::A1 as usize],
+                        (*frame).regs[Registers::A2 as usize],
+                    );
+                    set_return(frame, result.map(|_| 0));
+                    mepc + 4
+                }
+                Syscall::SigReturn => {
+                    // This restores every register including A0, so there is
+                    // nothing to return. We resume wherever the signal
+                    // interrupted the process.
+                    signal::sigreturn(frame).unwrap_or_else(|| {
+                        signal::force_signal((*frame).pid as u16, signal::SIGSEGV);
+                        mepc + 4
+                    })
+                }
+                Syscall::Syslog => {
+                    // A0 = action, A1 = buffer, A2 = length
+                    set_return(frame, syslog(frame));
+                    mepc + 4
+                }
+                Syscall::Futex => {
+                    // A0 = address, A1 = operation, A2 = value
+                    match futex::futex(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize],
+                        (*frame).regs[Registers::A1 as usize],
+                        (*frame).regs[Registers::A2 as usize],
+                    ) {
+                        // We're asleep now, so let somebody else run.
+                        Ok(None) => 0,
+                        Ok(Some(woken)) => {
+                            set_return(frame, Ok(woken));
+                            mepc + 4
+                        }
+                        Err(err) => {
+                            let errno = match err {
+                                FutexError::Fault => EFAULT,
+                                FutexError::WouldBlock => EAGAIN,
+                                FutexError::InvalidOp => ENOSYS,
+                            };
+                            set_return(frame, Err(errno));
+                            mepc + 4
+                        }
+                    }
+                }
+                Syscall::Clone => {
+                    // A0 = flags, A1 = stack, A2 = parent tid, A3 = tls,
+                    // A4 = child tid
+                    let p = get_by_pid((*frame).pid as u16);
+                    let tid = (*p).clone_thread(
+                        mepc,
+                        (*frame).regs[Registers::A0 as usize],
+                        (*frame).regs[Registers::A1 as usize],
+                        (*frame).regs[Registers::A2 as usize],
+                        (*frame).regs[Registers::A3 as usize],
+                        (*frame).regs[Registers::A4 as usize],
+                    );
+                    // The process list may have moved, so p is gone now.
+                    if let Ok(tid) = tid {
+                        if strace::is_enabled((*frame).pid as u16) {
+                            strace::set_enabled(tid, true);
+                        }
+                    }
+                    set_return(frame, tid.map(usize::from));
+                    mepc + 4
+                }
+                Syscall::Ptrace => {
+                    // A0 = request, A1 = pid, A2 = address, A3 = data
+                    let result = ptrace::ptrace(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize],
+                        (*frame).regs[Registers::A1 as usize],
+                        (*frame).regs[Registers::A2 as usize],
+                        (*frame).regs[Registers::A3 as usize],
+                    );
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Wait4 => {
+                    // A0 = pid, A1 = status, A2 = options, A3 = rusage (ignored)
+                    let result = match ptrace::wait4(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize] as isize,
+                        (*frame).regs[Registers::A1 as usize],
+                        (*frame).regs[Registers::A2 as usize],
+                    ) {
+                        WaitResult::Done(pid) => Ok(pid),
+                        WaitResult::WouldBlock => return block_and_restart(frame, mepc),
+                        WaitResult::Failed(errno) => Err(errno),
+                    };
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Readv | Syscall::Writev => {
+                    // A0 = fd, A1 = iovecs, A2 = how many
+                    fd_vectored(frame, mepc, matches!(syscall, Syscall::Writev))
+                }
+                Syscall::Fcntl => {
+                    // A0 = fd, A1 = command, A2 = argument
+                    set_return(frame, fd_fcntl(frame));
+                    mepc + 4
+                }
+                Syscall::Lseek => {
+                    // A0 = fd, A1 = offset, A2 = whence
+                    // Only files have a position.
+                    let p = get_by_pid((*frame).pid as u16);
+                    let fd = (*frame).regs[Registers::A0 as usize] as u16;
+                    let offset = (*frame).regs[Registers::A1 as usize] as i64;
+                    let result = match (*p).data.get_fd_mut(fd) {
+                        Some(FileDescriptor::File(inode, position)) => {
+                            let base = match (*frame).regs[Registers::A2 as usize] {
+                                SEEK_SET => Ok(0),
+                                SEEK_CUR => Ok(i64::from(*position)),
+                                SEEK_END => Ok(i64::from(inode.size)),
+                                _ => Err(EINVAL),
+                            };
+                            base.and_then(|base| {
+                                let new = u32::try_from(base + offset).map_err(|_| EINVAL)?;
+                                *position = new;
+                                Ok(new as usize)
+                            })
+                        }
+                        Some(_) => Err(ESPIPE),
+                        None => Err(EBADF),
+                    };
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Getcwd => {
+                    // A0 = buffer, A1 = size
+                    // There are no directories to change into yet, so we are
+                    // always at the root. Linux counts the NUL.
+                    let result = if (*frame).regs[Registers::A1 as usize] < 2 {
+                        Err(ERANGE)
+                    } else {
+                        copy_to_caller(frame, (*frame).regs[Registers::A0 as usize], b"/\0")
+                            .map(|_| 2)
+                    };
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::SetTidAddress => {
+                    // A0 = where to clear our TID when we exit
+                    let p = get_by_pid((*frame).pid as u16);
+                    (*p).data.clear_child_tid = (*frame).regs[Registers::A0 as usize];
+                    set_return(frame, Ok((*frame).pid));
+                    mepc + 4
+                }
+                Syscall::Nanosleep => {
+                    // A0 = how long, A1 = what's left (never anything)
+                    let result =
+                        read_from_caller::<Timespec>(frame, (*frame).regs[Registers::A0 as usize])
+                            .and_then(|req| req.to_ticks().ok_or(EINVAL));
+                    set_return(frame, result.map(|_| 0));
+                    if let Ok(ticks) = result {
+                        set_sleeping((*frame).pid as u16, ticks);
+                    }
+                    0
+                }
+                Syscall::ClockGettime => {
+                    // A0 = clock, A1 = struct timespec
+                    let result = match (*frame).regs[Registers::A0 as usize] {
+                        CLOCK_REALTIME
+                        | CLOCK_MONOTONIC
+                        | CLOCK_MONOTONIC_RAW
+                        | CLOCK_REALTIME_COARSE
+                        | CLOCK_MONOTONIC_COARSE
+                        | CLOCK_BOOTTIME => write_to_caller(
+                            frame,
+                            (*frame).regs[Registers::A1 as usize],
+                            &Timespec::from_ticks(vdso::uptime()),
+                        )
+                        .map(|_| 0),
+                        _ => Err(EINVAL),
+                    };
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::SchedYield => {
+                    set_return(frame, Ok(0));
+                    0
+                }
+                Syscall::Tkill => {
+                    // A0 = thread id, A1 = signal number
+                    // Every thread has a PID of its own, so this is kill.
+                    let result = match u16::try_from((*frame).regs[Registers::A0 as usize]) {
+                        Ok(tid) if tid > 0 => {
+                            signal::send_signal(tid, (*frame).regs[Registers::A1 as usize])
+                                .map(|_| 0)
+                                .map_err(Errno::from)
+                        }
+                        _ => Err(EINVAL),
+                    };
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Uname => {
+                    // A0 = struct utsname
+                    let result = write_to_caller(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize],
+                        &Utsname::new(),
+                    );
+                    set_return(frame, result.map(|_| 0));
+                    mepc + 4
+                }
+                Syscall::Getrlimit => {
+                    // A0 = resource, A1 = struct rlimit
+                    let result =
+                        resource::prlimit(frame, 0, (*frame).regs[Registers::A0 as usize], None)
+                            .and_then(|old| {
+                                write_to_caller(frame, (*frame).regs[Registers::A1 as usize], &old)
+                            });
+                    set_return(frame, result.map(|_| 0));
+                    mepc + 4
+                }
+                Syscall::Setrlimit => {
+                    // A0 = resource, A1 = struct rlimit
+                    let result =
+                        read_from_caller::<Rlimit>(frame, (*frame).regs[Registers::A1 as usize])
+                            .and_then(|new| {
+                                resource::prlimit(
+                                    frame,
+                                    0,
+                                    (*frame).regs[Registers::A0 as usize],
+                                    Some(new),
+                                )
+                            });
+                    set_return(frame, result.map(|_| 0));
+                    mepc + 4
+                }
+                Syscall::Prlimit64 => {
+                    // A0 = pid, A1 = resource, A2 = new limit or 0, A3 = old limit or 0
+                    let new_addr = (*frame).regs[Registers::A2 as usize];
+                    let old_addr = (*frame).regs[Registers::A3 as usize];
+                    let result = (if new_addr == 0 {
+                        Ok(None)
+                    } else {
+                        read_from_caller::<Rlimit>(frame, new_addr).map(Some)
+                    })
+                    .and_then(|new| {
+                        resource::prlimit(
+                            frame,
+                            (*frame).regs[Registers::A0 as usize],
+                            (*frame).regs[Registers::A1 as usize],
+                            new,
+                        )
+                    })
+                    .and_then(|old| {
+                        if old_addr == 0 {
+                            Ok(())
+                        } else {
+                            write_to_caller(frame, old_addr, &old)
+                        }
+                    });
+                    set_return(frame, result.map(|_| 0));
+                    mepc + 4
+                }
+                Syscall::Getrusage => {
+                    // A0 = who, A1 = struct rusage
+                    let result = resource::getrusage(frame, (*frame).regs[Registers::A0 as usize])
+                        .and_then(|usage| {
+                            write_to_caller(frame, (*frame).regs[Registers::A1 as usize], &usage)
+                        });
+                    set_return(frame, result.map(|_| 0));
+                    mepc + 4
+                }
+                Syscall::GetPpid => {
+                    let p = get_by_pid((*frame).pid as u16);
+                    set_return(frame, Ok(usize::from((*p).data.parent)));
+                    mepc + 4
+                }
+                Syscall::GetUid | Syscall::GetEuid | Syscall::GetGid | Syscall::GetEgid => {
+                    set_return(frame, Ok(0));
+                    mepc + 4
+                }
+                Syscall::GetTid => {
+                    set_return(frame, Ok((*frame).pid));
+                    mepc + 4
+                }
+                Syscall::Brk => {
+                    // A0 = the new end of the heap, or 0 to ask for it
+                    // brk doesn't fail, it returns the old end instead.
+                    (*frame).regs[Registers::A0 as usize] =
+                        mmap::brk(frame, (*frame).regs[Registers::A0 as usize]);
+                    mepc + 4
+                }
+                Syscall::Mmap => {
+                    // A0 = address, A1 = length, A2 = protection, A3 = flags,
+                    // A4 = fd, A5 = offset (both unused, it's all anonymous)
+                    let result = mmap::mmap(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize],
+                        (*frame).regs[Registers::A1 as usize],
+                        (*frame).regs[Registers::A2 as usize],
+                        (*frame).regs[Registers::A3 as usize],
+                    );
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Munmap => {
+                    // A0 = address, A1 = length
+                    let result = mmap::munmap(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize],
+                        (*frame).regs[Registers::A1 as usize],
+                    );
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Mprotect => {
+                    // A0 = address, A1 = length, A2 = protection
+                    let result = mmap::mprotect(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize],
+                        (*frame).regs[Registers::A1 as usize],
+                        (*frame).regs[Registers::A2 as usize],
+                    );
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Shmget => {
+                    // A0 = key, A1 = size, A2 = flags
+                    let result = shm::shmget(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize],
+                        (*frame).regs[Registers::A1 as usize],
+                        (*frame).regs[Registers::A2 as usize],
+                    );
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Shmctl => {
+                    // A0 = id, A1 = command, A2 = struct shmid_ds
+                    let id = (*frame).regs[Registers::A0 as usize];
+                    let buf = (*frame).regs[Registers::A2 as usize];
+                    let result = match (*frame).regs[Registers::A1 as usize] {
+                        IPC_RMID => shm::remove(id),
+                        IPC_SET => {
+                            read_from_caller::<ShmidDs>(frame, buf).and_then(|ds| shm::set(id, &ds))
+                        }
+                        IPC_STAT => shm::stat(id).and_then(|ds| write_to_caller(frame, buf, &ds)),
+                        _ => Err(EINVAL),
+                    };
+                    set_return(frame, result.map(|_| 0));
+                    mepc + 4
+                }
+                Syscall::Shmat => {
+                    // A0 = id, A1 = address or 0, A2 = flags
+                    let result = shm::shmat(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize],
+                        (*frame).regs[Registers::A1 as usize],
+                        (*frame).regs[Registers::A2 as usize],
+                    );
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Shmdt => {
+                    // A0 = address
+                    let result = shm::shmdt(frame, (*frame).regs[Registers::A0 as usize]);
+                    set_return(frame, result);
+                    mepc + 4
+                }
+                Syscall::Madvise => {
+                    // Advice is only advice.
+                    set_return(frame, Ok(0));
+                    mepc + 4
+                }
                 Syscall::BlockRead => {
+                    // The device writes into the buffer.
+                    let buffer = match block_buffer(frame, Access::Write) {
+                        Ok(buffer) => buffer,
+                        Err(Fault) => {
+                            set_return(frame, Err(EFAULT));
+                            return mepc + 4;
+                        }
+                    };
                     set_waiting((*frame).pid as u16);
                     let _ = block_op(
                         (*frame).regs[Registers::A0 as usize],
-                        (*frame).regs[Registers::A1 as usize] as *mut u8,
+                        buffer,
                         (*frame).regs[Registers::A2 as usize] as u32,
                         (*frame).regs[Registers::A3 as usize] as u64,
                         false,
@@ -270,33 +1051,61 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> usize {
                     );
                     0
                 }
+                Syscall::BlockWrite => {
+                    let buffer = match block_buffer(frame, Access::Read) {
+                        Ok(buffer) => buffer,
+                        Err(Fault) => {
+                            set_return(frame, Err(EFAULT));
+                            return mepc + 4;
+                        }
+                    };
+                    set_waiting((*frame).pid as u16);
+                    let queued = block_op(
+                        (*frame).regs[Registers::A0 as usize],
+                        buffer,
+                        (*frame).regs[Registers::A2 as usize] as u32,
+                        (*frame).regs[Registers::A3 as usize] as u64,
+                        true,
+                        (*frame).pid as u16,
+                    );
+                    if let Err(err) = queued {
+                        // Read-only disks are common. Nothing will wake us up
+                        // for a request that was never queued.
+                        set_return(frame, Err(Errno::from(err)));
+                        set_running((*frame).pid as u16);
+                    }
+                    0
+                }
                 // System calls 1000 and above are "special" system calls for our OS. I'll
                 // try to mimic the normal system calls below 1000 so that this OS is compatible
                 // with libraries.
                 Syscall::GetFramebuffer => {
                     // syscall_get_framebuffer(device)
                     let dev = (*frame).regs[Registers::A0 as usize];
-                    (*frame).regs[Registers::A0 as usize] = 0;
+                    let mut result = Err(ENODEV);
                     if dev > 0 && dev <= 8 {
                         if let Some(p) = gpu::GPU_DEVICES[dev - 1].take() {
                             let ptr = p.get_framebuffer() as usize;
-                            if (*frame).satp >> 60 != 0 {
+                            if (*frame).mode == CpuMode::User as usize {
                                 let process = get_by_pid((*frame).pid as u16);
                                 let table = ((*process).get_table_address() as *mut Table)
                                     .as_mut()
                                     .unwrap();
-                                let num_pages =
-                                    (p.get_width() * p.get_height() * 4) as usize / PAGE_SIZE;
-                                for i in 0..num_pages {
-                                    let vaddr = 0x3000_0000 + (i << 12);
-                                    let paddr = ptr + (i << 12);
-                                    map(table, vaddr, paddr, EntryBits::UserReadWrite as i64, 0);
-                                }
+                                // The framebuffer fills whole megapages, so this is
+                                // only a leaf or two.
+                                map_range(
+                                    table,
+                                    0x3000_0000,
+                                    ptr,
+                                    p.framebuffer_len(),
+                                    EntryBits::UserReadWrite.val(),
+                                );
                                 gpu::GPU_DEVICES[dev - 1].replace(p);
                             }
-                            (*frame).regs[Registers::A0 as usize] = 0x3000_0000;
+                            result = Ok(0x3000_0000);
                         }
                     }
+                    set_return(frame, result);
                     0
                 }
                 Syscall::TransferRectangleAndInvalidate => {
@@ -305,68 +1114,47 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> usize {
                     let y = (*frame).regs[Registers::A2 as usize] as u32;
                     let width = (*frame).regs[Registers::A3 as usize] as u32;
                     let height = (*frame).regs[Registers::A4 as usize] as u32;
-                    gpu::transfer(dev, x, y, width, height);
+                    if dev > 0 && dev <= 8 {
+                        gpu::transfer(dev, x, y, width, height);
+                        set_return(frame, Ok(0));
+                    } else {
+                        set_return(frame, Err(ENODEV));
+                    }
                     0
                 }
                 Syscall::WaitForKeyboardEvents => {
                     let mut ev = KEY_EVENTS.take().unwrap();
-                    let max_events = (*frame).regs[Registers::A1 as usize];
-                    let vaddr = (*frame).regs[Registers::A0 as usize] as *const Event;
-                    if (*frame).satp >> 60 != 0 {
-                        let process = get_by_pid((*frame).pid as u16);
-                        let table = ((*process).get_table_address() as *mut Table)
-                            .as_mut()
-                            .unwrap();
-                        (*frame).regs[Registers::A0 as usize] = 0;
-                        for i in 0..if max_events <= ev.len() {
-                            max_events
-                        } else {
-                            ev.len()
-                        } {
-                            let paddr = virt_to_phys(table, vaddr.add(i) as usize);
-                            if paddr.is_none() {
-                                break;
-                            }
-                            let paddr = paddr.unwrap() as *mut Event;
-                            *paddr = ev.pop_front().unwrap();
-                            (*frame).regs[Registers::A0 as usize] += 1;
-                        }
-                    }
+                    set_return(frame, copy_events(frame, &mut ev));
                     KEY_EVENTS.replace(ev);
                     0
                 }
                 Syscall::WaitForAbsEvents => {
                     let mut ev = ABS_EVENTS.take().unwrap();
-                    let max_events = (*frame).regs[Registers::A1 as usize];
-                    let v_addr = (*frame).regs[Registers::A0 as usize] as *const Event;
-                    if (*frame).satp >> 60 != 0 {
-                        let process = get_by_pid((*frame).pid as u16);
-                        let table = ((*process).get_table_address() as *mut Table)
-                            .as_mut()
-                            .unwrap();
-                        (*frame).regs[Registers::A0 as usize] = 0;
-                        for i in 0..if max_events <= ev.len() {
-                            max_events
-                        } else {
-                            ev.len()
-                        } {
-                            let paddr = virt_to_phys(table, v_addr.add(i) as usize);
-                            if paddr.is_none() {
-                                break;
-                            }
-                            let p_addr = paddr.unwrap() as *mut Event;
-                            *p_addr = ev.pop_front().unwrap();
-                            (*frame).regs[Registers::A0 as usize] += 1;
-                        }
-                    }
+                    set_return(frame, copy_events(frame, &mut ev));
                     ABS_EVENTS.replace(ev);
                     0
                 }
                 Syscall::GetTime => {
                     // gettime
-                    (*frame).regs[Registers::A0 as usize] = crate::cpu::get_mtime();
+                    (*frame).regs[Registers::A0 as usize] = get_mtime();
                     0
                 }
+                Syscall::Swapon => {
+                    // A0 = block device
+                    let result = swap::swapon((*frame).regs[Registers::A0 as usize]);
+                    set_return(frame, result.map(|_| 0));
+                    mepc + 4
+                }
+                Syscall::SwapInfo => {
+                    // A0 = struct swapinfo
+                    let result = write_to_caller(
+                        frame,
+                        (*frame).regs[Registers::A0 as usize],
+                        &swap::info(),
+                    );
+                    set_return(frame, result.map(|_| 0));
+                    mepc + 4
+                }
             }
         },
     )
@@ -405,34 +1193,34 @@ pub fn syscall_exit() {
 }
 
 /// Overlay Calling Process and Run New Program
-pub fn syscall_execv(path: *const u8, argv: usize) -> usize {
-    do_make_syscall(Syscall::Execv.into(), path as usize, argv, 0, 0, 0, 0)
+pub fn syscall_execve(path: *const u8, argv: usize, envp: usize) -> usize {
+    do_make_syscall(Syscall::Execve.into(), path as usize, argv, envp, 0, 0, 0)
 }
 
-/// Read file in file system
-pub fn syscall_fs_read(dev: usize, inode: u32, buffer: *mut u8, size: u32, offset: u32) -> usize {
+/// Read the block on device. Returns 0 or the negated error number.
+pub fn syscall_block_read(dev: usize, buffer: *mut u8, size: u32, offset: u32) -> isize {
     do_make_syscall(
-        Syscall::Read.into(),
+        Syscall::BlockRead.into(),
         dev,
-        inode as usize,
         buffer as usize,
         size as usize,
         offset as usize,
         0,
-    )
+        0,
+    ) as isize
 }
 
-/// Read the block on device
-pub fn syscall_block_read(dev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
+/// Write the block on device. Returns 0 or the negated error number.
+pub fn syscall_block_write(dev: usize, buffer: *mut u8, size: u32, offset: u32) -> isize {
     do_make_syscall(
-        Syscall::BlockRead.into(),
+        Syscall::BlockWrite.into(),
         dev,
         buffer as usize,
         size as usize,
         offset as usize,
         0,
         0,
-    ) as u8
+    ) as isize
 }
 
 /// Gives a little sleep to the process
@@ -447,6 +1235,58 @@ pub fn syscall_get_pid() -> u16 {
     do_make_syscall(Syscall::GetPid.into(), 0, 0, 0, 0, 0, 0) as u16
 }
 
+/// The longest path execv and openat take, not counting the NUL
+const PATH_MAX: usize = 4096;
+
+/// Read a NULL-terminated array of strings, like argv, out of the caller's
+/// memory. `used` adds up the room they'll take on the new stack, which
+/// mustn't go over [`elf::ARG_MAX`]. A NULL array is an empty one.
+unsafe fn strings_from_caller(
+    frame: *const TrapFrame,
+    vaddr: usize,
+    used: &mut usize,
+) -> Result<Vec<Vec<u8>>, Errno> {
+    let space = uaccess::caller(frame)?;
+    let mut strings = Vec::new();
+    if vaddr == 0 {
+        return Ok(strings);
+    }
+    loop {
+        let ptr: usize = uaccess::read_user(space, vaddr + strings.len() * size_of::<usize>())?;
+        if ptr == 0 {
+            return Ok(strings);
+        }
+        // Each string costs its pointer and its NUL as well.
+        *used += size_of::<usize>() + 1;
+        let left = elf::ARG_MAX.checked_sub(*used).ok_or(E2BIG)?;
+        let s = uaccess::bytes_from_user(space, ptr, left).map_err(|errno| {
+            if errno == ENAMETOOLONG {
+                E2BIG
+            } else {
+                errno
+            }
+        })?;
+        *used += s.len();
+        strings.push(s);
+    }
+}
+
+/// Everything [`exec_func`] needs to start the new program.
+struct ExecArgs {
+    inode: fs::Inode,
+    fdesc: BTreeMap<u16, FileDescriptor>,
+    path: String,
+    argv: Vec<Vec<u8>>,
+    envp: Vec<Vec<u8>>,
+    // The core dump switch survives exec, like open files do.
+    core_dump: bool,
+    // So do resource limits.
+    limits: Limits,
+    // And so does being traced.
+    trace: Option<ptrace::ExecTrace>,
+    strace: bool,
+}
+
 /// This is a helper function ran as a process in kernel space
 /// to finish loading and executing a process.
 fn exec_func(args: usize) {
@@ -454,24 +1294,503 @@ fn exec_func(args: usize) {
         // We got the inode from the syscall. Its Box rid itself of control, so
         // we take control back here. The Box now owns the Inode and will complete
         // freeing the heap memory allocated for it.
-        let inode = Box::from_raw(args as *mut fs::Inode);
+        let args = *Box::from_raw(args as *mut ExecArgs);
+        let inode = args.inode;
         let mut buffer = Buffer::new(inode.size as usize);
         // This is why we need to be in a process context. The read() call may sleep as it
         // waits for the block driver to return.
-        fs::MinixFileSystem::read(8, &inode, buffer.get_mut(), inode.size, 0);
+        fs::MinixFileSystem::read(fs::ROOT_DEVICE, &inode, buffer.get_mut(), inode.size, 0);
         // Now we have the data, so the following will load the ELF file and give us a process.
-        let proc = elf::File::load_proc(&buffer);
+        let mut proc =
+            elf::File::load_proc(&buffer, &args.argv, &args.envp, args.limits).map(|mut proc| {
+                proc.data.inherit_fds(args.fdesc);
+                proc.data.path = args.path;
+                proc.data.core_dump = args.core_dump;
+                proc
+            });
+        // A traced program is traced from its first instruction on.
+        if let Some(trace) = args.trace {
+            ptrace::exec_done(trace, proc.as_mut().ok());
+        }
+        if let Ok(proc) = &proc {
+            if args.strace || strace::trace_all() {
+                strace::set_enabled(proc.get_pid(), true);
+            }
+        }
         if proc.is_err() {
-            println!("Failed to launch process.");
+            warn!("Failed to launch process.");
         } else {
             // If we hold this lock, we can still be preempted, but the scheduler will
             // return control to us. This required us to use try_lock in the scheduler.
             PROCESS_LIST_MUTEX.sleep_lock();
             if let Some(mut proc_list) = PROCESS_LIST.take() {
-                proc_list.push_back(proc.ok().unwrap());
+                let proc = proc.ok().unwrap();
+                // Whatever we exec last owns the console, so Ctrl-C goes there.
+                FOREGROUND_PID = proc.pid;
+                proc_list.push_back(proc);
                 PROCESS_LIST.replace(proc_list);
             }
             PROCESS_LIST_MUTEX.unlock();
         }
     }
 }
+
+/// Fill `dst` from the calling process' memory at `vaddr`.
+unsafe fn copy_from_caller(
+    frame: *const TrapFrame,
+    dst: &mut [u8],
+    vaddr: usize,
+) -> Result<(), Errno> {
+    Ok(uaccess::copy_from_user(
+        uaccess::caller(frame)?,
+        dst,
+        vaddr,
+    )?)
+}
+
+/// Copy `src` into the calling process' memory at `vaddr`.
+unsafe fn copy_to_caller(frame: *const TrapFrame, vaddr: usize, src: &[u8]) -> Result<(), Errno> {
+    Ok(uaccess::copy_to_user(uaccess::caller(frame)?, vaddr, src)?)
+}
+
+/// Read a `T` from the calling process' memory at `vaddr`.
+unsafe fn read_from_caller<T: Copy>(frame: *const TrapFrame, vaddr: usize) -> Result<T, Errno> {
+    Ok(uaccess::read_user(uaccess::caller(frame)?, vaddr)?)
+}
+
+/// Write a `T` into the calling process' memory at `vaddr`.
+unsafe fn write_to_caller<T: Copy>(
+    frame: *const TrapFrame,
+    vaddr: usize,
+    val: &T,
+) -> Result<(), Errno> {
+    Ok(uaccess::write_user(uaccess::caller(frame)?, vaddr, val)?)
+}
+
+/// Put `result` into A0, as the value or as the negated error number.
+unsafe fn set_return(frame: *mut TrapFrame, result: SysResult) {
+    (*frame).regs[Registers::A0 as usize] = to_return(result);
+}
+
+/// The physical address of the buffer of a block read or write, A1 with
+/// A2 bytes. The device reads or writes it without us, so it has to be
+/// physically contiguous.
+unsafe fn block_buffer(frame: *const TrapFrame, access: Access) -> Result<*mut u8, Fault> {
+    let vaddr = (*frame).regs[Registers::A1 as usize];
+    let size = (*frame).regs[Registers::A2 as usize] as u32 as usize;
+    let paddr = uaccess::translate_range(uaccess::caller(frame)?, vaddr, size, access)?;
+    Ok(paddr as *mut u8)
+}
+
+/// Hand the caller as many of `events` as fit in its buffer, A1 events at
+/// A0, and return how many that were.
+unsafe fn copy_events(frame: *const TrapFrame, events: &mut VecDeque<Event>) -> SysResult {
+    let vaddr = (*frame).regs[Registers::A0 as usize];
+    let max_events = (*frame).regs[Registers::A1 as usize];
+    let space = uaccess::caller(frame)?;
+    let mut n = 0;
+    while n < max_events {
+        let ev = match events.front() {
+            Some(ev) => *ev,
+            None => break,
+        };
+        let dst = vaddr + n * size_of::<Event>();
+        match uaccess::write_user(space, dst, &ev) {
+            Ok(()) => {}
+            Err(Fault) if n == 0 => return Err(EFAULT),
+            Err(Fault) => break,
+        }
+        events.pop_front();
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// Put the caller to sleep until somebody calls `set_running` on it and then
+/// run the very same system call again.
+unsafe fn block_and_restart(frame: *mut TrapFrame, mepc: usize) -> usize {
+    set_waiting((*frame).pid as u16);
+    // m_trap steps over the ecall when we return 0, so back up one
+    // instruction to land on it again.
+    (*frame).pc = mepc - 4;
+    0
+}
+
+// The part of the openat() flags that says what a file is opened for,
+// one of the O_RDONLY, O_WRONLY and O_RDWR below
+const O_ACCMODE: usize = 3;
+
+/// `openat(dirfd, path, flags, mode)` for a file on the disk. There are no
+/// directories to be in but the root, so that is where relative paths
+/// start, whatever `dirfd` is. The file system can't be written yet, so
+/// files only open for reading.
+unsafe fn fd_open(frame: *mut TrapFrame) -> SysResult {
+    if (*frame).regs[Registers::A2 as usize] & O_ACCMODE != O_RDONLY {
+        return Err(EROFS);
+    }
+    let path_addr = (*frame).regs[Registers::A1 as usize];
+    let mut path = uaccess::string_from_user(uaccess::caller(frame)?, path_addr, PATH_MAX)?;
+    if !path.starts_with('/') {
+        path.insert(0, '/');
+    }
+    let inode = fs::MinixFileSystem::open(fs::ROOT_DEVICE, &path).map_err(|_| ENOENT)?;
+    let p = get_by_pid((*frame).pid as u16);
+    (*p).add_fd(FileDescriptor::File(inode, 0)).map(usize::from)
+}
+
+/// The most we read from a file at once. The kernel holds on to all of it
+/// until the caller picks it up.
+const MAX_FILE_READ: usize = 64 * 1024;
+
+// lseek() whence
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+const SEEK_END: usize = 2;
+
+/// How a read or a write on a descriptor went
+enum FdResult {
+    Done(SysResult),
+    /// Nothing can be read or written yet. The caller gets woken up once
+    /// that changes.
+    WouldBlock,
+}
+
+/// Read up to `count` bytes from `fd` into the caller's `buffer`.
+unsafe fn read_fd(frame: *mut TrapFrame, fd: u16, buffer: usize, count: usize) -> FdResult {
+    let pid = (*frame).pid as u16;
+    let p = get_by_pid(pid);
+    FdResult::Done(match (*p).data.get_fd(fd) {
+        Some(FileDescriptor::PipeRead(id)) => {
+            // We never need more than a pipe's worth of bytes.
+            let mut data = vec![0_u8; count.min(pipe::PIPE_SIZE)];
+            match pipe::read(*id, pid, &mut data) {
+                PipeResult::Done(n) => copy_to_caller(frame, buffer, &data[..n]).map(|_| n),
+                PipeResult::WouldBlock => return FdResult::WouldBlock,
+                PipeResult::Broken => Err(EPIPE),
+            }
+        }
+        Some(FileDescriptor::Device(CONSOLE_DEVICE)) => {
+            let mut data = vec![0_u8; count.min(console::INPUT_SIZE)];
+            match console::read(pid, &mut data) {
+                ConsoleResult::Done(n) => copy_to_caller(frame, buffer, &data[..n]).map(|_| n),
+                ConsoleResult::WouldBlock => return FdResult::WouldBlock,
+            }
+        }
+        Some(FileDescriptor::File(inode, offset)) => {
+            let (inode, offset) = (*inode, *offset);
+            match (*p).data.file_read.take() {
+                // The file system read this for us, we just have to hand it over.
+                Some(read) if read.fd == fd && read.offset == offset => {
+                    let n = read.data.len().min(count);
+                    copy_to_caller(frame, buffer, &read.data[..n]).map(|_| {
+                        if let Some(FileDescriptor::File(_, offset)) = (*p).data.get_fd_mut(fd) {
+                            *offset += n as u32;
+                        }
+                        n
+                    })
+                }
+                _ if count == 0 || offset >= inode.size => Ok(0),
+                _ => {
+                    // Reading the disk blocks, so a kernel process does it and
+                    // we come back here once it's done.
+                    let size = count.min(MAX_FILE_READ).min((inode.size - offset) as usize);
+                    fs::process_read(pid, fs::ROOT_DEVICE, fd, inode, size as u32, offset);
+                    return FdResult::WouldBlock;
+                }
+            }
+        }
+        _ => Err(EBADF),
+    })
+}
+
+/// Write up to `count` bytes from the caller's `buffer` to `fd`.
+unsafe fn write_fd(frame: *mut TrapFrame, fd: u16, buffer: usize, count: usize) -> FdResult {
+    let pid = (*frame).pid as u16;
+    let p = get_by_pid(pid);
+    FdResult::Done(match (*p).data.get_fd(fd) {
+        Some(FileDescriptor::PipeWrite(id)) => {
+            let mut data = vec![0_u8; count.min(pipe::PIPE_SIZE)];
+            match copy_from_caller(frame, &mut data, buffer) {
+                Ok(()) => match pipe::write(*id, pid, &data) {
+                    PipeResult::Done(n) => Ok(n),
+                    PipeResult::WouldBlock => return FdResult::WouldBlock,
+                    PipeResult::Broken => {
+                        let _ = signal::send_signal(pid, signal::SIGPIPE);
+                        Err(EPIPE)
+                    }
+                },
+                Err(errno) => Err(errno),
+            }
+        }
+        Some(FileDescriptor::Device(CONSOLE_DEVICE)) => {
+            // Short writes are fine, the caller will come back for the rest.
+            let mut data = vec![0_u8; count.min(PAGE_SIZE)];
+            copy_from_caller(frame, &mut data, buffer).map(|_| {
+                for c in &data {
+                    print!("{}", *c as char);
+                }
+                data.len()
+            })
+        }
+        _ => Err(EBADF),
+    })
+}
+
+/// `read(fd, buffer, count)` on whatever the descriptor refers to.
+unsafe fn fd_read(frame: *mut TrapFrame, mepc: usize) -> usize {
+    let fd = (*frame).regs[Registers::A0 as usize] as u16;
+    let buffer = (*frame).regs[Registers::A1 as usize];
+    let count = (*frame).regs[Registers::A2 as usize];
+    match read_fd(frame, fd, buffer, count) {
+        FdResult::Done(result) => {
+            set_return(frame, result);
+            mepc + 4
+        }
+        FdResult::WouldBlock => block_and_restart(frame, mepc),
+    }
+}
+
+/// `write(fd, buffer, count)` on whatever the descriptor refers to.
+unsafe fn fd_write(frame: *mut TrapFrame, mepc: usize) -> usize {
+    let fd = (*frame).regs[Registers::A0 as usize] as u16;
+    let buffer = (*frame).regs[Registers::A1 as usize];
+    let count = (*frame).regs[Registers::A2 as usize];
+    match write_fd(frame, fd, buffer, count) {
+        FdResult::Done(result) => {
+            set_return(frame, result);
+            mepc + 4
+        }
+        FdResult::WouldBlock => block_and_restart(frame, mepc),
+    }
+}
+
+/// `struct iovec`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IoVec {
+    base: usize,
+    len: usize,
+}
+
+/// The most iovecs readv and writev take, as on Linux
+const IOV_MAX: usize = 1024;
+
+/// `readv(fd, iov, iovcnt)` or `writev(fd, iov, iovcnt)`: a read or write
+/// per buffer, until one of them comes up short. Once something went
+/// through, that is what we report, even if a later buffer fails.
+unsafe fn fd_vectored(frame: *mut TrapFrame, mepc: usize, write: bool) -> usize {
+    let fd = (*frame).regs[Registers::A0 as usize] as u16;
+    let iov = (*frame).regs[Registers::A1 as usize];
+    let iovcnt = (*frame).regs[Registers::A2 as usize];
+    if iovcnt > IOV_MAX {
+        set_return(frame, Err(EINVAL));
+        return mepc + 4;
+    }
+    let mut done = 0;
+    let mut error = None;
+    for i in 0..iovcnt {
+        let vec = match read_from_caller::<IoVec>(frame, iov + i * size_of::<IoVec>()) {
+            Ok(vec) => vec,
+            Err(errno) => {
+                error = Some(errno);
+                break;
+            }
+        };
+        if vec.len == 0 {
+            continue;
+        }
+        let result = if write {
+            write_fd(frame, fd, vec.base, vec.len)
+        } else {
+            read_fd(frame, fd, vec.base, vec.len)
+        };
+        match result {
+            FdResult::Done(Ok(n)) => {
+                done += n;
+                if n < vec.len {
+                    break;
+                }
+            }
+            FdResult::Done(Err(errno)) => {
+                error = Some(errno);
+                break;
+            }
+            FdResult::WouldBlock if done == 0 => return block_and_restart(frame, mepc),
+            FdResult::WouldBlock => break,
+        }
+    }
+    set_return(
+        frame,
+        match error {
+            Some(errno) if done == 0 => Err(errno),
+            _ => Ok(done),
+        },
+    );
+    mepc + 4
+}
+
+// fcntl() commands
+const F_DUPFD: usize = 0;
+const F_GETFD: usize = 1;
+const F_SETFD: usize = 2;
+const F_GETFL: usize = 3;
+const F_SETFL: usize = 4;
+const F_DUPFD_CLOEXEC: usize = 1030;
+
+// What F_GETFL says a descriptor is open for
+const O_RDONLY: usize = 0;
+const O_WRONLY: usize = 1;
+const O_RDWR: usize = 2;
+
+/// `fcntl(fd, command, argument)`. There is no close-on-exec and there are
+/// no status flags that could be changed, so this is mostly dup.
+unsafe fn fd_fcntl(frame: *mut TrapFrame) -> SysResult {
+    let p = get_by_pid((*frame).pid as u16);
+    let fd = (*frame).regs[Registers::A0 as usize] as u16;
+    let command = (*frame).regs[Registers::A1 as usize];
+    let arg = (*frame).regs[Registers::A2 as usize];
+    let desc = (*p).data.get_fd(fd).ok_or(EBADF)?;
+    match command {
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            let min = u16::try_from(arg).map_err(|_| EINVAL)?;
+            let desc = desc.duplicate();
+            Ok(usize::from((*p).add_fd_from(min, desc)?))
+        }
+        F_GETFD | F_SETFD | F_SETFL => Ok(0),
+        F_GETFL => Ok(match desc {
+            FileDescriptor::PipeRead(_) | FileDescriptor::File(..) => O_RDONLY,
+            FileDescriptor::PipeWrite(_) => O_WRONLY,
+            _ => O_RDWR,
+        }),
+        _ => Err(EINVAL),
+    }
+}
+
+/// `ioctl(fd, request, arg)`. Only the console answers, and only to the
+/// termios and window size requests.
+unsafe fn fd_ioctl(frame: *mut TrapFrame, mepc: usize) -> usize {
+    let p = get_by_pid((*frame).pid as u16);
+    let fd = (*frame).regs[Registers::A0 as usize] as u16;
+    let request = (*frame).regs[Registers::A1 as usize];
+    let arg = (*frame).regs[Registers::A2 as usize];
+    let result = match (*p).data.get_fd(fd) {
+        Some(FileDescriptor::Device(CONSOLE_DEVICE)) => match request {
+            console::TCGETS => write_to_caller(frame, arg, &console::get_termios()).map(|_| 0),
+            console::TCSETS => read_from_caller::<Termios>(frame, arg).map(|termios| {
+                console::set_termios(termios);
+                0
+            }),
+            console::TIOCGWINSZ => write_to_caller(frame, arg, &console::WINSIZE).map(|_| 0),
+            _ => Err(EINVAL),
+        },
+        Some(_) => Err(ENOTTY),
+        None => Err(EBADF),
+    };
+    set_return(frame, result);
+    mepc + 4
+}
+
+// Clocks for clock_gettime(). We have no real-time clock, so the time of
+// day is the time since boot, which makes it 1970 all day.
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+const CLOCK_MONOTONIC_RAW: usize = 4;
+const CLOCK_REALTIME_COARSE: usize = 5;
+const CLOCK_MONOTONIC_COARSE: usize = 6;
+const CLOCK_BOOTTIME: usize = 7;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// `struct timespec`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Timespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+impl Timespec {
+    fn from_ticks(ticks: usize) -> Self {
+        let ticks = ticks as u64;
+        Self {
+            tv_sec: (ticks / FREQ) as i64,
+            tv_nsec: (ticks % FREQ * NSEC_PER_SEC / FREQ) as i64,
+        }
+    }
+
+    /// How many timer ticks this is, if it is a valid duration
+    fn to_ticks(self) -> Option<usize> {
+        let sec = u64::try_from(self.tv_sec).ok()?;
+        let nsec = u64::try_from(self.tv_nsec)
+            .ok()
+            .filter(|&n| n < NSEC_PER_SEC)?;
+        let ticks = sec
+            .checked_mul(FREQ)?
+            .checked_add(nsec * FREQ / NSEC_PER_SEC)?;
+        usize::try_from(ticks).ok()
+    }
+}
+
+/// `struct utsname`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Utsname {
+    sysname: [u8; 65],
+    nodename: [u8; 65],
+    release: [u8; 65],
+    version: [u8; 65],
+    machine: [u8; 65],
+    domainname: [u8; 65],
+}
+
+impl Utsname {
+    fn new() -> Self {
+        fn field(s: &str) -> [u8; 65] {
+            let mut field = [0; 65];
+            field[..s.len()].copy_from_slice(s.as_bytes());
+            field
+        }
+        Self {
+            sysname: field("Terrikon"),
+            nodename: field("terrikon"),
+            release: field(env!("CARGO_PKG_VERSION")),
+            version: field("#1"),
+            machine: field("riscv64"),
+            domainname: field("(none)"),
+        }
+    }
+}
+
+// syslog() actions, as Linux numbers them
+const SYSLOG_ACTION_READ_ALL: usize = 3;
+const SYSLOG_ACTION_READ_CLEAR: usize = 4;
+const SYSLOG_ACTION_CLEAR: usize = 5;
+const SYSLOG_ACTION_CONSOLE_LEVEL: usize = 8;
+const SYSLOG_ACTION_SIZE_UNREAD: usize = 9;
+const SYSLOG_ACTION_SIZE_BUFFER: usize = 10;
+
+/// `syslog(action, buffer, length)`, which is what dmesg uses to get at
+/// the kernel log.
+unsafe fn syslog(frame: *mut TrapFrame) -> SysResult {
+    let action = (*frame).regs[Registers::A0 as usize];
+    let buffer = (*frame).regs[Registers::A1 as usize];
+    let length = (*frame).regs[Registers::A2 as usize];
+    match action {
+        SYSLOG_ACTION_READ_ALL | SYSLOG_ACTION_READ_CLEAR => {
+            let mut data = vec![0_u8; length.min(log::LOG_BUFFER_SIZE)];
+            let n = log::read(&mut data, action == SYSLOG_ACTION_READ_CLEAR);
+            copy_to_caller(frame, buffer, &data[..n]).map(|_| n)
+        }
+        SYSLOG_ACTION_CLEAR => {
+            log::clear();
+            Ok(0)
+        }
+        SYSLOG_ACTION_CONSOLE_LEVEL => Level::from_usize(length).ok_or(EINVAL).map(|level| {
+            log::set_console_level(level);
+            0
+        }),
+        SYSLOG_ACTION_SIZE_UNREAD => Ok(log::len()),
+        SYSLOG_ACTION_SIZE_BUFFER => Ok(log::LOG_BUFFER_SIZE),
+        _ => Err(EINVAL),
+    }
+}
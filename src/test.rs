@@ -6,6 +6,6 @@ pub fn test() {
     MinixFileSystem::init(8);
     // let path = "/pong.elf\0".as_bytes().as_ptr();
     let path = b"/shell.elf\0".as_ptr();
-    syscall::syscall_execv(path, 0);
-    println!("I should never get here, execv should destroy our process.");
+    syscall::syscall_execve(path, 0, 0);
+    error!("I should never get here, execve should destroy our process.");
 }
@@ -6,12 +6,22 @@
 // };
 
 use crate::{
-    cpu::{TrapFrame, CONTEXT_SWITCH_TIME},
-    plic,
-    process::delete_process,
-    rust_switch_to_user,
+    backtrace,
+    cpu::{
+        dump_registers, get_mtime, mhartid_read, mie_write, mscratch_read, set_mtimecmp, CpuMode,
+        Registers, TrapFrame, CONTEXT_SWITCH_TIME, MSTATUS_MIE,
+    },
+    crash::FaultInfo,
+    gdb, kmem, mmap,
+    page::PAGE_SIZE,
+    plic, power,
+    process::{delete_process, get_by_pid},
+    ptrace, rust_switch_to_user,
     sched::schedule,
+    signal::{force_signal, has_deliverable, SIGILL, SIGSEGV, SIGTRAP},
+    strace,
     syscall::do_syscall,
+    vdso,
 };
 
 // #[derive(TryFromPrimitive)]
@@ -41,7 +51,7 @@ extern "C" fn m_trap(
     tval: usize,
     cause: usize,
     hart: usize,
-    _status: usize,
+    status: usize,
     frame: *mut TrapFrame,
 ) -> usize {
     // We're going to handle all traps in machine mode. RISC-V lets
@@ -52,13 +62,23 @@ extern "C" fn m_trap(
     // number. So, here we narrow down just the cause number.
     let cause_num = cause & 0xfff;
     let mut return_pc = epc;
+    if is_async && unsafe { interrupts_off(frame) } {
+        // A kernel thread in a critical section. Hold interrupts off until
+        // it leaves, see emulate_csr.
+        mie_write(0);
+        return return_pc;
+    }
     if is_async {
         // Asynchronous trap
         match cause_num {
             3 => {
                 // We will use this to awaken our other CPUs so they can process
                 // processes.
-                println!("Machine software interrupt CPU #{}", hart);
+                if power::is_halting() {
+                    // Somebody panicked and wants us to stop.
+                    power::park();
+                }
+                debug!("Machine software interrupt CPU #{}", hart);
             }
             7 => {
                 // This is the context-switch timer.
@@ -79,6 +99,14 @@ extern "C" fn m_trap(
                 // get an interrupt from a non-PLIC source. This is the main reason that the PLIC
                 // hardwires the id 0 to 0, so that we can use it as an error case.
                 plic::handle_interrupt();
+                // The debugger may have stopped whoever we interrupted.
+                if gdb::is_held(unsafe { (*frame).pid } as u16) {
+                    let new_frame = schedule();
+                    schedule_next_context_switch(1);
+                    if new_frame != 0 {
+                        rust_switch_to_user(new_frame);
+                    }
+                }
             }
             _ => {
                 panic!("Unhandled async trap CPU#{} -> {}\n", hart, cause_num);
@@ -89,35 +117,69 @@ extern "C" fn m_trap(
         match cause_num {
             2 => unsafe {
                 // Illegal instruction
-                println!(
-                    "Illegal instruction CPU#{} -> 0x{:08x}: 0x{:08x}\n",
-                    hart, epc, tval
-                );
-                // We need while trues here until we have a functioning "delete from scheduler"
-                // I use while true because Rust will warn us that it looks stupid.
-                // This is what I want so that I remember to remove this and replace
-                // them later.
-                delete_process((*frame).pid as u16);
-                let frame = schedule();
-                schedule_next_context_switch(1);
-                rust_switch_to_user(frame);
+                if vdso::emulate_rdtime(frame, tval) || emulate_csr(frame, tval) {
+                    // A read of the time CSR the hardware wouldn't let through,
+                    // or a machine CSR a kernel thread may use
+                    return_pc += 4;
+                } else {
+                    warn!(
+                        "Illegal instruction CPU#{} -> 0x{:08x}: 0x{:08x}",
+                        hart, epc, tval
+                    );
+                    fault(frame, cause_num, tval, SIGILL);
+                }
+            },
+            3 => unsafe {
+                // Breakpoint
+                if !gdb::breakpoint(frame) {
+                    // Not one of the debugger's, so it's the program's own ebreak.
+                    fault(frame, cause_num, tval, SIGTRAP);
+                }
+                if gdb::is_held((*frame).pid as u16) {
+                    // Stopped for the debugger. The breakpoint is taken out
+                    // before the process runs again, so it resumes right here.
+                    let frame = schedule();
+                    schedule_next_context_switch(1);
+                    rust_switch_to_user(frame);
+                }
             },
+            5 | 7
+                if status >> 11 & 3 == CpuMode::Machine as usize
+                    && is_boot_stack_guard(hart, tval) =>
+            {
+                // The trap handler itself ran out of stack. Trapping again
+                // saved its registers over those of the process it was
+                // working for, so there is nothing to go back to.
+                panic!(
+                    "Stack overflow in the trap handler on CPU#{}, working for pid {}",
+                    hart,
+                    unsafe { (*frame).pid }
+                );
+            }
             7 => unsafe {
-                println!(
+                warn!(
                     "Error with pid {}, at PC 0x{:08x}, mepc 0x{:08x}",
                     (*frame).pid,
                     (*frame).pc,
                     epc
                 );
-                delete_process((*frame).pid as u16);
-                let frame = schedule();
-                schedule_next_context_switch(1);
-                rust_switch_to_user(frame);
+                fault(frame, cause_num, tval, SIGSEGV);
             },
             8 | 9 | 11 => unsafe {
                 // Environment (system) call from User, Supervisor, and Machine modes
                 // println!("E-call from User mode! CPU#{} -> 0x{:08x}", hart, epc);
+                if ptrace::syscall_entry(frame) {
+                    // Stopped for the tracer, which may change the arguments.
+                    // We come back to the ecall once it lets us go.
+                    let frame = schedule();
+                    schedule_next_context_switch(1);
+                    rust_switch_to_user(frame);
+                }
+                let call = strace::enter(frame);
                 return_pc = do_syscall(return_pc, frame);
+                if let Some(call) = call {
+                    strace::exit(call, frame, return_pc);
+                }
                 if return_pc == 0 {
                     // We are about to schedule something else here, so we need to store PAST
                     // the system call so that when we resume this process, we're after the ecall.
@@ -125,41 +187,43 @@ extern "C" fn m_trap(
                     let frame = schedule();
                     schedule_next_context_switch(1);
                     rust_switch_to_user(frame);
+                } else if has_deliverable(frame) || ptrace::has_exit_stop(frame) {
+                    // The system call raised a signal for ourselves (kill, sigprocmask
+                    // unblocking something), or a tracer wants to see its result. Don't
+                    // wait for the next context switch to deliver it.
+                    (*frame).pc = return_pc;
+                    rust_switch_to_user(frame as usize);
                 }
             },
             // Page faults
+            12 | 13 | 15 if unsafe { mmap::fault(frame, tval, cause_num) } => {
+                // The page was in swap, or the hardware wanted us to set
+                // its Access or Dirty bit. Either way, the process tries
+                // again.
+            }
             12 => unsafe {
                 // Instruction page fault
-                println!(
+                warn!(
                     "Instruction page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
                     hart, epc, tval
                 );
-                delete_process((*frame).pid as u16);
-                let frame = schedule();
-                schedule_next_context_switch(1);
-                rust_switch_to_user(frame);
+                fault(frame, cause_num, tval, SIGSEGV);
             },
             13 => unsafe {
                 // Load page fault
-                println!(
+                warn!(
                     "Load page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
                     hart, epc, tval
                 );
-                delete_process((*frame).pid as u16);
-                let frame = schedule();
-                schedule_next_context_switch(1);
-                rust_switch_to_user(frame);
+                fault(frame, cause_num, tval, SIGSEGV);
             },
             15 => unsafe {
                 // Store page fault
-                println!(
+                warn!(
                     "Store page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
                     hart, epc, tval
                 );
-                delete_process((*frame).pid as u16);
-                let frame = schedule();
-                schedule_next_context_switch(1);
-                rust_switch_to_user(frame);
+                fault(frame, cause_num, tval, SIGSEGV);
             },
             _ => {
                 panic!(
@@ -173,15 +237,101 @@ extern "C" fn m_trap(
     return_pc
 }
 
-pub const MMIO_MTIMECMP: *mut u64 = 0x0200_4000_usize as *mut u64;
-pub const MMIO_MTIME: *const u64 = 0x0200_BFF8 as *const u64;
+/// Everything `switch_to_user` enables in `mie`
+const MIE_ALL: usize = 0xaaa;
 
-pub fn schedule_next_context_switch(qm: u16) {
-    unsafe {
-        MMIO_MTIMECMP.write_volatile(
-            MMIO_MTIME
-                .read_volatile()
-                .wrapping_add(CONTEXT_SWITCH_TIME * qm as u64),
-        );
+// CSR numbers
+const CSR_MSTATUS: usize = 0x300;
+const CSR_MSCRATCH: usize = 0x340;
+const CSR_MHARTID: usize = 0xf14;
+
+/// Did the kernel thread behind `frame` turn interrupts off?
+unsafe fn interrupts_off(frame: *const TrapFrame) -> bool {
+    (*frame).mode == CpuMode::Supervisor as usize && (*frame).mstatus & MSTATUS_MIE == 0
+}
+
+/// Kernel threads run in supervisor mode, where machine CSRs trap, but the
+/// code they run turns interrupts off in `mstatus` around locks that
+/// interrupt handlers take too, and a panic reads `mscratch` and `mhartid`.
+/// If the illegal instruction `insn` is one of those, do it for the thread.
+/// Its `mstatus` is [`TrapFrame::mstatus`], where only MIE counts: while it
+/// is clear, an interrupt only masks all of them in `mie` until the thread
+/// sets it again. Returns whether the instruction was emulated.
+unsafe fn emulate_csr(frame: *mut TrapFrame, insn: usize) -> bool {
+    let funct3 = (insn >> 12) & 7;
+    if (*frame).mode != CpuMode::Supervisor as usize || insn & 0x7f != 0x73 || funct3 & 3 == 0 {
+        return false;
+    }
+    let csr = insn >> 20;
+    let rd = (insn >> 7) & 0x1f;
+    let rs1 = (insn >> 15) & 0x1f;
+    // csrrwi, csrrsi and csrrci take rs1 as the value itself.
+    let src = if funct3 & 4 != 0 {
+        rs1
+    } else {
+        (*frame).regs[rs1]
+    };
+    let old = match csr {
+        CSR_MSTATUS => (*frame).mstatus,
+        CSR_MSCRATCH => mscratch_read(),
+        CSR_MHARTID => mhartid_read(),
+        _ => return false,
+    };
+    // csrrs and csrrc with x0 (or 0) only read.
+    let writes = funct3 & 3 == 1 || rs1 != 0;
+    if writes {
+        if csr != CSR_MSTATUS {
+            return false;
+        }
+        let new = match funct3 & 3 {
+            1 => src,
+            2 => old | src,
+            _ => old & !src,
+        };
+        (*frame).mstatus = new;
+        if new & MSTATUS_MIE != 0 {
+            // Let through whatever came in while they were off.
+            mie_write(MIE_ALL);
+        }
+    }
+    if rd != Registers::Zero as usize {
+        (*frame).regs[rd] = old;
+    }
+    true
+}
+
+/// Is `addr` in the guard page below the boot stack of `hart`? On hart 0,
+/// that is the stack of the trap handler.
+fn is_boot_stack_guard(hart: usize, addr: usize) -> bool {
+    kmem::boot_stack_guard(hart).map_or(false, |guard| (guard..guard + PAGE_SIZE).contains(&addr))
+}
+
+/// A process did something it isn't allowed to do. User processes get a
+/// signal, which they may catch, while kernel processes are simply deleted.
+unsafe fn fault(frame: *mut TrapFrame, cause: usize, tval: usize, signo: usize) -> ! {
+    let p = get_by_pid((*frame).pid as u16);
+    if matches!(cause, 12 | 13 | 15) && !p.is_null() && (*p).is_stack_guard(tval) {
+        error!("Stack overflow in pid {}", (*frame).pid);
+    }
+    if (*frame).mode == CpuMode::User as usize {
+        // Keep what happened around for the crash report.
+        if !p.is_null() {
+            (*p).data.fault = Some(FaultInfo { signo, cause, tval });
+        }
+        force_signal((*frame).pid as u16, signo);
+        // Switching back to ourselves delivers the signal.
+        rust_switch_to_user(frame as usize);
     }
+    // A kernel process faulted. That's a kernel bug, so show where it is.
+    error!("Kernel process {} faulted, deleting it", (*frame).pid);
+    dump_registers(frame);
+    backtrace::print_backtrace((*frame).pc, (*frame).regs[Registers::S0 as usize]);
+    delete_process((*frame).pid as u16);
+    let frame = schedule();
+    schedule_next_context_switch(1);
+    rust_switch_to_user(frame);
+}
+
+pub fn schedule_next_context_switch(qm: u16) {
+    set_mtimecmp((get_mtime() as u64).wrapping_add(CONTEXT_SWITCH_TIME * qm as u64));
 }
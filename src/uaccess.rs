@@ -0,0 +1,258 @@
+//! # User memory access
+//!
+//! System calls get pointers from whoever made them, and those pointers
+//! can't be trusted. Everything that reads or writes memory on behalf of a
+//! process goes through here. We walk the page table for every page of the
+//! range and make sure it is a user page with the permission we need
+//! before touching anything, so a bad pointer turns into [`Fault`] (EFAULT
+//! on Linux) instead of a kernel panic or a write to somebody else's
+//! memory.
+//!
+//! Consecutive virtual pages aren't necessarily physically consecutive, so
+//! copies go one page at a time. A page that is in swap is brought back
+//! first, and a page we write to is marked dirty, since the MMU never sees
+//! what the kernel does through physical addresses.
+
+use alloc::{string::String, vec::Vec};
+use core::mem::{size_of, MaybeUninit};
+
+use crate::{
+    cpu::{memcpy, CpuMode, TrapFrame},
+    errno::{Errno, ENAMETOOLONG},
+    mmap,
+    page::{lookup, mark_dirty, EntryBits, Table, PAGE_SIZE},
+    process::get_by_pid,
+};
+
+/// A user pointer that doesn't point at memory the process may access the
+/// way we want to.
+#[derive(Debug)]
+pub struct Fault;
+
+/// What we are about to do with user memory
+#[derive(Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+    /// Read or write any user page, whatever its permissions say. This is
+    /// for debuggers, which patch breakpoints into read-only text.
+    Debug,
+}
+
+/// Whose memory a user pointer points into
+#[derive(Clone, Copy)]
+pub enum Space {
+    /// A kernel process. These see the kernel mapped one to one, so their
+    /// pointers are physical addresses and we take them as they are.
+    Kernel,
+    /// A user process with this page table. Looking at its memory can
+    /// change it, when a page comes back from swap or gets dirty.
+    User(*mut Table),
+}
+
+/// The memory of the process behind `frame`.
+pub unsafe fn caller(frame: *const TrapFrame) -> Result<Space, Fault> {
+    if (*frame).mode != CpuMode::User as usize {
+        return Ok(Space::Kernel);
+    }
+    let p = get_by_pid((*frame).pid as u16);
+    if p.is_null() {
+        return Err(Fault);
+    }
+    Ok(Space::User((*p).get_table_address() as *mut Table))
+}
+
+/// Translate `vaddr` to a physical address if the page it is in allows
+/// `access` from user mode.
+pub fn translate(space: Space, vaddr: usize, access: Access) -> Result<usize, Fault> {
+    let table = match space {
+        Space::Kernel => return Ok(vaddr),
+        Space::User(table) => table,
+    };
+    let (bits, level) = match unsafe { lookup(&*table, vaddr) } {
+        Some((entry, level)) => (entry.get_entry(), level),
+        None if mmap::page_in(table, vaddr) => unsafe {
+            let (entry, level) = lookup(&*table, vaddr).ok_or(Fault)?;
+            (entry.get_entry(), level)
+        },
+        None => return Err(Fault),
+    };
+    let needed = match access {
+        Access::Read => EntryBits::User.val() | EntryBits::Read.val(),
+        Access::Write => EntryBits::User.val() | EntryBits::Write.val(),
+        Access::Debug => EntryBits::User.val(),
+    };
+    if bits & needed != needed {
+        return Err(Fault);
+    }
+    if let Access::Write | Access::Debug = access {
+        unsafe { mark_dirty(&mut *table, vaddr) };
+    }
+    let off_mask = (1 << (12 + level * 9)) - 1;
+    Ok(((bits << 2) as usize & !off_mask) | (vaddr & off_mask))
+}
+
+/// Check that all of `len` bytes at `vaddr` allow `access`.
+pub fn access_ok(space: Space, vaddr: usize, len: usize, access: Access) -> Result<(), Fault> {
+    if len == 0 {
+        return Ok(());
+    }
+    let last = vaddr.checked_add(len - 1).ok_or(Fault)?;
+    let mut page = vaddr & !(PAGE_SIZE - 1);
+    while page <= last {
+        translate(space, page.max(vaddr), access)?;
+        page = match page.checked_add(PAGE_SIZE) {
+            Some(page) => page,
+            None => break,
+        };
+    }
+    Ok(())
+}
+
+/// Call `f` with the physical address and length of each piece of the
+/// range that sits in a single page.
+unsafe fn for_each_page(
+    space: Space,
+    vaddr: usize,
+    len: usize,
+    access: Access,
+    mut f: impl FnMut(usize, usize, usize),
+) -> Result<(), Fault> {
+    // Check everything first, so that a fault doesn't leave half a copy
+    // behind.
+    access_ok(space, vaddr, len, access)?;
+    let mut done = 0;
+    while done < len {
+        let v = vaddr + done;
+        let chunk = (PAGE_SIZE - (v % PAGE_SIZE)).min(len - done);
+        f(done, translate(space, v, access)?, chunk);
+        done += chunk;
+    }
+    Ok(())
+}
+
+/// Copy `len` bytes from user memory at `vaddr` to `dst`.
+pub unsafe fn read_raw(
+    space: Space,
+    dst: *mut u8,
+    vaddr: usize,
+    len: usize,
+    access: Access,
+) -> Result<(), Fault> {
+    for_each_page(space, vaddr, len, access, |done, paddr, chunk| {
+        memcpy(dst.add(done), paddr as *const u8, chunk);
+    })
+}
+
+/// Copy `len` bytes from `src` to user memory at `vaddr`.
+pub unsafe fn write_raw(
+    space: Space,
+    vaddr: usize,
+    src: *const u8,
+    len: usize,
+    access: Access,
+) -> Result<(), Fault> {
+    for_each_page(space, vaddr, len, access, |done, paddr, chunk| {
+        memcpy(paddr as *mut u8, src.add(done), chunk);
+    })
+}
+
+/// Fill `dst` from user memory at `vaddr`.
+pub unsafe fn copy_from_user(space: Space, dst: &mut [u8], vaddr: usize) -> Result<(), Fault> {
+    read_raw(space, dst.as_mut_ptr(), vaddr, dst.len(), Access::Read)
+}
+
+/// Copy `src` into user memory at `vaddr`.
+pub unsafe fn copy_to_user(space: Space, vaddr: usize, src: &[u8]) -> Result<(), Fault> {
+    write_raw(space, vaddr, src.as_ptr(), src.len(), Access::Write)
+}
+
+/// Copy the NUL-terminated string at `vaddr` into `dst`, NUL included if
+/// it fits. Returns the length of the string, or `dst.len()` if there was
+/// no NUL in that many bytes. The string may end right before an unmapped
+/// page, so we can't check the whole of `dst` up front.
+pub unsafe fn strncpy_from_user(
+    space: Space,
+    dst: &mut [u8],
+    vaddr: usize,
+) -> Result<usize, Fault> {
+    let mut done = 0;
+    while done < dst.len() {
+        let v = vaddr.checked_add(done).ok_or(Fault)?;
+        let chunk = (PAGE_SIZE - (v % PAGE_SIZE)).min(dst.len() - done);
+        let paddr = translate(space, v, Access::Read)?;
+        for i in 0..chunk {
+            let c = *((paddr + i) as *const u8);
+            dst[done + i] = c;
+            if c == 0 {
+                return Ok(done + i);
+            }
+        }
+        done += chunk;
+    }
+    Ok(done)
+}
+
+/// Read the bytes of a NUL-terminated string of at most `max` bytes, not
+/// counting the NUL, out of user memory.
+pub unsafe fn bytes_from_user(space: Space, vaddr: usize, max: usize) -> Result<Vec<u8>, Errno> {
+    let mut buf = alloc::vec![0_u8; max + 1];
+    let len = strncpy_from_user(space, &mut buf, vaddr)?;
+    if len > max {
+        return Err(ENAMETOOLONG);
+    }
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Read a NUL-terminated string of at most `max` bytes, not counting the
+/// NUL, out of user memory.
+pub unsafe fn string_from_user(space: Space, vaddr: usize, max: usize) -> Result<String, Errno> {
+    let bytes = bytes_from_user(space, vaddr, max)?;
+    Ok(bytes.iter().map(|&c| c as char).collect())
+}
+
+/// Read a `T` out of user memory.
+pub unsafe fn read_user<T: Copy>(space: Space, vaddr: usize) -> Result<T, Fault> {
+    let mut val = MaybeUninit::<T>::uninit();
+    read_raw(
+        space,
+        val.as_mut_ptr() as *mut u8,
+        vaddr,
+        size_of::<T>(),
+        Access::Read,
+    )?;
+    Ok(val.assume_init())
+}
+
+/// Write a `T` into user memory.
+pub unsafe fn write_user<T: Copy>(space: Space, vaddr: usize, val: &T) -> Result<(), Fault> {
+    write_raw(
+        space,
+        vaddr,
+        val as *const T as *const u8,
+        size_of::<T>(),
+        Access::Write,
+    )
+}
+
+/// The physical address of `len` bytes at `vaddr`, for a device that
+/// reads or writes them by itself. The device knows nothing of pages, so
+/// the range has to be physically contiguous as well.
+pub fn translate_range(
+    space: Space,
+    vaddr: usize,
+    len: usize,
+    access: Access,
+) -> Result<usize, Fault> {
+    access_ok(space, vaddr, len, access)?;
+    let start = translate(space, vaddr, access)?;
+    let mut page = (vaddr & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+    while page < vaddr + len {
+        if translate(space, page, access)? != start + (page - vaddr) {
+            return Err(Fault);
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(start)
+}
@@ -1,8 +1,55 @@
+//! # UART
+//!
+//! The NS16550A serial port. [`Uart`] is the bare device. It writes
+//! synchronously, waiting for the transmitter before every byte.
+//!
+//! The kernel console goes through [`UART0`] instead. Output is queued in
+//! a transmit ring buffer and handed to the device 16 bytes at a time (the
+//! size of its FIFO) whenever it raises the THR-empty interrupt, so that
+//! printing doesn't stall the scheduler. A lock keeps lines from different
+//! harts and from interrupt context from getting mixed up. Once we panic,
+//! the driver drains the buffer and falls back to synchronous writes
+//! without taking the lock, since whoever holds it may never let go.
+
 use core::{
     convert::TryInto,
-    fmt::{Error, Write},
+    fmt::{self, Error, Write},
+};
+
+use crate::{
+    console,
+    cpu::{mstatus_read, mstatus_write},
+    fdt,
+    lock::Mutex,
+    page::PAGE_SIZE,
 };
 
+/// The base address of the UART on the QEMU virt machine, if the device
+/// tree doesn't say
+pub const UART0_BASE: usize = 0x1000_0000;
+/// The interrupt the UART raises on the QEMU virt machine
+pub const UART0_IRQ: u32 = 10;
+/// How many bytes of output we queue before writing synchronously
+pub const TX_BUFFER_SIZE: usize = 4096;
+/// The transmit FIFO of the 16550 holds this many bytes.
+const TX_FIFO_SIZE: usize = 16;
+
+// Registers, as offsets from the base address
+const RBR_THR: usize = 0;
+const IER: usize = 1;
+const LSR: usize = 5;
+
+// Interrupt enable register bits
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+
+// Line status register bits
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+// The machine interrupt enable bit in mstatus
+const MSTATUS_MIE: usize = 1 << 3;
+
 pub struct Uart {
     base_address: usize,
 }
@@ -45,7 +92,7 @@ impl Uart {
             // Enable receiver buffer interrupts, which is at bit
             // index 0 of the interrupt enable register (IER at
             // offset 1).
-            ptr.add(1).write_volatile(1 << 0);
+            ptr.add(IER).write_volatile(IER_RX_AVAILABLE);
 
             // If we cared about the divisor, the code below would
             // set the divisor from a global clock rate of 22.729
@@ -96,23 +143,201 @@ impl Uart {
         }
     }
 
+    /// Wait for the transmitter to become free and write `c`.
     pub fn put(&mut self, c: u8) {
         let ptr = self.base_address as *mut u8;
         unsafe {
-            ptr.add(0).write_volatile(c);
+            while ptr.add(LSR).read_volatile() & LSR_THR_EMPTY == 0 {}
+            ptr.add(RBR_THR).write_volatile(c);
         }
     }
 
     pub fn get(&mut self) -> Option<u8> {
         let ptr = self.base_address as *mut u8;
         unsafe {
-            if ptr.add(5).read_volatile() & 1 == 0 {
+            if ptr.add(LSR).read_volatile() & LSR_DATA_READY == 0 {
                 // The DR bit is 0, meaning no data
                 None
             } else {
                 // The DR bit is 1, meaning data!
-                Some(ptr.add(0).read_volatile())
+                Some(ptr.add(RBR_THR).read_volatile())
+            }
+        }
+    }
+
+    /// Is the transmit holding register (and with it the FIFO) empty?
+    fn is_tx_empty(&self) -> bool {
+        let ptr = self.base_address as *const u8;
+        unsafe { ptr.add(LSR).read_volatile() & LSR_THR_EMPTY != 0 }
+    }
+
+    /// Turn the THR-empty interrupt on or off.
+    fn set_tx_interrupt(&mut self, enabled: bool) {
+        let ptr = self.base_address as *mut u8;
+        unsafe {
+            let ier = ptr.add(IER).read_volatile();
+            if enabled {
+                ptr.add(IER).write_volatile(ier | IER_THR_EMPTY);
+            } else {
+                ptr.add(IER).write_volatile(ier & !IER_THR_EMPTY);
+            }
+        }
+    }
+}
+
+/// The interrupt-driven console UART.
+pub struct UartDriver {
+    uart: Uart,
+    lock: Mutex,
+    tx: [u8; TX_BUFFER_SIZE],
+    /// Next byte to hand to the device
+    head: usize,
+    /// Next free slot
+    tail: usize,
+    /// Set once we panic. Everything is written synchronously after that.
+    sync: bool,
+}
+
+/// Where the registers of [`UART0`] are, and how many bytes of them
+pub fn mmio() -> (usize, usize) {
+    (fdt::uart().map_or(UART0_BASE, |uart| uart.base), PAGE_SIZE)
+}
+
+/// The interrupt [`UART0`] raises
+pub fn irq() -> u32 {
+    fdt::uart().map_or(UART0_IRQ, |uart| uart.irq)
+}
+
+pub static mut UART0: UartDriver = UartDriver::new(UART0_BASE);
+
+impl UartDriver {
+    pub const fn new(base_address: usize) -> Self {
+        Self {
+            uart: Uart::new(base_address),
+            lock: Mutex::new(),
+            tx: [0; TX_BUFFER_SIZE],
+            head: 0,
+            tail: 0,
+            sync: false,
+        }
+    }
+
+    pub fn init(&mut self) {
+        if let Some(uart) = fdt::uart() {
+            self.uart = Uart::new(uart.base);
+        }
+        self.uart.init();
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    fn is_full(&self) -> bool {
+        (self.tail + 1) % TX_BUFFER_SIZE == self.head
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let c = self.tx[self.head];
+        self.head = (self.head + 1) % TX_BUFFER_SIZE;
+        Some(c)
+    }
+
+    /// Queue one byte. The lock has to be held.
+    fn push(&mut self, c: u8) {
+        if self.is_full() {
+            // Rather than throwing output away, we make room the slow way.
+            if let Some(old) = self.pop() {
+                self.uart.put(old);
             }
         }
+        self.tx[self.tail] = c;
+        self.tail = (self.tail + 1) % TX_BUFFER_SIZE;
+    }
+
+    /// Fill the device's FIFO from the buffer if it is empty and ask for an
+    /// interrupt once it has been sent. The lock has to be held.
+    fn kick(&mut self) {
+        if self.uart.is_tx_empty() {
+            for _ in 0..TX_FIFO_SIZE {
+                match self.pop() {
+                    Some(c) => unsafe {
+                        (self.uart.base_address as *mut u8)
+                            .add(RBR_THR)
+                            .write_volatile(c);
+                    },
+                    None => break,
+                }
+            }
+        }
+        self.uart.set_tx_interrupt(!self.is_empty());
+    }
+
+    /// Run `f` with the lock held and interrupts off. The interrupt handler
+    /// takes the same lock, so it must not be able to cut in on this hart.
+    fn locked<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let mstatus = mstatus_read();
+        mstatus_write(mstatus & !MSTATUS_MIE);
+        self.lock.spin_lock();
+        let ret = f(self);
+        self.lock.unlock();
+        mstatus_write(mstatus);
+        ret
+    }
+
+    /// Write formatted output as one piece.
+    pub fn write_fmt_locked(&mut self, args: fmt::Arguments) {
+        if self.sync {
+            let _ = self.uart.write_fmt(args);
+            return;
+        }
+        self.locked(|driver| {
+            let _ = fmt::write(&mut Queue(driver), args);
+            driver.kick();
+        });
+    }
+
+    /// The UART interrupted. Hand everything it received to the console and
+    /// keep the transmitter busy.
+    pub fn handle_interrupt(&mut self) {
+        // The console echoes what it receives, which takes the lock, so we
+        // can't hold it here.
+        while let Some(c) = self.uart.get() {
+            console::receive(c);
+        }
+        if !self.sync {
+            self.locked(Self::kick);
+        }
+    }
+
+    /// We're going down. Send whatever is still queued and write
+    /// synchronously from now on. We don't take the lock, because the code
+    /// that panicked may be holding it.
+    pub fn panic_mode(&mut self) {
+        self.sync = true;
+        self.uart.set_tx_interrupt(false);
+        while let Some(c) = self.pop() {
+            self.uart.put(c);
+        }
+    }
+}
+
+/// Print to the console. This is what `print!` and `println!` use.
+pub fn print(args: fmt::Arguments) {
+    unsafe { UART0.write_fmt_locked(args) };
+}
+
+/// Writes into the transmit buffer of a driver whose lock is held.
+struct Queue<'a>(&'a mut UartDriver);
+
+impl Write for Queue<'_> {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        for c in out.bytes() {
+            self.0.push(c);
+        }
+        Ok(())
     }
 }
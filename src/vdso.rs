@@ -0,0 +1,111 @@
+//! # Time page
+//!
+//! Asking the kernel for the time means a trap into machine mode just to
+//! read `mtime`. Like Linux' vDSO, we save programs the trip: every process
+//! gets a read-only page at [`TIME_PAGE_ADDR`] that says how fast the timer
+//! ticks and where it stood at boot. With that, a program reads the `time`
+//! CSR, which mirrors `mtime`, and works out `clock_gettime` by itself (see
+//! `userspace/startlib/time.cpp`).
+//!
+//! User mode may only read `time` if we say so in `mcounteren` and
+//! `scounteren`, and even then the hardware has to implement it. If the
+//! read traps anyway, we emulate it once, clear [`TIME_CSR`] in the page and
+//! programs go back to the `get_time` system call.
+
+use core::ptr::null_mut;
+
+use crate::{
+    cpu::{get_mtime, mcounteren_write, scounteren_write, CpuMode, Registers, TrapFrame, FREQ},
+    page::{map, zalloc, EntryBits, Table},
+};
+
+/// Where the time page is in every process, right below the sigreturn
+/// trampoline.
+pub const TIME_PAGE_ADDR: usize = 0x0fff_e000;
+
+/// What the time page starts with, "TIME"
+pub const TIME_PAGE_MAGIC: u32 = 0x454d_4954;
+
+/// `flags` bit: the `time` CSR can be read from user mode.
+pub const TIME_CSR: u32 = 1 << 0;
+
+/// The TM bit of `mcounteren` and `scounteren`
+const COUNTEREN_TM: usize = 1 << 1;
+
+/// What programs find at [`TIME_PAGE_ADDR`]. `startlib/time.h` mirrors it.
+#[repr(C)]
+pub struct TimePage {
+    pub magic: u32,
+    pub flags: u32,
+    /// Timer ticks per second
+    pub freq: u64,
+    /// The timer at boot. The time since boot is `time - boot_time`.
+    pub boot_time: u64,
+}
+
+static mut TIME_PAGE: *mut TimePage = null_mut();
+
+/// Fill in the time page and let user mode read the `time` CSR.
+pub fn init() {
+    mcounteren_write(COUNTEREN_TM);
+    scounteren_write(COUNTEREN_TM);
+    let page = zalloc(1) as *mut TimePage;
+    unsafe {
+        page.write(TimePage {
+            magic: TIME_PAGE_MAGIC,
+            flags: TIME_CSR,
+            freq: FREQ,
+            boot_time: get_mtime() as u64,
+        });
+        TIME_PAGE = page;
+    }
+}
+
+/// Map the time page into a process.
+pub fn map_time_page(table: &mut Table) {
+    unsafe {
+        if !TIME_PAGE.is_null() {
+            map(
+                table,
+                TIME_PAGE_ADDR,
+                TIME_PAGE as usize,
+                EntryBits::User.val() | EntryBits::Read.val(),
+                0,
+            );
+        }
+    }
+}
+
+/// Timer ticks since boot
+pub fn uptime() -> usize {
+    unsafe {
+        if TIME_PAGE.is_null() {
+            get_mtime()
+        } else {
+            get_mtime() - (*TIME_PAGE).boot_time as usize
+        }
+    }
+}
+
+/// `rdtime rd`, which is `csrrs rd, time, zero`, with rd masked out
+const RDTIME: usize = 0xc010_2073;
+const RDTIME_MASK: usize = 0xffff_f07f;
+
+/// If the illegal instruction `insn` of a user process is a read of the
+/// `time` CSR, do it for the process and stop advertising the CSR. Returns
+/// whether that is what happened, in which case the process goes on with
+/// the next instruction.
+pub unsafe fn emulate_rdtime(frame: *mut TrapFrame, insn: usize) -> bool {
+    if (*frame).mode != CpuMode::User as usize || insn & RDTIME_MASK != RDTIME {
+        return false;
+    }
+    if !TIME_PAGE.is_null() && (*TIME_PAGE).flags & TIME_CSR != 0 {
+        (*TIME_PAGE).flags &= !TIME_CSR;
+        info!("The time CSR can't be read from user mode, programs will ask instead");
+    }
+    let rd = (insn >> 7) & 0x1f;
+    if rd != Registers::Zero as usize {
+        (*frame).regs[rd] = get_mtime();
+    }
+    true
+}
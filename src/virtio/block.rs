@@ -1,7 +1,8 @@
-use alloc::boxed::Box;
-use core::mem::size_of;
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::{hint::spin_loop, mem::size_of};
 
 use crate::{
+    errno::{Errno, EINVAL, EIO, ENODEV, EROFS},
     kmem::{kfree, kmalloc},
     page::{zalloc, PAGE_SIZE},
     process::{add_kernel_process_args, get_by_pid, set_running, set_waiting},
@@ -98,6 +99,9 @@ pub struct BlockDevice {
     idx: u16,
     ack_used_idx: u16,
     read_only: bool,
+    // The buffer and size of each request the device hasn't finished, by
+    // the descriptor it starts at. Swap must not take these pages away.
+    in_flight: BTreeMap<u16, (usize, usize)>,
 }
 
 // Type values
@@ -132,6 +136,32 @@ pub enum BlockErrors {
     ReadOnly,
 }
 
+impl From<BlockErrors> for Errno {
+    fn from(err: BlockErrors) -> Self {
+        match err {
+            BlockErrors::Success => Self(0),
+            BlockErrors::BlockDeviceNotFound => ENODEV,
+            BlockErrors::InvalidArgument => EINVAL,
+            BlockErrors::ReadOnly => EROFS,
+        }
+    }
+}
+
+/// What the status the device wrote for a request means
+fn status_result(status: u8) -> Result<(), Errno> {
+    match status {
+        VIRTIO_BLK_S_OK => Ok(()),
+        VIRTIO_BLK_S_UNSUPP => Err(EINVAL),
+        _ => Err(EIO),
+    }
+}
+
+/// What the watcher of a request gets in A0 for the status the device
+/// wrote: 0, or the negated error number.
+fn status_return(status: u8) -> usize {
+    status_result(status).map_or_else(Errno::as_return, |_| 0)
+}
+
 // Much like with processes, Rust requires some initialization
 // when we declare a static. In this case, we use the Option
 // value type to signal that the variable exists, but not the
@@ -141,14 +171,8 @@ static mut BLOCK_DEVICES: [Option<BlockDevice>; 8] =
     [None, None, None, None, None, None, None, None];
 
 pub unsafe fn setup_block_device(ptr: *mut u32) -> bool {
-    // We can get the index of the device based on its address.
-    // 0x1000_1000 is index 0
-    // 0x1000_2000 is index 1
-    // ...
-    // 0x1000_8000 is index 7
-    // To get the number that changes over, we shift right 12 places
-    // (3 hex digits)
-    let idx = (ptr as usize - virtio::MMIO_VIRTIO_START) >> 12;
+    // Drivers keep their devices by slot in the device table.
+    let idx = virtio::slot(ptr);
     // [Driver] Device Initialization
     // 1. Reset the device (write 0 into status)
     ptr.add(MmioOffsets::Status.scale32()).write_volatile(0);
@@ -179,7 +203,7 @@ pub unsafe fn setup_block_device(ptr: *mut u32) -> bool {
     // the features that we request. Therefore, this is
     // considered a "failed" state.
     if !StatusField::features_ok(status_ok) {
-        print!("features fail...");
+        warn!("features fail");
         ptr.add(MmioOffsets::Status.scale32())
             .write_volatile(StatusField::Failed.val32());
         return false;
@@ -191,7 +215,7 @@ pub unsafe fn setup_block_device(ptr: *mut u32) -> bool {
     ptr.add(MmioOffsets::QueueNum.scale32())
         .write_volatile(VIRTIO_RING_SIZE as u32);
     if VIRTIO_RING_SIZE as u32 > qnmax {
-        print!("queue size fail...");
+        warn!("queue size fail");
         return false;
     } // First, if the block device array is empty, create it!
       // We add 4095 to round this up and then do an integer
@@ -235,6 +259,7 @@ pub unsafe fn setup_block_device(ptr: *mut u32) -> bool {
         idx: 0,
         ack_used_idx: 0,
         read_only: ro,
+        in_flight: BTreeMap::new(),
     };
     BLOCK_DEVICES[idx] = Some(bd);
 
@@ -262,6 +287,41 @@ pub fn fill_next_descriptor(bd: &mut BlockDevice, desc: Descriptor) -> u16 {
         bd.idx
     }
 }
+/// The block device with number `dev`, which counts from 1
+fn device(dev: usize) -> Option<&'static mut BlockDevice> {
+    unsafe {
+        dev.checked_sub(1)
+            .and_then(|idx| BLOCK_DEVICES.get_mut(idx))
+            .and_then(Option::as_mut)
+    }
+}
+
+/// How many bytes block device `dev` has
+pub fn capacity(dev: usize) -> Option<u64> {
+    let bdev = device(dev)?;
+    unsafe {
+        let config = bdev.dev.add(MmioOffsets::Config.scale32()) as *const Config;
+        Some((*config).capacity * 512)
+    }
+}
+
+/// Can block device `dev` only be read?
+pub fn read_only(dev: usize) -> Option<bool> {
+    device(dev).map(|bdev| bdev.read_only)
+}
+
+/// Is the device about to read or write any of the `len` bytes at the
+/// physical address `addr`?
+pub fn in_flight(addr: usize, len: usize) -> bool {
+    unsafe {
+        BLOCK_DEVICES.iter().flatten().any(|bdev| {
+            bdev.in_flight
+                .values()
+                .any(|&(buffer, size)| buffer < addr + len && addr < buffer + size)
+        })
+    }
+}
+
 /// This is now a common block operation for both reads and writes. Therefore,
 /// when one thing needs to change, we can change it for both reads and writes.
 /// There is a lot of error checking that I haven't done. The block device reads
@@ -278,12 +338,24 @@ pub fn block_op(
     write: bool,
     watcher: u16,
 ) -> Result<u32, BlockErrors> {
+    submit(dev, buffer, size, offset, write, watcher).map(|_| size)
+}
+
+/// Hand a request to the device and return the descriptor it starts at.
+fn submit(
+    dev: usize,
+    buffer: *mut u8,
+    size: u32,
+    offset: u64,
+    write: bool,
+    watcher: u16,
+) -> Result<u16, BlockErrors> {
     unsafe {
-        if let Some(bdev) = BLOCK_DEVICES[dev - 1].as_mut() {
+        if let Some(bdev) = device(dev) {
             // Check to see if we are trying to write to a read only
             // device.
             if bdev.read_only && write {
-                println!("Trying to write to read/only!");
+                warn!("Trying to write to read/only!");
                 return Err(BlockErrors::ReadOnly);
             }
             if size % 512 != 0 {
@@ -338,6 +410,8 @@ pub fn block_op(
                 next: 0,
             };
             let _status_idx = fill_next_descriptor(bdev, desc);
+            bdev.in_flight
+                .insert(head_idx, (buffer as usize, size as usize));
             (*bdev.queue).avail.ring[(*bdev.queue).avail.idx as usize % virtio::VIRTIO_RING_SIZE] =
                 head_idx;
             (*bdev.queue).avail.idx = (*bdev.queue).avail.idx.wrapping_add(1);
@@ -346,7 +420,7 @@ pub fn block_op(
             bdev.dev
                 .add(MmioOffsets::QueueNotify.scale32())
                 .write_volatile(0);
-            Ok(size)
+            Ok(head_idx)
         } else {
             Err(BlockErrors::BlockDeviceNotFound)
         }
@@ -361,20 +435,52 @@ pub fn write(dev: usize, buffer: *mut u8, size: u32, offset: u64) -> Result<u32,
     block_op(dev, buffer, size, offset, true, 0)
 }
 
+/// Read or write like [`block_op`], but wait right here until the device is
+/// done. This is for the trap handler, which can't sleep: swap pages in
+/// and out from there. Interrupts are off, so we wind up the used ring
+/// ourselves instead of waiting for [`handle_interrupt`].
+pub fn block_op_sync(
+    dev: usize,
+    buffer: *mut u8,
+    size: u32,
+    offset: u64,
+    write: bool,
+) -> Result<(), Errno> {
+    let head = submit(dev, buffer, size, offset, write, 0)?;
+    let bdev = device(dev).ok_or(ENODEV)?;
+    loop {
+        if let Some(status) = reap(bdev, Some(head)) {
+            return status_result(status);
+        }
+        spin_loop();
+    }
+}
+
 /// Here we handle block specific interrupts. Here, we need to check
 /// the used ring and wind it up until we've handled everything.
 /// This is how the device tells us that it's finished a request.
 pub fn pending(bd: &mut BlockDevice) {
+    reap(bd, None);
+}
+
+/// Wind up the used ring. If the request that starts at descriptor `head`
+/// is among those the device finished, return the status it got.
+fn reap(bd: &mut BlockDevice, head: Option<u16>) -> Option<u8> {
+    let mut head_status = None;
     // Here we need to check the used ring and then free the resources
     // given by the descriptor id.
     unsafe {
         let queue = &(*bd.queue);
-        while bd.ack_used_idx != queue.used.idx {
+        while bd.ack_used_idx != (&queue.used.idx as *const u16).read_volatile() {
             let elem = &queue.used.ring[bd.ack_used_idx as usize % VIRTIO_RING_SIZE];
             bd.ack_used_idx = bd.ack_used_idx.wrapping_add(1);
+            bd.in_flight.remove(&(elem.id as u16));
             // Requests stay resident on the heap until this
             // function, so we can recapture the address here
             let rq = queue.desc[elem.id as usize].addr as *const Request;
+            if head == Some(elem.id as u16) {
+                head_status = Some((*rq).status.status);
+            }
 
             // A process might be waiting for this interrupt. Awaken
             // the process attached here.
@@ -383,13 +489,16 @@ pub fn pending(bd: &mut BlockDevice) {
             if pid_of_watcher > 0 {
                 set_running(pid_of_watcher);
                 let proc = get_by_pid(pid_of_watcher);
-                (*(*proc).get_frame_mut()).regs[10] = (*rq).status.status as usize;
-                // TODO: Set GpA0 to the value of the return
-                // status.
+                // The watcher may have been killed by a signal while it
+                // waited for us.
+                if !proc.is_null() {
+                    (*(*proc).get_frame_mut()).regs[10] = status_return((*rq).status.status);
+                }
             }
             kfree(rq as *mut u8);
         }
     }
+    head_status
 }
 
 /// The trap code will route PLIC interrupts 1..=8 for virtio devices. When
@@ -399,7 +508,7 @@ pub fn handle_interrupt(idx: usize) {
         if let Some(bdev) = BLOCK_DEVICES[idx].as_mut() {
             pending(bdev);
         } else {
-            println!("Invalid block device for interrupt {}", idx + 1);
+            error!("Invalid block device for interrupt {}", idx + 1);
         }
     }
 }
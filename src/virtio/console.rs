@@ -0,0 +1,217 @@
+//! # Virtio console
+//!
+//! A virtio console port used as a second serial line. The UART belongs to
+//! the shell, so this is where the GDB stub (see [`crate::gdb`]) talks to
+//! the debugger. With QEMU:
+//!
+//! ```text
+//! -device virtio-serial-device -chardev socket,id=gdb,port=4321,host=localhost,server=on,wait=off
+//! -device virtconsole,chardev=gdb
+//! ```
+//!
+//! We don't negotiate `VIRTIO_CONSOLE_F_MULTIPORT`, so there is just port 0
+//! with its receive queue 0 and transmit queue 1.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use crate::{
+    cpu::memcpy,
+    gdb,
+    kmem::{kfree, kmalloc},
+    page::{zalloc, PAGE_SIZE},
+    virtio::{
+        self, Descriptor, MmioOffsets, Queue, StatusField, VIRTIO_DESC_F_WRITE,
+        VIRTIO_F_RING_EVENT_IDX, VIRTIO_RING_SIZE,
+    },
+};
+
+/// Feature bit for more than one port. We stick to port 0.
+const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1;
+
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+/// Receive buffers that we keep posted. Bigger packets simply come in
+/// several pieces.
+const RX_BUFFERS: usize = 8;
+const RX_BUFFER_SIZE: usize = 256;
+
+pub struct ConsoleDevice {
+    rx_queue: *mut Queue,
+    tx_queue: *mut Queue,
+    dev: *mut u32,
+    rx_ack_used_idx: u16,
+    rx_buffer: *mut u8,
+    tx_idx: u16,
+    tx_ack_used_idx: u16,
+}
+
+static mut CONSOLE_DEVICES: [Option<ConsoleDevice>; 8] =
+    [None, None, None, None, None, None, None, None];
+
+/// Give `queue` to the device as the currently selected queue.
+unsafe fn setup_queue(ptr: *mut u32, sel: u32) -> *mut Queue {
+    let num_pages = (size_of::<Queue>() + PAGE_SIZE - 1) / PAGE_SIZE;
+    ptr.add(MmioOffsets::QueueSel.scale32()).write_volatile(sel);
+    ptr.add(MmioOffsets::QueueNum.scale32())
+        .write_volatile(VIRTIO_RING_SIZE as u32);
+    let queue = zalloc(num_pages) as *mut Queue;
+    ptr.add(MmioOffsets::GuestPageSize.scale32())
+        .write_volatile(PAGE_SIZE as u32);
+    ptr.add(MmioOffsets::QueuePfn.scale32())
+        .write_volatile(queue as u32 / PAGE_SIZE as u32);
+    queue
+}
+
+pub unsafe fn setup_console_device(ptr: *mut u32) -> bool {
+    let idx = virtio::slot(ptr);
+    // The same dance as for every other device, see block.rs.
+    ptr.add(MmioOffsets::Status.scale32()).write_volatile(0);
+    let mut status_bits = StatusField::Acknowledge.val32();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits);
+    status_bits |= StatusField::Driver.val32();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits);
+    let mut host_features = ptr.add(MmioOffsets::HostFeatures.scale32()).read_volatile();
+    host_features &= !(1 << VIRTIO_F_RING_EVENT_IDX | 1 << VIRTIO_CONSOLE_F_MULTIPORT);
+    ptr.add(MmioOffsets::GuestFeatures.scale32())
+        .write_volatile(host_features);
+    status_bits |= StatusField::FeaturesOk.val32();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits);
+    let status_ok = ptr.add(MmioOffsets::Status.scale32()).read_volatile();
+    if !StatusField::features_ok(status_ok) {
+        warn!("features fail");
+        ptr.add(MmioOffsets::Status.scale32())
+            .write_volatile(StatusField::Failed.val32());
+        return false;
+    }
+    let qnmax = ptr.add(MmioOffsets::QueueNumMax.scale32()).read_volatile();
+    if VIRTIO_RING_SIZE as u32 > qnmax {
+        warn!("queue size fail");
+        return false;
+    }
+    let rx_queue = setup_queue(ptr, RX_QUEUE);
+    let tx_queue = setup_queue(ptr, TX_QUEUE);
+    status_bits |= StatusField::DriverOk.val32();
+    ptr.add(MmioOffsets::Status.scale32())
+        .write_volatile(status_bits);
+
+    let mut dev = ConsoleDevice {
+        rx_queue,
+        tx_queue,
+        dev: ptr,
+        rx_ack_used_idx: 0,
+        rx_buffer: kmalloc(RX_BUFFERS * RX_BUFFER_SIZE),
+        tx_idx: 0,
+        tx_ack_used_idx: 0,
+    };
+    // Receive descriptor i always points at receive buffer i.
+    for i in 0..RX_BUFFERS {
+        (*dev.rx_queue).desc[i] = Descriptor {
+            addr: dev.rx_buffer.add(i * RX_BUFFER_SIZE) as u64,
+            len: RX_BUFFER_SIZE as u32,
+            flags: VIRTIO_DESC_F_WRITE,
+            next: 0,
+        };
+        post_rx(&mut dev, i as u16);
+    }
+    dev.dev
+        .add(MmioOffsets::QueueNotify.scale32())
+        .write_volatile(RX_QUEUE);
+    CONSOLE_DEVICES[idx] = Some(dev);
+    true
+}
+
+/// Hand receive descriptor `desc` (back) to the device.
+unsafe fn post_rx(dev: &mut ConsoleDevice, desc: u16) {
+    let avail = &mut (*dev.rx_queue).avail;
+    avail.ring[avail.idx as usize % VIRTIO_RING_SIZE] = desc;
+    avail.idx = avail.idx.wrapping_add(1);
+}
+
+/// Send `data` out of the first console there is. Returns false if there
+/// is none.
+pub fn write(data: &[u8]) -> bool {
+    unsafe {
+        let dev = match CONSOLE_DEVICES.iter_mut().flatten().next() {
+            Some(dev) => dev,
+            None => return false,
+        };
+        // The device reads the data whenever it gets around to it, so it
+        // needs a copy of its own. That is freed once the device is done.
+        let buffer = kmalloc(data.len());
+        memcpy(buffer, data.as_ptr(), data.len());
+        let head = dev.tx_idx;
+        dev.tx_idx = (dev.tx_idx + 1) % VIRTIO_RING_SIZE as u16;
+        (*dev.tx_queue).desc[head as usize] = Descriptor {
+            addr: buffer as u64,
+            len: data.len() as u32,
+            flags: 0,
+            next: 0,
+        };
+        let avail = &mut (*dev.tx_queue).avail;
+        avail.ring[avail.idx as usize % VIRTIO_RING_SIZE] = head;
+        avail.idx = avail.idx.wrapping_add(1);
+        dev.dev
+            .add(MmioOffsets::QueueNotify.scale32())
+            .write_volatile(TX_QUEUE);
+        true
+    }
+}
+
+/// Collect what came in and free what went out.
+fn pending(dev: &mut ConsoleDevice) -> Vec<u8> {
+    let mut input = Vec::new();
+    unsafe {
+        let queue = &*dev.tx_queue;
+        while dev.tx_ack_used_idx != queue.used.idx {
+            let elem = &queue.used.ring[dev.tx_ack_used_idx as usize % VIRTIO_RING_SIZE];
+            kfree(queue.desc[elem.id as usize].addr as *mut u8);
+            dev.tx_ack_used_idx = dev.tx_ack_used_idx.wrapping_add(1);
+        }
+        let queue = &*dev.rx_queue;
+        let mut reposted = false;
+        while dev.rx_ack_used_idx != queue.used.idx {
+            let elem = &queue.used.ring[dev.rx_ack_used_idx as usize % VIRTIO_RING_SIZE];
+            let desc = &queue.desc[elem.id as usize];
+            let len = (elem.len as usize).min(RX_BUFFER_SIZE);
+            input.extend_from_slice(core::slice::from_raw_parts(desc.addr as *const u8, len));
+            post_rx(dev, elem.id as u16);
+            reposted = true;
+            dev.rx_ack_used_idx = dev.rx_ack_used_idx.wrapping_add(1);
+        }
+        if reposted {
+            dev.dev
+                .add(MmioOffsets::QueueNotify.scale32())
+                .write_volatile(RX_QUEUE);
+        }
+        // Lower the interrupt line again.
+        let status = dev
+            .dev
+            .add(MmioOffsets::InterruptStatus.scale32())
+            .read_volatile();
+        dev.dev
+            .add(MmioOffsets::InterruptAck.scale32())
+            .write_volatile(status);
+    }
+    input
+}
+
+pub fn handle_interrupt(idx: usize) {
+    let input = unsafe {
+        match CONSOLE_DEVICES[idx].as_mut() {
+            Some(dev) => pending(dev),
+            None => {
+                error!("Invalid console device for interrupt {}", idx + 1);
+                return;
+            }
+        }
+    };
+    // The stub answers through write(), so we must be done with the
+    // device before we hand it anything.
+    for c in input {
+        gdb::receive(c);
+    }
+}
@@ -3,7 +3,7 @@ use core::{mem::size_of, ptr::null_mut};
 
 use crate::{
     kmem::{kfree, kmalloc},
-    page::{zalloc, PAGE_SIZE},
+    page::{align_val, zalloc, PAGE_SIZE},
     virtio,
     virtio::{
         Descriptor, MmioOffsets, Queue, StatusField, VIRTIO_DESC_F_NEXT, VIRTIO_DESC_F_WRITE,
@@ -283,6 +283,13 @@ impl Device {
     pub const fn get_height(&self) -> u32 {
         self.height
     }
+
+    /// The bytes behind the framebuffer. We round it up to whole megapages,
+    /// so that user space gets it mapped with huge leaves.
+    pub const fn framebuffer_len(&self) -> usize {
+        let bytes = self.width as usize * self.height as usize * size_of::<Pixel>();
+        align_val(bytes, 21)
+    }
 }
 
 pub static mut GPU_DEVICES: [Option<Device>; 8] = [None, None, None, None, None, None, None, None];
@@ -610,13 +617,8 @@ pub fn transfer(gdev: usize, x: u32, y: u32, width: u32, height: u32) {
 }
 
 pub unsafe fn setup_gpu_device(ptr: *mut u32) -> bool {
-    // We can get the index of the device based on its address.
-    // 0x1000_1000 is index 0
-    // 0x1000_2000 is index 1
-    // ...
-    // 0x1000_8000 is index 7
-    // To get the number that changes over, we shift right 12 places (3 hex digits)
-    let idx = (ptr as usize - virtio::MMIO_VIRTIO_START) >> 12;
+    // Drivers keep their devices by slot in the device table.
+    let idx = virtio::slot(ptr);
     // [Driver] Device Initialization
     // 1. Reset the device (write 0 into status)
     ptr.add(MmioOffsets::Status.scale32()).write_volatile(0);
@@ -645,7 +647,7 @@ pub unsafe fn setup_gpu_device(ptr: *mut u32) -> bool {
     // the features that we request. Therefore, this is
     // considered a "failed" state.
     if !StatusField::features_ok(status_ok) {
-        print!("features fail...");
+        warn!("features fail");
         ptr.add(MmioOffsets::Status.scale32())
             .write_volatile(StatusField::Failed.val32());
         return false;
@@ -658,7 +660,7 @@ pub unsafe fn setup_gpu_device(ptr: *mut u32) -> bool {
     ptr.add(MmioOffsets::QueueNum.scale32())
         .write_volatile(VIRTIO_RING_SIZE as u32);
     if VIRTIO_RING_SIZE as u32 > qnmax {
-        print!("queue size fail...");
+        warn!("queue size fail");
         return false;
     }
     // First, if the block device array is empty, create it!
@@ -703,17 +705,16 @@ pub unsafe fn setup_gpu_device(ptr: *mut u32) -> bool {
 
     // We are going to give the framebuffer to user space, so this needs to be page aligned
     // so that we can map it into the user space's MMU. This is why we don't want kmalloc here!
-    let num_pages = (PAGE_SIZE * 2 + 640 * 480 * size_of::<Pixel>()) / PAGE_SIZE;
-    let page_alloc = zalloc(num_pages) as *mut Pixel;
-    let dev = Device {
+    let mut dev = Device {
         queue: queue_ptr,
         dev: ptr,
         idx: 0,
         ack_used_idx: 0,
-        framebuffer: page_alloc,
+        framebuffer: null_mut(),
         width: 640,
         height: 480,
     };
+    dev.framebuffer = zalloc(dev.framebuffer_len() / PAGE_SIZE) as *mut Pixel;
 
     GPU_DEVICES[idx] = Some(dev);
 
@@ -742,7 +743,7 @@ pub fn handle_interrupt(idx: usize) {
         if let Some(bdev) = GPU_DEVICES[idx].as_mut() {
             pending(bdev);
         } else {
-            println!("Invalid GPU device for interrupt {}", idx + 1);
+            error!("Invalid GPU device for interrupt {}", idx + 1);
         }
     }
 }
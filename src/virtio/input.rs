@@ -5,7 +5,7 @@ use crate::{
     kmem::kmalloc,
     page::{zalloc, PAGE_SIZE},
     virtio::{
-        Descriptor, MmioOffsets, Queue, StatusField, MMIO_VIRTIO_START, VIRTIO_DESC_F_WRITE,
+        self, Descriptor, MmioOffsets, Queue, StatusField, VIRTIO_DESC_F_WRITE,
         VIRTIO_F_RING_EVENT_IDX, VIRTIO_RING_SIZE,
     },
 };
@@ -114,13 +114,8 @@ pub static mut INPUT_DEVICES: [Option<Device>; 8] =
     [None, None, None, None, None, None, None, None];
 
 pub unsafe fn setup_input_device(ptr: *mut u32) -> bool {
-    // We can get the index of the device based on its address.
-    // 0x1000_1000 is index 0
-    // 0x1000_2000 is index 1
-    // ...
-    // 0x1000_8000 is index 7
-    // To get the number that changes over, we shift right 12 places (3 hex digits)
-    let idx = (ptr as usize - MMIO_VIRTIO_START) >> 12;
+    // Drivers keep their devices by slot in the device table.
+    let idx = virtio::slot(ptr);
     // [Driver] Device Initialization
     // 1. Reset the device (write 0 into status)
     ptr.add(MmioOffsets::Status.scale32()).write_volatile(0);
@@ -151,7 +146,7 @@ pub unsafe fn setup_input_device(ptr: *mut u32) -> bool {
     // the features that we request. Therefore, this is
     // considered a "failed" state.
     if !StatusField::features_ok(status_ok) {
-        print!("features fail...");
+        warn!("features fail");
         ptr.add(MmioOffsets::Status.scale32())
             .write_volatile(StatusField::Failed.val32());
         return false;
@@ -164,7 +159,7 @@ pub unsafe fn setup_input_device(ptr: *mut u32) -> bool {
     ptr.add(MmioOffsets::QueueNum.scale32())
         .write_volatile(VIRTIO_RING_SIZE as u32);
     if VIRTIO_RING_SIZE as u32 > qnmax {
-        print!("queue size fail...");
+        warn!("queue size fail");
         return false;
     } // First, if the block device array is empty, create it!
       // We add 4095 to round this up and then do an integer
@@ -298,15 +293,16 @@ fn pending(dev: &mut Device) {
         let queue = &(*dev.status_queue);
         while dev.status_ack_used_idx != queue.used.idx {
             let elem = &queue.used.ring[dev.status_ack_used_idx as usize % VIRTIO_RING_SIZE];
-            print!(
-                "SAck {}, elem {}, len {}: ",
-                dev.status_ack_used_idx, elem.id, elem.len
-            );
             let desc = &queue.desc[elem.id as usize];
             let event = (desc.addr as *const Event).as_ref().unwrap();
-            println!(
-                "Type = {:x}, Code = {:x}, Value = {:x}",
-                event.event_type as u8, event.code, event.value
+            trace!(
+                "SAck {}, elem {}, len {}: Type = {:x}, Code = {:x}, Value = {:x}",
+                dev.status_ack_used_idx,
+                elem.id,
+                elem.len,
+                event.event_type as u8,
+                event.code,
+                event.value
             );
             dev.status_ack_used_idx = dev.status_ack_used_idx.wrapping_add(1);
         }
@@ -318,7 +314,7 @@ pub fn handle_interrupt(idx: usize) {
         if let Some(bdev) = INPUT_DEVICES[idx].as_mut() {
             pending(bdev);
         } else {
-            println!("Invalid input device for interrupt {}", idx + 1);
+            error!("Invalid input device for interrupt {}", idx + 1);
         }
     }
 }
@@ -1,10 +1,11 @@
 use core::mem::size_of;
 
 use crate::{
+    fdt,
     page::PAGE_SIZE,
     virtio::{
-        block::setup_block_device, gpu::setup_gpu_device, input::setup_input_device,
-        rng::setup_entropy_device,
+        block::setup_block_device, console::setup_console_device, gpu::setup_gpu_device,
+        input::setup_input_device, rng::setup_entropy_device,
     },
 };
 
@@ -205,14 +206,53 @@ impl StatusField {
     }
 }
 
-// We probably shouldn't put these here, but it'll help
-// with probing the bus, etc. These are architecture specific
-// which is why I say that.
+// Where QEMU's virt machine has its virtio transports, for when there is
+// no device tree to tell us. The one at MMIO_VIRTIO_START raises
+// interrupt 1, the next one 2, and so on.
 pub const MMIO_VIRTIO_START: usize = 0x1000_1000;
 pub const MMIO_VIRTIO_END: usize = 0x1000_8000;
 pub const MMIO_VIRTIO_STRIDE: usize = 0x1000;
 pub const MMIO_VIRTIO_MAGIC: u32 = 0x74_72_69_76;
 
+/// The transport in `slot` of the device table: the device tree's
+/// `virtio,mmio` nodes by address, or the virt machine's.
+fn transport(slot: usize) -> Option<fdt::Device> {
+    let found = fdt::virtio();
+    if found.is_empty() {
+        let base = MMIO_VIRTIO_START + slot * MMIO_VIRTIO_STRIDE;
+        (base <= MMIO_VIRTIO_END).then(|| fdt::Device {
+            base,
+            irq: slot as u32 + 1,
+        })
+    } else {
+        found.get(slot).copied()
+    }
+}
+
+/// Every transport there is, with its slot
+fn transports() -> impl Iterator<Item = (usize, fdt::Device)> {
+    (0..fdt::MAX_VIRTIO).map_while(|slot| transport(slot).map(|dev| (slot, dev)))
+}
+
+/// The slot of the device table the transport at `ptr` is in. Drivers
+/// index their own tables with it.
+pub fn slot(ptr: *mut u32) -> usize {
+    transports()
+        .find(|(_, dev)| dev.base == ptr as usize)
+        .map(|(slot, _)| slot)
+        .expect("not a virtio transport")
+}
+
+/// Where the registers of each transport are, and how many bytes of them
+pub fn mmio() -> impl Iterator<Item = (usize, usize)> {
+    transports().map(|(_, dev)| (dev.base, PAGE_SIZE))
+}
+
+/// The interrupts the transports raise
+pub fn irqs() -> impl Iterator<Item = u32> {
+    transports().map(|(_, dev)| dev.irq)
+}
+
 // The VirtioDevice is essentially a structure we can put into an array
 // to determine what virtio devices are attached to the system. Right now,
 // we're using the 1..=8  linearity of the VirtIO devices on QEMU to help
@@ -240,11 +280,8 @@ static mut VIRTIO_DEVICES: [Option<VirtioDevice>; 8] =
 /// Probe the `VirtIO` bus for devices that might be
 /// out there.
 pub fn probe() {
-    // Rust's for loop uses an Iterator object, which now has a step_by
-    // modifier to change how much it steps. Also recall that ..= means up
-    // to AND including MMIO_VIRTIO_END.
-    for addr in (MMIO_VIRTIO_START..=MMIO_VIRTIO_END).step_by(MMIO_VIRTIO_STRIDE) {
-        print!("Virtio probing 0x{:08x}...", addr);
+    for (idx, dev) in transports() {
+        let addr = dev.base;
         let magicvalue;
         let deviceid;
         let ptr = addr as *mut u32;
@@ -256,77 +293,79 @@ pub fn probe() {
         // it is triv. All VirtIO devices have this attached to the
         // MagicValue register (offset 0x000)
         if MMIO_VIRTIO_MAGIC != magicvalue {
-            println!("not virtio.");
+            debug!("Virtio probing 0x{:08x}...not virtio.", addr);
         }
         // If we are a virtio device, we now need to see if anything
         // is actually attached to it. The DeviceID register will
         // contain what type of device this is. If this value is 0,
         // then it is not connected.
         else if 0 == deviceid {
-            println!("not connected.");
+            debug!("Virtio probing 0x{:08x}...not connected.", addr);
         }
         // If we get here, we have a connected virtio device. Now we have
         // to figure out what kind it is so we can do device-specific setup.
         else {
-            match deviceid {
+            let (name, succeeded) = match deviceid {
                 // DeviceID 1 is a network device
-                1 => {
-                    print!("network device...");
-                    if setup_network_device(ptr) {
-                        println!("setup succeeded!");
-                    } else {
-                        println!("setup failed.");
-                    }
-                }
+                1 => ("network device", setup_network_device(ptr)),
                 // DeviceID 2 is a block device
                 2 => {
-                    print!("block device...");
-                    if unsafe { setup_block_device(ptr) } {
-                        let idx = (addr - MMIO_VIRTIO_START) >> 12;
+                    let ok = unsafe { setup_block_device(ptr) };
+                    if ok {
                         unsafe {
                             VIRTIO_DEVICES[idx] = Some(VirtioDevice::new_with(DeviceTypes::Block));
                         }
-                        println!("setup succeeded!");
-                    } else {
-                        println!("setup failed.");
                     }
+                    ("block device", ok)
                 }
-                // DeviceID 4 is a random number generator device
-                4 => {
-                    print!("entropy device...");
-                    if unsafe { setup_entropy_device(ptr) } {
-                        println!("setup succeeded!");
-                    } else {
-                        println!("setup failed.");
+                // DeviceID 3 is a console, which we give to the GDB stub
+                3 => {
+                    let ok = unsafe { setup_console_device(ptr) };
+                    if ok {
+                        unsafe {
+                            VIRTIO_DEVICES[idx] =
+                                Some(VirtioDevice::new_with(DeviceTypes::Console));
+                        }
                     }
+                    ("console device", ok)
                 }
+                // DeviceID 4 is a random number generator device
+                4 => ("entropy device", unsafe { setup_entropy_device(ptr) }),
                 // DeviceID 16 is a GPU device
                 16 => {
-                    print!("GPU device...");
-                    if unsafe { setup_gpu_device(ptr) } {
-                        let idx = (addr - MMIO_VIRTIO_START) >> 12;
+                    let ok = unsafe { setup_gpu_device(ptr) };
+                    if ok {
                         unsafe {
                             VIRTIO_DEVICES[idx] = Some(VirtioDevice::new_with(DeviceTypes::Gpu));
                         }
-                        println!("setup succeeded!");
-                    } else {
-                        println!("setup failed.");
                     }
+                    ("GPU device", ok)
                 }
                 // DeviceID 18 is an input device
                 18 => {
-                    print!("input device...");
-                    if unsafe { setup_input_device(ptr) } {
-                        let idx = (addr - MMIO_VIRTIO_START) >> 12;
+                    let ok = unsafe { setup_input_device(ptr) };
+                    if ok {
                         unsafe {
                             VIRTIO_DEVICES[idx] = Some(VirtioDevice::new_with(DeviceTypes::Input));
                         }
-                        println!("setup succeeded!");
-                    } else {
-                        println!("setup failed.");
                     }
+                    ("input device", ok)
                 }
-                _ => println!("unknown device type."),
+                _ => {
+                    warn!(
+                        "Virtio probing 0x{:08x}...unknown device type {}.",
+                        addr, deviceid
+                    );
+                    continue;
+                }
+            };
+            if succeeded {
+                info!(
+                    "Virtio probing 0x{:08x}...{}...setup succeeded!",
+                    addr, name
+                );
+            } else {
+                warn!("Virtio probing 0x{:08x}...{}...setup failed.", addr, name);
             }
         }
     }
@@ -340,8 +379,13 @@ pub const fn setup_network_device(_ptr: *mut u32) -> bool {
 // determined that interrupts 1..=8 are what caused the interrupt.
 // In here, we try to figure out where to direct the interrupt
 // and then handle it.
-pub fn handle_interrupt(interrupt: u32) {
-    let idx = interrupt as usize - 1;
+/// Handle `interrupt` if one of our transports raised it. Returns whether
+/// one did.
+pub fn handle_interrupt(interrupt: u32) -> bool {
+    let idx = match transports().find(|(_, dev)| dev.irq == interrupt) {
+        Some((idx, _)) => idx,
+        None => return false,
+    };
     unsafe {
         // if let Some(vd) = &VIRTIO_DEVICES[idx] {
         if let Some(Some(vd)) = VIRTIO_DEVICES.get(idx) {
@@ -349,6 +393,9 @@ pub fn handle_interrupt(interrupt: u32) {
                 DeviceTypes::Block => {
                     block::handle_interrupt(idx);
                 }
+                DeviceTypes::Console => {
+                    console::handle_interrupt(idx);
+                }
                 DeviceTypes::Gpu => {
                     gpu::handle_interrupt(idx);
                 }
@@ -356,17 +403,20 @@ pub fn handle_interrupt(interrupt: u32) {
                     input::handle_interrupt(idx);
                 }
                 _ => {
-                    println!("Invalid device generated interrupt!");
+                    error!("Invalid device generated interrupt!");
                 }
             }
         } else {
-            println!("Spurious interrupt {}", interrupt);
+            warn!("Spurious interrupt {}", interrupt);
         }
     }
+    true
 }
 
 /// Block device
 pub mod block;
+/// Console device, for the GDB stub
+pub mod console;
 /// Gpu device
 pub mod gpu;
 /// Input device
@@ -29,13 +29,8 @@ static mut ENTROPY_DEVICES: [Option<EntropyDevice>; 8] =
     [None, None, None, None, None, None, None, None];
 
 pub unsafe fn setup_entropy_device(ptr: *mut u32) -> bool {
-    // We can get the index of the device based on its address.
-    // 0x1000_1000 is index 0
-    // 0x1000_2000 is index 1
-    // ...
-    // 0x1000_8000 is index 7
-    // To get the number that changes over, we shift right 12 places (3 hex digits)
-    let idx = (ptr as usize - virtio::MMIO_VIRTIO_START) >> 12;
+    // Drivers keep their devices by slot in the device table.
+    let idx = virtio::slot(ptr);
     // [Driver] Device Initialization
     // 1. Reset the device (write 0 into status)
     ptr.add(MmioOffsets::Status.scale32()).write_volatile(0);
@@ -64,7 +59,7 @@ pub unsafe fn setup_entropy_device(ptr: *mut u32) -> bool {
     // the features that we request. Therefore, this is
     // considered a "failed" state.
     if !StatusField::features_ok(status_ok) {
-        print!("features fail...");
+        warn!("features fail");
         ptr.add(MmioOffsets::Status.scale32())
             .write_volatile(StatusField::Failed.val32());
         return false;
@@ -77,7 +72,7 @@ pub unsafe fn setup_entropy_device(ptr: *mut u32) -> bool {
     ptr.add(MmioOffsets::QueueNum.scale32())
         .write_volatile(VIRTIO_RING_SIZE as u32);
     if VIRTIO_RING_SIZE as u32 > qnmax {
-        print!("queue size fail...");
+        warn!("queue size fail");
         return false;
     }
     // First, if the block device array is empty, create it!
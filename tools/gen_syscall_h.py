@@ -0,0 +1,140 @@
+#!/usr/bin/env python3
+"""Generate the system call headers of userspace from the kernel source.
+
+The kernel lists its system calls in the `Syscall` enum of src/syscall.rs
+and its error numbers in src/errno.rs. Userspace needs the same numbers,
+and keeping two copies in step by hand never works for long, so we read
+them out of the Rust source instead:
+
+    /// `read(fd, buf, count)`
+    Read = 63,
+
+becomes SYS_read and a syscall_read(fd, buf, count) macro in
+userspace/startlib/syscall.h, and
+
+    /// Bad address
+    pub const EFAULT: Errno = Errno(14);
+
+becomes EFAULT in userspace/startlib/errno.h.
+
+Usage: gen_syscall_h.py [kernel source directory] [startlib directory]
+"""
+
+import os
+import re
+import sys
+
+ROOT = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..")
+
+HEADER = "// Generated by tools/gen_syscall_h.py from src/{}. Do not edit.\n"
+
+# A doc comment line with the call, and the variant it belongs to
+CALL = re.compile(r"^\s*///\s*`(\w+)\(([\w, ]*)\)`:?\s*(.*)$")
+VARIANT = re.compile(r"^\s*(\w+)\s*=\s*(\d+),")
+ERRNO = re.compile(r"^pub const (E\w+): Errno = Errno\((\d+)\);")
+DOC = re.compile(r"^\s*///\s*(.*)$")
+
+
+def parse_syscalls(path):
+    """The (name, args, description, number) of every system call."""
+    with open(path) as f:
+        source = f.read()
+    body = re.search(r"pub enum Syscall \{(.*?)\n\}", source, re.S)
+    if body is None:
+        sys.exit(f"{path}: there is no Syscall enum")
+    calls = []
+    call = None
+    for line in body.group(1).splitlines():
+        m = CALL.match(line)
+        if m:
+            args = [arg.strip() for arg in m.group(2).split(",") if arg.strip()]
+            call = (m.group(1), args, m.group(3))
+            continue
+        m = VARIANT.match(line)
+        if m:
+            if call is None:
+                sys.exit(f"{path}: {m.group(1)} doesn't say how it is called")
+            calls.append(call + (int(m.group(2)),))
+            call = None
+    return calls
+
+
+def parse_errnos(path):
+    """The (name, number, description) of every error number."""
+    errnos = []
+    doc = ""
+    with open(path) as f:
+        for line in f:
+            m = ERRNO.match(line)
+            if m:
+                errnos.append((m.group(1), int(m.group(2)), doc))
+            m = DOC.match(line)
+            doc = m.group(1) if m else ""
+    return errnos
+
+
+def syscall_h(calls):
+    out = [HEADER.format("syscall.rs"), "#pragma once\n\n"]
+    for name, _args, _desc, nr in calls:
+        out.append(f"#define SYS_{name} {nr}\n")
+    out.append("""
+#ifndef __ASSEMBLER__
+extern "C" {
+    unsigned long make_syscall(
+        unsigned long sysno,
+        unsigned long a1=0,
+        unsigned long a2=0,
+        unsigned long a3=0,
+        unsigned long a4=0,
+        unsigned long a5=0,
+        unsigned long a6=0
+    );
+}
+
+// Failed calls return the negated error number, see errno.h.
+""")
+    for name, args, desc, _nr in calls:
+        if desc:
+            out.append(f"\n// {desc}")
+        params = ", ".join(args)
+        values = "".join(f", (unsigned long)({arg})" for arg in args)
+        out.append(f"\n#define syscall_{name}({params}) make_syscall(SYS_{name}{values})")
+    out.append("\n\nstatic inline const char *syscall_name(unsigned long n)\n{\n\tswitch (n) {\n")
+    for name, _args, _desc, nr in calls:
+        out.append(f"\tcase SYS_{name}: return \"{name}\";\n")
+    out.append("\tdefault: return 0;\n\t}\n}\n#endif\n")
+    return "".join(out)
+
+
+def errno_h(errnos):
+    out = [HEADER.format("errno.rs"), "#pragma once\n\n"]
+    width = max(len(name) for name, _nr, _doc in errnos)
+    for name, nr, doc in errnos:
+        comment = f" // {doc}" if doc else ""
+        out.append(f"#define {name.ljust(width)} {nr}{comment}\n")
+    return "".join(out)
+
+
+def write(path, text):
+    # Leave the file alone if nothing changed, so make doesn't rebuild
+    # everything for nothing.
+    if os.path.exists(path):
+        with open(path) as f:
+            if f.read() == text:
+                return
+    with open(path, "w") as f:
+        f.write(text)
+    print(f"gen_syscall_h: wrote {path}")
+
+
+def main():
+    src = sys.argv[1] if len(sys.argv) > 1 else os.path.join(ROOT, "src")
+    startlib = sys.argv[2] if len(sys.argv) > 2 else os.path.join(ROOT, "userspace", "startlib")
+    write(os.path.join(startlib, "syscall.h"),
+          syscall_h(parse_syscalls(os.path.join(src, "syscall.rs"))))
+    write(os.path.join(startlib, "errno.h"),
+          errno_h(parse_errnos(os.path.join(src, "errno.rs"))))
+
+
+if __name__ == "__main__":
+    main()
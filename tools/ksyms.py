@@ -0,0 +1,144 @@
+#!/usr/bin/env python3
+"""Embed the kernel's symbol table into the kernel image.
+
+The kernel reserves a buffer called KSYMS (see src/backtrace.rs) that it
+uses to turn return addresses into function names when it panics. The
+linker can't fill that buffer in for us, since the addresses are only
+known once linking is done. So after every build we read the function
+symbols out of the ELF file and write them into the buffer in place. No
+address changes, so nothing has to be linked again.
+
+Layout of the table (little endian):
+
+    magic   8 bytes  b"KSYMTAB\\0"
+    count   u64
+    entries count times, sorted by address:
+        addr      u64
+        size      u32
+        name_off  u32   offset of the NUL-terminated name after the entries
+    names
+
+Usage: ksyms.py <kernel ELF>
+"""
+
+import re
+import struct
+import sys
+
+MAGIC = b"KSYMTAB\0"
+# What the buffer holds before we get to it
+EMPTY_MAGIC = b"KSYMNONE"
+STT_FUNC = 2
+# Long generic names don't help anybody in a backtrace.
+MAX_NAME = 120
+
+ESCAPES = {
+    "$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">",
+    "$LP$": "(", "$RP$": ")", "$C$": ",", "$u20$": " ", "$u21$": "!",
+    "$u22$": "\"", "$u23$": "#", "$u27$": "'", "$u2b$": "+", "$u3b$": ";",
+    "$u5b$": "[", "$u5d$": "]", "$u7b$": "{", "$u7d$": "}", "$u7e$": "~",
+}
+
+
+def demangle(name):
+    """Undo legacy Rust mangling, minus the hash. Other names are kept."""
+    if not name.startswith("_ZN") or not name.endswith("E"):
+        return name
+    rest = name[3:-1]
+    parts = []
+    while rest:
+        m = re.match(r"(\d+)", rest)
+        if not m:
+            return name
+        length = int(m.group(1))
+        start = len(m.group(1))
+        parts.append(rest[start:start + length])
+        rest = rest[start + length:]
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+    out = []
+    for part in parts:
+        if part.startswith("_$"):
+            part = part[1:]
+        for k, v in ESCAPES.items():
+            part = part.replace(k, v)
+        out.append(part.replace("..", "::"))
+    return "::".join(out)
+
+
+def read_elf(data):
+    (e_shoff,) = struct.unpack_from("<Q", data, 0x28)
+    e_shentsize, e_shnum, e_shstrndx = struct.unpack_from("<HHH", data, 0x3a)
+    (e_phoff,) = struct.unpack_from("<Q", data, 0x20)
+    e_phentsize, e_phnum = struct.unpack_from("<HH", data, 0x36)
+    sections = []
+    for i in range(e_shnum):
+        off = e_shoff + i * e_shentsize
+        name, stype, _flags, addr, offset, size, link, _info, _align, entsize = \
+            struct.unpack_from("<IIQQQQIIQQ", data, off)
+        sections.append((name, stype, addr, offset, size, link, entsize))
+    segments = []
+    for i in range(e_phnum):
+        off = e_phoff + i * e_phentsize
+        ptype, _flags, offset, vaddr, _paddr, filesz, _memsz, _align = \
+            struct.unpack_from("<IIQQQQQQ", data, off)
+        if ptype == 1:
+            segments.append((vaddr, offset, filesz))
+    return sections, segments
+
+
+def main():
+    path = sys.argv[1]
+    with open(path, "rb") as f:
+        data = bytearray(f.read())
+    if data[:4] != b"\x7fELF" or data[4] != 2:
+        sys.exit(f"{path}: not a 64-bit ELF file")
+    sections, segments = read_elf(data)
+    symtab = next((s for s in sections if s[1] == 2), None)
+    if symtab is None:
+        sys.exit(f"{path}: no symbol table (was it stripped?)")
+    strtab = sections[symtab[5]]
+
+    def string(offset):
+        end = data.index(b"\0", strtab[3] + offset)
+        return data[strtab[3] + offset:end].decode(errors="replace")
+
+    funcs = {}
+    table = None
+    for i in range(symtab[4] // symtab[6]):
+        st_name, st_info, _other, _shndx, st_value, st_size = \
+            struct.unpack_from("<IBBHQQ", data, symtab[3] + i * symtab[6])
+        name = string(st_name)
+        if name == "KSYMS":
+            table = (st_value, st_size)
+        elif st_info & 0xf == STT_FUNC and st_value != 0:
+            funcs.setdefault(st_value, (st_size, demangle(name)[:MAX_NAME]))
+    if table is None:
+        sys.exit(f"{path}: there is no KSYMS buffer to fill in")
+
+    entries = sorted(funcs.items())
+    names = bytearray()
+    packed = bytearray(MAGIC + struct.pack("<Q", len(entries)))
+    for addr, (size, name) in entries:
+        packed += struct.pack("<QII", addr, size, len(names))
+        names += name.encode() + b"\0"
+    packed += names
+    vaddr, capacity = table
+    if len(packed) > capacity:
+        sys.exit(f"{path}: the symbol table needs {len(packed)} bytes, but KSYMS only "
+                 f"holds {capacity}. Raise KSYMS_SIZE in src/backtrace.rs.")
+
+    offset = next((off + vaddr - seg for seg, off, size in segments
+                   if seg <= vaddr and vaddr + capacity <= seg + size), None)
+    if offset is None:
+        sys.exit(f"{path}: KSYMS isn't stored in the file")
+    if data[offset:offset + 8] not in (MAGIC, EMPTY_MAGIC):
+        sys.exit(f"{path}: KSYMS doesn't look like a symbol table")
+    data[offset:offset + capacity] = packed + bytes(capacity - len(packed))
+    with open(path, "wb") as f:
+        f.write(data)
+    print(f"ksyms: {len(entries)} symbols, {len(packed)} of {capacity} bytes")
+
+
+if __name__ == "__main__":
+    main()
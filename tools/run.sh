@@ -0,0 +1,8 @@
+#!/bin/sh
+# Cargo runner: embed the kernel's symbol table so that panics print
+# function names, then boot the kernel in QEMU. The kernel's GDB stub
+# listens on localhost:4321.
+# Without the table we still boot, panics just print bare addresses.
+python3 "$(dirname "$0")/ksyms.py" "$1" ||
+    echo "warning: no symbol table in $1, backtraces will show addresses only" >&2
+exec qemu-system-riscv64 -machine virt -cpu rv64 -smp 4 -m 128M -drive if=none,format=raw,file=hdd.dsk,id=foo -device virtio-blk-device,scsi=off,drive=foo -nographic -serial mon:stdio -bios none -device virtio-rng-device -device virtio-gpu-device -device virtio-net-device -device virtio-tablet-device -device virtio-keyboard-device -device virtio-serial-device -chardev socket,id=gdb,port=4321,host=localhost,server=on,wait=off -device virtconsole,chardev=gdb -kernel "$@"
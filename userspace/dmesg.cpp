@@ -0,0 +1,21 @@
+#include <printf.h>
+#include <syscall.h>
+
+// Print the kernel log.
+
+#define SYSLOG_ACTION_READ_ALL   3
+
+const int BUFFER_SIZE = 16384;
+char buffer[BUFFER_SIZE];
+
+int main()
+{
+	// We don't get any arguments, so there is no -c.
+	long n = syscall_syslog(SYSLOG_ACTION_READ_ALL, buffer, BUFFER_SIZE);
+	if (n < 0) {
+		printf("dmesg: cannot read the kernel log\n");
+		return 1;
+	}
+	syscall_write(1, buffer, n);
+	return 0;
+}
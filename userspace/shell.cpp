@@ -1,10 +1,147 @@
 #include <printf.h>
+#include <prctl.h>
+#include <swap.h>
 #include <syscall.h>
+#include <termios.h>
 
-int main() {
-  unsigned long a;
-  asm volatile("mv %0, sp\n" : "=r"(a));
-  printf("Stack is at %p\n", a);
-  printf("Hello world\n");
-  return 0;
+// A tiny shell on the serial console. We can't fork yet, so running a
+// program with exec replaces the shell.
+
+const int LINE_SIZE = 256;
+const int MAX_ARGS = 16;
+
+static bool streq(const char *a, const char *b)
+{
+	while (*a && *a == *b) {
+		a++;
+		b++;
+	}
+	return *a == *b;
+}
+
+// Split line into words in place. Returns the number of words.
+static int split(char *line, char *argv[])
+{
+	int argc = 0;
+	while (*line && argc < MAX_ARGS - 1) {
+		while (*line == ' ' || *line == '\t') {
+			*line++ = 0;
+		}
+		if (!*line) {
+			break;
+		}
+		argv[argc++] = line;
+		while (*line && *line != ' ' && *line != '\t') {
+			line++;
+		}
+	}
+	argv[argc] = 0;
+	return argc;
+}
+
+// Show what the console hands us byte by byte until 'q' is pressed.
+static void rawtest()
+{
+	struct termios saved, raw;
+	syscall_ioctl(0, TCGETS, &saved);
+	raw = saved;
+	raw.c_lflag &= ~(ICANON | ECHO);
+	syscall_ioctl(0, TCSETS, &raw);
+	printf("Raw mode, press q to quit.\n");
+	char c;
+	while (syscall_read(0, &c, 1) == 1 && c != 'q') {
+		printf("0x%02x\n", c);
+	}
+	syscall_ioctl(0, TCSETS, &saved);
+}
+
+int main()
+{
+	char line[LINE_SIZE];
+	char *argv[MAX_ARGS];
+	printf("Welcome to the shell. Type 'help' for help.\n");
+	for (;;) {
+		printf("$ ");
+		long n = syscall_read(0, line, LINE_SIZE - 1);
+		if (n <= 0) {
+			// ^D or an error
+			printf("\n");
+			break;
+		}
+		line[n] = 0;
+		if (line[n - 1] == '\n') {
+			line[n - 1] = 0;
+		}
+		int argc = split(line, argv);
+		if (argc == 0) {
+			continue;
+		}
+		if (streq(argv[0], "help")) {
+			printf("help              show this\n");
+			printf("echo [words...]   print words\n");
+			printf("exec <program>    replace the shell with a program\n");
+			printf("rawtest           try the console's raw mode\n");
+			printf("core [on|off]     write crash reports of programs to disk\n");
+			printf("ktrace [on|off]   log the system calls of programs\n");
+			printf("swap [device]     swap to a block device, show swap usage\n");
+			printf("exit              leave the shell\n");
+		}
+		else if (streq(argv[0], "echo")) {
+			for (int i = 1;i < argc;i++) {
+				printf(i + 1 < argc ? "%s " : "%s", argv[i]);
+			}
+			printf("\n");
+		}
+		else if (streq(argv[0], "exec")) {
+			if (argc < 2) {
+				printf("usage: exec <program>\n");
+			}
+			else if ((long)syscall_execve(argv[1], &argv[1], 0) < 0) {
+				printf("exec: cannot run '%s'\n", argv[1]);
+			}
+		}
+		else if (streq(argv[0], "rawtest")) {
+			rawtest();
+		}
+		else if (streq(argv[0], "core")) {
+			if (argc > 1) {
+				syscall_prctl(PR_SET_DUMPABLE, streq(argv[1], "on") ? 1 : 0);
+			}
+			printf("core dumps are %s\n", syscall_prctl(PR_GET_DUMPABLE, 0) == 1 ? "on" : "off");
+		}
+		else if (streq(argv[0], "ktrace")) {
+			if (argc > 1) {
+				syscall_prctl(PR_SET_SYSCALL_TRACE, streq(argv[1], "on") ? 1 : 0);
+			}
+			printf("system call tracing is %s\n", syscall_prctl(PR_GET_SYSCALL_TRACE, 0) == 1 ? "on" : "off");
+		}
+		else if (streq(argv[0], "swap")) {
+			if (argc > 1) {
+				unsigned long dev = 0;
+				for (const char *c = argv[1];*c >= '0' && *c <= '9';c++) {
+					dev = dev * 10 + (*c - '0');
+				}
+				long err = (long)syscall_swapon(dev);
+				if (err < 0) {
+					printf("swap: cannot swap to device %lu (error %ld)\n", dev, -err);
+				}
+			}
+			struct swapinfo info;
+			syscall_swapinfo(&info);
+			if (info.dev == 0) {
+				printf("no swap\n");
+			}
+			else {
+				printf("device %lu: %lu of %lu pages used, %lu paged in, %lu paged out\n",
+					info.dev, info.used, info.pages, info.page_ins, info.page_outs);
+			}
+		}
+		else if (streq(argv[0], "exit")) {
+			break;
+		}
+		else {
+			printf("%s: unknown command\n", argv[0]);
+		}
+	}
+	return 0;
 }
@@ -0,0 +1,28 @@
+// Generated by tools/gen_syscall_h.py from src/errno.rs. Do not edit.
+#pragma once
+
+#define EPERM        1 // Operation not permitted
+#define ENOENT       2 // No such file or directory
+#define ESRCH        3 // No such process
+#define EINTR        4 // Interrupted system call
+#define EIO          5 // I/O error
+#define E2BIG        7 // Argument list too long
+#define ENOEXEC      8 // Exec format error
+#define EBADF        9 // Bad file descriptor
+#define ECHILD       10 // No child processes
+#define EAGAIN       11 // Try again
+#define ENOMEM       12 // Out of memory
+#define EACCES       13 // Permission denied
+#define EFAULT       14 // Bad address
+#define EBUSY        16 // Device or resource busy
+#define EEXIST       17 // File exists
+#define ENODEV       19 // No such device
+#define EINVAL       22 // Invalid argument
+#define EMFILE       24 // Too many open files
+#define ENOTTY       25 // Not a typewriter
+#define ESPIPE       29 // Illegal seek
+#define EROFS        30 // Read-only file system
+#define EPIPE        32 // Broken pipe
+#define ERANGE       34 // Math result not representable
+#define ENAMETOOLONG 36 // File name too long
+#define ENOSYS       38 // Function not implemented
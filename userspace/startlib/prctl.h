@@ -0,0 +1,8 @@
+#pragma once
+
+// prctl options. These mirror the kernel's crash.rs and strace.rs
+
+#define PR_GET_DUMPABLE      3
+#define PR_SET_DUMPABLE      4
+#define PR_GET_SYSCALL_TRACE 0x53540000
+#define PR_SET_SYSCALL_TRACE 0x53540001
@@ -0,0 +1,52 @@
+#pragma once
+
+// These mirror the kernel's ptrace.rs
+
+#define PTRACE_TRACEME     0
+#define PTRACE_PEEKTEXT    1
+#define PTRACE_PEEKDATA    2
+#define PTRACE_POKETEXT    4
+#define PTRACE_POKEDATA    5
+#define PTRACE_CONT        7
+#define PTRACE_KILL        8
+#define PTRACE_GETREGS     12
+#define PTRACE_SETREGS     13
+#define PTRACE_GETFPREGS   14
+#define PTRACE_SETFPREGS   15
+#define PTRACE_ATTACH      16
+#define PTRACE_DETACH      17
+#define PTRACE_SYSCALL     24
+#define PTRACE_SETOPTIONS  0x4200
+#define PTRACE_GETSIGINFO  0x4202
+
+#define PTRACE_O_TRACESYSGOOD 1
+
+#define WNOHANG 1
+
+#define WIFEXITED(s)   (((s) & 0x7f) == 0)
+#define WEXITSTATUS(s) (((s) >> 8) & 0xff)
+#define WIFSIGNALED(s) (((s) & 0x7f) != 0 && ((s) & 0x7f) != 0x7f)
+#define WTERMSIG(s)    ((s) & 0x7f)
+#define WIFSTOPPED(s)  (((s) & 0xff) == 0x7f)
+#define WSTOPSIG(s)    (((s) >> 8) & 0xff)
+
+// Like Linux' struct user_regs_struct: the pc takes the place of x0.
+struct user_regs_struct {
+	unsigned long pc;
+	unsigned long ra, sp, gp, tp;
+	unsigned long t0, t1, t2;
+	unsigned long s0, s1;
+	unsigned long a0, a1, a2, a3, a4, a5, a6, a7;
+	unsigned long s2, s3, s4, s5, s6, s7, s8, s9, s10, s11;
+	unsigned long t3, t4, t5, t6;
+};
+
+// The start of siginfo_t, followed by the mcause of a fault
+struct ptrace_siginfo {
+	int si_signo;
+	int si_errno;
+	int si_code;
+	int pad;
+	unsigned long si_addr;
+	unsigned long cause;
+};
@@ -0,0 +1,32 @@
+#pragma once
+
+// Resource limits and usage. These mirror the kernel's resource.rs
+
+#define RLIMIT_CPU    0
+#define RLIMIT_STACK  3
+#define RLIMIT_NOFILE 7
+#define RLIMIT_AS     9
+
+#define RLIM_INFINITY (~0UL)
+
+#define RUSAGE_SELF   0
+#define RUSAGE_THREAD 1
+
+struct rlimit {
+    unsigned long rlim_cur;
+    unsigned long rlim_max;
+};
+
+struct timeval {
+    long tv_sec;
+    long tv_usec;
+};
+
+struct rusage {
+    struct timeval ru_utime;
+    struct timeval ru_stime;
+    // The most memory the process ever had, in KiB
+    long ru_maxrss;
+    // Everything else Linux counts, which the kernel leaves at 0
+    long ru_unused[13];
+};
@@ -0,0 +1,39 @@
+#pragma once
+
+// System V shared memory. This mirrors the kernel's shm.rs
+
+#define IPC_PRIVATE 0
+
+#define IPC_CREAT   01000
+#define IPC_EXCL    02000
+
+#define IPC_RMID    0
+#define IPC_SET     1
+#define IPC_STAT    2
+
+#define SHM_RDONLY  010000
+#define SHM_RND     020000
+
+struct ipc_perm {
+    int key;
+    unsigned int uid;
+    unsigned int gid;
+    unsigned int cuid;
+    unsigned int cgid;
+    unsigned int mode;
+    unsigned short seq;
+    unsigned short __pad;
+    unsigned long __unused[2];
+};
+
+struct shmid_ds {
+    struct ipc_perm shm_perm;
+    unsigned long shm_segsz;
+    long shm_atime;
+    long shm_dtime;
+    long shm_ctime;
+    int shm_cpid;
+    int shm_lpid;
+    unsigned long shm_nattch;
+    unsigned long __unused[2];
+};
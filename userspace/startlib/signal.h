@@ -0,0 +1,45 @@
+#pragma once
+
+// These mirror the kernel's signal.rs
+#define SIGHUP     1
+#define SIGINT     2
+#define SIGQUIT    3
+#define SIGILL     4
+#define SIGTRAP    5
+#define SIGABRT    6
+#define SIGBUS     7
+#define SIGFPE     8
+#define SIGKILL    9
+#define SIGUSR1    10
+#define SIGSEGV    11
+#define SIGUSR2    12
+#define SIGPIPE    13
+#define SIGALRM    14
+#define SIGTERM    15
+#define SIGCHLD    17
+#define SIGCONT    18
+#define SIGSTOP    19
+#define SIGTSTP    20
+#define SIGTTIN    21
+#define SIGTTOU    22
+#define SIGXCPU    24
+
+#define SIG_DFL    0UL
+#define SIG_IGN    1UL
+
+#define SA_NODEFER   0x40000000UL
+#define SA_RESETHAND 0x80000000UL
+
+#define SIG_BLOCK    0
+#define SIG_UNBLOCK  1
+#define SIG_SETMASK  2
+
+#define sigmask(s) (1UL << (s))
+
+// Handlers return into a trampoline the kernel maps into every process,
+// so there is no sa_restorer.
+struct sigaction {
+    unsigned long sa_handler;
+    unsigned long sa_flags;
+    unsigned long sa_mask;
+};
@@ -0,0 +1,16 @@
+#pragma once
+
+// Swap. This mirrors the kernel's swap.rs
+
+struct swapinfo {
+    // The block device, or 0 if there is no swap
+    unsigned long dev;
+    // Pages of the swap area
+    unsigned long pages;
+    // Pages of it in use
+    unsigned long used;
+    // Pages read back in since boot
+    unsigned long page_ins;
+    // Pages written out since boot
+    unsigned long page_outs;
+};
@@ -1,5 +1,73 @@
+// Generated by tools/gen_syscall_h.py from src/syscall.rs. Do not edit.
 #pragma once
 
+#define SYS_getcwd 17
+#define SYS_dup 23
+#define SYS_dup3 24
+#define SYS_fcntl 25
+#define SYS_ioctl 29
+#define SYS_openat 56
+#define SYS_close 57
+#define SYS_pipe2 59
+#define SYS_lseek 62
+#define SYS_read 63
+#define SYS_write 64
+#define SYS_readv 65
+#define SYS_writev 66
+#define SYS_exit 93
+#define SYS_exit_group 94
+#define SYS_set_tid_address 96
+#define SYS_futex 98
+#define SYS_nanosleep 101
+#define SYS_clock_gettime 113
+#define SYS_syslog 116
+#define SYS_ptrace 117
+#define SYS_sched_yield 124
+#define SYS_kill 129
+#define SYS_tkill 130
+#define SYS_rt_sigaction 134
+#define SYS_rt_sigprocmask 135
+#define SYS_rt_sigreturn 139
+#define SYS_uname 160
+#define SYS_getrlimit 163
+#define SYS_setrlimit 164
+#define SYS_getrusage 165
+#define SYS_prctl 167
+#define SYS_getpid 172
+#define SYS_getppid 173
+#define SYS_getuid 174
+#define SYS_geteuid 175
+#define SYS_getgid 176
+#define SYS_getegid 177
+#define SYS_gettid 178
+#define SYS_shmget 194
+#define SYS_shmctl 195
+#define SYS_shmat 196
+#define SYS_shmdt 197
+#define SYS_brk 214
+#define SYS_munmap 215
+#define SYS_clone 220
+#define SYS_execve 221
+#define SYS_mmap 222
+#define SYS_mprotect 226
+#define SYS_madvise 233
+#define SYS_wait4 260
+#define SYS_prlimit64 261
+#define SYS_get_fb 1000
+#define SYS_inv_rect 1001
+#define SYS_get_key 1002
+#define SYS_get_abs 1004
+#define SYS_get_char 1005
+#define SYS_put_char 1006
+#define SYS_dump_registers 1007
+#define SYS_sleep 1008
+#define SYS_block_read 1009
+#define SYS_block_write 1010
+#define SYS_get_time 1011
+#define SYS_swapon 1012
+#define SYS_swapinfo 1013
+
+#ifndef __ASSEMBLER__
 extern "C" {
     unsigned long make_syscall(
         unsigned long sysno,
@@ -11,13 +79,157 @@ extern "C" {
         unsigned long a6=0
     );
 }
-#define syscall_exit()                  make_syscall(93)
-#define syscall_get_char()              make_syscall(1)
-#define syscall_put_char(x)             make_syscall(2, (unsigned long)x)
-#define syscall_yield()                 make_syscall(9)
-#define syscall_sleep(x)                make_syscall(10, (unsigned long)x)
-#define syscall_get_fb(x)               make_syscall(1000, (unsigned long)x)
-#define syscall_inv_rect(d, x, y, w, h) make_syscall(1001, (unsigned long) d, (unsigned long)x, (unsigned long)y, (unsigned long)w, (unsigned long)h)
-#define syscall_get_key(x, y)           make_syscall(1002, (unsigned long)x, (unsigned long)y)
-#define syscall_get_abs(x, y)           make_syscall(1004, (unsigned long)x, (unsigned long)y)
-#define syscall_get_time()              make_syscall(1062)
+
+// Failed calls return the negated error number, see errno.h.
+
+#define syscall_getcwd(buf, size) make_syscall(SYS_getcwd, (unsigned long)(buf), (unsigned long)(size))
+#define syscall_dup(fd) make_syscall(SYS_dup, (unsigned long)(fd))
+#define syscall_dup3(oldfd, newfd, flags) make_syscall(SYS_dup3, (unsigned long)(oldfd), (unsigned long)(newfd), (unsigned long)(flags))
+#define syscall_fcntl(fd, cmd, arg) make_syscall(SYS_fcntl, (unsigned long)(fd), (unsigned long)(cmd), (unsigned long)(arg))
+#define syscall_ioctl(fd, request, arg) make_syscall(SYS_ioctl, (unsigned long)(fd), (unsigned long)(request), (unsigned long)(arg))
+#define syscall_openat(dirfd, path, flags, mode) make_syscall(SYS_openat, (unsigned long)(dirfd), (unsigned long)(path), (unsigned long)(flags), (unsigned long)(mode))
+#define syscall_close(fd) make_syscall(SYS_close, (unsigned long)(fd))
+#define syscall_pipe2(fds, flags) make_syscall(SYS_pipe2, (unsigned long)(fds), (unsigned long)(flags))
+#define syscall_lseek(fd, offset, whence) make_syscall(SYS_lseek, (unsigned long)(fd), (unsigned long)(offset), (unsigned long)(whence))
+#define syscall_read(fd, buf, count) make_syscall(SYS_read, (unsigned long)(fd), (unsigned long)(buf), (unsigned long)(count))
+#define syscall_write(fd, buf, count) make_syscall(SYS_write, (unsigned long)(fd), (unsigned long)(buf), (unsigned long)(count))
+#define syscall_readv(fd, iov, iovcnt) make_syscall(SYS_readv, (unsigned long)(fd), (unsigned long)(iov), (unsigned long)(iovcnt))
+#define syscall_writev(fd, iov, iovcnt) make_syscall(SYS_writev, (unsigned long)(fd), (unsigned long)(iov), (unsigned long)(iovcnt))
+#define syscall_exit(code) make_syscall(SYS_exit, (unsigned long)(code))
+#define syscall_exit_group(code) make_syscall(SYS_exit_group, (unsigned long)(code))
+#define syscall_set_tid_address(tidptr) make_syscall(SYS_set_tid_address, (unsigned long)(tidptr))
+#define syscall_futex(uaddr, op, val) make_syscall(SYS_futex, (unsigned long)(uaddr), (unsigned long)(op), (unsigned long)(val))
+#define syscall_nanosleep(req, rem) make_syscall(SYS_nanosleep, (unsigned long)(req), (unsigned long)(rem))
+#define syscall_clock_gettime(clock, tp) make_syscall(SYS_clock_gettime, (unsigned long)(clock), (unsigned long)(tp))
+#define syscall_syslog(action, buf, len) make_syscall(SYS_syslog, (unsigned long)(action), (unsigned long)(buf), (unsigned long)(len))
+#define syscall_ptrace(request, pid, addr, data) make_syscall(SYS_ptrace, (unsigned long)(request), (unsigned long)(pid), (unsigned long)(addr), (unsigned long)(data))
+#define syscall_sched_yield() make_syscall(SYS_sched_yield)
+#define syscall_kill(pid, sig) make_syscall(SYS_kill, (unsigned long)(pid), (unsigned long)(sig))
+#define syscall_tkill(tid, sig) make_syscall(SYS_tkill, (unsigned long)(tid), (unsigned long)(sig))
+#define syscall_rt_sigaction(sig, act, oldact) make_syscall(SYS_rt_sigaction, (unsigned long)(sig), (unsigned long)(act), (unsigned long)(oldact))
+#define syscall_rt_sigprocmask(how, set, oldset) make_syscall(SYS_rt_sigprocmask, (unsigned long)(how), (unsigned long)(set), (unsigned long)(oldset))
+#define syscall_rt_sigreturn() make_syscall(SYS_rt_sigreturn)
+#define syscall_uname(buf) make_syscall(SYS_uname, (unsigned long)(buf))
+#define syscall_getrlimit(resource, rlim) make_syscall(SYS_getrlimit, (unsigned long)(resource), (unsigned long)(rlim))
+#define syscall_setrlimit(resource, rlim) make_syscall(SYS_setrlimit, (unsigned long)(resource), (unsigned long)(rlim))
+#define syscall_getrusage(who, usage) make_syscall(SYS_getrusage, (unsigned long)(who), (unsigned long)(usage))
+#define syscall_prctl(option, arg) make_syscall(SYS_prctl, (unsigned long)(option), (unsigned long)(arg))
+#define syscall_getpid() make_syscall(SYS_getpid)
+#define syscall_getppid() make_syscall(SYS_getppid)
+#define syscall_getuid() make_syscall(SYS_getuid)
+#define syscall_geteuid() make_syscall(SYS_geteuid)
+#define syscall_getgid() make_syscall(SYS_getgid)
+#define syscall_getegid() make_syscall(SYS_getegid)
+#define syscall_gettid() make_syscall(SYS_gettid)
+#define syscall_shmget(key, size, flags) make_syscall(SYS_shmget, (unsigned long)(key), (unsigned long)(size), (unsigned long)(flags))
+#define syscall_shmctl(id, cmd, buf) make_syscall(SYS_shmctl, (unsigned long)(id), (unsigned long)(cmd), (unsigned long)(buf))
+#define syscall_shmat(id, addr, flags) make_syscall(SYS_shmat, (unsigned long)(id), (unsigned long)(addr), (unsigned long)(flags))
+#define syscall_shmdt(addr) make_syscall(SYS_shmdt, (unsigned long)(addr))
+#define syscall_brk(addr) make_syscall(SYS_brk, (unsigned long)(addr))
+#define syscall_munmap(addr, length) make_syscall(SYS_munmap, (unsigned long)(addr), (unsigned long)(length))
+#define syscall_clone(flags, stack, ptid, tls, ctid) make_syscall(SYS_clone, (unsigned long)(flags), (unsigned long)(stack), (unsigned long)(ptid), (unsigned long)(tls), (unsigned long)(ctid))
+#define syscall_execve(path, argv, envp) make_syscall(SYS_execve, (unsigned long)(path), (unsigned long)(argv), (unsigned long)(envp))
+#define syscall_mmap(addr, length, prot, flags, fd, offset) make_syscall(SYS_mmap, (unsigned long)(addr), (unsigned long)(length), (unsigned long)(prot), (unsigned long)(flags), (unsigned long)(fd), (unsigned long)(offset))
+#define syscall_mprotect(addr, length, prot) make_syscall(SYS_mprotect, (unsigned long)(addr), (unsigned long)(length), (unsigned long)(prot))
+#define syscall_madvise(addr, length, advice) make_syscall(SYS_madvise, (unsigned long)(addr), (unsigned long)(length), (unsigned long)(advice))
+#define syscall_wait4(pid, status, options, rusage) make_syscall(SYS_wait4, (unsigned long)(pid), (unsigned long)(status), (unsigned long)(options), (unsigned long)(rusage))
+#define syscall_prlimit64(pid, resource, new, old) make_syscall(SYS_prlimit64, (unsigned long)(pid), (unsigned long)(resource), (unsigned long)(new), (unsigned long)(old))
+// map the framebuffer of a GPU and return its address.
+#define syscall_get_fb(dev) make_syscall(SYS_get_fb, (unsigned long)(dev))
+// show part of the framebuffer.
+#define syscall_inv_rect(dev, x, y, width, height) make_syscall(SYS_inv_rect, (unsigned long)(dev), (unsigned long)(x), (unsigned long)(y), (unsigned long)(width), (unsigned long)(height))
+// take keyboard events.
+#define syscall_get_key(events, max) make_syscall(SYS_get_key, (unsigned long)(events), (unsigned long)(max))
+// take tablet events.
+#define syscall_get_abs(events, max) make_syscall(SYS_get_abs, (unsigned long)(events), (unsigned long)(max))
+// read a byte from the console, -1 at the end of input.
+#define syscall_get_char() make_syscall(SYS_get_char)
+// write a byte to the console.
+#define syscall_put_char(c) make_syscall(SYS_put_char, (unsigned long)(c))
+// print the caller's registers.
+#define syscall_dump_registers() make_syscall(SYS_dump_registers)
+// sleep for this many timer ticks.
+#define syscall_sleep(ticks) make_syscall(SYS_sleep, (unsigned long)(ticks))
+// read sectors of a disk.
+#define syscall_block_read(dev, buf, size, offset) make_syscall(SYS_block_read, (unsigned long)(dev), (unsigned long)(buf), (unsigned long)(size), (unsigned long)(offset))
+// write sectors of a disk.
+#define syscall_block_write(dev, buf, size, offset) make_syscall(SYS_block_write, (unsigned long)(dev), (unsigned long)(buf), (unsigned long)(size), (unsigned long)(offset))
+// the timer count.
+#define syscall_get_time() make_syscall(SYS_get_time)
+// swap to a block device that holds a swap area.
+#define syscall_swapon(dev) make_syscall(SYS_swapon, (unsigned long)(dev))
+// how much swap there is and how much is used.
+#define syscall_swapinfo(info) make_syscall(SYS_swapinfo, (unsigned long)(info))
+
+static inline const char *syscall_name(unsigned long n)
+{
+	switch (n) {
+	case SYS_getcwd: return "getcwd";
+	case SYS_dup: return "dup";
+	case SYS_dup3: return "dup3";
+	case SYS_fcntl: return "fcntl";
+	case SYS_ioctl: return "ioctl";
+	case SYS_openat: return "openat";
+	case SYS_close: return "close";
+	case SYS_pipe2: return "pipe2";
+	case SYS_lseek: return "lseek";
+	case SYS_read: return "read";
+	case SYS_write: return "write";
+	case SYS_readv: return "readv";
+	case SYS_writev: return "writev";
+	case SYS_exit: return "exit";
+	case SYS_exit_group: return "exit_group";
+	case SYS_set_tid_address: return "set_tid_address";
+	case SYS_futex: return "futex";
+	case SYS_nanosleep: return "nanosleep";
+	case SYS_clock_gettime: return "clock_gettime";
+	case SYS_syslog: return "syslog";
+	case SYS_ptrace: return "ptrace";
+	case SYS_sched_yield: return "sched_yield";
+	case SYS_kill: return "kill";
+	case SYS_tkill: return "tkill";
+	case SYS_rt_sigaction: return "rt_sigaction";
+	case SYS_rt_sigprocmask: return "rt_sigprocmask";
+	case SYS_rt_sigreturn: return "rt_sigreturn";
+	case SYS_uname: return "uname";
+	case SYS_getrlimit: return "getrlimit";
+	case SYS_setrlimit: return "setrlimit";
+	case SYS_getrusage: return "getrusage";
+	case SYS_prctl: return "prctl";
+	case SYS_getpid: return "getpid";
+	case SYS_getppid: return "getppid";
+	case SYS_getuid: return "getuid";
+	case SYS_geteuid: return "geteuid";
+	case SYS_getgid: return "getgid";
+	case SYS_getegid: return "getegid";
+	case SYS_gettid: return "gettid";
+	case SYS_shmget: return "shmget";
+	case SYS_shmctl: return "shmctl";
+	case SYS_shmat: return "shmat";
+	case SYS_shmdt: return "shmdt";
+	case SYS_brk: return "brk";
+	case SYS_munmap: return "munmap";
+	case SYS_clone: return "clone";
+	case SYS_execve: return "execve";
+	case SYS_mmap: return "mmap";
+	case SYS_mprotect: return "mprotect";
+	case SYS_madvise: return "madvise";
+	case SYS_wait4: return "wait4";
+	case SYS_prlimit64: return "prlimit64";
+	case SYS_get_fb: return "get_fb";
+	case SYS_inv_rect: return "inv_rect";
+	case SYS_get_key: return "get_key";
+	case SYS_get_abs: return "get_abs";
+	case SYS_get_char: return "get_char";
+	case SYS_put_char: return "put_char";
+	case SYS_dump_registers: return "dump_registers";
+	case SYS_sleep: return "sleep";
+	case SYS_block_read: return "block_read";
+	case SYS_block_write: return "block_write";
+	case SYS_get_time: return "get_time";
+	case SYS_swapon: return "swapon";
+	case SYS_swapinfo: return "swapinfo";
+	default: return 0;
+	}
+}
+#endif
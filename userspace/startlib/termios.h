@@ -0,0 +1,22 @@
+#pragma once
+
+// The console's line discipline, switched with TCGETS/TCSETS. The layout is
+// the one Linux uses for riscv64, but only c_lflag is looked at.
+
+#define TCGETS 0x5401
+#define TCSETS 0x5402
+
+// c_lflag
+#define ISIG   0000001
+#define ICANON 0000002
+#define ECHO   0000010
+#define ECHOE  0000020
+
+struct termios {
+    unsigned int c_iflag;
+    unsigned int c_oflag;
+    unsigned int c_cflag;
+    unsigned int c_lflag;
+    unsigned char c_line;
+    unsigned char c_cc[19];
+};
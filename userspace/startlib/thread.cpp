@@ -0,0 +1,93 @@
+#include <syscall.h>
+#include <thread.h>
+
+static int futex_wait(volatile int *addr, int val)
+{
+	return syscall_futex(addr, FUTEX_WAIT | FUTEX_PRIVATE_FLAG, val);
+}
+
+static int futex_wake(volatile int *addr, int count)
+{
+	return syscall_futex(addr, FUTEX_WAKE | FUTEX_PRIVATE_FLAG, count);
+}
+
+int thread_create(thread_t *thread, void (*fn)(void *), void *arg)
+{
+	// A null stack asks the kernel for one.
+	long tid = thread_clone(
+		CLONE_VM | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD |
+		CLONE_PARENT_SETTID | CLONE_CHILD_CLEARTID,
+		0, &thread->tid, 0, &thread->tid, fn, arg);
+	return tid < 0 ? -1 : 0;
+}
+
+int thread_join(thread_t *thread)
+{
+	int tid;
+	while ((tid = thread->tid) != 0) {
+		futex_wait(&thread->tid, tid);
+	}
+	return 0;
+}
+
+// This is the mutex from Ulrich Drepper's "Futexes Are Tricky". Nobody
+// enters the kernel unless the lock is contended.
+void mutex_init(mutex_t *mutex)
+{
+	mutex->state = 0;
+}
+
+void mutex_lock(mutex_t *mutex)
+{
+	int c = 0;
+	if (__atomic_compare_exchange_n(&mutex->state, &c, 1, false, __ATOMIC_ACQUIRE, __ATOMIC_RELAXED)) {
+		return;
+	}
+	if (c != 2) {
+		c = __atomic_exchange_n(&mutex->state, 2, __ATOMIC_ACQUIRE);
+	}
+	while (c != 0) {
+		futex_wait(&mutex->state, 2);
+		c = __atomic_exchange_n(&mutex->state, 2, __ATOMIC_ACQUIRE);
+	}
+}
+
+bool mutex_trylock(mutex_t *mutex)
+{
+	int c = 0;
+	return __atomic_compare_exchange_n(&mutex->state, &c, 1, false, __ATOMIC_ACQUIRE, __ATOMIC_RELAXED);
+}
+
+void mutex_unlock(mutex_t *mutex)
+{
+	if (__atomic_fetch_sub(&mutex->state, 1, __ATOMIC_RELEASE) != 1) {
+		mutex->state = 0;
+		futex_wake(&mutex->state, 1);
+	}
+}
+
+void cond_init(cond_t *cond)
+{
+	cond->seq = 0;
+}
+
+void cond_wait(cond_t *cond, mutex_t *mutex)
+{
+	int seq = cond->seq;
+	mutex_unlock(mutex);
+	// If somebody signaled after we read seq, this returns right away.
+	futex_wait(&cond->seq, seq);
+	mutex_lock(mutex);
+}
+
+void cond_signal(cond_t *cond)
+{
+	__atomic_fetch_add(&cond->seq, 1, __ATOMIC_RELEASE);
+	futex_wake(&cond->seq, 1);
+}
+
+void cond_broadcast(cond_t *cond)
+{
+	__atomic_fetch_add(&cond->seq, 1, __ATOMIC_RELEASE);
+	futex_wake(&cond->seq, 0x7fffffff);
+}
@@ -0,0 +1,60 @@
+#pragma once
+
+// Threads, mutexes and condition variables on top of clone() and futex().
+// Threads share all memory with the process that created them, but each
+// one gets its own stack from the kernel.
+
+#define CLONE_VM             0x00000100
+#define CLONE_FILES          0x00000400
+#define CLONE_SIGHAND        0x00000800
+#define CLONE_THREAD         0x00010000
+#define CLONE_SETTLS         0x00080000
+#define CLONE_PARENT_SETTID  0x00100000
+#define CLONE_CHILD_CLEARTID 0x00200000
+
+#define FUTEX_WAIT           0
+#define FUTEX_WAKE           1
+#define FUTEX_PRIVATE_FLAG   128
+
+extern "C" {
+    long thread_clone(
+        unsigned long flags,
+        void *stack,
+        volatile int *ptid,
+        void *tls,
+        volatile int *ctid,
+        void (*fn)(void *),
+        void *arg
+    );
+}
+
+struct thread_t {
+    // The thread id. The kernel sets this back to 0 when the thread exits.
+    volatile int tid;
+};
+
+struct mutex_t {
+    // 0 = unlocked, 1 = locked, 2 = locked and somebody may be waiting
+    volatile int state;
+};
+
+struct cond_t {
+    // Bumped on every signal, so that waiters can tell they missed one.
+    volatile int seq;
+};
+
+#define MUTEX_INITIALIZER { 0 }
+#define COND_INITIALIZER  { 0 }
+
+int thread_create(thread_t *thread, void (*fn)(void *), void *arg);
+int thread_join(thread_t *thread);
+
+void mutex_init(mutex_t *mutex);
+void mutex_lock(mutex_t *mutex);
+bool mutex_trylock(mutex_t *mutex);
+void mutex_unlock(mutex_t *mutex);
+
+void cond_init(cond_t *cond);
+void cond_wait(cond_t *cond, mutex_t *mutex);
+void cond_signal(cond_t *cond);
+void cond_broadcast(cond_t *cond);
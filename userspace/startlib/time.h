@@ -0,0 +1,32 @@
+#pragma once
+
+// Reading the clock without a system call. The kernel maps a read-only
+// time page into every process (src/vdso.rs), and as long as it says the
+// time CSR may be read, clock_gettime() never leaves user mode.
+
+#define TIME_PAGE_ADDR  0x0fffe000
+#define TIME_PAGE_MAGIC 0x454d4954
+
+// time_page.flags: the time CSR can be read from user mode
+#define TIME_CSR        (1 << 0)
+
+struct time_page {
+    unsigned int magic;
+    unsigned int flags;
+    // Timer ticks per second
+    unsigned long freq;
+    // The timer at boot
+    unsigned long boot_time;
+};
+
+#define CLOCK_REALTIME  0
+#define CLOCK_MONOTONIC 1
+
+struct timespec {
+    long tv_sec;
+    long tv_nsec;
+};
+
+// The timer count, the same as syscall_get_time()
+unsigned long get_ticks();
+int clock_gettime(int clock, struct timespec *tp);
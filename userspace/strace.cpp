@@ -0,0 +1,104 @@
+#include <printf.h>
+#include <ptrace.h>
+#include <signal.h>
+#include <syscall.h>
+#include <thread.h>
+
+// Run a program and print every system call it makes. We don't get any
+// arguments, so we ask for the program. Since we can't fork, a thread of
+// ours asks to be traced and then becomes the program with exec.
+
+const int LINE_SIZE = 256;
+
+static char path[LINE_SIZE];
+static char *child_argv[] = { path, 0 };
+static volatile int traced = 0;
+
+static void child(void *)
+{
+	syscall_ptrace(PTRACE_TRACEME, 0, 0, 0);
+	traced = 1;
+	syscall_futex(&traced, FUTEX_WAKE | FUTEX_PRIVATE_FLAG, 1);
+	syscall_execve(path, child_argv, 0);
+	printf("strace: cannot run '%s'\n", path);
+}
+
+int main()
+{
+	printf("program: ");
+	long n = syscall_read(0, path, LINE_SIZE - 1);
+	if (n <= 0) {
+		return 1;
+	}
+	path[n] = 0;
+	if (path[n - 1] == '\n') {
+		path[n - 1] = 0;
+	}
+
+	thread_t thread;
+	if (thread_create(&thread, child, 0) < 0) {
+		printf("strace: cannot create a thread\n");
+		return 1;
+	}
+	// Nothing is traced until the thread says so, and wait4 would fail.
+	while (!traced) {
+		syscall_futex(&traced, FUTEX_WAIT | FUTEX_PRIVATE_FLAG, 0);
+	}
+
+	// The program starts in a SIGTRAP stop. Exec changes the PID, so
+	// we wait for anybody.
+	bool started = false;
+	bool entering = true;
+	for (;;) {
+		int status;
+		long pid = syscall_wait4(-1, &status, 0, 0);
+		if (pid < 0) {
+			break;
+		}
+		if (!WIFSTOPPED(status) && !entering) {
+			// The last system call never returned.
+			printf("\n");
+		}
+		if (WIFEXITED(status)) {
+			printf("+++ exited with %d +++\n", WEXITSTATUS(status));
+			break;
+		}
+		if (WIFSIGNALED(status)) {
+			printf("+++ killed by signal %d +++\n", WTERMSIG(status));
+			break;
+		}
+		int sig = WSTOPSIG(status);
+		if (sig == (SIGTRAP | 0x80)) {
+			struct user_regs_struct regs;
+			syscall_ptrace(PTRACE_GETREGS, pid, 0, &regs);
+			if (entering) {
+				const char *name = syscall_name(regs.a7);
+				if (name) {
+					printf("[%ld] %s(0x%lx, 0x%lx, 0x%lx)", pid, name, regs.a0, regs.a1, regs.a2);
+				}
+				else {
+					printf("[%ld] syscall_%lu(0x%lx, 0x%lx, 0x%lx)", pid, regs.a7, regs.a0, regs.a1, regs.a2);
+				}
+			}
+			else {
+				printf(" = %ld\n", (long)regs.a0);
+			}
+			entering = !entering;
+			sig = 0;
+		}
+		else if (sig == SIGTRAP && !started) {
+			// The stop right after exec. From now on we want to see
+			// system calls, told apart from real SIGTRAPs.
+			syscall_ptrace(PTRACE_SETOPTIONS, pid, 0, PTRACE_O_TRACESYSGOOD);
+			started = true;
+			sig = 0;
+		}
+		else {
+			printf("--- signal %d ---\n", sig);
+		}
+		// Whatever signal it stopped for goes on to the program.
+		syscall_ptrace(PTRACE_SYSCALL, pid, 0, sig);
+	}
+	thread_join(&thread);
+	return 0;
+}